@@ -28,8 +28,7 @@
 //!
 
 use clap::Parser;
-use etherparse::{IcmpEchoHeader, Icmpv4Header};
-use ipstack::{stream::IpStackStream, IpNumber};
+use ipstack::stream::IpStackStream;
 use std::net::{Ipv4Addr, SocketAddr};
 use tokio::net::TcpStream;
 use udp_stream::UdpStream;
@@ -96,10 +95,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         p_cfg.device_guid(12324323423423434234_u128);
     });
 
-    let mut ipstack_config = ipstack::IpStackConfig::default();
-    ipstack_config.mtu(MTU);
-    ipstack_config.tcp_timeout(std::time::Duration::from_secs(args.tcp_timeout));
-    ipstack_config.udp_timeout(std::time::Duration::from_secs(args.udp_timeout));
+    let ipstack_config = ipstack::IpStackConfig::builder()
+        .mtu(MTU)
+        .tcp_timeout(std::time::Duration::from_secs(args.tcp_timeout))
+        .udp_timeout(std::time::Duration::from_secs(args.udp_timeout))
+        .build()?;
 
     let mut ip_stack = ipstack::IpStack::new(ipstack_config, tun::create_as_async(&tun_config)?);
 
@@ -150,27 +150,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     log::info!("#{number2} UDP closed, session count {c}");
                 });
             }
+            IpStackStream::UdpBroadcast(b) => {
+                log::info!("#{number} UDP broadcast to {}", b.peer_addr());
+                continue;
+            }
+            IpStackStream::Dns(query) => {
+                log::info!("#{number} DNS query for {}", query.question().name());
+                continue;
+            }
+            IpStackStream::Icmp(icmp) => {
+                log::info!("#{number} ICMP echo");
+                icmp.reply()?;
+                continue;
+            }
+            IpStackStream::Ndp(ndp) => {
+                log::info!("#{number} NDP {:?}", ndp.message());
+                continue;
+            }
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Gre(gre) => {
+                log::info!("#{number} GRE to {}", gre.transport().dst_addr());
+                continue;
+            }
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Esp(esp) => {
+                log::info!("#{number} ESP SPI {:#x}", esp.header().spi);
+                continue;
+            }
             IpStackStream::UnknownTransport(u) => {
-                let n = number;
-                if u.src_addr().is_ipv4() && u.ip_protocol() == IpNumber::ICMP {
-                    let (icmp_header, req_payload) = Icmpv4Header::from_slice(u.payload())?;
-                    if let etherparse::Icmpv4Type::EchoRequest(req) = icmp_header.icmp_type {
-                        log::info!("#{n} ICMPv4 echo");
-                        let echo = IcmpEchoHeader {
-                            id: req.id,
-                            seq: req.seq,
-                        };
-                        let mut resp = Icmpv4Header::new(etherparse::Icmpv4Type::EchoReply(echo));
-                        resp.update_checksum(req_payload);
-                        let mut payload = resp.to_bytes().to_vec();
-                        payload.extend_from_slice(req_payload);
-                        u.send(payload)?;
-                    } else {
-                        log::info!("#{n} ICMPv4");
-                    }
-                    continue;
-                }
-                log::info!("#{n} unknown transport - Ip Protocol {:?}", u.ip_protocol());
+                log::info!(
+                    "#{number} unknown transport - Ip Protocol {:?}",
+                    u.ip_protocol()
+                );
                 continue;
             }
             IpStackStream::UnknownNetwork(pkt) => {
@@ -1,8 +1,7 @@
 use std::net::{Ipv4Addr, SocketAddr};
 
 use clap::Parser;
-use etherparse::{IcmpEchoHeader, Icmpv4Header};
-use ipstack::{stream::IpStackStream, IpNumber};
+use ipstack::stream::IpStackStream;
 use tokio::net::TcpStream;
 use udp_stream::UdpStream;
 
@@ -83,25 +82,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("==== end UDP connection ====");
                 });
             }
+            IpStackStream::UdpBroadcast(b) => {
+                println!("UDP broadcast to {}", b.peer_addr());
+                continue;
+            }
+            IpStackStream::Dns(query) => {
+                println!("DNS query for {}", query.question().name());
+                continue;
+            }
+            IpStackStream::Icmp(icmp) => {
+                println!("ICMP echo");
+                icmp.reply()?;
+                continue;
+            }
+            IpStackStream::Ndp(ndp) => {
+                println!("NDP {:?}", ndp.message());
+                continue;
+            }
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Gre(gre) => {
+                println!("GRE to {}", gre.transport().dst_addr());
+                continue;
+            }
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Esp(esp) => {
+                println!("ESP SPI {:#x}", esp.header().spi);
+                continue;
+            }
             IpStackStream::UnknownTransport(u) => {
-                if u.src_addr().is_ipv4() && u.ip_protocol() == IpNumber::ICMP {
-                    let (icmp_header, req_payload) = Icmpv4Header::from_slice(u.payload())?;
-                    if let etherparse::Icmpv4Type::EchoRequest(req) = icmp_header.icmp_type {
-                        println!("ICMPv4 echo");
-                        let echo = IcmpEchoHeader {
-                            id: req.id,
-                            seq: req.seq,
-                        };
-                        let mut resp = Icmpv4Header::new(etherparse::Icmpv4Type::EchoReply(echo));
-                        resp.update_checksum(req_payload);
-                        let mut payload = resp.to_bytes().to_vec();
-                        payload.extend_from_slice(req_payload);
-                        u.send(payload)?;
-                    } else {
-                        println!("ICMPv4");
-                    }
-                    continue;
-                }
                 println!("unknown transport - Ip Protocol {:?}", u.ip_protocol());
                 continue;
             }
@@ -0,0 +1,440 @@
+//! Capturing the packets the stack exchanges with the device and replaying captures, for
+//! debugging protocol issues. Enabled by the `capture` feature.
+
+use crate::{Direction, PacketDevice};
+use log::warn;
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NS: u32 = 0xa1b2_3c4d;
+const EPB_FLAGS: u16 = 2;
+const INBOUND: u32 = 1;
+const OUTBOUND: u32 = 2;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+
+/// A packet the stack exchanged with the device, as sent to [`CaptureSink::Channel`].
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// `Ingress` for packets from the device, `Egress` for packets to it.
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    /// The IP packet, without packet information or a link-layer header.
+    pub data: Vec<u8>,
+}
+
+/// Where [`IpStackConfig::with_capture`](crate::IpStackConfig::with_capture) mirrors the
+/// packets to.
+pub enum CaptureSink {
+    /// A pcapng file with the direction of every packet, as Wireshark reads it. Written
+    /// from the dispatcher, so it should not block for long, like a buffered file.
+    Pcapng(Box<dyn Write + Send>),
+    Channel(UnboundedSender<CapturedPacket>),
+}
+
+/// The capture of a stack, set with
+/// [`IpStackConfig::with_capture`](crate::IpStackConfig::with_capture).
+pub struct Capture {
+    sink: Mutex<Sink>,
+}
+
+enum Sink {
+    Pcapng {
+        writer: Box<dyn Write + Send>,
+        started: bool,
+    },
+    Channel(UnboundedSender<CapturedPacket>),
+    /// The writer failed or the receiver was dropped.
+    Closed,
+}
+
+impl Capture {
+    pub(crate) fn new(sink: CaptureSink) -> Self {
+        let sink = match sink {
+            CaptureSink::Pcapng(writer) => Sink::Pcapng {
+                writer,
+                started: false,
+            },
+            CaptureSink::Channel(sender) => Sink::Channel(sender),
+        };
+        Capture {
+            sink: Mutex::new(sink),
+        }
+    }
+
+    /// Mirrors the IP packet `data` passing the stack in `direction`.
+    pub(crate) fn record(&self, direction: Direction, data: &[u8]) {
+        let mut sink = self.sink.lock().unwrap();
+        let timestamp = SystemTime::now();
+        let open = match &mut *sink {
+            Sink::Pcapng { writer, started } => {
+                let result = match *started {
+                    true => Ok(()),
+                    false => write_pcapng_header(writer),
+                };
+                *started = true;
+                let result =
+                    result.and_then(|()| write_pcapng_packet(writer, direction, timestamp, data));
+                if let Err(err) = &result {
+                    warn!("writing the capture failed: {err}");
+                }
+                result.is_ok()
+            }
+            Sink::Channel(sender) => sender
+                .send(CapturedPacket {
+                    direction,
+                    timestamp,
+                    data: data.to_vec(),
+                })
+                .is_ok(),
+            Sink::Closed => true,
+        };
+        if !open {
+            *sink = Sink::Closed;
+        }
+    }
+}
+
+fn write_pcapng_header(writer: &mut dyn Write) -> io::Result<()> {
+    let mut block = Vec::with_capacity(48);
+    block.extend_from_slice(&SECTION_HEADER.to_le_bytes());
+    block.extend_from_slice(&28_u32.to_le_bytes());
+    block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    block.extend_from_slice(&1_u16.to_le_bytes());
+    block.extend_from_slice(&0_u16.to_le_bytes());
+    block.extend_from_slice(&(-1_i64).to_le_bytes()); // section length unknown
+    block.extend_from_slice(&28_u32.to_le_bytes());
+    block.extend_from_slice(&INTERFACE_DESCRIPTION.to_le_bytes());
+    block.extend_from_slice(&20_u32.to_le_bytes());
+    block.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    block.extend_from_slice(&0_u16.to_le_bytes());
+    block.extend_from_slice(&0_u32.to_le_bytes()); // no snapshot length
+    block.extend_from_slice(&20_u32.to_le_bytes());
+    writer.write_all(&block)
+}
+
+fn write_pcapng_packet(
+    writer: &mut dyn Write,
+    direction: Direction,
+    timestamp: SystemTime,
+    data: &[u8],
+) -> io::Result<()> {
+    let micros = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let padded = data.len().next_multiple_of(4);
+    // The block, the packet and the flags option with the end of options
+    let len = (28 + padded + 12 + 4) as u32;
+    let flags = match direction {
+        Direction::Ingress => INBOUND,
+        Direction::Egress => OUTBOUND,
+    };
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&ENHANCED_PACKET.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(&0_u32.to_le_bytes()); // the interface
+    block.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(micros as u32).to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    block.extend_from_slice(data);
+    block.resize(28 + padded, 0);
+    block.extend_from_slice(&EPB_FLAGS.to_le_bytes());
+    block.extend_from_slice(&4_u16.to_le_bytes());
+    block.extend_from_slice(&flags.to_le_bytes());
+    block.extend_from_slice(&[0; 4]);
+    block.extend_from_slice(&len.to_le_bytes());
+    writer.write_all(&block)
+}
+
+/// A [`PacketDevice`] replaying the packets to the stack in a pcap or pcapng capture, with
+/// their original timing. Packets marked as outbound and those the stack sends are
+/// dropped. Once all are replayed the device stays silent. Captures of raw IP and of
+/// Ethernet are understood, the stack is to be configured without packet information.
+#[derive(Debug)]
+pub struct PcapReplayDevice {
+    packets: VecDeque<(Duration, Vec<u8>)>,
+    start: Option<Instant>,
+}
+
+impl PcapReplayDevice {
+    /// Reads the capture at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Parses the capture in `capture`, failing with `InvalidData` if it is malformed.
+    pub fn from_bytes(capture: &[u8]) -> io::Result<Self> {
+        let packets = parse_capture(capture).ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a pcap or pcapng capture of IP packets",
+        ))?;
+        Ok(PcapReplayDevice {
+            packets,
+            start: None,
+        })
+    }
+}
+
+impl PacketDevice for PcapReplayDevice {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let Some((offset, _)) = self.packets.front() else {
+            return std::future::pending().await;
+        };
+        // The packet stays queued if this is cancelled while waiting for it
+        tokio::time::sleep_until(start + *offset).await;
+        let (_, packet) = self.packets.pop_front().unwrap();
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    async fn send(&mut self, _packet: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The inbound IP packets in `capture` with their time since the first packet.
+fn parse_capture(capture: &[u8]) -> Option<VecDeque<(Duration, Vec<u8>)>> {
+    let magic = capture.get(..4)?;
+    let mut packets = Vec::new();
+    if u32::from_le_bytes(magic.try_into().ok()?) == SECTION_HEADER {
+        parse_pcapng(capture, &mut packets)?;
+    } else {
+        parse_pcap(capture, &mut packets)?;
+    }
+    let first = packets
+        .iter()
+        .map(|(time, _)| *time)
+        .min()
+        .unwrap_or_default();
+    Some(
+        packets
+            .into_iter()
+            .map(|(time, packet)| (time.saturating_sub(first), packet))
+            .collect(),
+    )
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.buf.get(at..at + 2)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.buf.get(at..at + 4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+fn parse_pcap(capture: &[u8], packets: &mut Vec<(Duration, Vec<u8>)>) -> Option<()> {
+    let magic = u32::from_le_bytes(capture.get(..4)?.try_into().ok()?);
+    let (big_endian, nanos) = match magic {
+        PCAP_MAGIC => (false, false),
+        PCAP_MAGIC_NS => (false, true),
+        _ => match magic.swap_bytes() {
+            PCAP_MAGIC => (true, false),
+            PCAP_MAGIC_NS => (true, true),
+            _ => return None,
+        },
+    };
+    let reader = Reader {
+        buf: capture,
+        big_endian,
+    };
+    let link_type = reader.u32(20)? as u16;
+    let mut at = 24;
+    while at < capture.len() {
+        let (secs, fraction) = (reader.u32(at)?, reader.u32(at + 4)?);
+        let captured = reader.u32(at + 8)? as usize;
+        let data = capture.get(at + 16..at + 16 + captured)?;
+        let fraction = match nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+        if let Some(packet) = ip_packet(link_type, data) {
+            packets.push((Duration::from_secs(secs as u64) + fraction, packet.to_vec()));
+        }
+        at += 16 + captured;
+    }
+    Some(())
+}
+
+fn parse_pcapng(capture: &[u8], packets: &mut Vec<(Duration, Vec<u8>)>) -> Option<()> {
+    let mut reader = Reader {
+        buf: capture,
+        big_endian: false,
+    };
+    // The link type and timestamp resolution of every interface of the current section
+    let mut interfaces: Vec<(u16, u64)> = Vec::new();
+    let mut at = 0;
+    while at < capture.len() {
+        let block_type = reader.u32(at)?;
+        if block_type == SECTION_HEADER {
+            reader.big_endian = reader.u32(at + 8)? != BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let len = reader.u32(at + 4)? as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return None;
+        }
+        let block = capture.get(at..at + len)?;
+        match block_type {
+            INTERFACE_DESCRIPTION => {
+                let link_type = reader.u16(at + 8)?;
+                let resolution = interface_resolution(&reader, at + 16, at + len - 4)?;
+                interfaces.push((link_type, resolution));
+            }
+            ENHANCED_PACKET if len >= 32 => {
+                let &(link_type, resolution) = interfaces.get(reader.u32(at + 8)? as usize)?;
+                let ticks = ((reader.u32(at + 12)? as u64) << 32) | reader.u32(at + 16)? as u64;
+                let captured = reader.u32(at + 20)? as usize;
+                let data = block.get(28..28 + captured)?;
+                let options = 28 + captured.next_multiple_of(4);
+                let outbound = packet_flags(&reader, at + options, at + len - 4)
+                    .is_some_and(|flags| flags & 0x3 == OUTBOUND);
+                let time = Duration::from_nanos(
+                    (ticks as u128 * 1_000_000_000 / resolution as u128) as u64,
+                );
+                match ip_packet(link_type, data) {
+                    Some(packet) if !outbound => packets.push((time, packet.to_vec())),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    Some(())
+}
+
+/// The ticks per second of the timestamps of an interface, from its options between
+/// `at` and `end`.
+fn interface_resolution(reader: &Reader<'_>, mut at: usize, end: usize) -> Option<u64> {
+    while at + 4 <= end {
+        let (code, len) = (reader.u16(at)?, reader.u16(at + 2)? as usize);
+        if code == 0 {
+            break;
+        }
+        if code == 9 {
+            // if_tsresol, a power of 2 if the high bit is set, of 10 otherwise
+            let resolution = *reader.buf.get(at + 4)?;
+            let exponent = (resolution & 0x7f) as u32;
+            return match resolution & 0x80 {
+                0 => 10_u64.checked_pow(exponent),
+                _ => 2_u64.checked_pow(exponent),
+            };
+        }
+        at += 4 + len.next_multiple_of(4);
+    }
+    Some(1_000_000)
+}
+
+/// The epb_flags of an enhanced packet block, from its options between `at` and `end`.
+fn packet_flags(reader: &Reader<'_>, mut at: usize, end: usize) -> Option<u32> {
+    while at + 4 <= end {
+        let (code, len) = (reader.u16(at)?, reader.u16(at + 2)? as usize);
+        match code {
+            0 => break,
+            EPB_FLAGS => return reader.u32(at + 4),
+            _ => at += 4 + len.next_multiple_of(4),
+        }
+    }
+    None
+}
+
+/// The IP packet in a captured frame of `link_type`.
+fn ip_packet(link_type: u16, data: &[u8]) -> Option<&[u8]> {
+    match link_type {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(data),
+        LINKTYPE_ETHERNET => match u16::from_be_bytes(data.get(12..14)?.try_into().ok()?) {
+            0x0800 | 0x86dd => data.get(14..),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcapng_round_trip() {
+        let mut file = Vec::new();
+        write_pcapng_header(&mut file).unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let packets = [
+            (Direction::Ingress, Duration::ZERO, &[0x45, 1, 2][..]),
+            (Direction::Egress, Duration::from_millis(5), &[0x45, 3][..]),
+            (
+                Direction::Ingress,
+                Duration::from_millis(20),
+                &[0x60, 4, 5, 6, 7][..],
+            ),
+        ];
+        for (direction, offset, data) in packets {
+            write_pcapng_packet(&mut file, direction, start + offset, data).unwrap();
+        }
+        let replayed = parse_capture(&file).unwrap();
+        assert_eq!(
+            replayed,
+            [
+                (Duration::ZERO, vec![0x45, 1, 2]),
+                (Duration::from_millis(20), vec![0x60, 4, 5, 6, 7]),
+            ]
+        );
+        assert!(parse_capture(&file[..file.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn pcap() {
+        let mut file = Vec::new();
+        for field in [
+            PCAP_MAGIC,
+            0x0004_0002,
+            0,
+            0,
+            65535,
+            LINKTYPE_ETHERNET as u32,
+        ] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 9]);
+        for field in [10_u32, 500, frame.len() as u32, frame.len() as u32] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        file.extend_from_slice(&frame);
+        let replayed = parse_capture(&file).unwrap();
+        assert_eq!(replayed, [(Duration::ZERO, vec![0x45, 9])]);
+    }
+}
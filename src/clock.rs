@@ -0,0 +1,112 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+use tokio::time::Instant;
+
+/// A future that resolves once a deadline is reached.
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for the TCP timers: idle timeout, retransmission, persist, pacing and
+/// TIME_WAIT. Replacing it allows running the stack against a simulated clock.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+    /// A future resolving at `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> SleepFuture;
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// The default clock, backed by `tokio::time`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> SleepFuture {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A resettable timer on a [`Clock`]. A reset only records the deadline, the sleep future
+/// is replaced lazily, so timers that are pushed back on every segment do not allocate.
+/// When no pending sleep covers the new deadline, the last task that polled is woken to
+/// poll again, like a reset `tokio::time::Sleep` keeps its registration.
+pub(crate) struct Timer {
+    clock: Arc<dyn Clock>,
+    deadline: Instant,
+    sleep: Option<(Instant, SleepFuture)>,
+    waker: Option<Waker>,
+}
+
+impl Timer {
+    pub(crate) fn new(clock: Arc<dyn Clock>, deadline: Instant) -> Self {
+        Timer {
+            clock,
+            deadline,
+            sleep: None,
+            waker: None,
+        }
+    }
+
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub(crate) fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        if self.sleep.as_ref().is_none_or(|(at, _)| *at > deadline) {
+            if let Some(waker) = &self.waker {
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    /// Resolves once the deadline is reached, `cx` is woken then.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            self.waker = Some(cx.waker().clone());
+        }
+        if self.clock.now() >= self.deadline {
+            self.sleep = None;
+            return Poll::Ready(());
+        }
+        if let Some((at, sleep)) = self.sleep.as_mut() {
+            // A sleep for an earlier deadline still wakes us in time to re-arm
+            if *at <= self.deadline {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                if *at == self.deadline {
+                    self.sleep = None;
+                    return Poll::Ready(());
+                }
+            }
+        }
+        let mut sleep = self.clock.sleep_until(self.deadline);
+        if sleep.as_mut().poll(cx).is_ready() {
+            self.sleep = None;
+            return Poll::Ready(());
+        }
+        self.sleep = Some((self.deadline, sleep));
+        Poll::Pending
+    }
+}
+
+impl std::fmt::Debug for Timer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Timer")
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
@@ -0,0 +1,65 @@
+use std::{future::Future, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A device exchanging whole IP packets with the stack, given to
+/// [`IpStack::with_device`](crate::IpStack::with_device).
+pub trait PacketDevice: Send + 'static {
+    /// Receives the next packet into `buf` and returns its length. An error stops the stack,
+    /// [`IpStack::driver`](crate::IpStack::driver) resolves with it. The stack waits for it
+    /// alongside its other work, so the future has to be cancel safe: dropping it before
+    /// it resolved must not lose a packet.
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Sends the packet `packet`, as a whole.
+    fn send(&mut self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Receives up to `bufs.len()` packets at once, the i-th into `bufs[i]` with its length
+    /// in `lens[i]`, and returns how many, at least one. Devices able to, like a TUN read
+    /// with `recvmmsg`, save a wakeup per packet. By default one packet is received with
+    /// [`recv`](Self::recv). Has to be cancel safe like it.
+    fn recv_many(
+        &mut self,
+        bufs: &mut [Vec<u8>],
+        lens: &mut [usize],
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        async move {
+            lens[0] = self.recv(&mut bufs[0]).await?;
+            Ok(1)
+        }
+    }
+
+    /// Sends the packets `packets`, in order. By default each is sent with
+    /// [`send`](Self::send).
+    fn send_many(&mut self, packets: &[Vec<u8>]) -> impl Future<Output = io::Result<()>> + Send {
+        async move {
+            for packet in packets {
+                self.send(packet).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A [`PacketDevice`] over a device read and written as a byte stream, like the file
+/// descriptor of a TUN device that returns one packet per read.
+#[derive(Debug)]
+pub struct StreamDevice<D>(pub D);
+
+impl<D> PacketDevice for StreamDevice<D>
+where
+    D: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf).await? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the device was closed",
+            )),
+            n => Ok(n),
+        }
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.0.write_all(packet).await
+    }
+}
@@ -0,0 +1,257 @@
+//! Interception of plain DNS queries, enabled by
+//! [`IpStackConfig::intercept_dns`](crate::IpStackConfig::intercept_dns).
+
+use crate::{
+    egress::EgressSender,
+    stream::{send_datagram, IpStackStream, IpStackTcpStream, SendOptions},
+};
+use bytes::Bytes;
+use log::trace;
+use std::net::{IpAddr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc::{self, UnboundedSender},
+};
+
+/// The record type of an IPv4 address.
+pub const TYPE_A: u16 = 1;
+/// The record type of an IPv6 address.
+pub const TYPE_AAAA: u16 = 28;
+/// The Internet class.
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+
+/// The question of a [`DnsQuery`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl DnsQuestion {
+    /// The queried name in lowercase without the trailing dot, empty for the root.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+    pub fn qclass(&self) -> u16 {
+        self.qclass
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Udp {
+        pkt_sender: EgressSender,
+        send: SendOptions,
+    },
+    Tcp(UnboundedSender<Bytes>), // to the task writing responses to the connection
+}
+
+/// A query the device sent to an intercepted DNS port over UDP or TCP, answered on the same
+/// transport by [`respond`](Self::respond) instead of reaching a resolver.
+#[derive(Debug)]
+pub struct DnsQuery {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    message: Bytes,
+    question: DnsQuestion,
+    question_end: usize, // offset of the first byte after the question in `message`
+    reply: Reply,
+}
+
+impl DnsQuery {
+    /// Parses `message` as a standard query with one question, `None` for anything else.
+    fn new(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        message: Bytes,
+        reply: Reply,
+    ) -> Option<Self> {
+        let (question, question_end) = parse_query(&message)?;
+        Some(DnsQuery {
+            src_addr,
+            dst_addr,
+            message,
+            question,
+            question_end,
+            reply,
+        })
+    }
+
+    pub(crate) fn udp(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        message: Bytes,
+        pkt_sender: EgressSender,
+        send: SendOptions,
+    ) -> Option<Self> {
+        DnsQuery::new(src_addr, dst_addr, message, Reply::Udp { pkt_sender, send })
+    }
+
+    /// The client on the device side.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.src_addr
+    }
+
+    /// The resolver the query was sent to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.dst_addr
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes([self.message[0], self.message[1]])
+    }
+
+    pub fn question(&self) -> &DnsQuestion {
+        &self.question
+    }
+
+    /// The whole query message, without the length prefix of TCP.
+    pub fn message(&self) -> &Bytes {
+        &self.message
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.reply, Reply::Tcp(_))
+    }
+
+    /// Sends `response` to the client as is, its ID should be the query's. Fails with
+    /// `NotConnected` once a TCP connection is gone.
+    pub fn respond(self, response: Bytes) -> std::io::Result<()> {
+        match self.reply {
+            Reply::Udp { pkt_sender, send } => {
+                send_datagram(&pkt_sender, self.dst_addr, self.src_addr, &send, response)
+            }
+            Reply::Tcp(sender) => {
+                if response.len() > u16::MAX as usize {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "DNS message longer than 65535 bytes",
+                    ));
+                }
+                sender
+                    .send(response)
+                    .or(Err(std::io::ErrorKind::NotConnected.into()))
+            }
+        }
+    }
+
+    /// Answers with the addresses of the queried type, IPv4 for A and IPv6 for AAAA, leaving
+    /// out the others. An empty answer tells the client the name has no such records.
+    pub fn respond_addrs(self, addrs: &[IpAddr], ttl: u32) -> std::io::Result<()> {
+        let response = self.answer(addrs, ttl);
+        self.respond(response.into())
+    }
+
+    fn answer(&self, addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+        let records: Vec<Vec<u8>> = addrs
+            .iter()
+            .filter_map(
+                |addr| match (addr, self.question.qtype, self.question.qclass) {
+                    (IpAddr::V4(addr), TYPE_A, CLASS_IN) => Some(addr.octets().to_vec()),
+                    (IpAddr::V6(addr), TYPE_AAAA, CLASS_IN) => Some(addr.octets().to_vec()),
+                    _ => None,
+                },
+            )
+            .collect();
+        let mut response = Vec::with_capacity(self.question_end + records.len() * 28);
+        response.extend_from_slice(&self.message[..2]);
+        // QR with the query's RD, then RA and no error
+        response.extend_from_slice(&[0x80 | (self.message[2] & 0x01), 0x80]);
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0; 4]);
+        response.extend_from_slice(&self.message[HEADER_LEN..self.question_end]);
+        for rdata in records {
+            // The owner is the question's name, pointed to at the end of the header
+            response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            response.extend_from_slice(&self.question.qtype.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&ttl.to_be_bytes());
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(&rdata);
+        }
+        response
+    }
+}
+
+/// The question of a standard query (QR and opcode zero) with exactly one, and the offset
+/// after it.
+fn parse_query(message: &[u8]) -> Option<(DnsQuestion, usize)> {
+    let header = message.get(..HEADER_LEN)?;
+    if header[2] & 0xf8 != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *message.get(pos)? as usize;
+        pos += 1;
+        match len {
+            0 => break,
+            // The first name of a message has nothing to point back to
+            1..=63 => {
+                let label = std::str::from_utf8(message.get(pos..pos + len)?).ok()?;
+                labels.push(label.to_ascii_lowercase());
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+    let fields = message.get(pos..pos + 4)?;
+    let question = DnsQuestion {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+    };
+    Some((question, pos + 4))
+}
+
+/// Reads the length-prefixed queries of a DNS connection and accepts each as a
+/// [`DnsQuery`], writing their responses back. The connection is closed after a message
+/// that is not a query.
+pub(crate) fn serve_tcp(stream: IpStackTcpStream, accept_sender: UnboundedSender<IpStackStream>) {
+    let (src_addr, dst_addr) = (stream.local_addr(), stream.peer_addr());
+    let (mut reader, mut writer) = stream.into_split();
+    let (reply, mut replies) = mpsc::unbounded_channel::<Bytes>();
+    tokio::spawn(async move {
+        // Ends once the reader and every query answered or dropped
+        while let Some(response) = replies.recv().await {
+            let mut message = Vec::with_capacity(2 + response.len());
+            message.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            message.extend_from_slice(&response);
+            if writer.write_all(&message).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+    tokio::spawn(async move {
+        loop {
+            let mut len = [0; 2];
+            if reader.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut message = vec![0; u16::from_be_bytes(len) as usize];
+            if reader.read_exact(&mut message).await.is_err() {
+                return;
+            }
+            let reply = Reply::Tcp(reply.clone());
+            let Some(query) = DnsQuery::new(src_addr, dst_addr, message.into(), reply) else {
+                trace!(
+                    "closing DNS connection of {} on a malformed query",
+                    src_addr
+                );
+                return;
+            };
+            if accept_sender.send(IpStackStream::Dns(query)).is_err() {
+                return;
+            }
+        }
+    });
+}
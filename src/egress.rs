@@ -0,0 +1,128 @@
+use crate::{limiter::IcmpLimiter, packet::NetworkPacket, IpStackMetrics};
+use std::{
+    sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+use tokio::sync::mpsc::{self, error::SendError, UnboundedReceiver, UnboundedSender};
+
+/// The queue of packets waiting to be written to the device. Sending never fails for being
+/// full, so ACKs and resets always get out, but TCP data waits in `poll_ready` until the
+/// device catches up. ICMP messages the stack generates are subject to `icmp`, and all
+/// packets have to fit `mtu`, which [`IpStack::set_mtu`](crate::IpStack::set_mtu) changes.
+pub(crate) fn channel(
+    capacity: usize,
+    mtu: u16,
+    icmp: IcmpLimiter,
+    metrics: Arc<IpStackMetrics>,
+) -> (EgressSender, EgressReceiver) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queue = Arc::new(Queue {
+        len: AtomicUsize::new(0),
+        capacity,
+        mtu: AtomicU16::new(mtu),
+        wakers: Mutex::new(Vec::new()),
+    });
+    (
+        EgressSender {
+            sender,
+            queue: queue.clone(),
+            icmp,
+            metrics,
+        },
+        EgressReceiver { receiver, queue },
+    )
+}
+
+#[derive(Debug)]
+struct Queue {
+    len: AtomicUsize,
+    capacity: usize,
+    mtu: AtomicU16,            // of the device
+    wakers: Mutex<Vec<Waker>>, // senders waiting for the queue to drop below capacity
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct EgressSender {
+    sender: UnboundedSender<NetworkPacket>,
+    queue: Arc<Queue>,
+    icmp: IcmpLimiter,
+    metrics: Arc<IpStackMetrics>,
+}
+
+impl EgressSender {
+    pub(crate) fn send(&self, packet: NetworkPacket) -> Result<(), SendError<()>> {
+        self.queue.len.fetch_add(1, Ordering::SeqCst);
+        self.sender.send(packet).map_err(|_| {
+            self.queue.len.fetch_sub(1, Ordering::SeqCst);
+            SendError(())
+        })
+    }
+
+    /// The MTU of the device, the largest packet to send.
+    pub(crate) fn mtu(&self) -> u16 {
+        self.queue.mtu.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_mtu(&self, mtu: u16) {
+        self.queue.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// The counters of the stack, for the streams to count into.
+    pub(crate) fn metrics(&self) -> &IpStackMetrics {
+        &self.metrics
+    }
+
+    /// Whether the ICMP rate limit lets a generated ICMP message out, consuming a token if
+    /// so. A message over the limit is dropped.
+    pub(crate) fn allow_icmp(&self) -> bool {
+        self.icmp.allow()
+    }
+
+    /// Resolves once the queue has room, `cx` is woken when the device drained it.
+    pub(crate) fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.queue.len.load(Ordering::SeqCst) < self.queue.capacity {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.queue.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        drop(wakers);
+        // The receiver may have drained the queue before the waker was registered
+        if self.queue.len.load(Ordering::SeqCst) < self.queue.capacity {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EgressReceiver {
+    receiver: UnboundedReceiver<NetworkPacket>,
+    queue: Arc<Queue>,
+}
+
+impl EgressReceiver {
+    pub(crate) async fn recv(&mut self) -> Option<NetworkPacket> {
+        let packet = self.receiver.recv().await?;
+        self.dequeued();
+        Some(packet)
+    }
+
+    /// The next queued packet without waiting for one.
+    pub(crate) fn try_recv(&mut self) -> Option<NetworkPacket> {
+        let packet = self.receiver.try_recv().ok()?;
+        self.dequeued();
+        Some(packet)
+    }
+
+    fn dequeued(&self) {
+        if self.queue.len.fetch_sub(1, Ordering::SeqCst) == self.queue.capacity {
+            let wakers = std::mem::take(&mut *self.queue.wakers.lock().unwrap());
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
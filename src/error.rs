@@ -1,4 +1,4 @@
-#[allow(dead_code)]
+#[allow(dead_code, clippy::large_enum_variant)]
 #[derive(thiserror::Error, Debug)]
 pub enum IpStackError {
     #[error("The transport protocol is not supported")]
@@ -13,12 +13,18 @@ pub enum IpStackError {
     #[error("ValueTooBigError<usize> {0}")]
     ValueTooBigErrorUsize(#[from] etherparse::err::ValueTooBigError<usize>),
 
+    #[error("TcpOptionWriteError {0}")]
+    TcpOptionWriteError(#[from] etherparse::TcpOptionWriteError),
+
     #[error("Invalid Tcp packet")]
     InvalidTcpPacket,
 
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(&'static str),
+
     #[error("Accept Error")]
     AcceptError,
 
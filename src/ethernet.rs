@@ -0,0 +1,209 @@
+use crate::IpStackConfig;
+use ahash::AHashMap;
+use log::trace;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const ARP_LEN: usize = 28; // for Ethernet and IPv4
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const BROADCAST: [u8; 6] = [0xff; 6];
+const MAX_NEIGHBORS: usize = 4096;
+
+/// What the stack does with a frame from a TAP device.
+pub(crate) enum Frame<'a> {
+    /// The IP packet the frame carries.
+    Ip(&'a [u8]),
+    /// The frame was an ARP request for the stack, answered by this frame.
+    Reply(Vec<u8>),
+    Ignore,
+}
+
+/// The Ethernet link of a TAP device set with [`IpStackConfig::ethernet`]: the stack's MAC
+/// address, and the MAC addresses of the hosts that sent to it.
+#[derive(Debug)]
+pub(crate) struct Ethernet {
+    mac: [u8; 6],
+    arp_gateway: Option<Ipv4Addr>,
+    neighbors: AHashMap<IpAddr, [u8; 6]>,
+}
+
+impl Ethernet {
+    pub(crate) fn new(config: &IpStackConfig) -> Option<Self> {
+        Some(Ethernet {
+            mac: config.ethernet?,
+            arp_gateway: config.arp_gateway,
+            neighbors: AHashMap::new(),
+        })
+    }
+
+    /// Strips the Ethernet header of `frame`, learning the MAC address of its sender.
+    pub(crate) fn receive<'a>(&mut self, frame: &'a [u8]) -> Frame<'a> {
+        let Some((header, payload)) = frame.split_at_checked(HEADER_LEN) else {
+            return Frame::Ignore;
+        };
+        let src: [u8; 6] = header[6..12].try_into().unwrap();
+        let sender = match u16::from_be_bytes([header[12], header[13]]) {
+            ETHERTYPE_ARP => return self.receive_arp(payload),
+            ETHERTYPE_IPV4 => payload.get(12..16).map(|a| {
+                let octets: [u8; 4] = a.try_into().unwrap();
+                IpAddr::from(octets)
+            }),
+            ETHERTYPE_IPV6 => payload.get(8..24).map(|a| {
+                let octets: [u8; 16] = a.try_into().unwrap();
+                IpAddr::from(octets)
+            }),
+            ethertype => {
+                trace!("ignoring a frame of EtherType {ethertype:#06x}");
+                return Frame::Ignore;
+            }
+        };
+        match sender {
+            Some(sender) => {
+                self.learn(sender, src);
+                Frame::Ip(payload)
+            }
+            None => Frame::Ignore,
+        }
+    }
+
+    /// Answers an ARP request for the gateway, or for any address without one.
+    fn receive_arp(&mut self, arp: &[u8]) -> Frame<'static> {
+        let Some(arp) = arp.get(..ARP_LEN) else {
+            return Frame::Ignore;
+        };
+        // Hardware type Ethernet, protocol type IPv4 and their address lengths
+        if arp[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return Frame::Ignore;
+        }
+        let sender_mac: [u8; 6] = arp[8..14].try_into().unwrap();
+        let sender_ip = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+        let target_ip = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+        if !sender_ip.is_unspecified() {
+            self.learn(sender_ip.into(), sender_mac);
+        }
+        let request = u16::from_be_bytes([arp[6], arp[7]]) == ARP_REQUEST;
+        // A host announcing or probing its own address is not answered
+        if !request
+            || target_ip == sender_ip
+            || self.arp_gateway.is_some_and(|gateway| gateway != target_ip)
+        {
+            return Frame::Ignore;
+        }
+        let mut reply = Vec::with_capacity(HEADER_LEN + ARP_LEN);
+        reply.extend_from_slice(&sender_mac);
+        reply.extend_from_slice(&self.mac);
+        reply.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+        reply.extend_from_slice(&arp[..6]);
+        reply.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        reply.extend_from_slice(&self.mac);
+        reply.extend_from_slice(&target_ip.octets());
+        reply.extend_from_slice(&sender_mac);
+        reply.extend_from_slice(&sender_ip.octets());
+        Frame::Reply(reply)
+    }
+
+    fn learn(&mut self, ip: IpAddr, mac: [u8; 6]) {
+        if self.neighbors.len() < MAX_NEIGHBORS || self.neighbors.contains_key(&ip) {
+            self.neighbors.insert(ip, mac);
+        }
+    }
+
+    /// Prepends the Ethernet header for the IP packet `packet` to `dst`, addressed to the
+    /// host that sent from `dst`, or broadcast if none did.
+    pub(crate) fn encapsulate(&self, packet: &mut Vec<u8>, dst: IpAddr) {
+        let (dst_mac, ethertype) = match dst {
+            IpAddr::V4(addr) => (self.ipv4_mac(addr), ETHERTYPE_IPV4),
+            IpAddr::V6(addr) => (self.ipv6_mac(addr), ETHERTYPE_IPV6),
+        };
+        let mut header = [0; HEADER_LEN];
+        header[..6].copy_from_slice(&dst_mac);
+        header[6..12].copy_from_slice(&self.mac);
+        header[12..].copy_from_slice(&ethertype.to_be_bytes());
+        packet.splice(0..0, header);
+    }
+
+    fn ipv4_mac(&self, addr: Ipv4Addr) -> [u8; 6] {
+        if addr.is_multicast() {
+            // RFC 1112, the low 23 bits of the group
+            let [_, b, c, d] = addr.octets();
+            return [0x01, 0x00, 0x5e, b & 0x7f, c, d];
+        }
+        self.neighbors
+            .get(&addr.into())
+            .copied()
+            .unwrap_or(BROADCAST)
+    }
+
+    fn ipv6_mac(&self, addr: Ipv6Addr) -> [u8; 6] {
+        if addr.is_multicast() {
+            // RFC 2464, the low 32 bits of the group
+            let [.., a, b, c, d] = addr.octets();
+            return [0x33, 0x33, a, b, c, d];
+        }
+        self.neighbors
+            .get(&addr.into())
+            .copied()
+            .unwrap_or(BROADCAST)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const STACK: [u8; 6] = [0x02, 0, 0, 0, 0, 0xfe];
+
+    fn arp_request(sender: [u8; 4], target: [u8; 4]) -> Vec<u8> {
+        let mut frame = BROADCAST.to_vec();
+        frame.extend_from_slice(&HOST);
+        frame.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+        frame.extend_from_slice(&HOST);
+        frame.extend_from_slice(&sender);
+        frame.extend_from_slice(&[0; 6]);
+        frame.extend_from_slice(&target);
+        frame
+    }
+
+    #[test]
+    fn arp() {
+        let mut config = IpStackConfig::default();
+        config
+            .ethernet(STACK)
+            .arp_gateway(Ipv4Addr::new(10, 0, 0, 1));
+        let mut ethernet = Ethernet::new(&config).unwrap();
+
+        let request = arp_request([10, 0, 0, 2], [10, 0, 0, 1]);
+        let Frame::Reply(reply) = ethernet.receive(&request) else {
+            panic!("no ARP reply");
+        };
+        assert_eq!(reply[..6], HOST);
+        assert_eq!(reply[6..12], STACK);
+        assert_eq!(reply[20..22], [0, 2]);
+        assert_eq!(reply[22..28], STACK);
+        assert_eq!(reply[28..32], [10, 0, 0, 1]);
+        assert_eq!(reply[32..38], HOST);
+        assert_eq!(reply[38..42], [10, 0, 0, 2]);
+
+        // Neither other addresses nor announcements are answered
+        let other = arp_request([10, 0, 0, 2], [10, 0, 0, 3]);
+        assert!(matches!(ethernet.receive(&other), Frame::Ignore));
+        let announcement = arp_request([10, 0, 0, 2], [10, 0, 0, 2]);
+        assert!(matches!(ethernet.receive(&announcement), Frame::Ignore));
+
+        // The sender was learned
+        let mut packet = vec![0x45];
+        ethernet.encapsulate(&mut packet, Ipv4Addr::new(10, 0, 0, 2).into());
+        assert_eq!(packet[..6], HOST);
+        assert_eq!(packet[12..], [0x08, 0x00, 0x45]);
+        let mut packet = vec![0x45];
+        ethernet.encapsulate(&mut packet, Ipv4Addr::new(10, 0, 0, 9).into());
+        assert_eq!(packet[..6], BROADCAST);
+    }
+}
@@ -0,0 +1,141 @@
+//! Addresses that stand in for host names, handed out in DNS answers so that the flows
+//! opened to them can be routed by name.
+
+use crate::{
+    dns::{DnsQuery, CLASS_IN, TYPE_A, TYPE_AAAA},
+    Result,
+};
+use ahash::AHashMap;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+/// A pool of fake addresses from one CIDR block, each mapped to the host name it was
+/// allocated for. Clones share the pool, so the one given to
+/// [`IpStackConfig::fake_ip_pool`](crate::IpStackConfig::fake_ip_pool) can answer queries
+/// in the accept loop.
+#[derive(Debug, Clone)]
+pub struct FakeIpPool {
+    inner: Arc<Mutex<Pool>>,
+}
+
+#[derive(Debug)]
+struct Pool {
+    network: IpAddr,
+    size: u128,                      // addresses in the block
+    next: u128,                      // offset of the next address handed out
+    names: AHashMap<u128, Arc<str>>, // offset to host name
+    offsets: AHashMap<Arc<str>, u128>,
+}
+
+impl FakeIpPool {
+    /// A pool of the addresses in `network`/`prefix_len` but the first and the last, like
+    /// 198.18.0.0/15. Fails with `InvalidInput` for a block of fewer than four addresses.
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self> {
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > bits - 2 {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        let host_bits = (bits - prefix_len) as u32;
+        let size = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+        let network = mask(network, host_bits);
+        Ok(FakeIpPool {
+            inner: Arc::new(Mutex::new(Pool {
+                network,
+                size,
+                next: 1,
+                names: AHashMap::new(),
+                offsets: AHashMap::new(),
+            })),
+        })
+    }
+
+    /// The address of `name`, allocating one if it has none. Once the pool ran out the
+    /// oldest allocation is taken over.
+    pub fn allocate(&self, name: &str) -> IpAddr {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut pool = self.inner.lock().unwrap();
+        if let Some(&offset) = pool.offsets.get(name.as_str()) {
+            return pool.addr(offset);
+        }
+        let offset = pool.next;
+        pool.next = match offset + 1 {
+            next if next >= pool.size - 1 => 1,
+            next => next,
+        };
+        if let Some(old) = pool.names.remove(&offset) {
+            pool.offsets.remove(&old);
+        }
+        let name: Arc<str> = name.into();
+        pool.names.insert(offset, name.clone());
+        pool.offsets.insert(name, offset);
+        pool.addr(offset)
+    }
+
+    /// The host name `addr` was allocated for.
+    pub fn lookup(&self, addr: IpAddr) -> Option<String> {
+        let pool = self.inner.lock().unwrap();
+        let offset = pool.offset(addr)?;
+        pool.names.get(&offset).map(|name| name.to_string())
+    }
+
+    /// Whether `addr` lies in the block of the pool, allocated or not.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.inner.lock().unwrap().offset(addr).is_some()
+    }
+
+    /// Answers `query` with the address of its name if it asks for the pool's address
+    /// family, and with no records otherwise.
+    pub fn respond(&self, query: DnsQuery, ttl: u32) -> std::io::Result<()> {
+        let qtype = match self.inner.lock().unwrap().network {
+            IpAddr::V4(_) => TYPE_A,
+            IpAddr::V6(_) => TYPE_AAAA,
+        };
+        let question = query.question();
+        if question.qtype() != qtype || question.qclass() != CLASS_IN {
+            return query.respond_addrs(&[], ttl);
+        }
+        let addr = self.allocate(question.name());
+        query.respond_addrs(&[addr], ttl)
+    }
+}
+
+impl Pool {
+    fn addr(&self, offset: u128) -> IpAddr {
+        match self.network {
+            IpAddr::V4(network) => IpAddr::V4(Ipv4Addr::from(u32::from(network) + offset as u32)),
+            IpAddr::V6(network) => IpAddr::V6(Ipv6Addr::from(u128::from(network) + offset)),
+        }
+    }
+
+    fn offset(&self, addr: IpAddr) -> Option<u128> {
+        let offset = match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                u32::from(addr).checked_sub(u32::from(network))? as u128
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                u128::from(addr).checked_sub(u128::from(network))?
+            }
+            _ => return None,
+        };
+        (offset < self.size).then_some(offset)
+    }
+}
+
+/// `addr` with its `host_bits` low bits cleared.
+fn mask(addr: IpAddr, host_bits: u32) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{
+    packet::{IpHeader, NetworkPacket},
+    DeviceId, IpStackConfig,
+};
+use etherparse::IpNumber;
+use std::net::SocketAddr;
+
+/// A filter set with [`IpStackConfig::with_filter`], deciding over every packet the stack
+/// receives from or sends to the device.
+pub type PacketFilter = Box<dyn Fn(&PacketView<'_>, Direction) -> Verdict + Send>;
+
+/// A rewrite set with [`IpStackConfig::with_dnat`], giving the destination the new flow
+/// opened by a packet is redirected to, `None` to keep it.
+pub type DestinationRewrite = Box<dyn Fn(&PacketView<'_>) -> Option<SocketAddr> + Send>;
+
+/// A predicate set with [`IpStackConfig::with_forward`], giving the device a packet from
+/// the device is forwarded to instead of being terminated, `None` to terminate it.
+pub type ForwardPredicate = Box<dyn Fn(&PacketView<'_>) -> Option<DeviceId> + Send>;
+
+/// A policy set with [`IpStackConfig::with_accept_policy`], deciding over every new TCP
+/// connection and UDP flow from the device, given its source, destination and protocol,
+/// before a stream is created for it.
+pub type AcceptPolicy = Box<dyn Fn(SocketAddr, SocketAddr, IpNumber) -> Verdict + Send>;
+
+/// Which way a packet passes the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the device, filtered before it reaches a stream.
+    Ingress,
+    /// Written by the stack, filtered before it reaches the device.
+    Egress,
+}
+
+/// What a [`PacketFilter`] does with a packet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verdict {
+    #[default]
+    Accept,
+    /// Discard the packet silently.
+    Drop,
+    /// Discard the packet and refuse it to the sender, with an RST for TCP and an ICMP
+    /// administratively prohibited error otherwise. Packets the stack sends are only dropped.
+    Reject,
+}
+
+/// A packet as a [`PacketFilter`] sees it. Reassembled from its fragments if the device sent
+/// it fragmented.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketView<'a> {
+    packet: &'a NetworkPacket,
+}
+
+impl<'a> PacketView<'a> {
+    pub(crate) fn new(packet: &'a NetworkPacket) -> Self {
+        PacketView { packet }
+    }
+
+    /// The sender with its port, 0 for protocols without ports.
+    pub fn src_addr(&self) -> SocketAddr {
+        self.packet.src_addr()
+    }
+
+    /// The receiver with its port, 0 for protocols without ports.
+    pub fn dst_addr(&self) -> SocketAddr {
+        self.packet.dst_addr()
+    }
+
+    /// The protocol of the IP payload, for IPv6 the next header of the fixed header.
+    pub fn protocol(&self) -> IpNumber {
+        match &self.packet.ip {
+            IpHeader::Ipv4(ip) => ip.protocol,
+            IpHeader::Ipv6(ip) => ip.next_header,
+        }
+    }
+
+    /// The TTL (IPv4) or hop limit (IPv6).
+    pub fn ttl(&self) -> u8 {
+        self.packet.ttl()
+    }
+
+    /// The payload of the TCP or UDP header, for other protocols the whole IP payload.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.packet.payload
+    }
+}
+
+/// The verdict of [`IpStackConfig::filter`] over `packet`, `Accept` without a filter.
+pub(crate) fn verdict(
+    config: &IpStackConfig,
+    packet: &NetworkPacket,
+    direction: Direction,
+) -> Verdict {
+    config.filter.as_ref().map_or(Verdict::Accept, |filter| {
+        filter(&PacketView::new(packet), direction)
+    })
+}
+
+/// The verdict of [`IpStackConfig::accept_policy`] over the flow `packet` opens, `Accept`
+/// without a policy.
+pub(crate) fn accept_verdict(config: &IpStackConfig, packet: &NetworkPacket) -> Verdict {
+    config
+        .accept_policy
+        .as_ref()
+        .map_or(Verdict::Accept, |policy| {
+            let view = PacketView::new(packet);
+            policy(view.src_addr(), view.dst_addr(), view.protocol())
+        })
+}
+
+/// Where [`IpStackConfig::dnat`] redirects the flow `packet` opens, `None` if it is kept.
+pub(crate) fn rewrite_destination(
+    config: &IpStackConfig,
+    packet: &NetworkPacket,
+) -> Option<SocketAddr> {
+    let rewrite = config.dnat.as_ref()?;
+    rewrite(&PacketView::new(packet)).filter(|target| *target != packet.dst_addr())
+}
+
+/// The device [`IpStackConfig::forward`] forwards `packet` to, `None` to terminate it.
+pub(crate) fn forward_to(config: &IpStackConfig, packet: &NetworkPacket) -> Option<DeviceId> {
+    let forward = config.forward.as_ref()?;
+    forward(&PacketView::new(packet))
+}
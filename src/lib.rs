@@ -1,35 +1,98 @@
 #![doc = include_str!("../README.md")]
+#![allow(clippy::result_large_err, clippy::io_other_error)]
 
 use crate::{
+    egress::{EgressReceiver, EgressSender},
+    limiter::{FlowLimiter, IcmpLimiter, PendingConnections, RstLimiter, SynLimiter},
     packet::IpStackPacketProtocol,
-    stream::{IpStackStream, IpStackTcpStream, IpStackUdpStream, IpStackUnknownTransport},
+    routing::SharedRoutingTable,
+    stream::{
+        IcmpPacket, IpStackIcmpStream, IpStackStream, IpStackTcpStream, IpStackUdpBroadcast,
+        IpStackUdpSocket, IpStackUdpStream, IpStackUnknownTransport, TcpState,
+    },
 };
 use ahash::AHashMap;
+use bytes::Bytes;
 use log::{error, trace};
-use packet::{NetworkPacket, NetworkTuple};
+use packet::{Ipv4Reassembly, NetworkPacket, NetworkTuple};
 use std::{
+    any::Any,
     collections::hash_map::Entry::{Occupied, Vacant},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     select,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
     task::JoinHandle,
 };
 
 pub(crate) type PacketSender = UnboundedSender<NetworkPacket>;
 pub(crate) type PacketReceiver = UnboundedReceiver<NetworkPacket>;
 pub(crate) type SessionCollection = AHashMap<NetworkTuple, PacketSender>;
+pub(crate) type ReassemblyUsage = Arc<AtomicUsize>; // bytes buffered by all TCP streams
+/// A connection to open from the first address on the stack side to the second on the device.
+type ConnectRequest = (
+    SocketAddr,
+    SocketAddr,
+    oneshot::Sender<Result<IpStackTcpStream>>,
+);
+/// A device to replace the primary one with, of the type the dispatcher was created with.
+type DeviceReplacement = (Box<dyn Any + Send>, oneshot::Sender<std::io::Result<()>>);
 
+#[cfg(feature = "capture")]
+pub mod capture;
+mod clock;
+mod device;
+pub mod dns;
+mod egress;
 mod error;
+mod ethernet;
+pub mod fake_ip;
+mod filter;
+mod limiter;
+mod metrics;
+#[cfg(feature = "mobile")]
+mod mobile;
+pub mod ndp;
 mod packet;
+mod pool;
+mod routing;
 pub mod stream;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod vnet;
 
+pub use self::clock::{Clock, SleepFuture, TokioClock};
+pub use self::device::{PacketDevice, StreamDevice};
 pub use self::error::{IpStackError, Result};
+pub use self::filter::{
+    AcceptPolicy, DestinationRewrite, Direction, ForwardPredicate, PacketFilter, PacketView,
+    Verdict,
+};
+pub use self::limiter::{FlowLimitPolicy, RstPolicy, SynLimitPolicy};
+pub use self::metrics::IpStackMetrics;
+#[cfg(all(feature = "mobile", unix))]
+pub use self::mobile::FdDevice;
+#[cfg(feature = "mobile")]
+pub use self::mobile::{PacketFlow, PacketFlowDevice};
+pub use self::routing::DeviceId;
+pub use self::stream::{UdpBroadcastPolicy, UdpMode, UdpTimeoutRefresh};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use self::uring::UringDevice;
 pub use etherparse::IpNumber;
 
 const DROP_TTL: u8 = 0;
+/// The smallest MTU of an IPv4 link (RFC 791).
+const MIN_MTU: u16 = 68;
 
 #[cfg(unix)]
 const TTL: u8 = 64;
@@ -37,58 +100,543 @@ const TTL: u8 = 64;
 #[cfg(windows)]
 const TTL: u8 = 128;
 
-#[cfg(unix)]
-const TUN_FLAGS: [u8; 2] = [0x00, 0x00];
-
-#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
-const TUN_PROTO_IP6: [u8; 2] = [0x86, 0xdd];
-#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
-const TUN_PROTO_IP4: [u8; 2] = [0x08, 0x00];
-
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-const TUN_PROTO_IP6: [u8; 2] = [0x00, 0x0A];
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-const TUN_PROTO_IP4: [u8; 2] = [0x00, 0x02];
-
+/// The options of an [`IpStack`], set with the setters on a default config or with
+/// [`IpStackConfig::builder`].
+#[non_exhaustive]
 pub struct IpStackConfig {
     pub mtu: u16,
+    pub ttl: u8,
+    pub ipv6_flow_label: bool,
     pub packet_information: bool,
+    pub vnet_hdr: bool,
     pub tcp_timeout: Duration,
+    pub tcp_time_wait: Duration,
+    pub tcp_linger: Duration,
     pub udp_timeout: Duration,
+    pub udp_timeout_refresh: UdpTimeoutRefresh,
+    pub udp_fragmentation: bool,
+    pub udp_port_unreachable: bool,
+    pub tcp_window_scale: u8,
+    pub mss_clamp: Option<u16>,
+    pub tcp_ecn: bool,
+    pub tcp_deterministic_isn: bool,
+    pub tcp_fast_open: bool,
+    pub tcp_reassembly_limit: usize,
+    pub tcp_reassembly_global_limit: usize,
+    pub ipv4_reassembly_limit: usize,
+    pub ipv4_reassembly_timeout: Duration,
+    pub tcp_recv_buffer_size: usize,
+    pub tcp_send_buffer_size: usize,
+    pub max_pending_connections: Option<usize>,
+    pub max_tcp_connections: Option<usize>,
+    pub max_udp_flows: Option<usize>,
+    pub flow_limit_policy: FlowLimitPolicy,
+    pub syn_rate_limit: Option<u32>,
+    pub syn_limit_policy: SynLimitPolicy,
+    pub rst_policy: RstPolicy,
+    pub pacing: bool,
+    pub validate_checksums: bool,
+    pub validate_ip_tcp_checksums: bool,
+    pub icmp_time_exceeded: bool,
+    pub icmp_rate_limit: Option<u32>,
+    pub udp_mode: UdpMode,
+    pub udp_broadcast: UdpBroadcastPolicy,
+    pub subnet_broadcast: Option<Ipv4Addr>,
+    pub intercept_dns: bool,
+    pub dns_ports: Vec<u16>,
+    pub dns_tls_ports: Vec<u16>,
+    pub fake_ip_pool: Option<fake_ip::FakeIpPool>,
+    pub ndp_responder: bool,
+    pub ndp_gateway: Ipv6Addr,
+    pub ndp_prefix: Option<(Ipv6Addr, u8)>,
+    pub ethernet: Option<[u8; 6]>,
+    pub arp_gateway: Option<Ipv4Addr>,
+    pub clock: Arc<dyn Clock>,
+    pub egress_queue_size: usize,
+    pub device_batch_size: usize,
+    #[cfg(feature = "capture")]
+    pub capture: Option<capture::Capture>,
+    pub filter: Option<PacketFilter>,
+    pub accept_policy: Option<AcceptPolicy>,
+    pub dnat: Option<DestinationRewrite>,
+    pub forward: Option<ForwardPredicate>,
+    pub normalize_mapped_addrs: bool,
 }
 
 impl Default for IpStackConfig {
     fn default() -> Self {
         IpStackConfig {
             mtu: u16::MAX,
+            ttl: TTL,
+            ipv6_flow_label: true,
             packet_information: false,
+            vnet_hdr: false,
             tcp_timeout: Duration::from_secs(60),
+            tcp_time_wait: Duration::from_secs(30),
+            tcp_linger: Duration::from_secs(2),
             udp_timeout: Duration::from_secs(30),
+            udp_timeout_refresh: UdpTimeoutRefresh::Both,
+            udp_fragmentation: true,
+            udp_port_unreachable: true,
+            tcp_window_scale: 0,
+            mss_clamp: None,
+            tcp_ecn: true,
+            tcp_deterministic_isn: false,
+            tcp_fast_open: false,
+            tcp_reassembly_limit: 256 * 1024,
+            tcp_reassembly_global_limit: 64 * 1024 * 1024,
+            ipv4_reassembly_limit: 4 * 1024 * 1024,
+            ipv4_reassembly_timeout: Duration::from_secs(30),
+            tcp_recv_buffer_size: 16 * 1024,
+            tcp_send_buffer_size: 16 * 1024,
+            max_pending_connections: None,
+            max_tcp_connections: None,
+            max_udp_flows: None,
+            flow_limit_policy: FlowLimitPolicy::Drop,
+            syn_rate_limit: None,
+            syn_limit_policy: SynLimitPolicy::Drop,
+            rst_policy: RstPolicy::Always,
+            pacing: false,
+            validate_checksums: false,
+            validate_ip_tcp_checksums: true,
+            icmp_time_exceeded: false,
+            icmp_rate_limit: None,
+            udp_mode: UdpMode::PerFlow,
+            udp_broadcast: UdpBroadcastPolicy::Stream,
+            subnet_broadcast: None,
+            intercept_dns: false,
+            dns_ports: vec![53],
+            dns_tls_ports: vec![853],
+            fake_ip_pool: None,
+            ndp_responder: false,
+            ndp_gateway: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+            ndp_prefix: None,
+            ethernet: None,
+            arp_gateway: None,
+            clock: Arc::new(TokioClock),
+            egress_queue_size: 1024,
+            device_batch_size: 1,
+            #[cfg(feature = "capture")]
+            capture: None,
+            filter: None,
+            accept_policy: None,
+            dnat: None,
+            forward: None,
+            normalize_mapped_addrs: false,
         }
     }
 }
 
 impl IpStackConfig {
+    /// A builder starting from the default config, built in one expression.
+    pub fn builder() -> IpStackConfigBuilder {
+        IpStackConfigBuilder::default()
+    }
     pub fn tcp_timeout(&mut self, timeout: Duration) -> &mut Self {
         self.tcp_timeout = timeout;
         self
     }
+    /// Duration of the TIME_WAIT state (2MSL) after an actively closed connection.
+    pub fn tcp_time_wait(&mut self, time_wait: Duration) -> &mut Self {
+        self.tcp_time_wait = time_wait;
+        self
+    }
+    /// How long a dropped TCP stream tries to close with a FIN before resetting, 0 resets.
+    pub fn tcp_linger(&mut self, linger: Duration) -> &mut Self {
+        self.tcp_linger = linger;
+        self
+    }
     pub fn udp_timeout(&mut self, timeout: Duration) -> &mut Self {
         self.udp_timeout = timeout;
         self
     }
+    /// Fragments UDP datagrams larger than the MTU instead of failing with `EMSGSIZE`.
+    pub fn udp_fragmentation(&mut self, fragment: bool) -> &mut Self {
+        self.udp_fragmentation = fragment;
+        self
+    }
+    /// Whether [`IpStackUdpStream::reject`] answers with ICMP port unreachable.
+    pub fn udp_port_unreachable(&mut self, enabled: bool) -> &mut Self {
+        self.udp_port_unreachable = enabled;
+        self
+    }
+    /// Which datagrams push back the UDP idle timeout, those of both directions by default.
+    pub fn udp_timeout_refresh(&mut self, refresh: UdpTimeoutRefresh) -> &mut Self {
+        self.udp_timeout_refresh = refresh;
+        self
+    }
     pub fn mtu(&mut self, mtu: u16) -> &mut Self {
         self.mtu = mtu;
         self
     }
+    /// TTL (hop limit) of the packets the stack sends, the default of the OS unless set.
+    pub fn ttl(&mut self, ttl: u8) -> &mut Self {
+        self.ttl = ttl.max(1);
+        self
+    }
+    /// Labels the IPv6 packets of TCP and UDP flows with a flow label (RFC 6437).
+    pub fn ipv6_flow_label(&mut self, enabled: bool) -> &mut Self {
+        self.ipv6_flow_label = enabled;
+        self
+    }
+    /// The device puts 4 bytes of packet information before every packet.
     pub fn packet_information(&mut self, packet_information: bool) -> &mut Self {
         self.packet_information = packet_information;
         self
     }
+    /// The TUN device was opened with `IFF_VNET_HDR` and puts a virtio-net header before packets.
+    pub fn vnet_hdr(&mut self, enabled: bool) -> &mut Self {
+        self.vnet_hdr = enabled;
+        self
+    }
+    /// Window scale shift advertised to peers (RFC 7323), clamped to 14.
+    pub fn tcp_window_scale(&mut self, scale: u8) -> &mut Self {
+        self.tcp_window_scale = scale.min(14);
+        self
+    }
+    /// Upper bound for the MSS advertised to and used towards peers.
+    pub fn mss_clamp(&mut self, mss: u16) -> &mut Self {
+        self.mss_clamp = Some(mss);
+        self
+    }
+    /// Accepts ECN (RFC 3168) when the peer requests it in its SYN.
+    pub fn tcp_ecn(&mut self, ecn: bool) -> &mut Self {
+        self.tcp_ecn = ecn;
+        self
+    }
+    /// Derives initial sequence numbers from the tuple only, for reproducible tests.
+    pub fn tcp_deterministic_isn(&mut self, deterministic: bool) -> &mut Self {
+        self.tcp_deterministic_isn = deterministic;
+        self
+    }
+    /// Accepts data carried in a SYN (TCP Fast Open, RFC 7413) without a cookie.
+    pub fn tcp_fast_open(&mut self, enabled: bool) -> &mut Self {
+        self.tcp_fast_open = enabled;
+        self
+    }
+    /// Most out-of-order bytes a TCP stream buffers.
+    pub fn tcp_reassembly_limit(&mut self, limit: usize) -> &mut Self {
+        self.tcp_reassembly_limit = limit;
+        self
+    }
+    /// Most bytes all TCP streams of the stack buffer together.
+    pub fn tcp_reassembly_global_limit(&mut self, limit: usize) -> &mut Self {
+        self.tcp_reassembly_global_limit = limit;
+        self
+    }
+    /// Most bytes of IPv4 fragments buffered for reassembly, 0 turns it off.
+    pub fn ipv4_reassembly_limit(&mut self, limit: usize) -> &mut Self {
+        self.ipv4_reassembly_limit = limit;
+        self
+    }
+    /// How long the fragments of an IPv4 packet wait for the rest.
+    pub fn ipv4_reassembly_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.ipv4_reassembly_timeout = timeout;
+        self
+    }
+    /// Bytes a TCP stream buffers for reading, which is its receive window.
+    pub fn tcp_recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.tcp_recv_buffer_size = size;
+        self
+    }
+    /// Spreads the segments TCP streams send over the round-trip time.
+    pub fn pacing(&mut self, pacing: bool) -> &mut Self {
+        self.pacing = pacing;
+        self
+    }
+    /// Whether UDP datagrams are accepted as a stream per flow or on one socket.
+    pub fn udp_mode(&mut self, mode: UdpMode) -> &mut Self {
+        self.udp_mode = mode;
+        self
+    }
+    /// What happens to UDP datagrams sent to a broadcast or multicast address.
+    pub fn udp_broadcast(&mut self, policy: UdpBroadcastPolicy) -> &mut Self {
+        self.udp_broadcast = policy;
+        self
+    }
+    /// The broadcast address of the device's IPv4 subnet.
+    pub fn subnet_broadcast(&mut self, addr: Ipv4Addr) -> &mut Self {
+        self.subnet_broadcast = Some(addr);
+        self
+    }
+    /// Accepts DNS queries as [`IpStackStream::Dns`](stream::IpStackStream::Dns).
+    pub fn intercept_dns(&mut self, intercept: bool) -> &mut Self {
+        self.intercept_dns = intercept;
+        self
+    }
+    /// Destination ports of plain DNS, 53 by default.
+    pub fn dns_ports(&mut self, ports: Vec<u16>) -> &mut Self {
+        self.dns_ports = ports;
+        self
+    }
+    /// Destination ports of encrypted DNS refused while intercepting, 853 by default.
+    pub fn dns_tls_ports(&mut self, ports: Vec<u16>) -> &mut Self {
+        self.dns_tls_ports = ports;
+        self
+    }
+    /// Names TCP and UDP streams to addresses of `pool` after their host.
+    pub fn fake_ip_pool(&mut self, pool: fake_ip::FakeIpPool) -> &mut Self {
+        self.fake_ip_pool = Some(pool);
+        self
+    }
+    /// Answers router and neighbor solicitations for the [`ndp_gateway`](Self::ndp_gateway).
+    pub fn ndp_responder(&mut self, enabled: bool) -> &mut Self {
+        self.ndp_responder = enabled;
+        self
+    }
+    /// The link-local address of the router the NDP responder plays, `fe80::1` by default.
+    pub fn ndp_gateway(&mut self, gateway: Ipv6Addr) -> &mut Self {
+        self.ndp_gateway = gateway;
+        self
+    }
+    /// The prefix router advertisements offer for address autoconfiguration.
+    pub fn ndp_prefix(&mut self, prefix: Ipv6Addr, prefix_len: u8) -> &mut Self {
+        self.ndp_prefix = Some((prefix, prefix_len.min(128)));
+        self
+    }
+    /// Exchanges Ethernet frames with a TAP device, with `mac` as the stack's MAC address.
+    pub fn ethernet(&mut self, mac: [u8; 6]) -> &mut Self {
+        self.ethernet = Some(mac);
+        self
+    }
+    /// The only address ARP requests are answered for, all but the sender's by default.
+    pub fn arp_gateway(&mut self, gateway: Ipv4Addr) -> &mut Self {
+        self.arp_gateway = Some(gateway);
+        self
+    }
+    /// Drops UDP and UDP-Lite datagrams with a wrong checksum.
+    pub fn validate_checksums(&mut self, validate: bool) -> &mut Self {
+        self.validate_checksums = validate;
+        self
+    }
+    /// Drops IPv4 packets and TCP segments with a wrong checksum, on by default.
+    pub fn validate_ip_tcp_checksums(&mut self, validate: bool) -> &mut Self {
+        self.validate_ip_tcp_checksums = validate;
+        self
+    }
+    /// Answers packets arriving with a TTL of 1 or less with ICMP time exceeded.
+    pub fn icmp_time_exceeded(&mut self, enabled: bool) -> &mut Self {
+        self.icmp_time_exceeded = enabled;
+        self
+    }
+    /// Most ICMP messages per second the stack generates, unlimited by default.
+    pub fn icmp_rate_limit(&mut self, per_second: u32) -> &mut Self {
+        self.icmp_rate_limit = Some(per_second);
+        self
+    }
+    /// Time source of the TCP timers, `tokio::time` by default.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+    /// Most packets queued for the device before TCP writes stay pending.
+    pub fn egress_queue_size(&mut self, size: usize) -> &mut Self {
+        self.egress_queue_size = size.max(1);
+        self
+    }
+    /// Most packets exchanged with the device at once, 1 by default.
+    pub fn device_batch_size(&mut self, size: usize) -> &mut Self {
+        self.device_batch_size = size.max(1);
+        self
+    }
+    /// Mirrors every IP packet from and to the devices to `sink`.
+    #[cfg(feature = "capture")]
+    pub fn with_capture(&mut self, sink: capture::CaptureSink) -> &mut Self {
+        self.capture = Some(capture::Capture::new(sink));
+        self
+    }
+    /// Passes every packet from and to the device through `filter`.
+    pub fn with_filter(&mut self, filter: PacketFilter) -> &mut Self {
+        self.filter = Some(filter);
+        self
+    }
+    /// Asks `policy` whether to accept every new TCP connection and UDP flow.
+    pub fn with_accept_policy(&mut self, policy: AcceptPolicy) -> &mut Self {
+        self.accept_policy = Some(policy);
+        self
+    }
+    /// Asks `rewrite` for the destination of every new TCP and UDP flow.
+    pub fn with_dnat(&mut self, rewrite: DestinationRewrite) -> &mut Self {
+        self.dnat = Some(rewrite);
+        self
+    }
+    /// Forwards the packets `predicate` gives a device for verbatim to that device.
+    pub fn with_forward(&mut self, predicate: ForwardPredicate) -> &mut Self {
+        self.forward = Some(predicate);
+        self
+    }
+    /// Reports IPv4-mapped IPv6 addresses of streams as IPv4 addresses.
+    pub fn normalize_mapped_addrs(&mut self, normalize: bool) -> &mut Self {
+        self.normalize_mapped_addrs = normalize;
+        self
+    }
+    /// Most bytes a TCP stream holds unacknowledged or waiting to be sent.
+    pub fn tcp_send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.tcp_send_buffer_size = size;
+        self
+    }
+    /// Whether and how often TCP segments of unknown flows are answered with an RST.
+    pub fn rst_policy(&mut self, policy: RstPolicy) -> &mut Self {
+        self.rst_policy = policy;
+        self
+    }
+    /// Most TCP streams waiting to be taken by `accept`. SYNs beyond it are refused.
+    pub fn max_pending_connections(&mut self, max: usize) -> &mut Self {
+        self.max_pending_connections = Some(max);
+        self
+    }
+    /// Most TCP connections the stack tracks at once.
+    pub fn max_tcp_connections(&mut self, max: usize) -> &mut Self {
+        self.max_tcp_connections = Some(max);
+        self
+    }
+    /// Most UDP and UDP-Lite flows the stack tracks at once.
+    pub fn max_udp_flows(&mut self, max: usize) -> &mut Self {
+        self.max_udp_flows = Some(max);
+        self
+    }
+    /// What happens to new flows over the flow limits, dropped by default.
+    pub fn flow_limit_policy(&mut self, policy: FlowLimitPolicy) -> &mut Self {
+        self.flow_limit_policy = policy;
+        self
+    }
+    /// Most new TCP connections per second, with bursts of up to a second's worth.
+    pub fn syn_rate_limit(&mut self, per_second: u32) -> &mut Self {
+        self.syn_rate_limit = Some(per_second);
+        self
+    }
+    /// How SYNs refused by `max_pending_connections` or `syn_rate_limit` are handled.
+    pub fn syn_limit_policy(&mut self, policy: SynLimitPolicy) -> &mut Self {
+        self.syn_limit_policy = policy;
+        self
+    }
+    /// Checks that the options make sense, as [`IpStackConfigBuilder::build`] does.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason| Err(IpStackError::InvalidConfig(reason));
+        if self.mtu < MIN_MTU {
+            return invalid("the MTU is below the 68 bytes every IPv4 link carries");
+        }
+        if self.mss_clamp == Some(0) {
+            return invalid("the MSS clamp is 0");
+        }
+        if self.tcp_timeout.is_zero() || self.udp_timeout.is_zero() {
+            return invalid("a TCP or UDP timeout is 0");
+        }
+        if self.tcp_recv_buffer_size == 0 || self.tcp_send_buffer_size == 0 {
+            return invalid("a TCP buffer size is 0");
+        }
+        if self.intercept_dns && self.dns_ports.is_empty() {
+            return invalid("DNS is intercepted without DNS ports");
+        }
+        if self.arp_gateway.is_some() && self.ethernet.is_none() {
+            return invalid("an ARP gateway is set without ethernet");
+        }
+        Ok(())
+    }
+}
+
+/// Builds an [`IpStackConfig`] by value, from [`IpStackConfig::builder`]. Its setters are
+/// those of the config.
+#[derive(Default)]
+pub struct IpStackConfigBuilder {
+    config: IpStackConfig,
+}
+
+macro_rules! forward_setters {
+    ($($(#[$attr:meta])* $name:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            $(#[$attr])*
+            #[doc = concat!("See [`IpStackConfig::", stringify!($name), "`].")]
+            pub fn $name(mut self, $($arg: $ty),*) -> Self {
+                self.config.$name($($arg),*);
+                self
+            }
+        )*
+    };
+}
+
+impl IpStackConfigBuilder {
+    forward_setters! {
+        tcp_timeout(timeout: Duration);
+        tcp_time_wait(time_wait: Duration);
+        tcp_linger(linger: Duration);
+        udp_timeout(timeout: Duration);
+        udp_fragmentation(fragment: bool);
+        udp_port_unreachable(enabled: bool);
+        udp_timeout_refresh(refresh: UdpTimeoutRefresh);
+        mtu(mtu: u16);
+        ttl(ttl: u8);
+        ipv6_flow_label(enabled: bool);
+        packet_information(packet_information: bool);
+        vnet_hdr(enabled: bool);
+        tcp_window_scale(scale: u8);
+        mss_clamp(mss: u16);
+        tcp_ecn(ecn: bool);
+        tcp_deterministic_isn(deterministic: bool);
+        tcp_fast_open(enabled: bool);
+        tcp_reassembly_limit(limit: usize);
+        tcp_reassembly_global_limit(limit: usize);
+        ipv4_reassembly_limit(limit: usize);
+        ipv4_reassembly_timeout(timeout: Duration);
+        tcp_recv_buffer_size(size: usize);
+        pacing(pacing: bool);
+        udp_mode(mode: UdpMode);
+        udp_broadcast(policy: UdpBroadcastPolicy);
+        subnet_broadcast(addr: Ipv4Addr);
+        intercept_dns(intercept: bool);
+        dns_ports(ports: Vec<u16>);
+        dns_tls_ports(ports: Vec<u16>);
+        fake_ip_pool(pool: fake_ip::FakeIpPool);
+        ndp_responder(enabled: bool);
+        ndp_gateway(gateway: Ipv6Addr);
+        ndp_prefix(prefix: Ipv6Addr, prefix_len: u8);
+        ethernet(mac: [u8; 6]);
+        arp_gateway(gateway: Ipv4Addr);
+        validate_checksums(validate: bool);
+        validate_ip_tcp_checksums(validate: bool);
+        icmp_time_exceeded(enabled: bool);
+        icmp_rate_limit(per_second: u32);
+        clock(clock: Arc<dyn Clock>);
+        egress_queue_size(size: usize);
+        device_batch_size(size: usize);
+        #[cfg(feature = "capture")]
+        with_capture(sink: capture::CaptureSink);
+        with_filter(filter: PacketFilter);
+        with_accept_policy(policy: AcceptPolicy);
+        with_dnat(rewrite: DestinationRewrite);
+        with_forward(predicate: ForwardPredicate);
+        normalize_mapped_addrs(normalize: bool);
+        tcp_send_buffer_size(size: usize);
+        rst_policy(policy: RstPolicy);
+        max_pending_connections(max: usize);
+        max_tcp_connections(max: usize);
+        max_udp_flows(max: usize);
+        flow_limit_policy(policy: FlowLimitPolicy);
+        syn_rate_limit(per_second: u32);
+        syn_limit_policy(policy: SynLimitPolicy);
+    }
+
+    /// The config, or [`IpStackError::InvalidConfig`] if
+    /// [`IpStackConfig::validate`] rejects it.
+    pub fn build(self) -> Result<IpStackConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
 }
 
 pub struct IpStack {
     accept_receiver: UnboundedReceiver<IpStackStream>,
+    pending: PendingConnections,
+    metrics: Arc<IpStackMetrics>,
+    udp_socket: Option<IpStackUdpSocket>,
+    pkt_sender: EgressSender,
+    connect_sender: UnboundedSender<ConnectRequest>,
+    replace_sender: UnboundedSender<DeviceReplacement>,
+    ingress_sender: UnboundedSender<(DeviceId, Vec<u8>)>, // read from added devices
+    routes: SharedRoutingTable,
+    devices: usize,
+    udp_send: stream::SendOptions,
+    fatal: watch::Receiver<Option<std::io::Error>>,
     pub handle: JoinHandle<Result<()>>,
 }
 
@@ -97,93 +645,777 @@ impl IpStack {
     where
         D: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
+        IpStack::with_device(config, StreamDevice(device))
+    }
+
+    /// Like [`new`](Self::new) for a device exchanging whole packets through the
+    /// [`PacketDevice`] trait rather than being read and written as a byte stream.
+    pub fn with_device<P: PacketDevice>(config: IpStackConfig, device: P) -> IpStack {
         let (accept_sender, accept_receiver) = mpsc::unbounded_channel::<IpStackStream>();
-        let handle = run(config, device, accept_sender);
+        let (connect_sender, connect_receiver) = mpsc::unbounded_channel();
+        let (replace_sender, replace_receiver) = mpsc::unbounded_channel();
+        let (ingress_sender, ingress_receiver) = mpsc::unbounded_channel();
+        let (fatal_sender, fatal) = watch::channel(None);
+        let routes = SharedRoutingTable::default();
+        let pending = PendingConnections::default();
+        let metrics = Arc::new(IpStackMetrics::default());
+        let egress = egress::channel(
+            config.egress_queue_size,
+            config.mtu,
+            IcmpLimiter::new(&config),
+            metrics.clone(),
+        );
+        let (udp_socket, udp_sender) = match (config.udp_mode, config.udp_broadcast) {
+            (UdpMode::PerFlow, policy) if policy != UdpBroadcastPolicy::Socket => (None, None),
+            _ => {
+                let (socket, sender) = IpStackUdpSocket::new(egress.0.clone(), &config);
+                (Some(socket), Some(sender))
+            }
+        };
+        let (pkt_sender, udp_send) = (egress.0.clone(), stream::SendOptions::new(&config));
+        let channels = Channels {
+            accept: accept_sender,
+            connect: connect_receiver,
+            replace: replace_receiver,
+            udp: udp_sender,
+            egress,
+            ingress: ingress_receiver,
+            fatal: fatal_sender,
+        };
+        let handle = run(
+            config,
+            device,
+            channels,
+            pending.clone(),
+            metrics.clone(),
+            routes.clone(),
+        );
 
         IpStack {
             accept_receiver,
+            pending,
+            metrics,
+            udp_socket,
+            pkt_sender,
+            connect_sender,
+            replace_sender,
+            ingress_sender,
+            routes,
+            devices: 1,
+            udp_send,
+            fatal,
             handle,
         }
     }
 
+    /// Like [`new`](Self::new) for a device given as separate halves, like the reader and
+    /// writer of a wintun session or of a file descriptor.
+    pub fn new_split<R, W>(config: IpStackConfig, reader: R, writer: W) -> IpStack
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        IpStack::new(config, tokio::io::join(reader, writer))
+    }
+
+    /// Replaces the device, like a TUN whose file descriptor was revoked and reissued on a
+    /// network change, keeping every stream and flow. The new device has to be of the type
+    /// the stack was created with, a [`tokio::io::Join`] for [`new_split`](Self::new_split),
+    /// otherwise this fails with `InvalidInput`. The old device is dropped, an error it fails
+    /// with before still stops the stack.
+    pub async fn replace_device<D>(&self, device: D) -> std::io::Result<()>
+    where
+        D: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.replace_packet_device(StreamDevice(device)).await
+    }
+
+    /// Like [`replace_device`](Self::replace_device) for a stack created with
+    /// [`with_device`](Self::with_device).
+    pub async fn replace_packet_device<P: PacketDevice>(&self, device: P) -> std::io::Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        if self
+            .replace_sender
+            .send((Box::new(device), sender))
+            .is_err()
+        {
+            return Err(self.stopped_error());
+        }
+        receiver.await.unwrap_or_else(|_| Err(self.stopped_error()))
+    }
+
+    /// Adds another device, whose packets are dispatched like those of the first one. The
+    /// stack writes to it the packets for destinations routed to it with
+    /// [`add_route`](Self::add_route), and forwards packets there that arrive on another
+    /// device, so one TUN can reach the networks behind another.
+    pub fn add_device<D>(&mut self, device: D) -> DeviceId
+    where
+        D: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let id = DeviceId(self.devices);
+        self.devices += 1;
+        let (mut reader, mut writer) = tokio::io::split(device);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+        self.routes.write().unwrap().add_device(id, sender);
+        let ingress = self.ingress_sender.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0_u8; u16::MAX as usize + 4];
+            while let Ok(n @ 1..) = reader.read(&mut buffer).await {
+                if ingress.send((id, buffer[..n].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(bytes) = receiver.recv().await {
+                if let Err(err) = writer.write_all(&bytes).await {
+                    error!("writing to device {id:?} failed: {err}");
+                    break;
+                }
+            }
+        });
+        id
+    }
+
+    /// Routes the destinations in `prefix`/`len` to `device`, the longest matching prefix
+    /// wins. Destinations no route matches are sent to [`DeviceId::PRIMARY`]. Prefix lengths
+    /// beyond the address are clamped.
+    pub fn add_route(&self, prefix: IpAddr, len: u8, device: DeviceId) {
+        self.routes.write().unwrap().add_route(prefix, len, device);
+    }
+
+    /// The next stream from the device. Once the stack stopped it fails with the error that
+    /// stopped it, like the device failing, see [`driver`](Self::driver).
     pub async fn accept(&mut self) -> Result<IpStackStream, IpStackError> {
-        self.accept_receiver
-            .recv()
+        let Some(stream) = self.accept_receiver.recv().await else {
+            return Err(self
+                .fatal_error()
+                .map_or(IpStackError::AcceptError, IpStackError::IoError));
+        };
+        if let IpStackStream::Tcp(_) = stream {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(stream)
+    }
+
+    /// Opens a TCP connection from `local` to `remote` on the device, the stack sending the
+    /// SYN. Resolves once the handshake is done, fails with `ConnectionRefused` when the
+    /// device resets it, `TimedOut` when the SYN stays unanswered and `AddrInUse` when a
+    /// live connection has the same addresses. As for accepted streams, `local_addr` of
+    /// the stream is the device side, here `remote`.
+    pub async fn connect_tcp(
+        &self,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> Result<IpStackTcpStream, IpStackError> {
+        let (sender, receiver) = oneshot::channel();
+        self.connect_sender
+            .send((local, remote, sender))
+            .or(Err(IpStackError::AcceptError))?;
+        let mut stream = receiver.await.or(Err(IpStackError::AcceptError))??;
+        let state = *stream
+            .watch_state()
+            .wait_for(|state| *state != TcpState::SynSent)
             .await
-            .ok_or(IpStackError::AcceptError)
+            .or(Err(IpStackError::AcceptError))?;
+        if state == TcpState::Closed {
+            let kind = std::io::ErrorKind::ConnectionAborted;
+            return Err(stream.take_error().unwrap_or_else(|| kind.into()).into());
+        }
+        Ok(stream)
+    }
+
+    /// Takes the socket receiving all UDP traffic under [`UdpMode::Single`], or the
+    /// broadcasts under [`UdpBroadcastPolicy::Socket`]. `None` otherwise or once taken.
+    pub fn udp_socket(&mut self) -> Option<IpStackUdpSocket> {
+        self.udp_socket.take()
+    }
+
+    /// Sends an ICMP message to the device, fragmented if it exceeds the MTU, for example
+    /// an [`IcmpPacket::administratively_prohibited`] error for a flow a policy blocks.
+    /// Unlike the messages the stack generates it is not subject to
+    /// [`IpStackConfig::icmp_rate_limit`].
+    pub fn send_icmp(&self, packet: IcmpPacket) -> std::io::Result<()> {
+        let packet = packet.into_packet(self.udp_send.ttl)?;
+        stream::send_icmp_packet(&self.pkt_sender, self.pkt_sender.mtu(), packet)
+    }
+
+    /// Sends a UDP datagram from `src` to `dst` on the device without a flow it answers,
+    /// like a pushed DNS response or a keepalive. Fails with `InvalidInput` if the
+    /// addresses are of different IP versions, large datagrams are handled as by
+    /// [`IpStackUdpStream::send_datagram`].
+    pub fn udp_send_to(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: Bytes,
+    ) -> std::io::Result<()> {
+        stream::send_datagram(&self.pkt_sender, src, dst, &self.udp_send, payload)
+    }
+
+    /// Writes the IP packet `packet` to the device as it is, to answer protocols the stack
+    /// does not terminate. Fails with `InvalidInput` unless it is a well-formed IPv4 or IPv6
+    /// packet with a TTL. Replies to an [`IpStackUnknownTransport`] are easier sent with its
+    /// [`send`](IpStackUnknownTransport::send).
+    pub fn send_raw(&self, packet: &[u8]) -> std::io::Result<()> {
+        // A TTL of 0 would release a flow of the stack
+        let packet = NetworkPacket::raw(packet)
+            .ok()
+            .filter(|packet| packet.ttl() != DROP_TTL)
+            .ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        self.pkt_sender
+            .send(packet)
+            .or(Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+    }
+
+    /// Resolves with the error that stopped the stack, like reading from or writing to the
+    /// device failing or the device being closed, so it can be logged or the stack be
+    /// recreated. Pending while the stack runs.
+    pub async fn driver(&self) -> std::io::Error {
+        let mut fatal = self.fatal.clone();
+        // The sender is dropped without an error if the dispatcher panicked or was aborted
+        let _ = fatal.wait_for(Option::is_some).await;
+        self.stopped_error()
+    }
+
+    fn stopped_error(&self) -> std::io::Error {
+        self.fatal_error()
+            .unwrap_or_else(|| std::io::Error::other("the stack stopped"))
+    }
+
+    /// A copy of the error that stopped the stack.
+    fn fatal_error(&self) -> Option<std::io::Error> {
+        let fatal = self.fatal.borrow();
+        let err = fatal.as_ref()?;
+        Some(std::io::Error::new(err.kind(), err.to_string()))
+    }
+
+    /// Changes the MTU of the device while the stack runs, like when a phone moves from
+    /// cellular to Wi-Fi. Packets sent from now on fit it, and connections clamp their MSS
+    /// to a lower MTU, resending the segments in flight that no longer fit. Connections keep
+    /// their MSS when the MTU rises, new ones use the new MTU.
+    pub fn set_mtu(&self, mtu: u16) {
+        self.pkt_sender.set_mtu(mtu);
+    }
+
+    /// The MTU of the device, [`IpStackConfig::mtu`] until changed with
+    /// [`set_mtu`](Self::set_mtu).
+    pub fn mtu(&self) -> u16 {
+        self.pkt_sender.mtu()
+    }
+
+    /// UDP datagrams dropped for a wrong checksum under
+    /// [`IpStackConfig::validate_checksums`].
+    pub fn udp_checksum_errors(&self) -> u64 {
+        self.metrics.udp_checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// IPv4 packets dropped for a wrong header checksum under
+    /// [`IpStackConfig::validate_ip_tcp_checksums`].
+    pub fn ip_checksum_errors(&self) -> u64 {
+        self.metrics.ipv4_checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// TCP segments dropped for a wrong checksum under
+    /// [`IpStackConfig::validate_ip_tcp_checksums`].
+    pub fn tcp_checksum_errors(&self) -> u64 {
+        self.metrics.tcp_checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// The counters of the packets the stack handled.
+    pub fn metrics(&self) -> Arc<IpStackMetrics> {
+        self.metrics.clone()
+    }
+}
+
+#[cfg(feature = "tun-device")]
+impl IpStack {
+    /// Creates the TUN device of `tun_config` with the `tun` crate, on every platform it
+    /// supports, and a stack on it. The MTU of `config` is taken from the device, and
+    /// packet information is turned off as the crate strips it.
+    pub fn from_tun_config(
+        tun_config: tun::Configuration,
+        mut config: IpStackConfig,
+    ) -> Result<IpStack> {
+        use tun::AbstractDevice;
+        let device = tun::create_as_async(&tun_config).map_err(std::io::Error::other)?;
+        if let Ok(mtu) = device.mtu() {
+            config.mtu(mtu);
+        }
+        config.packet_information(false);
+        Ok(IpStack::new(config, device))
     }
 }
 
-fn run<D>(
+/// The channels between an [`IpStack`] and its dispatcher.
+struct Channels {
+    accept: UnboundedSender<IpStackStream>,
+    connect: UnboundedReceiver<ConnectRequest>,
+    replace: UnboundedReceiver<DeviceReplacement>,
+    udp: Option<PacketSender>, // all UDP datagrams under `UdpMode::Single`
+    egress: (EgressSender, EgressReceiver),
+    ingress: UnboundedReceiver<(DeviceId, Vec<u8>)>,
+    fatal: watch::Sender<Option<std::io::Error>>, // the error the dispatcher stopped with
+}
+
+fn run<P: PacketDevice>(
     config: IpStackConfig,
-    mut device: D,
-    accept_sender: UnboundedSender<IpStackStream>,
-) -> JoinHandle<Result<()>>
-where
-    D: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
+    mut device: P,
+    channels: Channels,
+    pending: PendingConnections,
+    metrics: Arc<IpStackMetrics>,
+    routes: SharedRoutingTable,
+) -> JoinHandle<Result<()>> {
     let mut sessions: SessionCollection = AHashMap::new();
-    let pi = config.packet_information;
+    // Frames of a TAP device carry no packet information
+    let pi = config.packet_information && config.ethernet.is_none();
+    let mut ethernet = ethernet::Ethernet::new(&config);
     let offset = if pi && cfg!(unix) { 4 } else { 0 };
-    let mut buffer = [0_u8; u16::MAX as usize + 4];
-    let (pkt_sender, mut pkt_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
+    let batch_size = config.device_batch_size;
+    let mut buffers = vec![vec![0_u8; u16::MAX as usize + 4 + vnet::HEADER_LEN]; batch_size];
+    let mut lens = vec![0; batch_size];
+    let mut pool = pool::BufferPool::new(config.mtu);
+    let reassembly = ReassemblyUsage::default();
+    let mut limiter = SynLimiter::new(&config, pending.clone());
+    let mut rst_limiter = RstLimiter::new(&config);
+    let mut flows = FlowLimiter::new(&config);
+    let mut fragments =
+        Ipv4Reassembly::new(config.ipv4_reassembly_limit, config.ipv4_reassembly_timeout);
+    let Channels {
+        accept: accept_sender,
+        connect: mut connect_receiver,
+        replace: mut replace_receiver,
+        udp: udp_sender,
+        egress: (pkt_sender, mut pkt_receiver),
+        ingress: mut ingress_receiver,
+        fatal,
+    } = channels;
 
-    tokio::spawn(async move {
+    let dispatcher = async move {
         loop {
-            select! {
-                Ok(n) = device.read(&mut buffer) => {
-                    if let Some(stream) = process_device_read(
-                        &buffer[offset..n],
+            let (from, count) = select! {
+                result = device.recv_many(&mut buffers, &mut lens) => {
+                    (DeviceId::PRIMARY, result?)
+                }
+                Some((from, data)) = ingress_receiver.recv() => {
+                    let n = data.len().min(buffers[0].len());
+                    buffers[0][..n].copy_from_slice(&data[..n]);
+                    lens[0] = n;
+                    (from, 1)
+                }
+                Some((replacement, reply)) = replace_receiver.recv() => {
+                    let result = match replacement.downcast::<P>() {
+                        Ok(replacement) => {
+                            trace!("replacing the device");
+                            device = *replacement;
+                            Ok(())
+                        }
+                        Err(_) => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "not of the type of the device the stack was created with",
+                        )),
+                    };
+                    let _ = reply.send(result);
+                    continue;
+                }
+                Some((local, remote, reply)) = connect_receiver.recv() => {
+                    let stream = process_connect(
+                        local,
+                        remote,
                         &mut sessions,
                         pkt_sender.clone(),
                         &config,
-                    ) {
-                        accept_sender.send(stream)?;
-                    }
+                        &reassembly,
+                    );
+                    let _ = reply.send(stream);
+                    continue;
                 }
                 Some(packet) = pkt_receiver.recv() => {
-                    process_upstream_recv(
-                        packet,
-                        &mut sessions,
-                        &mut device,
-                        #[cfg(unix)]
-                        pi,
-                    )
-                    .await?;
+                    // Packets queued meanwhile go to the device with it
+                    let mut batch = Vec::new();
+                    let mut next = Some(packet);
+                    while let Some(packet) = next {
+                        let bytes = process_upstream_recv(
+                            packet,
+                            &mut sessions,
+                            &mut pool,
+                            &config,
+                            &metrics,
+                            &routes,
+                            ethernet.as_ref(),
+                            #[cfg(unix)]
+                            pi,
+                        );
+                        batch.extend(bytes);
+                        next = match batch.len() < batch_size {
+                            true => pkt_receiver.try_recv(),
+                            false => None,
+                        };
+                    }
+                    if config.vnet_hdr {
+                        batch = vnet::encode(batch, offset);
+                    }
+                    if !batch.is_empty() {
+                        device.send_many(&batch).await?;
+                    }
+                    pool.recycle(batch);
+                    continue;
+                }
+            };
+            // Devices added with add_device exchange bare IP packets
+            let (vnet_hdr, start) = match from {
+                DeviceId::PRIMARY => (config.vnet_hdr, offset),
+                _ => (false, 0),
+            };
+            let packets = buffers
+                .iter()
+                .zip(&lens)
+                .take(count)
+                .flat_map(|(buffer, &n)| vnet::segments(&buffer[start..n], vnet_hdr));
+            for segment in packets {
+                let data = &*segment;
+                let data = match ethernet.as_mut().filter(|_| from == DeviceId::PRIMARY) {
+                    Some(ethernet) => match ethernet.receive(data) {
+                        ethernet::Frame::Ip(packet) => packet,
+                        ethernet::Frame::Reply(reply) => {
+                            device.send(&reply).await?;
+                            continue;
+                        }
+                        ethernet::Frame::Ignore => continue,
+                    },
+                    None => data,
+                };
+                IpStackMetrics::add(&metrics.packets_in, 1);
+                IpStackMetrics::add(&metrics.bytes_in, data.len() as u64);
+                #[cfg(feature = "capture")]
+                if let Some(capture) = &config.capture {
+                    capture.record(Direction::Ingress, data);
                 }
+                if config.validate_ip_tcp_checksums && !packet::has_valid_ipv4_checksum(data) {
+                    trace!("dropping IPv4 packet with a bad header checksum");
+                    IpStackMetrics::add(&metrics.ipv4_checksum_errors, 1);
+                    continue;
+                }
+                let Some(data) = fragments.push(data) else {
+                    continue;
+                };
+                let packet = match NetworkPacket::parse_pooled(&data, &mut pool) {
+                    Ok(packet) => packet,
+                    Err(_) => {
+                        IpStackMetrics::add(&metrics.parse_errors, 1);
+                        accept_sender.send(IpStackStream::UnknownNetwork(data.into_owned()))?;
+                        continue;
+                    }
+                };
+                if config.validate_checksums && !packet.has_valid_udp_checksum() {
+                    trace!(
+                        "dropping UDP datagram with a bad checksum from {}",
+                        packet.src_addr()
+                    );
+                    IpStackMetrics::add(&metrics.udp_checksum_errors, 1);
+                    continue;
+                }
+                if config.validate_ip_tcp_checksums && !packet.has_valid_tcp_checksum() {
+                    trace!(
+                        "dropping TCP segment with a bad checksum from {}",
+                        packet.src_addr()
+                    );
+                    IpStackMetrics::add(&metrics.tcp_checksum_errors, 1);
+                    continue;
+                }
+                match filter::verdict(&config, &packet, Direction::Ingress) {
+                    Verdict::Accept => {}
+                    Verdict::Drop => {
+                        trace!("packet from {} dropped by the filter", packet.src_addr());
+                        continue;
+                    }
+                    Verdict::Reject => {
+                        trace!("packet from {} rejected by the filter", packet.src_addr());
+                        reject(packet, &data, &pkt_sender, &config, &mut rst_limiter);
+                        continue;
+                    }
+                }
+                if config.icmp_time_exceeded
+                    && packet.ttl() <= 1
+                    && !is_broadcast(packet.dst_addr().ip(), &config)
+                    && !packet.is_icmp_error_exempt()
+                {
+                    trace!("TTL of a packet from {} exceeded", packet.src_addr());
+                    if !pkt_sender.allow_icmp() {
+                        trace!("ICMP rate limit reached for {}", packet.src_addr());
+                    } else if let Ok(error) = packet.time_exceeded(config.ttl) {
+                        let _ = pkt_sender.send(error);
+                    }
+                    continue;
+                }
+                let route = filter::forward_to(&config, &packet)
+                    .or_else(|| routes.read().unwrap().route(packet.dst_addr().ip()));
+                if let Some(to) = route.filter(|to| *to != from) {
+                    let mut bytes = data.into_owned();
+                    if !packet::decrement_ttl(&mut bytes) {
+                        trace!(
+                            "TTL of a packet from {} to forward exceeded",
+                            packet.src_addr()
+                        );
+                        continue;
+                    }
+                    IpStackMetrics::add(&metrics.packets_out, 1);
+                    IpStackMetrics::add(&metrics.bytes_out, bytes.len() as u64);
+                    #[cfg(feature = "capture")]
+                    if let Some(capture) = &config.capture {
+                        capture.record(Direction::Egress, &bytes);
+                    }
+                    match to {
+                        DeviceId::PRIMARY => {
+                            #[cfg(unix)]
+                            frame(&mut bytes, packet.src_addr().is_ipv4(), pi);
+                            if let Some(ethernet) = &ethernet {
+                                ethernet.encapsulate(&mut bytes, packet.dst_addr().ip());
+                            }
+                            if config.vnet_hdr {
+                                vnet::prepend(&mut bytes, offset);
+                            }
+                            device.send(&bytes).await?
+                        }
+                        to => routes.read().unwrap().send(to, bytes),
+                    }
+                    continue;
+                }
+                if matches!(packet.transport_protocol(), IpStackPacketProtocol::Udp) {
+                    if config.intercept_dns {
+                        let port = packet.dst_addr().port();
+                        if config.dns_tls_ports.contains(&port) {
+                            if !pkt_sender.allow_icmp() {
+                                trace!("ICMP rate limit reached for {}", packet.src_addr());
+                            } else if let Ok(refusal) = packet.port_unreachable(config.ttl) {
+                                let _ = pkt_sender.send(refusal);
+                            }
+                            continue;
+                        }
+                        if config.dns_ports.contains(&port) {
+                            let query = dns::DnsQuery::udp(
+                                packet.src_addr(),
+                                packet.dst_addr(),
+                                packet.payload.clone(),
+                                pkt_sender.clone(),
+                                stream::SendOptions::new(&config),
+                            );
+                            if let Some(query) = query {
+                                accept_sender.send(IpStackStream::Dns(query))?;
+                                continue;
+                            }
+                        }
+                    }
+                    let policy = match is_broadcast(packet.dst_addr().ip(), &config) {
+                        true => config.udp_broadcast,
+                        false => UdpBroadcastPolicy::Stream,
+                    };
+                    match (policy, &udp_sender) {
+                        (UdpBroadcastPolicy::Drop, _) => {
+                            trace!("dropping UDP broadcast to {}", packet.dst_addr());
+                            continue;
+                        }
+                        (UdpBroadcastPolicy::Deliver, _) => {
+                            let broadcast = IpStackUdpBroadcast::new(packet);
+                            accept_sender.send(IpStackStream::UdpBroadcast(broadcast))?;
+                            continue;
+                        }
+                        (UdpBroadcastPolicy::Socket, Some(udp_sender)) => {
+                            let _ = udp_sender.send(packet);
+                            continue;
+                        }
+                        (_, Some(udp_sender)) if config.udp_mode == UdpMode::Single => {
+                            let _ = udp_sender.send(packet);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                if opens_flow(&packet, &sessions) {
+                    match filter::accept_verdict(&config, &packet) {
+                        Verdict::Accept => {}
+                        Verdict::Drop => {
+                            trace!("flow from {} dropped by the policy", packet.src_addr());
+                            continue;
+                        }
+                        Verdict::Reject => {
+                            trace!("flow from {} rejected by the policy", packet.src_addr());
+                            reject(packet, &data, &pkt_sender, &config, &mut rst_limiter);
+                            continue;
+                        }
+                    }
+                    if !flows.admit(&packet.network_tuple(), &mut sessions) {
+                        trace!("flow from {} over the flow limits", packet.src_addr());
+                        if config.flow_limit_policy == FlowLimitPolicy::Reject {
+                            reject(packet, &data, &pkt_sender, &config, &mut rst_limiter);
+                        }
+                        continue;
+                    }
+                } else {
+                    flows.touch(&packet.network_tuple());
+                }
+                if let Some(stream) = process_device_read(
+                    packet,
+                    &mut sessions,
+                    pkt_sender.clone(),
+                    &config,
+                    &reassembly,
+                    &mut limiter,
+                    &mut rst_limiter,
+                ) {
+                    if let IpStackStream::Tcp(tcp) = stream {
+                        if config.intercept_dns
+                            && config.dns_ports.contains(&tcp.peer_addr().port())
+                        {
+                            dns::serve_tcp(tcp, accept_sender.clone());
+                            continue;
+                        }
+                        pending.fetch_add(1, Ordering::Relaxed);
+                        accept_sender.send(IpStackStream::Tcp(tcp))?;
+                    } else {
+                        accept_sender.send(stream)?;
+                    }
+                }
+            }
+        }
+    };
+    tokio::spawn(async move {
+        let result: Result<()> = dispatcher.await;
+        match &result {
+            // The IpStack was dropped
+            Ok(()) | Err(IpStackError::SendError(_)) => {}
+            Err(err) => {
+                error!("the stack stopped: {err}");
+                let err = match err {
+                    IpStackError::IoError(err) => std::io::Error::new(err.kind(), err.to_string()),
+                    err => std::io::Error::other(err.to_string()),
+                };
+                fatal.send_replace(Some(err));
             }
         }
+        result
     })
 }
 
-fn process_device_read(
+/// Refuses a packet from the device the filter rejected: a TCP segment with an RST, anything
+/// else with an ICMP administratively prohibited error.
+fn reject(
+    packet: NetworkPacket,
     data: &[u8],
-    sessions: &mut SessionCollection,
-    pkt_sender: PacketSender,
+    pkt_sender: &EgressSender,
     config: &IpStackConfig,
-) -> Option<IpStackStream> {
-    let Ok(packet) = NetworkPacket::parse(data) else {
-        return Some(IpStackStream::UnknownNetwork(data.to_owned()));
+    rst_limiter: &mut RstLimiter,
+) {
+    if let IpStackPacketProtocol::Tcp(h) = packet.transport_protocol() {
+        if !h.inner().rst && rst_limiter.allow() {
+            IpStackTcpStream::reset_unknown(
+                packet.src_addr(),
+                packet.dst_addr(),
+                &h,
+                packet.payload.len(),
+                pkt_sender.clone(),
+                config,
+            );
+        }
+        return;
+    }
+    if packet.is_icmp_error_exempt() || is_broadcast(packet.dst_addr().ip(), config) {
+        return;
+    }
+    if !pkt_sender.allow_icmp() {
+        trace!("ICMP rate limit reached for {}", packet.src_addr());
+        return;
+    }
+    let error = IcmpPacket::administratively_prohibited(data)
+        .and_then(|error| error.into_packet(config.ttl));
+    match error {
+        Ok(error) => {
+            let _ = pkt_sender.send(error);
+        }
+        Err(err) => trace!(
+            "administratively prohibited for {}: {}",
+            packet.src_addr(),
+            err
+        ),
+    }
+}
+
+/// Whether `packet` opens a TCP connection or UDP flow, which the stream it goes to is
+/// created for.
+fn opens_flow(packet: &NetworkPacket, sessions: &SessionCollection) -> bool {
+    let opens = match packet.transport_protocol() {
+        IpStackPacketProtocol::Tcp(h) => h.inner().syn && !h.inner().ack,
+        IpStackPacketProtocol::Udp | IpStackPacketProtocol::UdpLite => true,
+        IpStackPacketProtocol::Unknown => false,
     };
+    opens
+        && sessions
+            .get(&packet.network_tuple())
+            .is_none_or(|s| s.is_closed())
+}
 
+/// Whether `addr` reaches more than one host, 255.255.255.255, the configured subnet
+/// broadcast or a multicast group.
+fn is_broadcast(addr: IpAddr, config: &IpStackConfig) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            addr.is_broadcast() || addr.is_multicast() || config.subnet_broadcast == Some(addr)
+        }
+        IpAddr::V6(addr) => addr.is_multicast(),
+    }
+}
+
+fn process_device_read(
+    packet: NetworkPacket,
+    sessions: &mut SessionCollection,
+    pkt_sender: EgressSender,
+    config: &IpStackConfig,
+    reassembly: &ReassemblyUsage,
+    limiter: &mut SynLimiter,
+    rst_limiter: &mut RstLimiter,
+) -> Option<IpStackStream> {
     if let IpStackPacketProtocol::Unknown = packet.transport_protocol() {
-        return Some(IpStackStream::UnknownTransport(
-            IpStackUnknownTransport::new(
-                packet.src_addr().ip(),
-                packet.dst_addr().ip(),
-                packet.payload,
-                &packet.ip,
-                config.mtu,
-                pkt_sender,
-            ),
-        ));
+        if let Some(error) = packet.icmp_error() {
+            if let Some(session) = sessions.get(&error.tuple) {
+                // The stream that sent the quoted packet handles the error
+                let _ = session.send(packet);
+                return None;
+            }
+        }
+        if let Some(ndp) = ndp::NdpPacket::new(&packet) {
+            if let Some(reply) = config.ndp_responder.then(|| ndp.respond(config)).flatten() {
+                if pkt_sender.allow_icmp() {
+                    let _ = pkt_sender.send(reply);
+                }
+                return None;
+            }
+            return Some(IpStackStream::Ndp(ndp));
+        }
+        if let Some(icmp) =
+            IpStackIcmpStream::new(&packet, config.mtu, config.ttl, pkt_sender.clone())
+        {
+            return Some(IpStackStream::Icmp(icmp));
+        }
+        let unknown = IpStackUnknownTransport::new(
+            packet.src_addr().ip(),
+            packet.dst_addr().ip(),
+            packet.payload,
+            &packet.ip,
+            config.mtu,
+            config.ttl,
+            pkt_sender,
+        );
+        #[cfg(feature = "tunnel")]
+        return Some(stream::classify_tunnel(unknown));
+        #[cfg(not(feature = "tunnel"))]
+        return Some(IpStackStream::UnknownTransport(unknown));
     }
 
     match sessions.entry(packet.network_tuple()) {
         Occupied(mut entry) => {
             if let Err(e) = entry.get().send(packet) {
                 trace!("New stream because: {}", e);
-                create_stream(e.0, config, pkt_sender).map(|s| {
+                create_stream(e.0, config, pkt_sender, reassembly, limiter, rst_limiter).map(|s| {
                     entry.insert(s.0);
                     s.1
                 })
@@ -191,29 +1423,118 @@ fn process_device_read(
                 None
             }
         }
-        Vacant(entry) => create_stream(packet, config, pkt_sender).map(|s| {
-            entry.insert(s.0);
-            s.1
-        }),
+        Vacant(entry) => {
+            create_stream(packet, config, pkt_sender, reassembly, limiter, rst_limiter).map(|s| {
+                entry.insert(s.0);
+                s.1
+            })
+        }
+    }
+}
+
+fn process_connect(
+    local: SocketAddr,
+    remote: SocketAddr,
+    sessions: &mut SessionCollection,
+    pkt_sender: EgressSender,
+    config: &IpStackConfig,
+    reassembly: &ReassemblyUsage,
+) -> Result<IpStackTcpStream> {
+    if local.is_ipv4() != remote.is_ipv4() {
+        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+    }
+    let tuple = NetworkTuple {
+        src: remote,
+        dst: local,
+        protocol: IpNumber::TCP,
+    };
+    if sessions.get(&tuple).is_some_and(|s| !s.is_closed()) {
+        return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse).into());
     }
+    let (stream_sender, stream_receiver) = mpsc::unbounded_channel();
+    let stream = IpStackTcpStream::connect(
+        remote,
+        local,
+        stream_sender.clone(),
+        stream_receiver,
+        pkt_sender,
+        config,
+        reassembly.clone(),
+    )?;
+    sessions.insert(tuple, stream_sender);
+    Ok(stream)
 }
 
 fn create_stream(
     packet: NetworkPacket,
     config: &IpStackConfig,
-    pkt_sender: PacketSender,
+    pkt_sender: EgressSender,
+    reassembly: &ReassemblyUsage,
+    limiter: &mut SynLimiter,
+    rst_limiter: &mut RstLimiter,
 ) -> Option<(PacketSender, IpStackStream)> {
     match packet.transport_protocol() {
         IpStackPacketProtocol::Tcp(h) => {
+            if h.inner().syn && !h.inner().ack && !limiter.allow() {
+                trace!(
+                    "SYN from {} refused by the connection limits",
+                    packet.src_addr()
+                );
+                IpStackMetrics::add(&pkt_sender.metrics().accept_queue_drops, 1);
+                if config.syn_limit_policy == SynLimitPolicy::Reset && rst_limiter.allow() {
+                    IpStackTcpStream::reset_unknown(
+                        packet.src_addr(),
+                        packet.dst_addr(),
+                        &h,
+                        packet.payload.len(),
+                        pkt_sender,
+                        config,
+                    );
+                }
+                return None;
+            }
+            if h.inner().syn
+                && config.intercept_dns
+                && config.dns_tls_ports.contains(&packet.dst_addr().port())
+            {
+                trace!("refusing encrypted DNS to {}", packet.dst_addr());
+                if rst_limiter.allow() {
+                    IpStackTcpStream::reset_unknown(
+                        packet.src_addr(),
+                        packet.dst_addr(),
+                        &h,
+                        packet.payload.len(),
+                        pkt_sender,
+                        config,
+                    );
+                }
+                return None;
+            }
+            if !h.inner().syn {
+                trace!("TCP segment from {} on an unknown flow", packet.src_addr());
+                if !h.inner().rst && rst_limiter.allow() {
+                    IpStackTcpStream::reset_unknown(
+                        packet.src_addr(),
+                        packet.dst_addr(),
+                        &h,
+                        packet.payload.len(),
+                        pkt_sender,
+                        config,
+                    );
+                }
+                return None;
+            }
+            let (stream_sender, stream_receiver) = mpsc::unbounded_channel();
             match IpStackTcpStream::new(
-                packet.src_addr(),
-                packet.dst_addr(),
+                packet,
                 h,
+                stream_sender.clone(),
+                stream_receiver,
                 pkt_sender,
-                config.mtu,
-                config.tcp_timeout,
+                config,
+                reassembly.clone(),
             ) {
-                Ok(stream) => Some((stream.stream_sender(), IpStackStream::Tcp(stream))),
+                Ok(stream) => Some((stream_sender, IpStackStream::Tcp(stream))),
                 Err(e) => {
                     if matches!(e, IpStackError::InvalidTcpPacket) {
                         trace!("Invalid TCP packet");
@@ -224,16 +1545,10 @@ fn create_stream(
                 }
             }
         }
-        IpStackPacketProtocol::Udp => {
-            let stream = IpStackUdpStream::new(
-                packet.src_addr(),
-                packet.dst_addr(),
-                packet.payload,
-                pkt_sender,
-                config.mtu,
-                config.udp_timeout,
-            );
-            Some((stream.stream_sender(), IpStackStream::Udp(stream)))
+        IpStackPacketProtocol::Udp | IpStackPacketProtocol::UdpLite => {
+            let (stream_sender, stream_receiver) = mpsc::unbounded_channel();
+            let stream = IpStackUdpStream::new(packet, stream_receiver, pkt_sender, config);
+            Some((stream_sender, IpStackStream::Udp(stream)))
         }
         IpStackPacketProtocol::Unknown => {
             unreachable!()
@@ -241,34 +1556,66 @@ fn create_stream(
     }
 }
 
-async fn process_upstream_recv<D>(
+/// The bytes of `packet` for the primary device, `None` if the filter drops it or it goes
+/// to no device or another one, to which it is sent.
+#[allow(clippy::too_many_arguments)]
+fn process_upstream_recv(
     packet: NetworkPacket,
     sessions: &mut SessionCollection,
-    device: &mut D,
+    pool: &mut pool::BufferPool,
+    config: &IpStackConfig,
+    metrics: &IpStackMetrics,
+    routes: &SharedRoutingTable,
+    ethernet: Option<&ethernet::Ethernet>,
     #[cfg(unix)] packet_information: bool,
-) -> Result<()>
-where
-    D: AsyncWrite + Unpin + 'static,
-{
+) -> Option<Vec<u8>> {
     if packet.ttl() == 0 {
         sessions.remove(&packet.reverse_network_tuple());
-        return Ok(());
+        return None;
     }
-    #[allow(unused_mut)]
-    let Ok(mut packet_bytes) = packet.to_bytes() else {
+    if filter::verdict(config, &packet, Direction::Egress) != Verdict::Accept {
+        trace!("packet to {} dropped by the filter", packet.dst_addr());
+        return None;
+    }
+    let mut packet_bytes = pool.frame();
+    if packet.write_to(&mut packet_bytes).is_err() {
         trace!("to_bytes error");
-        return Ok(());
-    };
-    #[cfg(unix)]
-    if packet_information {
-        if packet.src_addr().is_ipv4() {
-            packet_bytes.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP4].concat());
-        } else {
-            packet_bytes.splice(0..0, [TUN_FLAGS, TUN_PROTO_IP6].concat());
+        return None;
+    }
+    IpStackMetrics::add(&metrics.packets_out, 1);
+    IpStackMetrics::add(&metrics.bytes_out, packet_bytes.len() as u64);
+    if matches!(&packet.transport, packet::TransportHeader::Tcp(h) if h.rst) {
+        IpStackMetrics::add(&metrics.rsts_sent, 1);
+    }
+    #[cfg(feature = "capture")]
+    if let Some(capture) = &config.capture {
+        capture.record(Direction::Egress, &packet_bytes);
+    }
+    let route = routes.read().unwrap().route(packet.dst_addr().ip());
+    match route {
+        Some(to) if to != DeviceId::PRIMARY => {
+            routes.read().unwrap().send(to, packet_bytes);
+            None
+        }
+        _ => {
+            #[cfg(unix)]
+            frame(
+                &mut packet_bytes,
+                packet.src_addr().is_ipv4(),
+                packet_information,
+            );
+            if let Some(ethernet) = ethernet {
+                ethernet.encapsulate(&mut packet_bytes, packet.dst_addr().ip());
+            }
+            Some(packet_bytes)
         }
     }
-    device.write_all(&packet_bytes).await?;
-    // device.flush().await.unwrap();
+}
 
-    Ok(())
+/// Prepends the packet information header to a packet for the device if it expects one.
+#[cfg(unix)]
+fn frame(packet_bytes: &mut Vec<u8>, is_ipv4: bool, packet_information: bool) {
+    if packet_information {
+        packet_bytes.splice(0..0, packet::packet_information(is_ipv4));
+    }
 }
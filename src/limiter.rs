@@ -0,0 +1,253 @@
+use crate::{packet::NetworkTuple, IpStackConfig, SessionCollection};
+use ahash::AHashMap;
+use etherparse::IpNumber;
+use log::trace;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::time::Instant;
+
+pub(crate) type PendingConnections = Arc<AtomicUsize>; // TCP streams queued for `accept`
+
+/// What happens to a SYN refused by the connection limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SynLimitPolicy {
+    /// Silently discard the SYN, the peer retransmits it later.
+    #[default]
+    Drop,
+    /// Answer the SYN with an RST so the peer gives up right away.
+    Reset,
+}
+
+/// What happens to a new TCP connection or UDP flow over `max_tcp_connections` or
+/// `max_udp_flows`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlowLimitPolicy {
+    /// Silently discard the packet opening it.
+    #[default]
+    Drop,
+    /// Refuse it, a connection with an RST and a flow with an ICMP administratively
+    /// prohibited error.
+    Reject,
+    /// Make room by closing the connection or flow that received nothing from the device
+    /// for the longest. A connection is reset and its stream fails with `ConnectionAborted`,
+    /// a flow's stream reads `UnexpectedEof`.
+    EvictIdle,
+}
+
+/// Whether and how often segments that belong to no connection are answered with an RST.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RstPolicy {
+    /// Reset every such segment (RFC 9293).
+    #[default]
+    Always,
+    /// Never reset, the segments are dropped silently.
+    Never,
+    /// Send at most this many resets per second, the rest is dropped.
+    RateLimited(u32),
+}
+
+/// Admission control for new TCP connections: a cap on the streams waiting to be accepted and
+/// a token bucket over incoming SYNs.
+#[derive(Debug)]
+pub(crate) struct SynLimiter {
+    max_pending: Option<usize>,
+    pending: PendingConnections,
+    rate: Option<TokenBucket>,
+}
+
+impl SynLimiter {
+    pub(crate) fn new(config: &IpStackConfig, pending: PendingConnections) -> Self {
+        SynLimiter {
+            max_pending: config.max_pending_connections,
+            pending,
+            rate: config.syn_rate_limit.map(TokenBucket::new),
+        }
+    }
+
+    /// Whether a new connection may be set up, consuming a token if so.
+    pub(crate) fn allow(&mut self) -> bool {
+        if self
+            .max_pending
+            .is_some_and(|max| self.pending.load(Ordering::Relaxed) >= max)
+        {
+            return false;
+        }
+        self.rate.as_mut().is_none_or(TokenBucket::take)
+    }
+}
+
+/// Caps the TCP connections and UDP flows of the session table.
+#[derive(Debug)]
+pub(crate) struct FlowLimiter {
+    max_tcp: Option<usize>,
+    max_udp: Option<usize>,
+    policy: FlowLimitPolicy,
+    tcp: usize, // sessions counted when last recounted, plus those admitted since
+    udp: usize,
+    last_seen: AHashMap<NetworkTuple, Instant>, // packets from the device, for `EvictIdle`
+}
+
+impl FlowLimiter {
+    pub(crate) fn new(config: &IpStackConfig) -> Self {
+        FlowLimiter {
+            max_tcp: config.max_tcp_connections,
+            max_udp: config.max_udp_flows,
+            policy: config.flow_limit_policy,
+            tcp: 0,
+            udp: 0,
+            last_seen: AHashMap::new(),
+        }
+    }
+
+    fn is_udp(protocol: IpNumber) -> bool {
+        protocol == IpNumber::UDP || protocol == IpNumber::UDP_LITE
+    }
+
+    /// Notes a packet from the device on the flow `tuple`.
+    pub(crate) fn touch(&mut self, tuple: &NetworkTuple) {
+        if self.policy != FlowLimitPolicy::EvictIdle {
+            return;
+        }
+        if let Some(seen) = self.last_seen.get_mut(tuple) {
+            *seen = Instant::now();
+        }
+    }
+
+    /// Whether the flow `tuple` may be added to `sessions`, after evicting the most idle one
+    /// of its protocol from them under [`FlowLimitPolicy::EvictIdle`].
+    pub(crate) fn admit(&mut self, tuple: &NetworkTuple, sessions: &mut SessionCollection) -> bool {
+        let udp = Self::is_udp(tuple.protocol);
+        let max = match tuple.protocol {
+            IpNumber::TCP => self.max_tcp,
+            _ if udp => self.max_udp,
+            _ => None,
+        };
+        let Some(max) = max else {
+            return true;
+        };
+        if (if udp { self.udp } else { self.tcp }) >= max {
+            // Streams do not report closing, find out which did
+            let (mut tcp, mut udp_flows) = (0, 0);
+            sessions.retain(|tuple, session| {
+                let open = !session.is_closed();
+                match tuple.protocol {
+                    IpNumber::TCP if open => tcp += 1,
+                    protocol if open && Self::is_udp(protocol) => udp_flows += 1,
+                    _ => {}
+                }
+                open
+            });
+            self.last_seen
+                .retain(|tuple, _| sessions.contains_key(tuple));
+            (self.tcp, self.udp) = (tcp, udp_flows);
+        }
+        let count = if udp { &mut self.udp } else { &mut self.tcp };
+        if *count >= max {
+            if self.policy != FlowLimitPolicy::EvictIdle {
+                return false;
+            }
+            let idle = self
+                .last_seen
+                .iter()
+                .filter(|(t, _)| Self::is_udp(t.protocol) == udp)
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(t, _)| *t);
+            let Some(idle) = idle else {
+                return false;
+            };
+            trace!("evicting the idle flow {:?}", idle);
+            self.last_seen.remove(&idle);
+            sessions.remove(&idle);
+            *count -= 1;
+        }
+        *count += 1;
+        if self.policy == FlowLimitPolicy::EvictIdle {
+            self.last_seen.insert(*tuple, Instant::now());
+        }
+        true
+    }
+}
+
+/// Applies the [`RstPolicy`] to the resets sent for unknown flows.
+#[derive(Debug)]
+pub(crate) struct RstLimiter {
+    policy: RstPolicy,
+    rate: Option<TokenBucket>,
+}
+
+impl RstLimiter {
+    pub(crate) fn new(config: &IpStackConfig) -> Self {
+        let rate = match config.rst_policy {
+            RstPolicy::RateLimited(rate) => Some(TokenBucket::new(rate)),
+            _ => None,
+        };
+        RstLimiter {
+            policy: config.rst_policy,
+            rate,
+        }
+    }
+
+    /// Whether a reset may be sent now, consuming a token if so.
+    pub(crate) fn allow(&mut self) -> bool {
+        match self.policy {
+            RstPolicy::Always => true,
+            RstPolicy::Never => false,
+            RstPolicy::RateLimited(_) => self.rate.as_mut().is_some_and(TokenBucket::take),
+        }
+    }
+}
+
+/// A token bucket over the ICMP messages the stack generates, shared by all streams.
+#[derive(Debug, Clone)]
+pub(crate) struct IcmpLimiter {
+    rate: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl IcmpLimiter {
+    pub(crate) fn new(config: &IpStackConfig) -> Self {
+        IcmpLimiter {
+            rate: config
+                .icmp_rate_limit
+                .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+        }
+    }
+
+    /// Whether an ICMP message may be sent now, consuming a token if so.
+    pub(crate) fn allow(&self) -> bool {
+        self.rate
+            .as_ref()
+            .is_none_or(|rate| rate.lock().unwrap().take())
+    }
+}
+
+/// Allows `rate` events per second with bursts of up to a second's worth.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the packets an [`IpStack`](crate::IpStack) handled, from
+/// [`IpStack::metrics`](crate::IpStack::metrics). They are live, only ever grow, and are read
+/// without a lock.
+#[derive(Debug, Default)]
+pub struct IpStackMetrics {
+    pub(crate) packets_in: AtomicU64,
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) packets_out: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+    pub(crate) parse_errors: AtomicU64,
+    pub(crate) ipv4_checksum_errors: AtomicU64,
+    pub(crate) tcp_checksum_errors: AtomicU64,
+    pub(crate) udp_checksum_errors: AtomicU64,
+    pub(crate) rsts_sent: AtomicU64,
+    pub(crate) accept_queue_drops: AtomicU64,
+    pub(crate) retransmissions: AtomicU64,
+}
+
+impl IpStackMetrics {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// IP packets read from the devices, without link-layer headers.
+    pub fn packets_in(&self) -> u64 {
+        self.packets_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the [`packets_in`](Self::packets_in).
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// IP packets written to the devices, forwarded ones included.
+    pub fn packets_out(&self) -> u64 {
+        self.packets_out.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the [`packets_out`](Self::packets_out).
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Packets from the devices the stack could not parse, accepted as
+    /// [`IpStackStream::UnknownNetwork`](crate::stream::IpStackStream::UnknownNetwork).
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Packets from the devices dropped for a wrong IPv4 header, TCP or UDP checksum.
+    pub fn checksum_drops(&self) -> u64 {
+        self.ipv4_checksum_errors.load(Ordering::Relaxed)
+            + self.tcp_checksum_errors.load(Ordering::Relaxed)
+            + self.udp_checksum_errors.load(Ordering::Relaxed)
+    }
+
+    /// TCP segments with the RST flag sent to the device.
+    pub fn rsts_sent(&self) -> u64 {
+        self.rsts_sent.load(Ordering::Relaxed)
+    }
+
+    /// SYNs refused as [`max_pending_connections`](crate::IpStackConfig::max_pending_connections)
+    /// streams were waiting to be accepted or the
+    /// [`syn_rate_limit`](crate::IpStackConfig::syn_rate_limit) was reached.
+    pub fn accept_queue_drops(&self) -> u64 {
+        self.accept_queue_drops.load(Ordering::Relaxed)
+    }
+
+    /// TCP segments sent again by the streams, after a timeout or for fast retransmit.
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,230 @@
+//! Devices for the tunnels of mobile platforms, to give to
+//! [`IpStack::new`](crate::IpStack::new). Enabled by the `mobile` feature.
+
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+/// A device over a TUN file descriptor that returns a packet per read and takes one per
+/// write, without packet information, like the one Android's `VpnService.Builder.establish`
+/// returns, detached from its `ParcelFileDescriptor`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct FdDevice {
+    fd: tokio::io::unix::AsyncFd<OwnedFd>,
+}
+
+#[cfg(unix)]
+impl FdDevice {
+    /// Takes over `fd`, making it non-blocking. Has to be called within a tokio runtime with
+    /// IO enabled.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FdDevice {
+            fd: tokio::io::unix::AsyncFd::new(fd)?,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn syscall_result(n: isize) -> io::Result<usize> {
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for FdDevice {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let read = guard.try_io(|fd| {
+                syscall_result(unsafe {
+                    libc::read(fd.as_raw_fd(), unfilled.as_mut_ptr().cast(), unfilled.len())
+                })
+            });
+            if let Ok(result) = read {
+                buf.advance(result?);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for FdDevice {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            let write = guard.try_io(|fd| {
+                syscall_result(unsafe {
+                    libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len())
+                })
+            });
+            if let Ok(result) = write {
+                return Poll::Ready(result);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The device of an iOS or macOS packet tunnel, following the callback model of
+/// `NEPacketTunnelFlow`. Packets are written with the callback given to
+/// [`new`](Self::new), which is expected to pass them on to `writePackets:withProtocols:`,
+/// with `AF_INET` or `AF_INET6` after their IP version. Packets read are handed to the
+/// [`PacketFlow`] it returns, from the completion handler of `readPacketsWithCompletionHandler:`.
+///
+/// The stack stops once all [`PacketFlow`]s were dropped.
+pub struct PacketFlowDevice {
+    packets: UnboundedReceiver<Vec<u8>>,
+    write: Box<dyn Fn(Vec<u8>) + Send + Sync>,
+}
+
+impl std::fmt::Debug for PacketFlowDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketFlowDevice").finish_non_exhaustive()
+    }
+}
+
+/// Hands the packets read from the packet flow to a [`PacketFlowDevice`].
+#[derive(Debug, Clone)]
+pub struct PacketFlow {
+    sender: UnboundedSender<Vec<u8>>,
+}
+
+impl PacketFlowDevice {
+    /// A device writing its packets with `write`, along with the [`PacketFlow`] to hand it the
+    /// packets read.
+    pub fn new<F>(write: F) -> (PacketFlowDevice, PacketFlow)
+    where
+        F: Fn(Vec<u8>) + Send + Sync + 'static,
+    {
+        let (sender, packets) = mpsc::unbounded_channel();
+        (
+            PacketFlowDevice {
+                packets,
+                write: Box::new(write),
+            },
+            PacketFlow { sender },
+        )
+    }
+}
+
+impl PacketFlow {
+    /// Hands `packets` to the device, returning `false` if the stack stopped, after which
+    /// the packet flow should not be read anymore.
+    pub fn receive<I>(&self, packets: I) -> bool
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+    {
+        packets
+            .into_iter()
+            .all(|packet| self.sender.send(packet).is_ok())
+    }
+}
+
+impl AsyncRead for PacketFlowDevice {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Dropping the flows reads as the device closing
+        if let Some(packet) = ready!(self.packets.poll_recv(cx)) {
+            let len = packet.len().min(buf.remaining());
+            buf.put_slice(&packet[..len]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PacketFlowDevice {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        (self.write)(buf.to_vec());
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn packet_flow() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (mut device, flow) = PacketFlowDevice::new({
+            let written = written.clone();
+            move |packet| written.lock().unwrap().push(packet)
+        });
+        assert!(flow.receive([vec![0x45; 20], vec![0x60; 40]]));
+        let mut buf = [0; 1500];
+        assert_eq!(device.read(&mut buf).await.unwrap(), 20);
+        assert_eq!(device.read(&mut buf).await.unwrap(), 40);
+        device.write_all(&[0x45; 28]).await.unwrap();
+        assert_eq!(*written.lock().unwrap(), vec![vec![0x45; 28]]);
+        drop(flow);
+        assert_eq!(device.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fd() {
+        use std::os::fd::FromRawFd;
+        let mut fds = [0; 2];
+        let n = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(n, 0);
+        let [a, b] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        let (mut a, mut b) = (FdDevice::new(a).unwrap(), FdDevice::new(b).unwrap());
+        a.write_all(&[0x45; 20]).await.unwrap();
+        a.write_all(&[0x45; 30]).await.unwrap();
+        let mut buf = [0; 1500];
+        assert_eq!(b.read(&mut buf).await.unwrap(), 20);
+        assert_eq!(b.read(&mut buf).await.unwrap(), 30);
+    }
+}
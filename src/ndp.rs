@@ -0,0 +1,216 @@
+//! Neighbor Discovery (RFC 4861) messages from the device, and the responder enabled by
+//! [`IpStackConfig::ndp_responder`](crate::IpStackConfig::ndp_responder) that answers them
+//! like the router of the link.
+
+use crate::{
+    packet::{IpHeader, NetworkPacket, TransportHeader},
+    IpStackConfig,
+};
+use etherparse::{Icmpv6Header, Icmpv6Type, IpNumber, Ipv6FlowLabel, Ipv6Header};
+use std::net::Ipv6Addr;
+
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const REDIRECT: u8 = 137;
+
+const OPTION_SOURCE_LINK_LAYER_ADDRESS: u8 = 1;
+const OPTION_TARGET_LINK_LAYER_ADDRESS: u8 = 2;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_MTU: u8 = 5;
+
+const HOP_LIMIT: u8 = 255; // NDP messages never leave the link
+const ROUTER_LIFETIME: u16 = 1800;
+const VALID_LIFETIME: u32 = 30 * 24 * 3600;
+const PREFERRED_LIFETIME: u32 = 7 * 24 * 3600;
+
+/// The message of an [`NdpPacket`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NdpMessage {
+    RouterSolicitation,
+    RouterAdvertisement {
+        hop_limit: u8,
+        router_lifetime: u16,
+    },
+    NeighborSolicitation {
+        target: Ipv6Addr,
+    },
+    NeighborAdvertisement {
+        target: Ipv6Addr,
+        router: bool,
+        solicited: bool,
+        overrides: bool,
+    },
+    Redirect {
+        target: Ipv6Addr,
+        destination: Ipv6Addr,
+    },
+}
+
+/// A Neighbor Discovery message from the device.
+#[derive(Debug, Clone)]
+pub struct NdpPacket {
+    src_addr: Ipv6Addr,
+    dst_addr: Ipv6Addr,
+    message: NdpMessage,
+    options: Vec<u8>,
+}
+
+impl NdpPacket {
+    /// The NDP message in `packet`, `None` for other packets and for messages that are
+    /// malformed or came from off the link.
+    pub(crate) fn new(packet: &NetworkPacket) -> Option<Self> {
+        let IpHeader::Ipv6(ip) = &packet.ip else {
+            return None;
+        };
+        if ip.next_header != IpNumber::IPV6_ICMP || ip.hop_limit != HOP_LIMIT {
+            return None;
+        }
+        let (icmp, body) = Icmpv6Header::from_slice(&packet.payload).ok()?;
+        let Icmpv6Type::Unknown {
+            type_u8,
+            code_u8: 0,
+            bytes5to8,
+        } = icmp.icmp_type
+        else {
+            return None;
+        };
+        let addr = |at: usize| -> Option<Ipv6Addr> {
+            let octets: [u8; 16] = body.get(at..at + 16)?.try_into().ok()?;
+            Some(Ipv6Addr::from(octets))
+        };
+        let (message, fixed_len) = match type_u8 {
+            ROUTER_SOLICITATION => (NdpMessage::RouterSolicitation, 0),
+            ROUTER_ADVERTISEMENT if body.len() >= 8 => (
+                NdpMessage::RouterAdvertisement {
+                    hop_limit: bytes5to8[0],
+                    router_lifetime: u16::from_be_bytes([bytes5to8[2], bytes5to8[3]]),
+                },
+                8,
+            ),
+            NEIGHBOR_SOLICITATION => (NdpMessage::NeighborSolicitation { target: addr(0)? }, 16),
+            NEIGHBOR_ADVERTISEMENT => (
+                NdpMessage::NeighborAdvertisement {
+                    target: addr(0)?,
+                    router: bytes5to8[0] & 0x80 != 0,
+                    solicited: bytes5to8[0] & 0x40 != 0,
+                    overrides: bytes5to8[0] & 0x20 != 0,
+                },
+                16,
+            ),
+            REDIRECT => (
+                NdpMessage::Redirect {
+                    target: addr(0)?,
+                    destination: addr(16)?,
+                },
+                32,
+            ),
+            _ => return None,
+        };
+        Some(NdpPacket {
+            src_addr: Ipv6Addr::from(ip.source),
+            dst_addr: Ipv6Addr::from(ip.destination),
+            message,
+            options: body[fixed_len..].to_vec(),
+        })
+    }
+
+    /// The sender on the device side, unspecified during duplicate address detection.
+    pub fn local_addr(&self) -> Ipv6Addr {
+        self.src_addr
+    }
+
+    pub fn peer_addr(&self) -> Ipv6Addr {
+        self.dst_addr
+    }
+
+    pub fn message(&self) -> &NdpMessage {
+        &self.message
+    }
+
+    /// The options following the message, as type-length-value records.
+    pub fn options(&self) -> &[u8] {
+        &self.options
+    }
+
+    /// The answer of the responder: a router advertisement for a router solicitation, and a
+    /// neighbor advertisement for a solicitation of the gateway.
+    pub(crate) fn respond(&self, config: &IpStackConfig) -> Option<NetworkPacket> {
+        let gateway = config.ndp_gateway;
+        // A host without an address yet is answered on all-nodes
+        let (dst, solicited) = match self.src_addr.is_unspecified() {
+            true => (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), false),
+            false => (self.src_addr, true),
+        };
+        match self.message {
+            NdpMessage::RouterSolicitation => {
+                let lifetime = ROUTER_LIFETIME.to_be_bytes();
+                let header = [config.ttl, 0, lifetime[0], lifetime[1]];
+                // Reachable time and retransmission timer left unspecified
+                let mut body = vec![0; 8];
+                if let Some((prefix, prefix_len)) = config.ndp_prefix {
+                    let mask = u128::MAX
+                        .checked_shl(128u32.saturating_sub(prefix_len as u32))
+                        .unwrap_or(0);
+                    body.extend_from_slice(&[OPTION_PREFIX_INFORMATION, 4, prefix_len, 0xc0]);
+                    body.extend_from_slice(&VALID_LIFETIME.to_be_bytes());
+                    body.extend_from_slice(&PREFERRED_LIFETIME.to_be_bytes());
+                    body.extend_from_slice(&[0; 4]);
+                    body.extend_from_slice(&(u128::from(prefix) & mask).to_be_bytes());
+                }
+                if config.mtu >= 1280 && config.mtu < u16::MAX {
+                    body.extend_from_slice(&[OPTION_MTU, 1, 0, 0]);
+                    body.extend_from_slice(&(config.mtu as u32).to_be_bytes());
+                }
+                if let Some(mac) = config.ethernet {
+                    body.extend_from_slice(&[OPTION_SOURCE_LINK_LAYER_ADDRESS, 1]);
+                    body.extend_from_slice(&mac);
+                }
+                create_packet(gateway, dst, ROUTER_ADVERTISEMENT, header, body)
+            }
+            NdpMessage::NeighborSolicitation { target } if target == gateway => {
+                let flags = 0x80 | 0x20 | if solicited { 0x40 } else { 0 };
+                let mut body = target.octets().to_vec();
+                if let Some(mac) = config.ethernet {
+                    // On an Ethernet link the solicitor learns our MAC address from it
+                    body.extend_from_slice(&[OPTION_TARGET_LINK_LAYER_ADDRESS, 1]);
+                    body.extend_from_slice(&mac);
+                }
+                create_packet(gateway, dst, NEIGHBOR_ADVERTISEMENT, [flags, 0, 0, 0], body)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn create_packet(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    type_u8: u8,
+    bytes5to8: [u8; 4],
+    body: Vec<u8>,
+) -> Option<NetworkPacket> {
+    let icmp_type = Icmpv6Type::Unknown {
+        type_u8,
+        code_u8: 0,
+        bytes5to8,
+    };
+    let icmp = Icmpv6Header::with_checksum(icmp_type, src.octets(), dst.octets(), &body).ok()?;
+    let mut payload = icmp.to_bytes().to_vec();
+    payload.extend_from_slice(&body);
+    let ip = Ipv6Header {
+        traffic_class: 0,
+        flow_label: Ipv6FlowLabel::ZERO,
+        payload_length: payload.len() as u16,
+        next_header: IpNumber::IPV6_ICMP,
+        hop_limit: HOP_LIMIT,
+        source: src.octets(),
+        destination: dst.octets(),
+    };
+    Some(NetworkPacket {
+        ip: IpHeader::Ipv6(ip),
+        transport: TransportHeader::Unknown,
+        payload: payload.into(),
+    })
+}
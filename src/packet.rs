@@ -1,12 +1,26 @@
-use crate::error::IpStackError;
-use etherparse::{Ipv4Header, Ipv6Header, NetSlice, SlicedPacket, TcpHeader, UdpHeader};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use crate::{error::IpStackError, pool::BufferPool};
+use ahash::AHashMap;
+use bytes::Bytes;
+use etherparse::{
+    icmpv4, icmpv4::DestUnreachableHeader, icmpv6, icmpv6::DestUnreachableCode, Icmpv4Header,
+    Icmpv4Type, Icmpv6Header, Icmpv6Type, IpNumber, Ipv4Header, Ipv4HeaderSlice, Ipv4Options,
+    Ipv6FlowLabel, Ipv6Header, Ipv6HeaderSlice, NetSlice, SlicedPacket, TcpHeader,
+    TcpOptionElement, UdpHeader,
+};
+use log::trace;
+use std::{
+    borrow::Cow,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::time::Instant;
 
 #[derive(Eq, Hash, PartialEq, Debug, Clone, Copy)]
 pub struct NetworkTuple {
     pub src: SocketAddr,
     pub dst: SocketAddr,
-    pub tcp: bool,
+    pub protocol: IpNumber,
 }
 pub mod tcp_flags {
     pub const CWR: u8 = 0b10000000;
@@ -25,6 +39,7 @@ pub(crate) enum IpStackPacketProtocol {
     Tcp(TcpHeaderWrapper),
     Unknown,
     Udp,
+    UdpLite,
 }
 
 #[derive(Debug, Clone)]
@@ -37,29 +52,360 @@ pub(crate) enum IpHeader {
 pub(crate) enum TransportHeader {
     Tcp(TcpHeader),
     Udp(UdpHeader),
+    UdpLite(UdpLiteHeader),
     Unknown,
+    Raw, // the payload is the whole IP packet, written as it is
+}
+
+/// A UDP-Lite header (RFC 3828), a UDP header whose length field tells how much of the
+/// datagram the checksum covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UdpLiteHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub coverage: u16, // bytes covered from the header on, 0 for the whole datagram
+    pub checksum: u16,
+}
+
+impl UdpLiteHeader {
+    pub const LEN: usize = 8;
+
+    /// Splits off the header, rejecting a coverage that ends inside it or beyond the
+    /// datagram (RFC 3828 3.1).
+    fn from_slice(buf: &[u8]) -> Option<(Self, &[u8])> {
+        let header = buf.get(..Self::LEN)?;
+        let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        let header = UdpLiteHeader {
+            source_port: field(0),
+            destination_port: field(2),
+            coverage: field(4),
+            checksum: field(6),
+        };
+        let coverage = header.coverage as usize;
+        if coverage != 0 && (coverage < Self::LEN || coverage > buf.len()) {
+            return None;
+        }
+        Some((header, &buf[Self::LEN..]))
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[0..2].copy_from_slice(&self.source_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.destination_port.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.coverage.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.checksum.to_be_bytes());
+        buf
+    }
+
+    /// The checksum over the pseudo header of `ip` and the covered part of the datagram.
+    pub fn calc_checksum(&self, ip: &IpHeader, payload: &[u8]) -> u16 {
+        let len = Self::LEN + payload.len();
+        let mut sum = 0u32;
+        let mut add = |bytes: &[u8]| {
+            for pair in bytes.chunks(2) {
+                sum += u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32;
+            }
+        };
+        match ip {
+            IpHeader::Ipv4(ip) => {
+                add(&ip.source);
+                add(&ip.destination);
+                add(&[0, IpNumber::UDP_LITE.0]);
+                add(&(len as u16).to_be_bytes());
+            }
+            IpHeader::Ipv6(ip) => {
+                add(&ip.source);
+                add(&ip.destination);
+                add(&(len as u32).to_be_bytes());
+                add(&[0, 0, 0, IpNumber::UDP_LITE.0]);
+            }
+        }
+        let mut header = *self;
+        header.checksum = 0;
+        add(&header.to_bytes());
+        let covered = match self.coverage as usize {
+            0 => payload.len(),
+            coverage => coverage.saturating_sub(Self::LEN).min(payload.len()),
+        };
+        add(&payload[..covered]);
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        match !(sum as u16) {
+            0 => 0xffff,
+            checksum => checksum,
+        }
+    }
+}
+
+/// An ICMP error about a TCP segment or UDP datagram the stack sent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IcmpError {
+    pub tuple: NetworkTuple, // the flow as keyed for packets coming from the device
+    pub seq: u32,            // sequence number of a quoted TCP segment, 0 for UDP
+    pub kind: IcmpErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IcmpErrorKind {
+    /// "Fragmentation needed" (v4) or "packet too big" (v6), with the next-hop MTU.
+    PacketTooBig(u16),
+    /// Destination unreachable or time exceeded, as the error a socket reports for it.
+    Unreachable(std::io::ErrorKind),
+}
+
+/// Flow label of the packets from `src` to `dst` (RFC 6437): a keyed hash of the tuple, so
+/// it stays the same for the flow and cannot be guessed for others. Never 0, which would
+/// leave them unlabeled.
+pub(crate) fn flow_label(src: SocketAddr, dst: SocketAddr, protocol: IpNumber) -> Ipv6FlowLabel {
+    static SECRET: OnceLock<ahash::RandomState> = OnceLock::new();
+    let hash = SECRET
+        .get_or_init(ahash::RandomState::new)
+        .hash_one((src, dst, protocol.0));
+    Ipv6FlowLabel::try_new((hash as u32 & 0xfffff).max(1)).unwrap_or(Ipv6FlowLabel::ZERO)
+}
+
+/// Whether the header checksum of the IPv4 packet in `buf` matches. Anything else passes,
+/// and is left for parsing to reject.
+pub(crate) fn has_valid_ipv4_checksum(buf: &[u8]) -> bool {
+    match Ipv4HeaderSlice::from_slice(buf) {
+        Ok(ip) => ip.to_header().calc_header_checksum() == ip.header_checksum(),
+        Err(_) => true,
+    }
+}
+
+/// Decrements the TTL (hop limit) of the IP packet in `buf` as a router forwarding it,
+/// fixing the IPv4 header checksum. False if the packet must not be forwarded, for a TTL of
+/// 1 or less or no valid IP header.
+pub(crate) fn decrement_ttl(buf: &mut [u8]) -> bool {
+    match buf.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Ok(mut ip) = Ipv4HeaderSlice::from_slice(buf).map(|ip| ip.to_header()) else {
+                return false;
+            };
+            if ip.time_to_live <= 1 {
+                return false;
+            }
+            ip.time_to_live -= 1;
+            ip.header_checksum = ip.calc_header_checksum();
+            buf[..ip.header_len()].copy_from_slice(&ip.to_bytes());
+            true
+        }
+        Some(6) if buf.len() >= Ipv6Header::LEN && buf[7] > 1 => {
+            buf[7] -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The packet information a TUN device puts before an IPv4 or IPv6 packet: flags and the
+/// EtherType on Linux, the address family in network byte order on macOS (utun) and the
+/// BSDs.
+#[cfg(unix)]
+pub(crate) fn packet_information(is_ipv4: bool) -> [u8; 4] {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        let ethertype: u16 = if is_ipv4 { 0x0800 } else { 0x86dd };
+        let [high, low] = ethertype.to_be_bytes();
+        [0, 0, high, low]
+    } else {
+        let family = if is_ipv4 {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        (family as u32).to_be_bytes()
+    }
+}
+
+/// Reassembles fragmented IPv4 packets from the device (RFC 791, RFC 815) so they are
+/// dispatched like unfragmented ones. Fragments of one packet are keyed by addresses,
+/// identification and protocol, and dropped once incomplete past the timeout or to stay
+/// within the memory limit.
+#[derive(Debug)]
+pub(crate) struct Ipv4Reassembly {
+    packets: AHashMap<FragmentKey, Fragments>,
+    buffered: usize, // bytes held by all incomplete packets
+    limit: usize,
+    timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: [u8; 4],
+    dst: [u8; 4],
+    id: u16,
+    protocol: IpNumber,
+}
+
+#[derive(Debug)]
+struct Fragments {
+    header: Option<Ipv4Header>, // of the first fragment
+    data: Vec<u8>,
+    received: Vec<(usize, usize)>, // sorted, disjoint ranges of `data` filled in
+    total_len: Option<usize>,      // payload length, known once the last fragment arrived
+    deadline: Instant,
+}
+
+impl Ipv4Reassembly {
+    pub(crate) fn new(limit: usize, timeout: Duration) -> Self {
+        Ipv4Reassembly {
+            packets: AHashMap::new(),
+            buffered: 0,
+            limit,
+            timeout,
+        }
+    }
+
+    /// The packet `buf` is part of: `buf` itself unless it is an IPv4 fragment, the whole
+    /// packet once `buf` completed it, and `None` while fragments are missing.
+    pub(crate) fn push<'a>(&mut self, buf: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let Ok(ip) = Ipv4HeaderSlice::from_slice(buf) else {
+            return Some(Cow::Borrowed(buf));
+        };
+        let offset = ip.fragments_offset().value() as usize * 8;
+        if self.limit == 0 || (!ip.more_fragments() && offset == 0) {
+            return Some(Cow::Borrowed(buf));
+        }
+        let header_len = ip.slice().len();
+        let end = (ip.total_len() as usize).min(buf.len());
+        let payload = buf.get(header_len..end)?;
+        let now = Instant::now();
+        self.expire(now);
+        // Only the last fragment may end off an 8-byte boundary, nothing beyond 64 KiB
+        let last = offset + payload.len();
+        if (ip.more_fragments() && payload.len() % 8 != 0) || last + header_len > u16::MAX as usize
+        {
+            trace!("dropping malformed fragment from {}", ip.source_addr());
+            return None;
+        }
+        let key = FragmentKey {
+            src: ip.source(),
+            dst: ip.destination(),
+            id: ip.identification(),
+            protocol: ip.protocol(),
+        };
+        let grown = last.saturating_sub(self.packets.get(&key).map_or(0, |f| f.data.len()));
+        while self.buffered + grown > self.limit {
+            let Some(oldest) = self.oldest() else {
+                trace!(
+                    "fragment from {} exceeds the reassembly limit",
+                    ip.source_addr()
+                );
+                return None;
+            };
+            self.remove(&oldest);
+        }
+        let timeout = self.timeout;
+        let fragments = self.packets.entry(key).or_insert_with(|| Fragments {
+            header: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            total_len: None,
+            deadline: now + timeout,
+        });
+        if fragments.data.len() < last {
+            self.buffered += last - fragments.data.len();
+            fragments.data.resize(last, 0);
+        }
+        fragments.data[offset..last].copy_from_slice(payload);
+        fragments.insert(offset, last);
+        if offset == 0 {
+            fragments.header = Ipv4Header::from_slice(buf).ok().map(|(h, _)| h);
+        }
+        if !ip.more_fragments() {
+            fragments.total_len = Some(last);
+        }
+        if !fragments.is_complete() {
+            return None;
+        }
+        let fragments = self.remove(&key)?;
+        let mut header = fragments.header?;
+        let total_len = fragments.total_len?;
+        header.more_fragments = false;
+        header.fragment_offset = Default::default();
+        header.set_payload_len(total_len).ok()?;
+        header.header_checksum = header.calc_header_checksum();
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(&fragments.data[..total_len]);
+        Some(Cow::Owned(packet))
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<FragmentKey> = self
+            .packets
+            .iter()
+            .filter(|(_, f)| f.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            trace!("reassembly of a packet from {:?} timed out", key.src);
+            self.remove(&key);
+        }
+    }
+
+    fn oldest(&self) -> Option<FragmentKey> {
+        let (key, _) = self.packets.iter().min_by_key(|(_, f)| f.deadline)?;
+        Some(*key)
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<Fragments> {
+        let fragments = self.packets.remove(key)?;
+        self.buffered -= fragments.data.len();
+        Some(fragments)
+    }
+}
+
+impl Fragments {
+    fn insert(&mut self, start: usize, end: usize) {
+        let (mut start, mut end) = (start, end);
+        self.received.retain(|&(s, e)| {
+            if e < start || s > end {
+                return true;
+            }
+            start = start.min(s);
+            end = end.max(e);
+            false
+        });
+        let at = self.received.partition_point(|&(s, _)| s < start);
+        self.received.insert(at, (start, end));
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .total_len
+                .is_some_and(|len| self.received.first() == Some(&(0, len)))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct NetworkPacket {
     pub(crate) ip: IpHeader,
     pub(crate) transport: TransportHeader,
-    pub(crate) payload: Vec<u8>,
+    pub(crate) payload: Bytes,
 }
 
 impl NetworkPacket {
     pub fn parse(buf: &[u8]) -> Result<Self, IpStackError> {
+        Self::parse_with(buf, Bytes::copy_from_slice)
+    }
+    /// Like [`parse`](Self::parse) with the payload copied into `pool`.
+    pub(crate) fn parse_pooled(buf: &[u8], pool: &mut BufferPool) -> Result<Self, IpStackError> {
+        Self::parse_with(buf, |payload| pool.copy(payload))
+    }
+    fn parse_with(buf: &[u8], copy: impl FnOnce(&[u8]) -> Bytes) -> Result<Self, IpStackError> {
         let p = SlicedPacket::from_ip(buf).map_err(|_| IpStackError::InvalidPacket)?;
         let ip = p.net.ok_or(IpStackError::InvalidPacket)?;
 
         let (ip, ip_payload) = match ip {
             NetSlice::Ipv4(ip) => (
                 IpHeader::Ipv4(ip.header().to_header()),
-                ip.payload().payload,
+                ip.payload().clone(),
             ),
             NetSlice::Ipv6(ip) => (
                 IpHeader::Ipv6(ip.header().to_header()),
-                ip.payload().payload,
+                ip.payload().clone(),
             ),
             NetSlice::Arp(_) => return Err(IpStackError::UnsupportedTransportProtocol),
         };
@@ -70,9 +416,14 @@ impl NetworkPacket {
             Some(etherparse::TransportSlice::Udp(u)) => {
                 (TransportHeader::Udp(u.to_header()), u.payload())
             }
-            _ => (TransportHeader::Unknown, ip_payload),
+            None if ip_payload.ip_number == IpNumber::UDP_LITE && !ip_payload.fragmented => {
+                let (h, payload) = UdpLiteHeader::from_slice(ip_payload.payload)
+                    .ok_or(IpStackError::InvalidPacket)?;
+                (TransportHeader::UdpLite(h), payload)
+            }
+            _ => (TransportHeader::Unknown, ip_payload.payload),
         };
-        let payload = payload.to_vec();
+        let payload = copy(payload);
 
         Ok(NetworkPacket {
             ip,
@@ -80,9 +431,20 @@ impl NetworkPacket {
             payload,
         })
     }
+    /// The IP packet `buf` to write to the device byte for byte, its headers only parsed
+    /// to route it.
+    pub(crate) fn raw(buf: &[u8]) -> Result<Self, IpStackError> {
+        let packet = Self::parse(buf)?;
+        Ok(NetworkPacket {
+            transport: TransportHeader::Raw,
+            payload: Bytes::copy_from_slice(buf),
+            ..packet
+        })
+    }
     pub(crate) fn transport_protocol(&self) -> IpStackPacketProtocol {
         match self.transport {
             TransportHeader::Udp(_) => IpStackPacketProtocol::Udp,
+            TransportHeader::UdpLite(_) => IpStackPacketProtocol::UdpLite,
             TransportHeader::Tcp(ref h) => IpStackPacketProtocol::Tcp(h.into()),
             _ => IpStackPacketProtocol::Unknown,
         }
@@ -90,6 +452,7 @@ impl NetworkPacket {
     pub fn src_addr(&self) -> SocketAddr {
         let port = match &self.transport {
             TransportHeader::Udp(udp) => udp.source_port,
+            TransportHeader::UdpLite(udp) => udp.source_port,
             TransportHeader::Tcp(tcp) => tcp.source_port,
             _ => 0,
         };
@@ -101,6 +464,7 @@ impl NetworkPacket {
     pub fn dst_addr(&self) -> SocketAddr {
         let port = match &self.transport {
             TransportHeader::Udp(udp) => udp.destination_port,
+            TransportHeader::UdpLite(udp) => udp.destination_port,
             TransportHeader::Tcp(tcp) => tcp.destination_port,
             _ => 0,
         };
@@ -109,33 +473,281 @@ impl NetworkPacket {
             IpHeader::Ipv6(ip) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip.destination)), port),
         }
     }
+    /// Parses an ICMP error quoting a TCP, UDP or UDP-Lite packet the stack sent.
+    pub(crate) fn icmp_error(&self) -> Option<IcmpError> {
+        use std::io::ErrorKind::{ConnectionRefused, HostUnreachable, NetworkUnreachable};
+        let (kind, original) = match &self.ip {
+            IpHeader::Ipv4(ip) if ip.protocol == IpNumber::ICMP => {
+                let (icmp, original) = Icmpv4Header::from_slice(&self.payload).ok()?;
+                let kind = match icmp.icmp_type {
+                    Icmpv4Type::DestinationUnreachable(unreachable) => match unreachable {
+                        DestUnreachableHeader::FragmentationNeeded { next_hop_mtu } => {
+                            IcmpErrorKind::PacketTooBig(next_hop_mtu)
+                        }
+                        DestUnreachableHeader::Network
+                        | DestUnreachableHeader::NetworkUnknown
+                        | DestUnreachableHeader::NetworkProhibited
+                        | DestUnreachableHeader::TosNetwork => {
+                            IcmpErrorKind::Unreachable(NetworkUnreachable)
+                        }
+                        DestUnreachableHeader::Protocol | DestUnreachableHeader::Port => {
+                            IcmpErrorKind::Unreachable(ConnectionRefused)
+                        }
+                        _ => IcmpErrorKind::Unreachable(HostUnreachable),
+                    },
+                    Icmpv4Type::TimeExceeded(_) => IcmpErrorKind::Unreachable(HostUnreachable),
+                    _ => return None,
+                };
+                (kind, original)
+            }
+            IpHeader::Ipv6(ip) if ip.next_header == IpNumber::IPV6_ICMP => {
+                let (icmp, original) = Icmpv6Header::from_slice(&self.payload).ok()?;
+                let kind = match icmp.icmp_type {
+                    Icmpv6Type::PacketTooBig { mtu } => {
+                        IcmpErrorKind::PacketTooBig(mtu.min(u16::MAX as u32) as u16)
+                    }
+                    Icmpv6Type::DestinationUnreachable(DestUnreachableCode::NoRoute) => {
+                        IcmpErrorKind::Unreachable(NetworkUnreachable)
+                    }
+                    Icmpv6Type::DestinationUnreachable(DestUnreachableCode::Port) => {
+                        IcmpErrorKind::Unreachable(ConnectionRefused)
+                    }
+                    Icmpv6Type::DestinationUnreachable(_) | Icmpv6Type::TimeExceeded(_) => {
+                        IcmpErrorKind::Unreachable(HostUnreachable)
+                    }
+                    _ => return None,
+                };
+                (kind, original)
+            }
+            _ => return None,
+        };
+        // The error quotes the IP header and at least the first 8 bytes of the segment
+        let (src, dst, protocol, transport) = match original.first()? >> 4 {
+            4 => {
+                let ip = Ipv4HeaderSlice::from_slice(original).ok()?;
+                (
+                    IpAddr::V4(ip.source_addr()),
+                    IpAddr::V4(ip.destination_addr()),
+                    ip.protocol(),
+                    original.get(ip.slice().len()..)?,
+                )
+            }
+            6 => {
+                let ip = Ipv6HeaderSlice::from_slice(original).ok()?;
+                (
+                    IpAddr::V6(ip.source_addr()),
+                    IpAddr::V6(ip.destination_addr()),
+                    ip.next_header(),
+                    original.get(Ipv6Header::LEN..)?,
+                )
+            }
+            _ => return None,
+        };
+        if ![IpNumber::TCP, IpNumber::UDP, IpNumber::UDP_LITE].contains(&protocol) {
+            return None;
+        }
+        let transport = transport.get(..8)?;
+        let seq = match protocol {
+            IpNumber::TCP => {
+                u32::from_be_bytes([transport[4], transport[5], transport[6], transport[7]])
+            }
+            _ => 0,
+        };
+        Some(IcmpError {
+            tuple: NetworkTuple {
+                src: SocketAddr::new(dst, u16::from_be_bytes([transport[2], transport[3]])),
+                dst: SocketAddr::new(src, u16::from_be_bytes([transport[0], transport[1]])),
+                protocol,
+            },
+            seq,
+            kind,
+        })
+    }
+    /// The ECN field of the IP header.
+    pub(crate) fn ecn(&self) -> u8 {
+        match &self.ip {
+            IpHeader::Ipv4(ip) => ip.ecn.value(),
+            IpHeader::Ipv6(ip) => ip.traffic_class & 0b11,
+        }
+    }
+    /// An ICMP port unreachable error answering this packet, quoting as much of it as fits
+    /// into the minimum MTU (RFC 1812 4.3.2.3, RFC 4443 2.4), sent with `ttl`.
+    pub(crate) fn port_unreachable(&self, ttl: u8) -> Result<NetworkPacket, IpStackError> {
+        self.icmp_error_reply(
+            ttl,
+            Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port),
+            Icmpv6Type::DestinationUnreachable(DestUnreachableCode::Port),
+        )
+    }
+    /// An ICMP time exceeded error answering this packet as a router would that cannot
+    /// forward it any further.
+    pub(crate) fn time_exceeded(&self, ttl: u8) -> Result<NetworkPacket, IpStackError> {
+        self.icmp_error_reply(
+            ttl,
+            Icmpv4Type::TimeExceeded(icmpv4::TimeExceededCode::TtlExceededInTransit),
+            Icmpv6Type::TimeExceeded(icmpv6::TimeExceededCode::HopLimitExceeded),
+        )
+    }
+    fn icmp_error_reply(
+        &self,
+        ttl: u8,
+        v4: Icmpv4Type,
+        v6: Icmpv6Type,
+    ) -> Result<NetworkPacket, IpStackError> {
+        let mut original = self.to_bytes()?;
+        let (ip, payload) = match &self.ip {
+            IpHeader::Ipv4(ip) => {
+                original.truncate(576 - Ipv4Header::MIN_LEN - Icmpv4Header::MIN_LEN);
+                let icmp = Icmpv4Header::with_checksum(v4, &original);
+                let mut payload = icmp.to_bytes().to_vec();
+                payload.extend_from_slice(&original);
+                let ip_h = Ipv4Header::new(
+                    payload.len() as u16,
+                    ttl,
+                    IpNumber::ICMP,
+                    ip.destination,
+                    ip.source,
+                )?;
+                (IpHeader::Ipv4(ip_h), payload)
+            }
+            IpHeader::Ipv6(ip) => {
+                original.truncate(1280 - Ipv6Header::LEN - Icmpv6Header::MIN_LEN);
+                let icmp = Icmpv6Header::with_checksum(v6, ip.destination, ip.source, &original)?;
+                let mut payload = icmp.to_bytes().to_vec();
+                payload.extend_from_slice(&original);
+                let ip_h = Ipv6Header {
+                    traffic_class: 0,
+                    flow_label: Default::default(),
+                    payload_length: payload.len() as u16,
+                    next_header: IpNumber::IPV6_ICMP,
+                    hop_limit: ttl,
+                    source: ip.destination,
+                    destination: ip.source,
+                };
+                (IpHeader::Ipv6(ip_h), payload)
+            }
+        };
+        Ok(NetworkPacket {
+            ip,
+            transport: TransportHeader::Unknown,
+            payload: payload.into(),
+        })
+    }
+    /// Whether an ICMP error must not answer this packet: ICMP errors themselves and IPv4
+    /// fragments but the first (RFC 1812 4.3.2.7, RFC 4443 2.4).
+    pub(crate) fn is_icmp_error_exempt(&self) -> bool {
+        let icmp_type = self.payload.first().copied();
+        match &self.ip {
+            IpHeader::Ipv4(ip) => {
+                ip.fragment_offset.value() != 0
+                    || (ip.protocol == IpNumber::ICMP
+                        && matches!(icmp_type, None | Some(3 | 4 | 5 | 11 | 12)))
+            }
+            IpHeader::Ipv6(ip) => {
+                ip.next_header == IpNumber::IPV6_ICMP && icmp_type.is_none_or(|t| t < 128)
+            }
+        }
+    }
+    /// The options of an IPv4 header, like record route or timestamp, empty for IPv6.
+    pub(crate) fn ip_options(&self) -> Ipv4Options {
+        match &self.ip {
+            IpHeader::Ipv4(ip) => ip.options.clone(),
+            IpHeader::Ipv6(_) => Ipv4Options::default(),
+        }
+    }
+    /// The DSCP, the upper six bits of the TOS byte or traffic class.
+    pub(crate) fn dscp(&self) -> u8 {
+        self.tos() >> 2
+    }
+    /// The TOS byte (IPv4) or traffic class (IPv6).
+    pub(crate) fn tos(&self) -> u8 {
+        match &self.ip {
+            IpHeader::Ipv4(ip) => ip.dscp.value() << 2 | ip.ecn.value(),
+            IpHeader::Ipv6(ip) => ip.traffic_class,
+        }
+    }
+    /// Whether a UDP checksum matches, an IPv4 datagram may leave it zero to send none
+    /// (RFC 768) but IPv6 and UDP-Lite require it (RFC 8200 8.1, RFC 3828 3.1). Other
+    /// packets pass.
+    pub(crate) fn has_valid_udp_checksum(&self) -> bool {
+        if let TransportHeader::UdpLite(udp) = &self.transport {
+            return udp.calc_checksum(&self.ip, &self.payload) == udp.checksum;
+        }
+        let TransportHeader::Udp(udp) = &self.transport else {
+            return true;
+        };
+        let checksum = match &self.ip {
+            IpHeader::Ipv4(_) if udp.checksum == 0 => return true,
+            IpHeader::Ipv4(ip) => udp.calc_checksum_ipv4(ip, &self.payload),
+            IpHeader::Ipv6(ip) => udp.calc_checksum_ipv6(ip, &self.payload),
+        };
+        checksum.is_ok_and(|checksum| checksum == udp.checksum)
+    }
+    /// Whether a TCP checksum matches, other packets pass.
+    pub(crate) fn has_valid_tcp_checksum(&self) -> bool {
+        let TransportHeader::Tcp(tcp) = &self.transport else {
+            return true;
+        };
+        let checksum = match &self.ip {
+            IpHeader::Ipv4(ip) => tcp.calc_checksum_ipv4(ip, &self.payload),
+            IpHeader::Ipv6(ip) => tcp.calc_checksum_ipv6(ip, &self.payload),
+        };
+        checksum.is_ok_and(|checksum| checksum == tcp.checksum)
+    }
+    /// The checksum coverage of a UDP-Lite datagram, `None` for other packets.
+    pub(crate) fn udp_lite_coverage(&self) -> Option<u16> {
+        match &self.transport {
+            TransportHeader::UdpLite(udp) => Some(udp.coverage),
+            _ => None,
+        }
+    }
+    /// The transport protocol number.
+    fn protocol(&self) -> IpNumber {
+        match (&self.transport, &self.ip) {
+            (TransportHeader::Tcp(_), _) => IpNumber::TCP,
+            (TransportHeader::Udp(_), _) => IpNumber::UDP,
+            (TransportHeader::UdpLite(_), _) => IpNumber::UDP_LITE,
+            (TransportHeader::Unknown | TransportHeader::Raw, IpHeader::Ipv4(ip)) => ip.protocol,
+            (TransportHeader::Unknown | TransportHeader::Raw, IpHeader::Ipv6(ip)) => ip.next_header,
+        }
+    }
     pub fn network_tuple(&self) -> NetworkTuple {
         NetworkTuple {
             src: self.src_addr(),
             dst: self.dst_addr(),
-            tcp: matches!(self.transport, TransportHeader::Tcp(_)),
+            protocol: self.protocol(),
         }
     }
     pub fn reverse_network_tuple(&self) -> NetworkTuple {
         NetworkTuple {
             src: self.dst_addr(),
             dst: self.src_addr(),
-            tcp: matches!(self.transport, TransportHeader::Tcp(_)),
+            protocol: self.protocol(),
         }
     }
     pub fn to_bytes(&self) -> Result<Vec<u8>, IpStackError> {
         let mut buf = Vec::new();
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+    /// Appends the packet to `buf`, like [`to_bytes`](Self::to_bytes) without allocating.
+    pub(crate) fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), IpStackError> {
+        if let TransportHeader::Raw = self.transport {
+            buf.extend_from_slice(&self.payload);
+            return Ok(());
+        }
         match self.ip {
-            IpHeader::Ipv4(ref ip) => ip.write(&mut buf)?,
-            IpHeader::Ipv6(ref ip) => ip.write(&mut buf)?,
+            IpHeader::Ipv4(ref ip) => ip.write(buf)?,
+            IpHeader::Ipv6(ref ip) => ip.write(buf)?,
         }
         match self.transport {
-            TransportHeader::Tcp(ref h) => h.write(&mut buf)?,
-            TransportHeader::Udp(ref h) => h.write(&mut buf)?,
+            TransportHeader::Tcp(ref h) => h.write(buf)?,
+            TransportHeader::Udp(ref h) => h.write(buf)?,
+            TransportHeader::UdpLite(ref h) => buf.extend_from_slice(&h.to_bytes()),
             _ => {}
         };
         buf.extend_from_slice(&self.payload);
-        Ok(buf)
+        Ok(())
     }
     pub fn ttl(&self) -> u8 {
         match &self.ip {
@@ -184,6 +796,42 @@ impl TcpHeaderWrapper {
 
         flags
     }
+    pub fn mss(&self) -> Option<u16> {
+        self.inner()
+            .options_iterator()
+            .find_map(|option| match option {
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+                _ => None,
+            })
+    }
+    pub fn sack_permitted(&self) -> bool {
+        self.inner().options_iterator().any(|option| {
+            matches!(
+                option,
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted)
+            )
+        })
+    }
+    pub fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        self.inner()
+            .options_iterator()
+            .filter_map(|option| match option {
+                Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => {
+                    Some(std::iter::once(first).chain(rest.into_iter().flatten()))
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+    pub fn window_scale(&self) -> Option<u8> {
+        self.inner()
+            .options_iterator()
+            .find_map(|option| match option {
+                Ok(TcpOptionElement::WindowScale(scale)) => Some(scale),
+                _ => None,
+            })
+    }
 }
 
 impl From<&TcpHeader> for TcpHeaderWrapper {
@@ -216,6 +864,7 @@ impl From<&TcpHeader> for TcpHeaderWrapper {
 pub mod tests {
     use super::*;
     use criterion::{black_box, Criterion};
+    use etherparse::IpFragOffset;
     use rand::random;
     use std::time::Duration;
 
@@ -295,4 +944,156 @@ pub mod tests {
 
         Criterion::default().final_summary();
     }
+
+    #[test]
+    fn udp_checksum() {
+        let mut buf = Vec::new();
+        etherparse::PacketBuilder::ipv6([1; 16], [2; 16], 64)
+            .udp(1000, 53)
+            .write(&mut buf, b"query")
+            .unwrap();
+        let mut packet = NetworkPacket::parse(&buf).unwrap();
+        assert!(packet.has_valid_udp_checksum());
+        packet.payload = Bytes::from_static(b"qu3ry");
+        assert!(!packet.has_valid_udp_checksum());
+        // IPv6 has no "no checksum" value
+        if let TransportHeader::Udp(udp) = &mut packet.transport {
+            udp.checksum = 0;
+        }
+        assert!(!packet.has_valid_udp_checksum());
+    }
+
+    #[test]
+    fn udp_lite() {
+        let mut ip =
+            Ipv4Header::new(27, 64, IpNumber::UDP_LITE, [10, 0, 0, 2], [1, 2, 3, 4]).unwrap();
+        ip.header_checksum = ip.calc_header_checksum();
+        let payload = b"covered|not covered";
+        let mut udp = UdpLiteHeader {
+            source_port: 40000,
+            destination_port: 5000,
+            coverage: 16,
+            checksum: 0,
+        };
+        udp.checksum = udp.calc_checksum(&IpHeader::Ipv4(ip.clone()), payload);
+        let mut buf = ip.to_bytes().to_vec();
+        buf.extend_from_slice(&udp.to_bytes());
+        buf.extend_from_slice(payload);
+        let mut packet = NetworkPacket::parse(&buf).unwrap();
+        let TransportHeader::UdpLite(header) = packet.transport else {
+            panic!("not UDP-Lite");
+        };
+        assert_eq!(header, udp);
+        assert_eq!(packet.dst_addr().port(), 5000);
+        assert_eq!(&packet.payload[..], payload);
+        assert!(packet.has_valid_udp_checksum());
+        assert_eq!(packet.to_bytes().unwrap(), buf);
+        // Only the first eight bytes of the payload are covered
+        packet.payload = Bytes::from_static(b"covered|NOT COVERED");
+        assert!(packet.has_valid_udp_checksum());
+        packet.payload = Bytes::from_static(b"Covered|not covered");
+        assert!(!packet.has_valid_udp_checksum());
+        // A coverage inside the header is invalid
+        buf[25] = 4;
+        assert!(NetworkPacket::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn port_unreachable() {
+        let mut buf = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [1, 2, 3, 4], 64)
+            .udp(40000, 53)
+            .write(&mut buf, &[0; 1000])
+            .unwrap();
+        let packet = NetworkPacket::parse(&buf).unwrap();
+        let bytes = packet.port_unreachable(64).unwrap().to_bytes().unwrap();
+        assert_eq!(bytes.len(), 576);
+        let icmp = SlicedPacket::from_ip(&bytes).unwrap();
+        let Some(etherparse::TransportSlice::Icmpv4(icmp)) = icmp.transport else {
+            panic!("not ICMP");
+        };
+        assert_eq!(
+            icmp.icmp_type(),
+            Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port)
+        );
+        assert_eq!(icmp.payload(), &buf[..548]);
+    }
+
+    #[test]
+    fn ipv4_reassembly() {
+        let mut buf = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [1, 2, 3, 4], 64)
+            .udp(40000, 53)
+            .write(&mut buf, &(0..100).collect::<Vec<u8>>())
+            .unwrap();
+        let (whole, payload) = Ipv4Header::from_slice(&buf).unwrap();
+        let fragment = |range: std::ops::Range<usize>, more: bool| {
+            let mut ip = whole.clone();
+            ip.identification = 7;
+            ip.more_fragments = more;
+            ip.fragment_offset = IpFragOffset::try_new(range.start as u16 / 8).unwrap();
+            ip.set_payload_len(range.len()).unwrap();
+            ip.header_checksum = ip.calc_header_checksum();
+            let mut fragment = ip.to_bytes().to_vec();
+            fragment.extend_from_slice(&payload[range]);
+            fragment
+        };
+        let mut reassembly = Ipv4Reassembly::new(1024, Duration::from_secs(1));
+        assert!(matches!(reassembly.push(&buf), Some(Cow::Borrowed(_))));
+        // Out of order and overlapping
+        assert!(reassembly.push(&fragment(64..108, false)).is_none());
+        assert!(reassembly.push(&fragment(0..40, true)).is_none());
+        assert!(reassembly.push(&fragment(40..43, true)).is_none());
+        let last = fragment(32..72, true);
+        let packet = reassembly.push(&last).unwrap();
+        let mut expected = whole.clone();
+        expected.identification = 7;
+        expected.header_checksum = expected.calc_header_checksum();
+        assert_eq!(&packet[..Ipv4Header::MIN_LEN], &expected.to_bytes()[..]);
+        assert_eq!(&packet[Ipv4Header::MIN_LEN..], payload);
+        assert_eq!(reassembly.buffered, 0);
+        let packet = NetworkPacket::parse(&packet).unwrap();
+        assert!(packet.has_valid_udp_checksum());
+
+        // Over the limit the oldest incomplete packet is dropped
+        let mut reassembly = Ipv4Reassembly::new(64, Duration::from_secs(1));
+        assert!(reassembly.push(&fragment(0..40, true)).is_none());
+        assert!(reassembly.push(&fragment(64..108, false)).is_none());
+        assert_eq!(reassembly.buffered, 0);
+        assert!(Ipv4Reassembly::new(0, Duration::ZERO)
+            .push(&fragment(0..40, true))
+            .is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn packet_information_header() {
+        let (ipv4, ipv6) = (packet_information(true), packet_information(false));
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            assert_eq!(ipv4, [0x00, 0x00, 0x08, 0x00]);
+            assert_eq!(ipv6, [0x00, 0x00, 0x86, 0xdd]);
+        } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+            assert_eq!(ipv4, [0x00, 0x00, 0x00, 0x02]);
+            assert_eq!(ipv6, [0x00, 0x00, 0x00, 0x1e]);
+        }
+    }
+
+    #[test]
+    fn icmp_error() {
+        // The device refusing a datagram the stack sent it
+        let mut buf = Vec::new();
+        etherparse::PacketBuilder::ipv4([1, 2, 3, 4], [10, 0, 0, 2], 64)
+            .udp(53, 40000)
+            .write(&mut buf, b"answer")
+            .unwrap();
+        let sent = NetworkPacket::parse(&buf).unwrap();
+        let bytes = sent.port_unreachable(64).unwrap().to_bytes().unwrap();
+        let error = NetworkPacket::parse(&bytes).unwrap().icmp_error().unwrap();
+        assert_eq!(error.tuple, sent.reverse_network_tuple());
+        assert_eq!(
+            error.kind,
+            IcmpErrorKind::Unreachable(std::io::ErrorKind::ConnectionRefused)
+        );
+        assert!(sent.icmp_error().is_none());
+    }
 }
@@ -0,0 +1,54 @@
+use bytes::{Bytes, BytesMut};
+
+/// Packets the arena holds before it is allocated anew, at most.
+const ARENA_PACKETS: usize = 32;
+const MAX_ARENA: usize = 1 << 20;
+/// Buffers for packets to the device kept for reuse, at most.
+const MAX_FRAMES: usize = 256;
+
+/// Buffers the dispatcher reuses rather than allocating one for every packet.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    /// The payloads of packets from the device are cut off its front. Its allocation is
+    /// reused once the streams dropped all of them, otherwise another one is made.
+    arena: BytesMut,
+    arena_size: usize,
+    /// Buffers of packets written to the device.
+    frames: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(mtu: u16) -> Self {
+        let arena_size = (mtu as usize * ARENA_PACKETS).min(MAX_ARENA);
+        BufferPool {
+            arena: BytesMut::with_capacity(arena_size),
+            arena_size,
+            frames: Vec::new(),
+        }
+    }
+
+    /// `data` copied into the arena.
+    pub(crate) fn copy(&mut self, data: &[u8]) -> Bytes {
+        if self.arena.capacity() < data.len() {
+            self.arena.reserve(self.arena_size.max(data.len()));
+        }
+        self.arena.extend_from_slice(data);
+        self.arena.split().freeze()
+    }
+
+    /// An empty buffer for a packet to the device.
+    pub(crate) fn frame(&mut self) -> Vec<u8> {
+        self.frames.pop().unwrap_or_default()
+    }
+
+    /// Takes back the buffers of packets written to the device.
+    pub(crate) fn recycle(&mut self, frames: Vec<Vec<u8>>) {
+        for mut frame in frames {
+            if self.frames.len() >= MAX_FRAMES {
+                break;
+            }
+            frame.clear();
+            self.frames.push(frame);
+        }
+    }
+}
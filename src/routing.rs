@@ -0,0 +1,83 @@
+use ahash::AHashMap;
+use log::trace;
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A device of an [`IpStack`](crate::IpStack), the one it was created with or one added by
+/// [`IpStack::add_device`](crate::IpStack::add_device).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub(crate) usize);
+
+impl DeviceId {
+    /// The device given to [`IpStack::new`](crate::IpStack::new), which gets the packets no
+    /// route matches.
+    pub const PRIMARY: DeviceId = DeviceId(0);
+}
+
+pub(crate) type SharedRoutingTable = Arc<RwLock<RoutingTable>>;
+
+/// The destination prefixes reached through other devices than the primary one.
+#[derive(Debug, Default)]
+pub(crate) struct RoutingTable {
+    routes: Vec<(IpAddr, u8, DeviceId)>, // longest prefix first
+    devices: AHashMap<DeviceId, UnboundedSender<Vec<u8>>>,
+}
+
+impl RoutingTable {
+    /// Registers the writer of the added device `id`.
+    pub(crate) fn add_device(&mut self, id: DeviceId, writer: UnboundedSender<Vec<u8>>) {
+        self.devices.insert(id, writer);
+    }
+
+    /// Routes `prefix`/`len` to `device`, replacing a route of the same prefix.
+    pub(crate) fn add_route(&mut self, prefix: IpAddr, len: u8, device: DeviceId) {
+        let len = len.min(max_prefix_len(prefix));
+        let prefix = masked(prefix, len);
+        self.routes.retain(|&(p, l, _)| (p, l) != (prefix, len));
+        let at = self.routes.partition_point(|&(_, l, _)| l >= len);
+        self.routes.insert(at, (prefix, len, device));
+    }
+
+    /// The device of the longest prefix containing `addr`, `None` without one.
+    pub(crate) fn route(&self, addr: IpAddr) -> Option<DeviceId> {
+        self.routes
+            .iter()
+            .find(|&&(prefix, len, _)| masked(addr, len) == prefix)
+            .map(|&(_, _, device)| device)
+    }
+
+    /// Queues `bytes` for the added device `to`, dropping them if it is gone.
+    pub(crate) fn send(&self, to: DeviceId, bytes: Vec<u8>) {
+        let sent = self.devices.get(&to).is_some_and(|w| w.send(bytes).is_ok());
+        if !sent {
+            trace!("dropping a packet routed to the missing device {to:?}");
+        }
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// `addr` with all but its first `len` bits cleared. Addresses of the other IP version than
+/// a prefix never match it, as the masked address keeps its version.
+fn masked(addr: IpAddr, len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - len.min(32) as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX
+                .checked_shl(128 - len.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use super::udp::{fragment, message_too_long};
+use crate::{
+    egress::EgressSender,
+    packet::{IpHeader, NetworkPacket, TransportHeader},
+    IpStackError,
+};
+use bytes::Bytes;
+use etherparse::{
+    IcmpEchoHeader, Icmpv4Header, Icmpv4Type, Icmpv6Header, Icmpv6Type, IpNumber, Ipv4Header,
+    Ipv4HeaderSlice, Ipv4Options, Ipv6FlowLabel, Ipv6Header, Ipv6HeaderSlice,
+};
+use log::trace;
+use std::{cmp, net::IpAddr};
+
+/// An ICMP or ICMPv6 echo request from the device, answered by [`reply`](Self::reply).
+#[derive(Debug)]
+pub struct IpStackIcmpStream {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    identifier: u16,
+    sequence: u16,
+    payload: Bytes,
+    ip_options: Ipv4Options,
+    mtu: u16,
+    ttl: u8,
+    pkt_sender: EgressSender,
+}
+
+impl IpStackIcmpStream {
+    /// The echo request in `packet`, `None` for other ICMP messages and other protocols.
+    pub(crate) fn new(
+        packet: &NetworkPacket,
+        mtu: u16,
+        ttl: u8,
+        pkt_sender: EgressSender,
+    ) -> Option<Self> {
+        let (echo, payload) = match &packet.ip {
+            IpHeader::Ipv4(ip) if ip.protocol == IpNumber::ICMP && !ip.is_fragmenting_payload() => {
+                let (icmp, payload) = Icmpv4Header::from_slice(&packet.payload).ok()?;
+                match icmp.icmp_type {
+                    Icmpv4Type::EchoRequest(echo) => (echo, payload),
+                    _ => return None,
+                }
+            }
+            IpHeader::Ipv6(ip) if ip.next_header == IpNumber::IPV6_ICMP => {
+                let (icmp, payload) = Icmpv6Header::from_slice(&packet.payload).ok()?;
+                match icmp.icmp_type {
+                    Icmpv6Type::EchoRequest(echo) => (echo, payload),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let offset = packet.payload.len() - payload.len();
+        Some(IpStackIcmpStream {
+            src_addr: packet.src_addr().ip(),
+            dst_addr: packet.dst_addr().ip(),
+            identifier: echo.id,
+            sequence: echo.seq,
+            payload: packet.payload.slice(offset..),
+            ip_options: packet.ip_options(),
+            mtu,
+            ttl,
+            pkt_sender,
+        })
+    }
+
+    /// The pinging host on the device side.
+    pub fn local_addr(&self) -> IpAddr {
+        self.src_addr
+    }
+
+    /// The pinged address.
+    pub fn peer_addr(&self) -> IpAddr {
+        self.dst_addr
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// The data of the request, which the reply echoes.
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.src_addr.is_ipv6()
+    }
+
+    /// The IPv4 options of the request, like record route or timestamp, empty for IPv6.
+    pub fn ip_options(&self) -> &[u8] {
+        self.ip_options.as_slice()
+    }
+
+    /// Answers with an echo reply carrying the request's data, from the pinged address.
+    pub fn reply(&self) -> std::io::Result<()> {
+        self.send(self.payload.clone())
+    }
+
+    /// Answers with an echo reply carrying `payload` and the IPv4 options of the request
+    /// (RFC 1122 3.2.2.6), fragmented if it exceeds the MTU. The reply is dropped over [`IpStackConfig::icmp_rate_limit`](crate::IpStackConfig::icmp_rate_limit).
+    pub fn send(&self, payload: Bytes) -> std::io::Result<()> {
+        if !self.pkt_sender.allow_icmp() {
+            trace!("ICMP rate limit reached for {}", self.src_addr);
+            return Ok(());
+        }
+        let echo = IcmpEchoHeader {
+            id: self.identifier,
+            seq: self.sequence,
+        };
+        let mut packet = create_packet(
+            self.dst_addr,
+            self.src_addr,
+            self.ttl,
+            Icmpv4Type::EchoReply(echo),
+            Icmpv6Type::EchoReply(echo),
+            &payload,
+        )?;
+        if let IpHeader::Ipv4(ip) = &mut packet.ip {
+            ip.options = self.ip_options.clone();
+            ip.set_payload_len(packet.payload.len())
+                .map_err(|_| message_too_long())?;
+        }
+        let mtu = cmp::min(self.mtu, self.pkt_sender.mtu());
+        send_icmp_packet(&self.pkt_sender, mtu, packet)
+    }
+}
+
+/// An ICMP or ICMPv6 message an application sends to the device with
+/// [`IpStack::send_icmp`](crate::IpStack::send_icmp), given by its raw type and code.
+#[derive(Debug, Clone)]
+pub struct IcmpPacket {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    icmp_type: u8,
+    code: u8,
+    rest_of_header: [u8; 4],
+    payload: Bytes,
+}
+
+impl IcmpPacket {
+    /// A message of `icmp_type` and `code` from `src_addr` to `dst_addr`, an ICMPv6 one if
+    /// they are IPv6 addresses.
+    pub fn new(
+        src_addr: IpAddr,
+        dst_addr: IpAddr,
+        icmp_type: u8,
+        code: u8,
+        payload: Bytes,
+    ) -> Self {
+        IcmpPacket {
+            src_addr,
+            dst_addr,
+            icmp_type,
+            code,
+            rest_of_header: [0; 4],
+            payload,
+        }
+    }
+
+    /// An error of `icmp_type` and `code` answering the IP packet `original` from its
+    /// destination, quoting as much of it as fits into the minimum MTU. Fails with
+    /// `InvalidInput` if `original` is no IP packet.
+    pub fn error_for(original: &[u8], icmp_type: u8, code: u8) -> std::io::Result<Self> {
+        let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidInput);
+        let (src, dst, quoted_len) = match original.first().ok_or_else(invalid)? >> 4 {
+            4 => {
+                let ip = Ipv4HeaderSlice::from_slice(original).map_err(|_| invalid())?;
+                let len = 576 - Ipv4Header::MIN_LEN - Icmpv4Header::MIN_LEN;
+                (
+                    IpAddr::V4(ip.source_addr()),
+                    IpAddr::V4(ip.destination_addr()),
+                    len,
+                )
+            }
+            6 => {
+                let ip = Ipv6HeaderSlice::from_slice(original).map_err(|_| invalid())?;
+                let len = 1280 - Ipv6Header::LEN - Icmpv6Header::MIN_LEN;
+                (
+                    IpAddr::V6(ip.source_addr()),
+                    IpAddr::V6(ip.destination_addr()),
+                    len,
+                )
+            }
+            _ => return Err(invalid()),
+        };
+        let quoted = &original[..original.len().min(quoted_len)];
+        Ok(IcmpPacket::new(
+            dst,
+            src,
+            icmp_type,
+            code,
+            Bytes::copy_from_slice(quoted),
+        ))
+    }
+
+    /// A destination unreachable error answering `original` for being administratively
+    /// prohibited, as a firewall refuses a flow (RFC 1812 5.2.7.1, RFC 4443 3.1).
+    pub fn administratively_prohibited(original: &[u8]) -> std::io::Result<Self> {
+        match original.first().map(|b| b >> 4) {
+            Some(6) => Self::error_for(original, 1, 1),
+            _ => Self::error_for(original, 3, 13),
+        }
+    }
+
+    /// Sets the four bytes following the checksum, like the MTU of a packet too big error
+    /// or the identifier and sequence number of an echo. Zero by default.
+    pub fn rest_of_header(mut self, rest: [u8; 4]) -> Self {
+        self.rest_of_header = rest;
+        self
+    }
+
+    pub fn src_addr(&self) -> IpAddr {
+        self.src_addr
+    }
+
+    pub fn dst_addr(&self) -> IpAddr {
+        self.dst_addr
+    }
+
+    pub(crate) fn into_packet(self, ttl: u8) -> std::io::Result<NetworkPacket> {
+        let v4 = Icmpv4Type::Unknown {
+            type_u8: self.icmp_type,
+            code_u8: self.code,
+            bytes5to8: self.rest_of_header,
+        };
+        let v6 = Icmpv6Type::Unknown {
+            type_u8: self.icmp_type,
+            code_u8: self.code,
+            bytes5to8: self.rest_of_header,
+        };
+        create_packet(self.src_addr, self.dst_addr, ttl, v4, v6, &self.payload)
+    }
+}
+
+/// Writes `packet` to the device, fragmented if it exceeds `mtu`.
+pub(crate) fn send_icmp_packet(
+    pkt_sender: &EgressSender,
+    mtu: u16,
+    packet: NetworkPacket,
+) -> std::io::Result<()> {
+    let header_len = match &packet.ip {
+        IpHeader::Ipv4(ip) => ip.header_len(),
+        IpHeader::Ipv6(_) => Ipv6Header::LEN,
+    };
+    let packets = if header_len + packet.payload.len() <= mtu as usize {
+        vec![packet]
+    } else {
+        fragment(packet, mtu)?
+    };
+    for packet in packets {
+        pkt_sender
+            .send(packet)
+            .or(Err(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    Ok(())
+}
+
+/// An ICMP message of `v4` or an ICMPv6 one of `v6`, depending on the addresses. Fails with
+/// `InvalidInput` if they are of different IP versions.
+fn create_packet(
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: u8,
+    v4: Icmpv4Type,
+    v6: Icmpv6Type,
+    payload: &[u8],
+) -> std::io::Result<NetworkPacket> {
+    let (ip, icmp) = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let icmp = Icmpv4Header::with_checksum(v4, payload);
+            let len = icmp.header_len() + payload.len();
+            let mut ip = Ipv4Header::new(0, ttl, IpNumber::ICMP, src.octets(), dst.octets())
+                .map_err(IpStackError::from)?;
+            ip.set_payload_len(len).map_err(|_| message_too_long())?;
+            (IpHeader::Ipv4(ip), icmp.to_bytes().to_vec())
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let icmp = Icmpv6Header::with_checksum(v6, src.octets(), dst.octets(), payload)
+                .map_err(|_| message_too_long())?;
+            let len = icmp.header_len() + payload.len();
+            let ip = Ipv6Header {
+                traffic_class: 0,
+                flow_label: Ipv6FlowLabel::ZERO,
+                payload_length: u16::try_from(len).map_err(|_| message_too_long())?,
+                next_header: IpNumber::IPV6_ICMP,
+                hop_limit: ttl,
+                source: src.octets(),
+                destination: dst.octets(),
+            };
+            (IpHeader::Ipv6(ip), icmp.to_bytes().to_vec())
+        }
+        _ => return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+    };
+    let mut message = icmp;
+    message.extend_from_slice(payload);
+    Ok(NetworkPacket {
+        ip,
+        transport: TransportHeader::Unknown,
+        payload: message.into(),
+    })
+}
@@ -1,18 +1,58 @@
+use crate::{dns::DnsQuery, ndp::NdpPacket};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-pub use self::tcp_wrapper::IpStackTcpStream;
-pub use self::udp::IpStackUdpStream;
+pub(crate) use self::icmp::send_icmp_packet;
+pub use self::icmp::{IcmpPacket, IpStackIcmpStream};
+pub use self::tcb::TcpState;
+pub use self::tcp_split::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
+pub use self::tcp_wrapper::{IpStackTcpStream, TcpStats, TcpSynOptions};
+#[cfg(feature = "tunnel")]
+pub(crate) use self::tunnel::classify as classify_tunnel;
+#[cfg(feature = "tunnel")]
+pub use self::tunnel::{EspHeader, GreHeader, IpStackEsp, IpStackGre};
+pub(crate) use self::udp::{send_datagram, SendOptions};
+pub use self::udp::{IpStackUdpStream, UdpStats, UdpTimeoutRefresh};
+pub use self::udp_socket::{IpStackUdpBroadcast, IpStackUdpSocket, UdpBroadcastPolicy, UdpMode};
 pub use self::unknown::IpStackUnknownTransport;
 
+mod icmp;
 mod tcb;
 mod tcp;
+mod tcp_split;
 mod tcp_wrapper;
+#[cfg(feature = "tunnel")]
+mod tunnel;
 mod udp;
+mod udp_socket;
 mod unknown;
 
+/// `addr` with an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) replaced by its IPv4 address
+/// if `normalize`, as [`IpStackConfig::normalize_mapped_addrs`](crate::IpStackConfig::normalize_mapped_addrs)
+/// reports them.
+pub(crate) fn normalize_addr(addr: SocketAddr, normalize: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) if normalize => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
 pub enum IpStackStream {
     Tcp(IpStackTcpStream),
     Udp(IpStackUdpStream),
+    UdpBroadcast(IpStackUdpBroadcast),
+    Dns(DnsQuery),
+    Icmp(IpStackIcmpStream),
+    Ndp(NdpPacket),
+    /// A GRE packet, with the `tunnel` feature. Packets of other protocols the stack does
+    /// not terminate arrive as [`UnknownTransport`](Self::UnknownTransport).
+    #[cfg(feature = "tunnel")]
+    Gre(IpStackGre),
+    /// An ESP packet, with the `tunnel` feature.
+    #[cfg(feature = "tunnel")]
+    Esp(IpStackEsp),
     UnknownTransport(IpStackUnknownTransport),
     UnknownNetwork(Vec<u8>),
 }
@@ -22,6 +62,14 @@ impl IpStackStream {
         match self {
             IpStackStream::Tcp(tcp) => tcp.local_addr(),
             IpStackStream::Udp(udp) => udp.local_addr(),
+            IpStackStream::UdpBroadcast(udp) => udp.local_addr(),
+            IpStackStream::Dns(query) => query.local_addr(),
+            IpStackStream::Icmp(icmp) => SocketAddr::new(icmp.local_addr(), 0),
+            IpStackStream::Ndp(ndp) => SocketAddr::new(ndp.local_addr().into(), 0),
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Gre(gre) => SocketAddr::new(gre.transport().src_addr(), 0),
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Esp(esp) => SocketAddr::new(esp.transport().src_addr(), 0),
             IpStackStream::UnknownNetwork(_) => {
                 SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
             }
@@ -35,6 +83,14 @@ impl IpStackStream {
         match self {
             IpStackStream::Tcp(tcp) => tcp.peer_addr(),
             IpStackStream::Udp(udp) => udp.peer_addr(),
+            IpStackStream::UdpBroadcast(udp) => udp.peer_addr(),
+            IpStackStream::Dns(query) => query.peer_addr(),
+            IpStackStream::Icmp(icmp) => SocketAddr::new(icmp.peer_addr(), 0),
+            IpStackStream::Ndp(ndp) => SocketAddr::new(ndp.peer_addr().into(), 0),
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Gre(gre) => SocketAddr::new(gre.transport().dst_addr(), 0),
+            #[cfg(feature = "tunnel")]
+            IpStackStream::Esp(esp) => SocketAddr::new(esp.transport().dst_addr(), 0),
             IpStackStream::UnknownNetwork(_) => {
                 SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
             }
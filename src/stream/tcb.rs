@@ -1,16 +1,52 @@
-use crate::packet::TcpHeaderWrapper;
-use std::{collections::BTreeMap, pin::Pin, time::Duration};
-use tokio::time::Sleep;
+use crate::{
+    clock::{Clock, Timer},
+    packet::TcpHeaderWrapper,
+    IpStackConfig, ReassemblyUsage,
+};
+use bytes::{Bytes, BytesMut};
+use log::trace;
+use std::{
+    cell::Cell,
+    cmp,
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::watch, time::Instant};
 
-const MAX_UNACK: u32 = 1024 * 16; // 16KB
-const READ_BUFFER_SIZE: usize = 1024 * 16; // 16KB
+const MAX_WINDOW_SCALE: u8 = 14; // RFC 7323
+const MAX_SACK_BLOCKS: usize = 4; // RFC 2018, without timestamps
+pub(super) const DEFAULT_MSS: u16 = 536; // RFC 9293
+pub(super) const DEFAULT_MSS_V6: u16 = 1220; // RFC 8200
+const INITIAL_RTO: Duration = Duration::from_secs(1); // RFC 6298
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+const MAX_RETRANSMISSIONS: u32 = 10;
+const DUP_ACK_THRESHOLD: u32 = 3; // RFC 5681
+const CHALLENGE_ACK_LIMIT: u32 = 10; // per second, RFC 5961 section 7
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+/// State of a TCP connection (RFC 9293 3.3.2), seen from our side.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TcpState {
-    SynReceived(bool), // bool means if syn/ack is sent
+    /// The handshake is in progress, the bool tells whether our SYN/ACK was sent.
+    SynReceived(bool),
+    /// Our SYN is sent and waits for the peer's SYN/ACK.
+    SynSent,
     Established,
-    FinWait1(bool),
-    FinWait2(bool), // bool means waiting for ack
+    /// The peer sent its FIN, we may still send.
+    CloseWait,
+    /// Our FIN followed the peer's and waits for its ACK.
+    LastAck,
+    /// Our FIN is sent but not acknowledged, the peer may still send.
+    FinWait1,
+    /// Our FIN is acknowledged, the peer may still send.
+    FinWait2,
+    /// Both sides sent a FIN at the same time.
+    Closing,
+    TimeWait,
     Closed,
 }
 
@@ -30,63 +66,659 @@ pub(super) struct Tcb {
     pub(super) retransmission: Option<u32>,
     ack: u32,
     last_ack: u32,
-    pub(super) timeout: Pin<Box<Sleep>>,
+    pub(super) timeout: Timer,
     tcp_timeout: Duration,
-    recv_window: u16,
-    send_window: u16,
+    time_wait: Duration,
+    recv_window: u32,
+    send_window: u32,
+    send_window_scale: u8,
+    recv_window_scale: Option<u8>, // None means window scaling is not negotiated
     state: TcpState,
+    state_notify: watch::Sender<TcpState>,
     avg_send_window: (u64, u64), // (avg, count)
     pub(super) inflight_packets: Vec<InflightPacket>,
     unordered_packets: BTreeMap<u32, UnorderedPacket>,
+    unordered_bytes: usize,
+    recv_buffer_size: usize,
+    reassembly_limit: usize,
+    reassembly: ReassemblyUsage,
+    reassembly_global_limit: usize,
+    sack_permitted: bool,
+    last_unordered_seq: Option<u32>,
+    dsack: Cell<Option<(u32, u32)>>, // duplicate range for the next ACK (RFC 2883)
+    mss: u16,
+    local_mss: u16,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    rto_timer: Timer,
+    rto_armed: bool,
+    rto_retries: u32,
+    cwnd: u32,
+    ssthresh: u32,
+    dup_acks: u32,
+    recovery: Option<u32>, // SND.NXT when fast recovery started
+    nodelay: bool,
+    unsent: BytesMut, // small writes held back by Nagle's algorithm
+    send_buffer_size: u32,
+    persist_timer: Timer,
+    pacing: bool,
+    pacing_timer: Timer, // fires when the next paced segment may leave
+    persist_armed: bool,
+    persist_backoff: u32,
+    linger_timer: Option<Timer>, // the deadline of a lingering shutdown
+    fin_seq: Option<u32>,        // sequence number of the peer's FIN
+    urgent: Option<u32>,         // the peer's latest urgent mark
+    local_fin: Option<u32>,      // sequence number of our FIN
+    ecn: bool,
+    ecn_echo: bool,                 // CE was seen, ECE is set until the peer sends CWR
+    ecn_cwr: bool,                  // CWR goes out with the next new data
+    ecn_recover: Option<u32>,       // SND.NXT when the window was last reduced for ECE
+    challenge_acks: (Instant, u32), // (start of the current second, count)
+    clock: Arc<dyn Clock>,
 }
 
 impl Tcb {
-    pub(super) fn new(ack: u32, tcp_timeout: Duration) -> Tcb {
-        #[cfg(debug_assertions)]
-        let seq = 100;
-        #[cfg(not(debug_assertions))]
-        let seq = rand::random::<u32>();
-        let deadline = tokio::time::Instant::now() + tcp_timeout;
+    pub(super) fn new(
+        seq: u32,
+        ack: u32,
+        config: &IpStackConfig,
+        reassembly: ReassemblyUsage,
+    ) -> Tcb {
+        let tcp_timeout = config.tcp_timeout;
+        let clock = config.clock.clone();
+        let now = clock.now();
+        let deadline = now + tcp_timeout;
         Tcb {
             seq,
             retransmission: None,
             ack,
             last_ack: seq,
             tcp_timeout,
-            timeout: Box::pin(tokio::time::sleep_until(deadline)),
-            send_window: u16::MAX,
+            time_wait: config.tcp_time_wait,
+            timeout: Timer::new(clock.clone(), deadline),
+            send_window: u16::MAX as u32,
             recv_window: 0,
+            send_window_scale: 0,
+            recv_window_scale: None,
             state: TcpState::SynReceived(false),
+            state_notify: watch::Sender::new(TcpState::SynReceived(false)),
             avg_send_window: (1, 1),
             inflight_packets: Vec::new(),
             unordered_packets: BTreeMap::new(),
+            unordered_bytes: 0,
+            recv_buffer_size: config.tcp_recv_buffer_size,
+            reassembly_limit: config.tcp_reassembly_limit,
+            reassembly,
+            reassembly_global_limit: config.tcp_reassembly_global_limit,
+            sack_permitted: false,
+            last_unordered_seq: None,
+            dsack: Cell::new(None),
+            mss: DEFAULT_MSS,
+            local_mss: DEFAULT_MSS,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            rto_timer: Timer::new(clock.clone(), deadline),
+            rto_armed: false,
+            rto_retries: 0,
+            cwnd: initial_window(DEFAULT_MSS),
+            ssthresh: u32::MAX,
+            dup_acks: 0,
+            recovery: None,
+            nodelay: false,
+            unsent: BytesMut::new(),
+            send_buffer_size: config.tcp_send_buffer_size.min(u32::MAX as usize) as u32,
+            persist_timer: Timer::new(clock.clone(), deadline),
+            pacing: config.pacing,
+            pacing_timer: Timer::new(clock.clone(), now),
+            persist_armed: false,
+            persist_backoff: 0,
+            linger_timer: None,
+            fin_seq: None,
+            urgent: None,
+            local_fin: None,
+            ecn: false,
+            ecn_echo: false,
+            ecn_cwr: false,
+            ecn_recover: None,
+            challenge_acks: (now, 0),
+            clock,
         }
     }
-    pub(super) fn add_inflight_packet(&mut self, seq: u32, buf: Vec<u8>) {
+    pub(super) fn add_inflight_packet(&mut self, seq: u32, buf: Bytes) {
         let buf_len = buf.len() as u32;
-        self.inflight_packets.push(InflightPacket::new(seq, buf));
+        let now = self.clock.now();
+        self.inflight_packets
+            .push(InflightPacket::new(seq, buf, now));
         self.seq = self.seq.wrapping_add(buf_len);
+        if !self.rto_armed {
+            self.arm_rto();
+        }
     }
-    pub(super) fn add_unordered_packet(&mut self, seq: u32, buf: Vec<u8>) {
-        if seq < self.ack {
-            return;
+    /// Queues a received segment, returns false when it was dropped for exceeding the
+    /// per-stream or global reassembly limit.
+    pub(super) fn add_unordered_packet(&mut self, seq: u32, buf: Bytes) -> bool {
+        if seq_lt(seq, self.ack) {
+            return true;
+        }
+        let end = seq.wrapping_sub(self.ack) as usize + buf.len();
+        let replaced = self
+            .unordered_packets
+            .get(&seq)
+            .map_or(0, |p| p.payload.len());
+        if end > self.reassembly_limit
+            || self.unordered_bytes - replaced + buf.len() > self.reassembly_limit
+        {
+            trace!("segment {} beyond the reassembly limit", seq);
+            return false;
+        }
+        let used = self.reassembly.fetch_add(buf.len(), Ordering::Relaxed);
+        if used + buf.len() > self.reassembly_global_limit {
+            self.reassembly.fetch_sub(buf.len(), Ordering::Relaxed);
+            trace!("segment {} beyond the global reassembly limit", seq);
+            return false;
+        }
+        self.insert_unordered(seq, buf);
+        self.last_unordered_seq = Some(seq);
+        true
+    }
+    fn insert_unordered(&mut self, seq: u32, payload: Bytes) {
+        self.unordered_bytes += payload.len();
+        if let Some(old) = self
+            .unordered_packets
+            .insert(seq, UnorderedPacket::new(payload))
+        {
+            self.release_unordered(old.payload.len());
         }
-        self.unordered_packets
-            .insert(seq, UnorderedPacket::new(buf));
+    }
+    fn remove_unordered(&mut self, seq: u32) -> Option<Bytes> {
+        let payload = self.unordered_packets.remove(&seq)?.payload;
+        self.release_unordered(payload.len());
+        Some(payload)
+    }
+    fn release_unordered(&mut self, len: usize) {
+        self.unordered_bytes -= len;
+        self.reassembly.fetch_sub(len, Ordering::Relaxed);
     }
     pub(super) fn get_available_read_buffer_size(&self) -> usize {
-        READ_BUFFER_SIZE.saturating_sub(
-            self.unordered_packets
+        self.recv_buffer_size.saturating_sub(self.unordered_bytes)
+    }
+    pub(super) fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_buffer_size = size;
+    }
+    /// Takes up to `max` bytes of in-order data, the rest stays queued.
+    pub(super) fn get_unordered_packets(&mut self, max: usize) -> Option<Bytes> {
+        let mut payload = self.remove_unordered(self.ack)?;
+        if payload.len() > max {
+            let rest = payload.split_off(max);
+            self.reassembly.fetch_add(rest.len(), Ordering::Relaxed);
+            self.insert_unordered(self.ack.wrapping_add(max as u32), rest);
+        }
+        Some(payload)
+    }
+    /// Copies in-order data into `buf` without taking it, returns the number of bytes copied.
+    pub(super) fn peek_unordered_packets(&self, buf: &mut [u8]) -> usize {
+        let mut next = self.ack;
+        let mut copied = 0;
+        while let Some(p) = self.unordered_packets.get(&next) {
+            let n = cmp::min(p.payload.len(), buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&p.payload[..n]);
+            copied += n;
+            if copied == buf.len() {
+                break;
+            }
+            next = next.wrapping_add(p.payload.len() as u32);
+        }
+        copied
+    }
+    /// The sequence number following the queued in-order data.
+    pub(super) fn get_recv_next(&self) -> u32 {
+        let mut next = self.ack;
+        while let Some(p) = self.unordered_packets.get(&next) {
+            next = next.wrapping_add(p.payload.len() as u32);
+        }
+        next
+    }
+    /// Sets the MSS we advertise (`local`) and the one used for sending, which never exceeds
+    /// our own limit.
+    pub(super) fn set_mss(&mut self, peer: u16, local: u16) {
+        self.local_mss = local;
+        self.mss = cmp::min(peer, local);
+        self.cwnd = initial_window(self.mss);
+    }
+    /// Lowers the MSS of both sides to at most `max`, for a lower MTU of the device.
+    pub(super) fn clamp_mss(&mut self, max: u16) {
+        self.local_mss = cmp::min(self.local_mss, max);
+        self.mss = cmp::min(self.mss, max);
+    }
+    pub(super) fn get_mss(&self) -> u16 {
+        self.mss
+    }
+    pub(super) fn get_local_mss(&self) -> u16 {
+        self.local_mss
+    }
+    /// Records the sequence number of an in-order FIN, it is acknowledged once all data
+    /// before it has been read.
+    pub(super) fn set_fin_seq(&mut self, seq: u32) {
+        self.fin_seq = Some(seq);
+    }
+    pub(super) fn is_fin_reached(&self) -> bool {
+        self.fin_seq == Some(self.ack)
+    }
+    /// Records the urgent mark of a received segment, a later mark replaces an earlier one.
+    pub(super) fn set_urgent(&mut self, mark: u32) {
+        match self.urgent {
+            Some(urgent) if !seq_lt(urgent, mark) => {}
+            _ => self.urgent = Some(mark),
+        }
+    }
+    /// Takes the urgent mark as the number of bytes to read before reaching it.
+    pub(super) fn take_urgent(&mut self) -> Option<usize> {
+        let mark = self.urgent.take()?;
+        Some(if seq_lt(mark, self.ack) {
+            0
+        } else {
+            mark.wrapping_sub(self.ack) as usize
+        })
+    }
+    pub(super) fn enable_sack(&mut self) {
+        self.sack_permitted = true;
+    }
+    pub(super) fn is_sack_permitted(&self) -> bool {
+        self.sack_permitted
+    }
+    /// Blocks of buffered data above RCV.NXT, the one holding the latest segment first.
+    pub(super) fn get_sack_blocks(&self) -> Vec<(u32, u32)> {
+        if !self.sack_permitted {
+            return Vec::new();
+        }
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for (seq, p) in self.unordered_packets.iter() {
+            let end = seq.wrapping_add(p.payload.len() as u32);
+            match blocks.last_mut() {
+                Some(last) if !seq_lt(last.1, *seq) => {
+                    if seq_lt(last.1, end) {
+                        last.1 = end;
+                    }
+                }
+                _ => blocks.push((*seq, end)),
+            }
+        }
+        blocks.retain(|(left, _)| *left != self.ack);
+        if let Some(seq) = self.last_unordered_seq {
+            if let Some(i) = blocks
                 .iter()
-                .fold(0, |acc, (_, p)| acc + p.payload.len()),
-        )
+                .position(|(left, right)| !seq_lt(seq, *left) && seq_lt(seq, *right))
+            {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+        if let Some(dsack) = self.dsack.take() {
+            blocks.insert(0, dsack);
+        }
+        blocks.truncate(MAX_SACK_BLOCKS);
+        blocks
+    }
+    /// Remembers the part of a received segment that was already read, it is reported as a
+    /// D-SACK block in the next ACK so the peer can tell its retransmission was spurious.
+    pub(super) fn record_duplicate(&mut self, seq: u32, len: usize) {
+        if !self.sack_permitted || len == 0 || !seq_lt(seq, self.ack) {
+            return;
+        }
+        let end = seq.wrapping_add(len as u32);
+        let end = if seq_lt(self.ack, end) { self.ack } else { end };
+        self.dsack.set(Some((seq, end)));
+    }
+    /// Marks inflight packets covered by the peer's SACK blocks.
+    pub(super) fn update_sack(&mut self, blocks: &[(u32, u32)]) {
+        for p in self.inflight_packets.iter_mut() {
+            let end = p.seq.wrapping_add(p.payload.len() as u32);
+            if blocks
+                .iter()
+                .any(|(left, right)| !seq_lt(p.seq, *left) && !seq_lt(*right, end))
+            {
+                p.sacked = true;
+            }
+        }
+    }
+    /// Inflight packets to resend for a retransmission request at `seq`. With SACK, every
+    /// hole below the highest SACKed sequence is resent as well.
+    pub(super) fn get_retransmission_packets(&self, seq: u32) -> Vec<&InflightPacket> {
+        // Nothing below SND.UNA is kept, a stale request starts from there instead
+        let seq = if seq_lt(seq, self.last_ack) {
+            self.last_ack
+        } else {
+            seq
+        };
+        let Some(seq) = self
+            .inflight_packets
+            .iter()
+            .find(|p| (seq.wrapping_sub(p.seq) as usize) < p.payload.len())
+            .map(|p| p.seq)
+        else {
+            return Vec::new();
+        };
+        let highest_sacked = self
+            .inflight_packets
+            .iter()
+            .filter(|p| p.sacked)
+            .map(|p| p.seq.wrapping_add(p.payload.len() as u32))
+            .reduce(|a, b| if seq_lt(a, b) { b } else { a });
+        let mut packets: Vec<&InflightPacket> = self
+            .inflight_packets
+            .iter()
+            .filter(|p| {
+                p.seq == seq || (!p.sacked && highest_sacked.is_some_and(|h| seq_lt(p.seq, h)))
+            })
+            .collect();
+        packets.sort_by_key(|p| p.seq.wrapping_sub(seq));
+        packets
+    }
+    /// Takes a sequence number for our SYN and times it like a data segment.
+    pub(super) fn add_syn(&mut self) {
+        self.seq = self.seq.wrapping_add(1);
+        self.arm_rto();
+    }
+    /// Takes over the peer's initial sequence number and options from its SYN/ACK. Window
+    /// scaling and SACK stay on only if both sides offered them.
+    pub(super) fn on_syn_ack(&mut self, irs: u32, window_scale: Option<u8>, sack_permitted: bool) {
+        self.ack = irs.wrapping_add(1);
+        match window_scale {
+            Some(scale) => self.send_window_scale = scale.min(MAX_WINDOW_SCALE),
+            None => self.recv_window_scale = None,
+        }
+        self.sack_permitted &= sack_permitted;
+    }
+    fn arm_rto(&mut self) {
+        self.rto_armed = true;
+        let deadline = self.clock.now() + self.rto;
+        self.rto_timer.reset(deadline);
+    }
+    /// Updates SRTT, RTTVAR and RTO from a new round-trip sample (RFC 6298).
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(sample);
+        self.rto = (srtt + cmp::max(CLOCK_GRANULARITY, self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+    pub(super) fn get_srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+    /// Takes a sequence number for our FIN and times it like a data segment.
+    pub(super) fn add_fin(&mut self) {
+        self.local_fin = Some(self.seq);
+        self.seq = self.seq.wrapping_add(1);
+        self.arm_rto();
     }
-    pub(super) fn get_unordered_packets(&mut self) -> Option<Vec<u8>> {
-        // dbg!(self.ack);
-        // for (seq,_) in self.unordered_packets.iter() {
-        //     dbg!(seq);
-        // }
-        self.unordered_packets.remove(&self.ack).map(|p| p.payload)
+    /// Whether our FIN was sent but not acknowledged yet.
+    pub(super) fn is_fin_unacked(&self) -> bool {
+        self.local_fin
+            .is_some_and(|fin| !seq_lt(fin, self.last_ack))
+    }
+    /// Resolves once the retransmission timer expires while our SYN, data or our FIN is in
+    /// flight.
+    pub(super) fn poll_rto(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let syn_unacked = self.state == TcpState::SynSent;
+        if !self.rto_armed
+            || (self.inflight_packets.is_empty() && !self.is_fin_unacked() && !syn_unacked)
+        {
+            self.rto_armed = false;
+            return Poll::Pending;
+        }
+        self.rto_timer.poll(cx)
+    }
+    /// Backs off the timer after an expiry and returns the oldest unacknowledged segment to
+    /// resend, our SYN or FIN if nothing else is in flight, or `None` once retransmissions
+    /// are exhausted.
+    pub(super) fn on_rto_expired(&mut self) -> Option<u32> {
+        if self.rto_retries >= MAX_RETRANSMISSIONS {
+            return None;
+        }
+        self.rto_retries += 1;
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+        self.ssthresh = self.loss_ssthresh();
+        self.cwnd = self.mss as u32;
+        self.recovery = None;
+        self.dup_acks = 0;
+        self.arm_rto();
+        if self.state == TcpState::SynSent {
+            return Some(self.last_ack);
+        }
+        let last_ack = self.last_ack;
+        self.inflight_packets
+            .iter()
+            .min_by_key(|p| p.seq.wrapping_sub(last_ack))
+            .map(|p| p.seq)
+            .or(self.local_fin.filter(|_| self.is_fin_unacked()))
+    }
+    /// Whether `seq` falls into the receive window.
+    pub(super) fn in_recv_window(&self, seq: u32) -> bool {
+        seq.wrapping_sub(self.ack) < cmp::max(self.recv_window, 1)
+    }
+    /// Cuts a received segment down to the part that is new and inside the receive window
+    /// (RFC 793 3.3). Returns None when nothing of it is acceptable, the segment is then an
+    /// old duplicate or lies beyond the window and only deserves a duplicate ACK.
+    pub(super) fn trim_to_recv_window(
+        &self,
+        seq: u32,
+        mut payload: Bytes,
+        fin: bool,
+    ) -> Option<(u32, Bytes)> {
+        let next = self.get_recv_next();
+        let end = seq.wrapping_add(payload.len() as u32 + fin as u32);
+        if !seq_lt(next, end) {
+            return None;
+        }
+        let seq = if seq_lt(seq, next) {
+            payload = payload.slice(cmp::min(next.wrapping_sub(seq) as usize, payload.len())..);
+            next
+        } else {
+            seq
+        };
+        if payload.is_empty() {
+            // A FIN right at the next expected sequence number is taken even by a closed window
+            return Some((seq, payload));
+        }
+        let room = self.ack.wrapping_add(self.recv_window).wrapping_sub(seq);
+        if room == 0 || room > self.recv_window {
+            return None;
+        }
+        payload.truncate(room as usize);
+        Some((seq, payload))
+    }
+    /// Counts a challenge ACK (RFC 5961) and returns false once the limit for the current
+    /// second is reached.
+    pub(super) fn allow_challenge_ack(&mut self) -> bool {
+        let now = self.clock.now();
+        let (start, count) = &mut self.challenge_acks;
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= CHALLENGE_ACK_LIMIT
+    }
+    pub(super) fn enable_ecn(&mut self) {
+        self.ecn = true;
+    }
+    pub(super) fn is_ecn_enabled(&self) -> bool {
+        self.ecn
+    }
+    pub(super) fn get_ecn_echo(&self) -> bool {
+        self.ecn_echo
+    }
+    /// Handles the ECN signals of a received segment: `ce` for a CE-marked IP header and the
+    /// `ece`/`cwr` TCP flags.
+    pub(super) fn update_ecn(&mut self, ce: bool, ece: bool, cwr: bool) {
+        if !self.ecn {
+            return;
+        }
+        if cwr {
+            self.ecn_echo = false;
+        }
+        if ce {
+            self.ecn_echo = true;
+        }
+        let reduced = self
+            .ecn_recover
+            .is_some_and(|recover| seq_lt(self.last_ack, recover));
+        if ece && !reduced && self.recovery.is_none() {
+            // Reacts like a loss, at most once per window of data
+            self.ssthresh = self.loss_ssthresh();
+            self.cwnd = self.ssthresh;
+            self.ecn_recover = Some(self.seq);
+            self.ecn_cwr = true;
+        }
+    }
+    pub(super) fn take_ecn_cwr(&mut self) -> bool {
+        std::mem::take(&mut self.ecn_cwr)
+    }
+    fn loss_ssthresh(&self) -> u32 {
+        let flight_size = self.seq.wrapping_sub(self.last_ack);
+        cmp::max(flight_size / 2, 2 * self.mss as u32)
+    }
+    /// Counts a duplicate ACK and returns true when it should trigger a fast retransmit.
+    pub(super) fn on_dup_ack(&mut self) -> bool {
+        self.dup_acks += 1;
+        if self.recovery.is_some() {
+            // Window inflation for every additional segment that left the network
+            self.cwnd = self.cwnd.saturating_add(self.mss as u32);
+            false
+        } else if self.dup_acks == DUP_ACK_THRESHOLD {
+            self.ssthresh = self.loss_ssthresh();
+            self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD * self.mss as u32;
+            self.recovery = Some(self.seq);
+            true
+        } else {
+            false
+        }
+    }
+    /// Grows or deflates the congestion window for `acked` newly acknowledged bytes.
+    fn on_new_ack(&mut self, ack: u32, acked: u32) {
+        self.dup_acks = 0;
+        let mss = self.mss as u32;
+        match self.recovery {
+            Some(recover) if !seq_lt(ack, recover) => {
+                self.recovery = None;
+                self.cwnd = self.ssthresh;
+            }
+            Some(_) => {
+                // NewReno partial ACK, the next hole is lost as well
+                self.cwnd = self.cwnd.saturating_sub(acked).saturating_add(mss);
+                self.retransmission = Some(ack);
+            }
+            None if self.cwnd < self.ssthresh => {
+                self.cwnd = self.cwnd.saturating_add(cmp::min(acked, mss));
+            }
+            None => {
+                self.cwnd = self.cwnd.saturating_add(cmp::max(1, mss * mss / self.cwnd));
+            }
+        }
+    }
+    /// Starts the persist timer while the peer advertises a zero window.
+    pub(super) fn arm_persist(&mut self) {
+        if self.persist_armed || self.send_window != 0 {
+            return;
+        }
+        self.persist_armed = true;
+        let interval = cmp::min(
+            self.rto * 2u32.saturating_pow(self.persist_backoff),
+            MAX_RTO,
+        );
+        self.persist_timer.reset(self.clock.now() + interval);
+    }
+    /// Resolves when a window probe is due; the timer is re-armed with backoff as long as
+    /// the window stays closed.
+    pub(super) fn poll_persist(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.persist_armed {
+            return Poll::Pending;
+        }
+        if self.send_window != 0 {
+            self.persist_armed = false;
+            self.persist_backoff = 0;
+            return Poll::Pending;
+        }
+        match self.persist_timer.poll(cx) {
+            Poll::Ready(()) => {
+                self.persist_armed = false;
+                self.persist_backoff = self.persist_backoff.saturating_add(1);
+                self.arm_persist();
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+    pub(super) fn get_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+    /// Starts the deadline for the data to be acknowledged once a shutdown lingers.
+    pub(super) fn arm_linger(&mut self, linger: Duration) {
+        let deadline = self.clock.now() + linger;
+        self.linger_timer = Some(Timer::new(self.clock.clone(), deadline));
+    }
+    /// Resolves once the linger deadline passed, never when no shutdown lingers.
+    pub(super) fn poll_linger(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.linger_timer.as_mut() {
+            Some(timer) => timer.poll(cx),
+            None => Poll::Pending,
+        }
+    }
+    /// Resolves when pacing lets the next segment of new data leave.
+    pub(super) fn poll_pacing(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.pacing {
+            return Poll::Ready(());
+        }
+        self.pacing_timer.poll(cx)
+    }
+    /// Holds the next segment back for the time `len` bytes take at the pacing rate. As in
+    /// Linux, the rate is twice cwnd / SRTT in slow start and 1.2 times it afterwards.
+    pub(super) fn on_paced_send(&mut self, len: usize) {
+        let (true, Some(srtt)) = (self.pacing, self.srtt) else {
+            return;
+        };
+        let factor = if self.cwnd < self.ssthresh { 2.0 } else { 1.2 };
+        let interval = srtt.mul_f64(len as f64 / (factor * cmp::max(self.cwnd, 1) as f64));
+        let start = cmp::max(self.pacing_timer.deadline(), self.clock.now());
+        self.pacing_timer.reset(start + interval);
+    }
+    pub(super) fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+    pub(super) fn get_nodelay(&self) -> bool {
+        self.nodelay
+    }
+    /// Nagle's algorithm (RFC 896): a segment smaller than the MSS may only be sent when
+    /// nothing is left unacknowledged.
+    pub(super) fn nagle_allows(&self, len: usize) -> bool {
+        self.nodelay || self.seq == self.last_ack || len >= self.mss as usize
+    }
+    pub(super) fn has_unsent(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+    pub(super) fn get_unsent_len(&self) -> usize {
+        self.unsent.len()
+    }
+    pub(super) fn add_unsent(&mut self, buf: &[u8]) {
+        self.unsent.extend_from_slice(buf);
+    }
+    pub(super) fn take_unsent(&mut self) -> BytesMut {
+        self.unsent.split()
+    }
+    pub(super) fn mark_retransmitted(&mut self, seq: u32) {
+        if let Some(p) = self.inflight_packets.iter_mut().find(|p| p.seq == seq) {
+            p.retransmitted = true;
+        }
     }
     pub(super) fn add_seq_one(&mut self) {
         self.seq = self.seq.wrapping_add(1);
@@ -96,6 +728,29 @@ impl Tcb {
     }
     pub(super) fn add_ack(&mut self, add: u32) {
         self.ack = self.ack.wrapping_add(add);
+        // Overlapping out-of-order segments may start behind the new position, keep only
+        // their unread tail
+        let stale: Vec<u32> = self
+            .unordered_packets
+            .keys()
+            .copied()
+            .filter(|&seq| seq_lt(seq, self.ack))
+            .collect();
+        for seq in stale {
+            let Some(payload) = self.remove_unordered(seq) else {
+                continue;
+            };
+            let read = self.ack.wrapping_sub(seq) as usize;
+            let queued = self
+                .unordered_packets
+                .get(&self.ack)
+                .map_or(0, |p| p.payload.len());
+            if payload.len().saturating_sub(read) > queued {
+                let rest = payload.slice(read..);
+                self.reassembly.fetch_add(rest.len(), Ordering::Relaxed);
+                self.insert_unordered(self.ack, rest);
+            }
+        }
     }
     pub(super) fn get_ack(&self) -> u32 {
         self.ack
@@ -105,133 +760,703 @@ impl Tcb {
     }
     pub(super) fn change_state(&mut self, state: TcpState) {
         self.state = state;
+        self.state_notify.send_replace(state);
+    }
+    pub(super) fn subscribe_state(&self) -> watch::Receiver<TcpState> {
+        self.state_notify.subscribe()
     }
     pub(super) fn get_state(&self) -> TcpState {
         self.state
     }
+    /// Whether the peer may still send data, which stays true after our FIN.
+    pub(super) fn can_recv(&self) -> bool {
+        matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        )
+    }
+    /// Whether data may still be sent, which stays true after the peer's FIN.
+    pub(super) fn can_send(&self) -> bool {
+        matches!(self.state, TcpState::Established | TcpState::CloseWait)
+    }
+    /// Enables window scaling once the peer offered it in its SYN; `send` is the peer's shift
+    /// and `recv` is the shift we advertise back in the SYN/ACK.
+    pub(super) fn set_window_scale(&mut self, send: u8, recv: u8) {
+        self.send_window_scale = send.min(MAX_WINDOW_SCALE);
+        self.recv_window_scale = Some(recv.min(MAX_WINDOW_SCALE));
+    }
+    pub(super) fn get_recv_window_scale(&self) -> Option<u8> {
+        self.recv_window_scale
+    }
+    fn scale_send_window(&self, window: u16) -> u32 {
+        (window as u32) << self.send_window_scale
+    }
     pub(super) fn change_send_window(&mut self, window: u16) {
+        let window = self.scale_send_window(window);
         let avg_send_window = ((self.avg_send_window.0 * self.avg_send_window.1) + window as u64)
             / (self.avg_send_window.1 + 1);
         self.avg_send_window.0 = avg_send_window;
         self.avg_send_window.1 += 1;
         self.send_window = window;
     }
-    pub(super) fn get_send_window(&self) -> u16 {
+    /// Bytes sent but not acknowledged yet.
+    pub(super) fn get_inflight_bytes(&self) -> u32 {
+        self.seq.wrapping_sub(self.last_ack)
+    }
+    /// The receive window in bytes, before it is scaled down for the window field.
+    pub(super) fn get_recv_window_size(&self) -> u32 {
+        self.recv_window
+    }
+    pub(super) fn get_send_window(&self) -> u32 {
         self.send_window
     }
+    /// The congestion window in bytes.
+    pub(super) fn get_cwnd(&self) -> u32 {
+        self.cwnd
+    }
     pub(super) fn get_avg_send_window(&self) -> u64 {
         self.avg_send_window.0
     }
-    pub(super) fn change_recv_window(&mut self, window: u16) {
+    /// Sets the receive window and returns true when it reopened past `min(MSS, buffer / 2)`
+    /// (RFC 1122 4.2.3.3), a peer stalled on the small window needs a window update then.
+    /// The window is clamped to what the window field can express.
+    pub(super) fn change_recv_window(&mut self, window: u32) -> bool {
+        let max = (u16::MAX as u32) << self.recv_window_scale.unwrap_or(0);
+        let window = cmp::min(window, max);
+        let threshold = cmp::min(self.local_mss as u32, self.recv_buffer_size as u32 / 2);
+        let reopened = self.recv_window < threshold && window >= threshold;
         self.recv_window = window;
+        reopened
     }
+    /// Returns the value for the window field of outgoing segments. The window of SYN
+    /// segments is never scaled.
     pub(super) fn get_recv_window(&self) -> u16 {
-        self.recv_window
+        let window = match (self.state, self.recv_window_scale) {
+            (TcpState::SynSent | TcpState::SynReceived(_), _) | (_, None) => self.recv_window,
+            (_, Some(scale)) => self.recv_window >> scale,
+        };
+        window.min(u16::MAX as u32) as u16
     }
     // #[inline(always)]
-    // pub(super) fn buffer_size(&self, payload_len: u16) -> u16 {
-    //     match MAX_UNACK - self.inflight_packets.len() as u32 {
-    //         // b if b.saturating_sub(payload_len as u32 + 64) != 0 => payload_len,
-    //         // b if b < 128 && b >= 4 => (b / 2) as u16,
-    //         // b if b < 4 => b as u16,
-    //         // b => (b - 64) as u16,
-    //         b if b >= payload_len as u32 * 2 && b > 0 => payload_len,
-    //         b if b < 4 => b as u16,
-    //         b => (b / 2) as u16,
-    //     }
-    // }
-
     pub(super) fn check_pkt_type(&self, header: &TcpHeaderWrapper, p: &[u8]) -> PacketStatus {
         let tcp_header = header.inner();
-        let received_ack_distance = self.seq.wrapping_sub(tcp_header.acknowledgment_number);
-
-        let current_ack_distance = self.seq.wrapping_sub(self.last_ack);
-        if received_ack_distance > current_ack_distance
-            || (tcp_header.acknowledgment_number != self.seq
-                && self.seq.saturating_sub(tcp_header.acknowledgment_number) == 0)
-        {
+        let ack = tcp_header.acknowledgment_number;
+        // An ACK for unsent data is never valid, an old one still carries usable data
+        if seq_lt(self.seq, ack) || (seq_lt(ack, self.last_ack) && p.is_empty()) {
             PacketStatus::Invalid
-        } else if self.last_ack == tcp_header.acknowledgment_number {
+        } else if !seq_lt(self.last_ack, ack) {
             if !p.is_empty() {
                 PacketStatus::NewPacket
-            } else if self.send_window == tcp_header.window_size && self.seq != self.last_ack {
+            } else if self.send_window == self.scale_send_window(tcp_header.window_size)
+                && self.seq != self.last_ack
+            {
                 PacketStatus::RetransmissionRequest
             } else if self.ack.wrapping_sub(1) == tcp_header.sequence_number {
                 PacketStatus::KeepAlive
             } else {
                 PacketStatus::WindowUpdate
             }
-        } else if self.last_ack < tcp_header.acknowledgment_number {
-            if !p.is_empty() {
-                PacketStatus::NewPacket
-            } else {
-                PacketStatus::Ack
-            }
+        } else if !p.is_empty() {
+            PacketStatus::NewPacket
         } else {
-            PacketStatus::Invalid
+            PacketStatus::Ack
         }
     }
     pub(super) fn change_last_ack(&mut self, ack: u32) {
+        if seq_lt(ack, self.last_ack) {
+            return;
+        }
         let distance = ack.wrapping_sub(self.last_ack);
         self.last_ack = self.last_ack.wrapping_add(distance);
 
-        if self.state == TcpState::Established {
+        if self.can_send() {
+            // Karn's algorithm, only segments that were never retransmitted give a sample
+            let sample = self
+                .inflight_packets
+                .iter()
+                .filter(|p| {
+                    !p.retransmitted && !seq_lt(ack, p.seq.wrapping_add(p.payload.len() as u32))
+                })
+                .map(|p| p.send_time)
+                .max();
+            if let Some(send_time) = sample {
+                let now = self.clock.now();
+                self.update_rtt(now.saturating_duration_since(send_time));
+            }
+            if distance > 0 {
+                self.rto_retries = 0;
+                self.on_new_ack(ack, distance);
+            }
             if let Some(i) = self.inflight_packets.iter().position(|p| p.contains(ack)) {
                 let mut inflight_packet = self.inflight_packets.remove(i);
                 let distance = ack.wrapping_sub(inflight_packet.seq) as usize;
                 if distance < inflight_packet.payload.len() {
-                    inflight_packet.payload.drain(0..distance);
+                    inflight_packet.payload = inflight_packet.payload.slice(distance..);
                     inflight_packet.seq = ack;
                     self.inflight_packets.push(inflight_packet);
                 }
             }
             self.inflight_packets.retain(|p| {
                 let last_byte = p.seq.wrapping_add(p.payload.len() as u32);
-                last_byte.saturating_sub(self.last_ack) > 0
+                seq_lt(self.last_ack, last_byte)
             });
+            if distance > 0 {
+                if self.inflight_packets.is_empty() {
+                    self.rto_armed = false;
+                } else {
+                    self.arm_rto();
+                }
+            }
         }
     }
     pub fn is_send_buffer_full(&self) -> bool {
-        self.seq.wrapping_sub(self.last_ack) >= MAX_UNACK
+        self.seq.wrapping_sub(self.last_ack) >= cmp::min(self.send_buffer_size, self.cwnd)
+    }
+    /// Bytes that may still leave before the peer's window, cwnd or the send buffer is full.
+    pub(super) fn get_usable_window(&self) -> u32 {
+        let limit = cmp::min(cmp::min(self.send_window, self.cwnd), self.send_buffer_size);
+        limit.saturating_sub(self.seq.wrapping_sub(self.last_ack))
+    }
+    /// Whether `seq` was sent but is not acknowledged yet.
+    pub(super) fn is_in_flight(&self, seq: u32) -> bool {
+        !seq_lt(seq, self.last_ack) && seq_lt(seq, self.seq)
+    }
+    /// Splits the in-flight segment holding `seq` into pieces of at most `max` bytes and
+    /// returns them, so they can be resent after the path MTU dropped.
+    pub(super) fn split_inflight_packet(&mut self, seq: u32, max: usize) -> Vec<(u32, Bytes)> {
+        let Some(i) = self
+            .inflight_packets
+            .iter()
+            .position(|p| (seq.wrapping_sub(p.seq) as usize) < p.payload.len())
+        else {
+            return Vec::new();
+        };
+        let packet = &self.inflight_packets[i];
+        let pieces: Vec<_> = (0..packet.payload.len())
+            .step_by(cmp::max(max, 1))
+            .map(|offset| {
+                let end = cmp::min(offset + max, packet.payload.len());
+                let mut piece = InflightPacket::new(
+                    packet.seq.wrapping_add(offset as u32),
+                    packet.payload.slice(offset..end),
+                    packet.send_time,
+                );
+                piece.retransmitted = true;
+                piece
+            })
+            .collect();
+        let resend = pieces.iter().map(|p| (p.seq, p.payload.clone())).collect();
+        self.inflight_packets.splice(i..=i, pieces);
+        resend
+    }
+    /// Bytes a write may still add next to what is unacknowledged or unsent.
+    pub(super) fn get_send_buffer_space(&self) -> usize {
+        let used = self.seq.wrapping_sub(self.last_ack) as usize + self.unsent.len();
+        (self.send_buffer_size as usize).saturating_sub(used)
     }
 
+    pub(super) fn set_timeout(&mut self, timeout: Duration) {
+        self.tcp_timeout = timeout;
+        self.reset_timeout();
+    }
     pub(crate) fn reset_timeout(&mut self) {
-        let deadline = tokio::time::Instant::now() + self.tcp_timeout;
-        self.timeout.as_mut().reset(deadline);
+        let deadline = self.clock.now() + self.tcp_timeout;
+        self.timeout.reset(deadline);
+    }
+
+    /// Rearms `timeout` for the 2MSL period of TIME_WAIT.
+    pub(crate) fn reset_time_wait(&mut self) {
+        let deadline = self.clock.now() + self.time_wait;
+        self.timeout.reset(deadline);
     }
 }
 
 #[derive(Debug)]
 pub struct InflightPacket {
     pub seq: u32,
-    pub payload: Vec<u8>,
-    // pub send_time: SystemTime, // todo
+    pub payload: Bytes,
+    pub sacked: bool,
+    pub retransmitted: bool,
+    pub send_time: Instant,
 }
 
 impl InflightPacket {
-    fn new(seq: u32, payload: Vec<u8>) -> Self {
+    fn new(seq: u32, payload: Bytes, send_time: Instant) -> Self {
         Self {
             seq,
             payload,
-            // send_time: SystemTime::now(), // todo
+            sacked: false,
+            retransmitted: false,
+            send_time,
         }
     }
     pub(crate) fn contains(&self, seq: u32) -> bool {
-        self.seq < seq && seq <= self.seq + self.payload.len() as u32
+        let offset = seq.wrapping_sub(self.seq);
+        offset > 0 && offset as usize <= self.payload.len()
+    }
+}
+
+/// Initial congestion window (RFC 6928).
+fn initial_window(mss: u16) -> u32 {
+    let mss = mss as u32;
+    cmp::min(10 * mss, cmp::max(2 * mss, 14600))
+}
+
+/// Initial sequence number for the connection between `local` and `remote` (RFC 6528): a
+/// keyed hash of the tuple plus a clock ticking every 4 microseconds. The deterministic mode
+/// uses a fixed key and no clock, so the same tuple always starts at the same number.
+pub(super) fn initial_sequence_number(
+    local: SocketAddr,
+    remote: SocketAddr,
+    deterministic: bool,
+) -> u32 {
+    static SECRET: OnceLock<ahash::RandomState> = OnceLock::new();
+    if deterministic {
+        let state = ahash::RandomState::with_seeds(0, 0, 0, 0);
+        return state.hash_one((local, remote)) as u32;
+    }
+    let clock = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+        / 4;
+    let hash = SECRET
+        .get_or_init(ahash::RandomState::new)
+        .hash_one((local, remote));
+    (hash as u32).wrapping_add(clock as u32)
+}
+
+/// Compares sequence numbers modulo 2^32.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Drop for Tcb {
+    fn drop(&mut self) {
+        self.reassembly
+            .fetch_sub(self.unordered_bytes, Ordering::Relaxed);
+        self.state_notify.send_replace(TcpState::Closed);
     }
 }
 
 #[derive(Debug)]
 struct UnorderedPacket {
-    payload: Vec<u8>,
-    // pub recv_time: SystemTime, // todo
+    payload: Bytes,
 }
 
 impl UnorderedPacket {
-    pub(crate) fn new(payload: Vec<u8>) -> Self {
-        Self {
-            payload,
-            // recv_time: SystemTime::now(), // todo
+    pub(crate) fn new(payload: Bytes) -> Self {
+        Self { payload }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcb(ack: u32) -> Tcb {
+        Tcb::new(
+            100,
+            ack,
+            &IpStackConfig::default(),
+            ReassemblyUsage::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn partial_read() {
+        let mut tcb = tcb(1000);
+        tcb.add_unordered_packet(1000, Bytes::from_static(b"hello"));
+        tcb.add_unordered_packet(1005, Bytes::from_static(b"world"));
+        assert_eq!(tcb.get_recv_next(), 1010);
+
+        assert_eq!(tcb.get_unordered_packets(3).unwrap(), &b"hel"[..]);
+        tcb.add_ack(3);
+        assert_eq!(tcb.get_unordered_packets(100).unwrap(), &b"lo"[..]);
+        tcb.add_ack(2);
+        assert_eq!(tcb.get_recv_next(), 1010);
+    }
+
+    #[test]
+    fn isn() {
+        let a: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let b: SocketAddr = "1.2.3.4:80".parse().unwrap();
+        let c: SocketAddr = "1.2.3.4:443".parse().unwrap();
+        assert_eq!(
+            initial_sequence_number(a, b, true),
+            initial_sequence_number(a, b, true)
+        );
+        assert_ne!(
+            initial_sequence_number(a, b, true),
+            initial_sequence_number(a, c, true)
+        );
+        assert_ne!(
+            initial_sequence_number(a, b, false),
+            initial_sequence_number(a, c, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn reassembly_limit() {
+        let mut config = IpStackConfig::default();
+        config
+            .tcp_reassembly_limit(100)
+            .tcp_reassembly_global_limit(150);
+        let usage = ReassemblyUsage::default();
+        let mut a = Tcb::new(100, 1000, &config, usage.clone());
+        let mut b = Tcb::new(100, 1000, &config, usage.clone());
+        assert!(!a.add_unordered_packet(1_000_000, vec![0; 10].into()));
+        assert!(a.add_unordered_packet(1000, vec![0; 80].into()));
+        assert!(a.add_unordered_packet(1080, vec![0; 20].into()));
+        assert!(!a.add_unordered_packet(1100, vec![0; 1].into()));
+        assert!(!b.add_unordered_packet(1000, vec![0; 60].into()));
+        assert!(b.add_unordered_packet(1000, vec![0; 50].into()));
+        assert_eq!(usage.load(Ordering::Relaxed), 150);
+        assert_eq!(a.get_unordered_packets(50).unwrap().len(), 50);
+        assert_eq!(usage.load(Ordering::Relaxed), 100);
+        drop(a);
+        assert_eq!(usage.load(Ordering::Relaxed), 50);
+    }
+
+    #[tokio::test]
+    async fn sequence_wraparound() {
+        let mut tcb = Tcb::new(
+            u32::MAX - 150,
+            1,
+            &IpStackConfig::default(),
+            ReassemblyUsage::default(),
+        );
+        tcb.change_state(TcpState::Established);
+        let seq = tcb.get_seq();
+        for _ in 0..3 {
+            tcb.add_inflight_packet(tcb.get_seq(), vec![0; 100].into());
         }
+        tcb.change_last_ack(seq.wrapping_add(150));
+        assert_eq!(tcb.inflight_packets.len(), 2);
+        let packets = tcb.get_retransmission_packets(seq.wrapping_add(150));
+        assert_eq!(packets[0].seq, seq.wrapping_add(150));
+        assert_eq!(packets[0].payload.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn window_scale() {
+        let mut tcb = tcb(1);
+        tcb.change_recv_window(tcb.recv_buffer_size as u32);
+        tcb.set_window_scale(7, 2);
+        assert_eq!(tcb.get_recv_window(), tcb.recv_buffer_size as u16);
+
+        tcb.change_state(TcpState::Established);
+        tcb.change_send_window(10);
+        assert_eq!(tcb.get_send_window(), 10 << 7);
+        assert_eq!(tcb.get_recv_window(), (tcb.recv_buffer_size >> 2) as u16);
+
+        tcb.set_window_scale(20, 20);
+        assert_eq!(tcb.get_recv_window_scale(), Some(MAX_WINDOW_SCALE));
+
+        assert!(!tcb.change_recv_window(0));
+        assert!(!tcb.change_recv_window(100));
+        assert!(tcb.change_recv_window(tcb.recv_buffer_size as u32));
+        assert!(!tcb.change_recv_window(tcb.recv_buffer_size as u32));
+    }
+
+    #[tokio::test]
+    async fn sack() {
+        let mut tcb = tcb(1000);
+        tcb.enable_sack();
+        tcb.add_unordered_packet(1000, vec![0; 10].into());
+        tcb.add_unordered_packet(1020, vec![0; 10].into());
+        tcb.add_unordered_packet(1030, vec![0; 10].into());
+        tcb.add_unordered_packet(1050, vec![0; 10].into());
+        assert_eq!(tcb.get_sack_blocks(), vec![(1050, 1060), (1020, 1040)]);
+
+        let seq = tcb.get_seq();
+        for _ in 0..4 {
+            tcb.add_inflight_packet(tcb.get_seq(), vec![0; 100].into());
+        }
+        tcb.update_sack(&[(seq.wrapping_add(100), seq.wrapping_add(200))]);
+        tcb.update_sack(&[(seq.wrapping_add(300), seq.wrapping_add(400))]);
+        let packets = tcb.get_retransmission_packets(seq);
+        let seqs: Vec<u32> = packets.iter().map(|p| p.seq).collect();
+        assert_eq!(seqs, vec![seq, seq.wrapping_add(200)]);
+        // A request inside a segment resends the whole segment
+        let packets = tcb.get_retransmission_packets(seq.wrapping_add(1));
+        assert_eq!(packets[0].seq, seq);
+        assert!(tcb
+            .get_retransmission_packets(seq.wrapping_add(400))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn rto_backoff() {
+        let mut tcb = tcb(1);
+        tcb.change_state(TcpState::Established);
+        let seq = tcb.get_seq();
+        tcb.add_inflight_packet(seq, vec![0; 10].into());
+        for i in 1..=MAX_RETRANSMISSIONS {
+            assert_eq!(tcb.on_rto_expired(), Some(seq));
+            assert_eq!(tcb.rto, cmp::min(INITIAL_RTO * 2u32.pow(i), MAX_RTO));
+        }
+        assert_eq!(tcb.on_rto_expired(), None);
+
+        assert_eq!(tcb.get_srtt(), None);
+        tcb.update_rtt(Duration::from_millis(100));
+        assert_eq!(tcb.rto, MIN_RTO.max(Duration::from_millis(300)));
+        tcb.update_rtt(Duration::from_millis(180));
+        assert_eq!(tcb.get_srtt(), Some(Duration::from_millis(110)));
+    }
+
+    #[tokio::test]
+    async fn active_open() {
+        let mut tcb = tcb(0);
+        tcb.set_window_scale(0, 4);
+        tcb.enable_sack();
+        tcb.change_state(TcpState::SynSent);
+        tcb.add_syn();
+        assert_eq!(tcb.on_rto_expired(), Some(100));
+        assert_eq!(tcb.get_seq(), 101);
+
+        tcb.on_syn_ack(5000, None, true);
+        assert_eq!(tcb.get_ack(), 5001);
+        assert_eq!(tcb.get_recv_window_scale(), None);
+        assert!(tcb.is_sack_permitted());
+        tcb.change_state(TcpState::Established);
+        tcb.change_last_ack(101);
+        assert_eq!(tcb.get_inflight_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn fin_retransmit() {
+        let mut tcb = tcb(1);
+        tcb.change_state(TcpState::FinWait1);
+        let fin = tcb.get_seq();
+        tcb.add_fin();
+        assert!(tcb.is_fin_unacked());
+        assert_eq!(tcb.on_rto_expired(), Some(fin));
+        tcb.change_last_ack(fin.wrapping_add(1));
+        assert!(!tcb.is_fin_unacked());
+        assert_eq!(tcb.on_rto_expired(), None);
+    }
+
+    #[tokio::test]
+    async fn clamp_mss() {
+        let mut tcb = tcb(1);
+        tcb.set_mss(1460, 1400);
+        tcb.clamp_mss(1200);
+        assert_eq!((tcb.get_mss(), tcb.get_local_mss()), (1200, 1200));
+        // A higher MTU leaves it
+        tcb.clamp_mss(9000);
+        assert_eq!((tcb.get_mss(), tcb.get_local_mss()), (1200, 1200));
+    }
+
+    #[tokio::test]
+    async fn fast_retransmit() {
+        let mut tcb = tcb(1);
+        tcb.set_mss(1000, 1000);
+        tcb.change_state(TcpState::Established);
+        let seq = tcb.get_seq();
+        for _ in 0..8 {
+            tcb.add_inflight_packet(tcb.get_seq(), vec![0; 1000].into());
+        }
+        assert!(!tcb.on_dup_ack());
+        assert!(!tcb.on_dup_ack());
+        assert!(tcb.on_dup_ack());
+        assert_eq!(tcb.ssthresh, 4000);
+        assert_eq!(tcb.cwnd, 7000);
+        assert!(!tcb.on_dup_ack());
+        assert_eq!(tcb.cwnd, 8000);
+
+        tcb.change_last_ack(seq.wrapping_add(2000));
+        assert_eq!(tcb.retransmission, Some(seq.wrapping_add(2000)));
+        tcb.change_last_ack(seq.wrapping_add(8000));
+        assert_eq!(tcb.recovery, None);
+        assert_eq!(tcb.cwnd, 4000);
+    }
+
+    #[tokio::test]
+    async fn ecn_reduction() {
+        let mut tcb = tcb(1);
+        tcb.set_mss(1000, 1000);
+        tcb.change_state(TcpState::Established);
+        tcb.enable_ecn();
+        let seq = tcb.get_seq();
+        for _ in 0..8 {
+            tcb.add_inflight_packet(tcb.get_seq(), vec![0; 1000].into());
+        }
+        tcb.update_ecn(true, false, false);
+        assert!(tcb.get_ecn_echo());
+        tcb.update_ecn(false, false, true);
+        assert!(!tcb.get_ecn_echo());
+
+        tcb.update_ecn(false, true, false);
+        assert_eq!(tcb.cwnd, 4000);
+        assert!(tcb.take_ecn_cwr());
+        // Only once per window of data
+        tcb.change_last_ack(seq.wrapping_add(1000));
+        tcb.update_ecn(false, true, false);
+        assert!(!tcb.take_ecn_cwr());
+        tcb.change_last_ack(seq.wrapping_add(8000));
+        tcb.update_ecn(false, true, false);
+        assert!(tcb.take_ecn_cwr());
+    }
+
+    #[tokio::test]
+    async fn recv_window_trim() {
+        let mut tcb = tcb(1000);
+        tcb.change_recv_window(4096);
+        let data = Bytes::from_static(b"0123456789abcdef");
+
+        assert_eq!(
+            tcb.trim_to_recv_window(990, data.clone(), false),
+            Some((1000, Bytes::from_static(b"abcdef")))
+        );
+        assert_eq!(tcb.trim_to_recv_window(984, data.clone(), false), None);
+        assert_eq!(tcb.trim_to_recv_window(5096, data.clone(), false), None);
+        assert_eq!(
+            tcb.trim_to_recv_window(5090, data.clone(), false)
+                .unwrap()
+                .1,
+            &b"012345"[..]
+        );
+        // Only the FIN is new
+        assert_eq!(
+            tcb.trim_to_recv_window(984, data.clone(), true),
+            Some((1000, Bytes::new()))
+        );
+
+        // A segment overlapping queued data leaves nothing stale behind
+        tcb.add_unordered_packet(1000, data.slice(..10));
+        tcb.add_unordered_packet(1005, data.clone());
+        assert_eq!(tcb.get_unordered_packets(100).unwrap(), &data[..10]);
+        tcb.add_ack(10);
+        assert_eq!(tcb.get_recv_next(), 1021);
+        assert_eq!(tcb.get_unordered_packets(100).unwrap(), &data[5..]);
+        tcb.add_ack(11);
+        assert_eq!(tcb.get_available_read_buffer_size(), tcb.recv_buffer_size);
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_size() {
+        let mut config = IpStackConfig::default();
+        config.tcp_recv_buffer_size(1 << 20);
+        let mut tcb = Tcb::new(100, 1, &config, ReassemblyUsage::default());
+        tcb.change_state(TcpState::Established);
+        tcb.change_recv_window(tcb.get_available_read_buffer_size() as u32);
+        assert_eq!(tcb.recv_window, u16::MAX as u32);
+
+        tcb.set_window_scale(7, 5);
+        tcb.change_recv_window(tcb.get_available_read_buffer_size() as u32);
+        assert_eq!(tcb.get_recv_window(), (1 << 20 >> 5) as u16);
+
+        tcb.set_recv_buffer_size(1000);
+        tcb.add_unordered_packet(1, Bytes::from_static(&[0; 600]));
+        assert_eq!(tcb.get_available_read_buffer_size(), 400);
+    }
+
+    #[tokio::test]
+    async fn urgent_mark() {
+        let mut tcb = tcb(1000);
+        assert_eq!(tcb.take_urgent(), None);
+        tcb.set_urgent(1010);
+        tcb.set_urgent(1005);
+        assert_eq!(tcb.take_urgent(), Some(10));
+        assert_eq!(tcb.take_urgent(), None);
+        tcb.set_urgent(1003);
+        tcb.add_ack(5);
+        assert_eq!(tcb.take_urgent(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn dsack() {
+        let mut tcb = tcb(1000);
+        tcb.enable_sack();
+        tcb.add_unordered_packet(1000, Bytes::from_static(&[0; 100]));
+        tcb.get_unordered_packets(100);
+        tcb.add_ack(100);
+        tcb.add_unordered_packet(1200, Bytes::from_static(&[0; 10]));
+
+        tcb.record_duplicate(1050, 100);
+        assert_eq!(tcb.get_sack_blocks(), vec![(1050, 1100), (1200, 1210)]);
+        // Reported once
+        assert_eq!(tcb.get_sack_blocks(), vec![(1200, 1210)]);
+
+        tcb.record_duplicate(1100, 10);
+        assert_eq!(tcb.get_sack_blocks(), vec![(1200, 1210)]);
+    }
+
+    #[tokio::test]
+    async fn pacing() {
+        let mut config = IpStackConfig::default();
+        config.pacing(true);
+        let mut tcb = Tcb::new(100, 1, &config, ReassemblyUsage::default());
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        // No rate before the first RTT sample
+        tcb.on_paced_send(1000);
+        assert!(tcb.poll_pacing(&mut cx).is_ready());
+
+        tcb.update_rtt(Duration::from_millis(100));
+        let start = Instant::now();
+        tcb.on_paced_send(tcb.cwnd as usize / 2);
+        assert!(tcb.poll_pacing(&mut cx).is_pending());
+        let delay = tcb.pacing_timer.deadline() - start;
+        assert!(delay >= Duration::from_millis(24) && delay <= Duration::from_millis(26));
+    }
+
+    /// A clock that only moves when told to, its sleeps never fire on their own.
+    struct ManualClock(std::sync::Mutex<Instant>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+        fn sleep_until(&self, _deadline: Instant) -> crate::SleepFuture {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    #[tokio::test]
+    async fn manual_clock() {
+        let clock = Arc::new(ManualClock(std::sync::Mutex::new(Instant::now())));
+        let mut config = IpStackConfig::default();
+        config.clock(clock.clone());
+        let mut tcb = Tcb::new(100, 1, &config, ReassemblyUsage::default());
+        tcb.change_state(TcpState::Established);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+
+        let seq = tcb.get_seq();
+        tcb.add_inflight_packet(seq, vec![0; 10].into());
+        clock.advance(Duration::from_millis(40));
+        tcb.change_last_ack(seq.wrapping_add(10));
+        assert_eq!(tcb.get_srtt(), Some(Duration::from_millis(40)));
+
+        tcb.add_inflight_packet(seq.wrapping_add(10), vec![0; 10].into());
+        assert!(tcb.poll_rto(&mut cx).is_pending());
+        clock.advance(tcb.rto);
+        assert!(tcb.poll_rto(&mut cx).is_ready());
+
+        assert!(tcb.timeout.poll(&mut cx).is_pending());
+        clock.advance(config.tcp_timeout);
+        assert!(tcb.timeout.poll(&mut cx).is_ready());
+    }
+
+    #[tokio::test]
+    async fn linger() {
+        let clock = Arc::new(ManualClock(std::sync::Mutex::new(Instant::now())));
+        let mut config = IpStackConfig::default();
+        config.clock(clock.clone());
+        let mut tcb = Tcb::new(100, 1, &config, ReassemblyUsage::default());
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        assert!(tcb.poll_linger(&mut cx).is_pending());
+
+        tcb.arm_linger(Duration::from_secs(1));
+        clock.advance(Duration::from_millis(999));
+        assert!(tcb.poll_linger(&mut cx).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(tcb.poll_linger(&mut cx).is_ready());
     }
 }
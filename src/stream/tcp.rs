@@ -1,25 +1,41 @@
 use crate::{
+    clock::Clock,
+    egress::EgressSender,
     error::IpStackError,
     packet::{
-        tcp_flags::{ACK, FIN, NON, PSH, RST, SYN},
-        IpHeader, IpStackPacketProtocol, NetworkPacket, TcpHeaderWrapper, TransportHeader,
+        flow_label,
+        tcp_flags::{ACK, CWR, ECE, FIN, NON, PSH, RST, SYN, URG},
+        IcmpError, IcmpErrorKind, IpHeader, IpStackPacketProtocol, NetworkPacket, TcpHeaderWrapper,
+        TransportHeader,
     },
-    stream::tcb::{PacketStatus, Tcb, TcpState},
-    PacketReceiver, PacketSender, DROP_TTL, TTL,
+    stream::{
+        tcb::{initial_sequence_number, PacketStatus, Tcb, TcpState, DEFAULT_MSS, DEFAULT_MSS_V6},
+        TcpStats,
+    },
+    IpStackConfig, IpStackMetrics, PacketReceiver, ReassemblyUsage, DROP_TTL,
+};
+use bytes::Bytes;
+use etherparse::{
+    IpNumber, Ipv4Dscp, Ipv4Ecn, Ipv4Header, Ipv6FlowLabel, Ipv6Header, TcpHeader, TcpOptionElement,
 };
-use etherparse::{IpNumber, Ipv4Header, Ipv6FlowLabel};
 use log::{error, trace, warn};
 use std::{
+    cell::Cell,
     cmp,
-    future::Future,
-    io::{Error, ErrorKind},
-    mem::MaybeUninit,
+    io::{Error, ErrorKind, IoSlice},
     net::SocketAddr,
     pin::Pin,
     task::{Context, Poll, Waker},
-    time::Duration,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+};
+
+const MIN_MTU_V4: u16 = 576;
+const MIN_MTU_V6: u16 = 1280;
+const ECT_0: u8 = 0b10;
+const CE: u8 = 0b11;
 
 #[derive(Debug)]
 enum Shutdown {
@@ -45,12 +61,24 @@ pub(crate) struct IpStackTcpStream {
     src_addr: SocketAddr,
     dst_addr: SocketAddr,
     stream_receiver: PacketReceiver,
-    packet_sender: PacketSender,
+    packet_sender: EgressSender,
     packet_to_send: Option<NetworkPacket>,
     tcb: Tcb,
     mtu: u16,
     shutdown: Shutdown,
     write_notify: Option<Waker>,
+    read_notify: Option<Waker>, // a reader waiting while another task drives the stream
+    driver: Option<Waker>,      // the background task receiving segments
+    driver_error: Option<ErrorKind>, // the error the driver ran into, for the next read
+    evicted: bool,              // the dispatcher dropped the connection, reads and writes fail
+    soft_error: Option<ErrorKind>, // the last ICMP error, reported if the connection times out
+    linger: std::time::Duration,
+    close_with_rst: bool, // resets instead of closing with a FIN once dropped
+    shutdown_linger: Option<std::time::Duration>, // bounds the wait for ACKs before our FIN
+    dscp: u8,             // of the packets sent
+    ttl: u8,
+    flow_label: Ipv6FlowLabel, // of the packets sent over IPv6
+    stats: Cell<TcpStats>,     // the counters, segments are created through `&self`
 }
 
 impl IpStackTcpStream {
@@ -58,39 +86,184 @@ impl IpStackTcpStream {
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
         tcp: TcpHeaderWrapper,
-        packet_sender: PacketSender,
+        packet_sender: EgressSender,
         stream_receiver: PacketReceiver,
-        mtu: u16,
-        tcp_timeout: Duration,
+        config: &IpStackConfig,
+        reassembly: ReassemblyUsage,
     ) -> Result<IpStackTcpStream, IpStackError> {
-        let stream = IpStackTcpStream {
+        let mtu = packet_sender.mtu();
+        let mut stream = IpStackTcpStream {
             src_addr,
             dst_addr,
             stream_receiver,
             packet_sender,
             packet_to_send: None,
-            tcb: Tcb::new(tcp.inner().sequence_number + 1, tcp_timeout),
+            tcb: Tcb::new(
+                initial_sequence_number(dst_addr, src_addr, config.tcp_deterministic_isn),
+                tcp.inner().sequence_number.wrapping_add(1),
+                config,
+                reassembly,
+            ),
             mtu,
             shutdown: Shutdown::None,
             write_notify: None,
+            read_notify: None,
+            driver: None,
+            driver_error: None,
+            evicted: false,
+            soft_error: None,
+            linger: config.tcp_linger,
+            close_with_rst: false,
+            shutdown_linger: None,
+            dscp: 0,
+            ttl: config.ttl,
+            flow_label: tcp_flow_label(dst_addr, src_addr, config),
+            stats: Cell::default(),
         };
         if tcp.inner().syn {
+            if let Some(scale) = tcp.window_scale() {
+                stream.tcb.set_window_scale(scale, config.tcp_window_scale);
+            }
+            if tcp.sack_permitted() {
+                stream.tcb.enable_sack();
+            }
+            if config.tcp_ecn && tcp.inner().ece && tcp.inner().cwr {
+                stream.tcb.enable_ecn();
+            }
+            let (default_mss, local_mss) = mss_limits(src_addr, stream.mtu, config);
+            stream
+                .tcb
+                .set_mss(tcp.mss().unwrap_or(default_mss), local_mss);
             return Ok(stream);
         }
-        if !tcp.inner().rst {
-            let pkt = stream.create_rev_packet(RST | ACK, TTL, None, Vec::new())?;
-            if let Err(err) = stream.packet_sender.send(pkt) {
-                warn!("Error sending RST/ACK packet: {:?}", err);
+        Err(IpStackError::InvalidTcpPacket)
+    }
+
+    /// Opens a connection to `src_addr` on the device by sending our SYN, the handshake is
+    /// completed by polling the stream.
+    pub(crate) fn connect(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        packet_sender: EgressSender,
+        stream_receiver: PacketReceiver,
+        config: &IpStackConfig,
+        reassembly: ReassemblyUsage,
+    ) -> Result<IpStackTcpStream, IpStackError> {
+        let mtu = packet_sender.mtu();
+        let mut stream = IpStackTcpStream {
+            src_addr,
+            dst_addr,
+            stream_receiver,
+            packet_sender,
+            packet_to_send: None,
+            tcb: Tcb::new(
+                initial_sequence_number(dst_addr, src_addr, config.tcp_deterministic_isn),
+                0,
+                config,
+                reassembly,
+            ),
+            mtu,
+            shutdown: Shutdown::None,
+            write_notify: None,
+            read_notify: None,
+            driver: None,
+            driver_error: None,
+            evicted: false,
+            soft_error: None,
+            linger: config.tcp_linger,
+            close_with_rst: false,
+            shutdown_linger: None,
+            dscp: 0,
+            ttl: config.ttl,
+            flow_label: tcp_flow_label(dst_addr, src_addr, config),
+            stats: Cell::default(),
+        };
+        // Offer window scaling and SACK, the SYN/ACK tells whether the peer agrees
+        stream.tcb.set_window_scale(0, config.tcp_window_scale);
+        stream.tcb.enable_sack();
+        let (default_mss, local_mss) = mss_limits(src_addr, stream.mtu, config);
+        stream.tcb.set_mss(default_mss, local_mss);
+        let window = stream.tcb.get_available_read_buffer_size() as u32;
+        stream.tcb.change_recv_window(window);
+        stream.tcb.change_state(TcpState::SynSent);
+        let syn = stream.create_rev_packet(SYN, stream.ttl, None, Bytes::new())?;
+        stream.send_packet(syn)?;
+        stream.tcb.add_syn();
+        Ok(stream)
+    }
+
+    /// Answers a segment that belongs to no connection, like a refused SYN, with a reset
+    /// (RFC 9293 3.10.7.1).
+    pub(crate) fn reset_unknown(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        tcp: &TcpHeaderWrapper,
+        payload_len: usize,
+        packet_sender: EgressSender,
+        config: &IpStackConfig,
+    ) {
+        let h = tcp.inner();
+        let mut tcp_header = TcpHeader::new(dst_addr.port(), src_addr.port(), 0, 0);
+        tcp_header.rst = true;
+        if h.ack {
+            tcp_header.sequence_number = h.acknowledgment_number;
+        } else {
+            let len = payload_len as u32 + h.syn as u32 + h.fin as u32;
+            tcp_header.ack = true;
+            tcp_header.acknowledgment_number = h.sequence_number.wrapping_add(len);
+        }
+        let len = tcp_header.header_len();
+        let ip_header = match (dst_addr.ip(), src_addr.ip()) {
+            (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
+                let ip_h = Ipv4Header::new(
+                    len as u16,
+                    config.ttl,
+                    IpNumber::TCP,
+                    dst.octets(),
+                    src.octets(),
+                );
+                let Ok(mut ip_h) = ip_h else {
+                    return warn!("Error creating RST packet: {:?}", ip_h);
+                };
+                ip_h.dont_fragment = true;
+                tcp_header.checksum = tcp_header.calc_checksum_ipv4(&ip_h, &[]).unwrap_or(0);
+                IpHeader::Ipv4(ip_h)
             }
+            (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
+                let ip_h = Ipv6Header {
+                    traffic_class: 0,
+                    flow_label: tcp_flow_label(dst_addr, src_addr, config),
+                    payload_length: len as u16,
+                    next_header: IpNumber::TCP,
+                    hop_limit: config.ttl,
+                    source: dst.octets(),
+                    destination: src.octets(),
+                };
+                tcp_header.checksum = tcp_header.calc_checksum_ipv6(&ip_h, &[]).unwrap_or(0);
+                IpHeader::Ipv6(ip_h)
+            }
+            _ => unreachable!(),
+        };
+        let pkt = NetworkPacket {
+            ip: ip_header,
+            transport: TransportHeader::Tcp(tcp_header),
+            payload: Bytes::new(),
+        };
+        if let Err(err) = packet_sender.send(pkt) {
+            warn!("Error sending RST packet: {:?}", err);
         }
-        Err(IpStackError::InvalidTcpPacket)
     }
 
     fn calculate_payload_len(&self, ip_header_size: u16, tcp_header_size: u16) -> u16 {
+        let options_size = tcp_header_size.saturating_sub(TcpHeader::MIN_LEN as u16);
+        let mss = self.tcb.get_mss().saturating_sub(options_size);
         cmp::min(
             self.tcb.get_send_window(),
-            self.mtu.saturating_sub(ip_header_size + tcp_header_size),
-        )
+            cmp::min(
+                mss,
+                self.mtu.saturating_sub(ip_header_size + tcp_header_size),
+            ) as u32,
+        ) as u16
     }
 
     fn create_rev_packet(
@@ -98,12 +271,13 @@ impl IpStackTcpStream {
         flags: u8,
         ttl: u8,
         seq: impl Into<Option<u32>>,
-        mut payload: Vec<u8>,
+        mut payload: Bytes,
     ) -> Result<NetworkPacket, Error> {
+        let seq = seq.into();
         let mut tcp_header = etherparse::TcpHeader::new(
             self.dst_addr.port(),
             self.src_addr.port(),
-            seq.into().unwrap_or(self.tcb.get_seq()),
+            seq.unwrap_or(self.tcb.get_seq()),
             self.tcb.get_recv_window(),
         );
 
@@ -113,11 +287,51 @@ impl IpStackTcpStream {
         tcp_header.rst = flags & RST != 0;
         tcp_header.fin = flags & FIN != 0;
         tcp_header.psh = flags & PSH != 0;
+        tcp_header.cwr = flags & CWR != 0;
+        tcp_header.ece =
+            flags & ECE != 0 || (self.tcb.get_ecn_echo() && tcp_header.ack && !tcp_header.syn);
+        // Only new data is ECN-capable, retransmissions go out as not-ECT (RFC 3168 6.1.5)
+        let ect = self.tcb.is_ecn_enabled() && seq.is_none() && !payload.is_empty();
+
+        let mut options = Vec::new();
+        if tcp_header.syn {
+            options.push(TcpOptionElement::MaximumSegmentSize(
+                self.tcb.get_local_mss(),
+            ));
+            if let Some(scale) = self.tcb.get_recv_window_scale() {
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::WindowScale(scale));
+            }
+            if self.tcb.is_sack_permitted() {
+                options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+            }
+        } else if tcp_header.ack && !tcp_header.rst {
+            let blocks = self.tcb.get_sack_blocks();
+            if let Some((first, rest)) = blocks.split_first() {
+                let mut others = [None; 3];
+                others
+                    .iter_mut()
+                    .zip(rest)
+                    .for_each(|(other, block)| *other = Some(*block));
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::SelectiveAcknowledgement(*first, others));
+            }
+        }
+        if !options.is_empty() {
+            tcp_header
+                .set_options(&options)
+                .map_err(IpStackError::from)?;
+        }
 
         let ip_header = match (self.dst_addr.ip(), self.src_addr.ip()) {
             (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
                 let mut ip_h = Ipv4Header::new(0, ttl, IpNumber::TCP, dst.octets(), src.octets())
                     .map_err(IpStackError::from)?;
+                ip_h.dscp = Ipv4Dscp::try_new(self.dscp).unwrap_or(Ipv4Dscp::ZERO);
+                if ect {
+                    ip_h.ecn = Ipv4Ecn::TWO;
+                }
                 let payload_len = self.calculate_payload_len(
                     ip_h.header_len() as u16,
                     tcp_header.header_len() as u16,
@@ -129,9 +343,9 @@ impl IpStackTcpStream {
                 IpHeader::Ipv4(ip_h)
             }
             (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
-                let mut ip_h = etherparse::Ipv6Header {
-                    traffic_class: 0,
-                    flow_label: Ipv6FlowLabel::ZERO,
+                let mut ip_h = Ipv6Header {
+                    traffic_class: self.dscp << 2 | if ect { ECT_0 } else { 0 },
+                    flow_label: self.flow_label,
                     payload_length: 0,
                     next_header: IpNumber::TCP,
                     hop_limit: ttl,
@@ -171,46 +385,614 @@ impl IpStackTcpStream {
     }
 }
 
-impl AsyncRead for IpStackTcpStream {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
+impl IpStackTcpStream {
+    /// Queues the data of the SYN (TCP Fast Open, RFC 7413), it is read after the handshake.
+    pub(crate) fn accept_syn_data(&mut self, payload: Bytes) {
+        let seq = self.tcb.get_ack();
+        self.tcb.add_unordered_packet(seq, payload);
+    }
+
+    pub(crate) fn take_urgent(&mut self) -> Option<usize> {
+        self.tcb.take_urgent()
+    }
+
+    pub(crate) fn set_nodelay(&mut self, nodelay: bool) -> std::io::Result<()> {
+        self.tcb.set_nodelay(nodelay);
+        self.send_unsent(false)
+    }
+
+    pub(crate) fn nodelay(&self) -> bool {
+        self.tcb.get_nodelay()
+    }
+
+    pub(crate) fn rtt(&self) -> Option<std::time::Duration> {
+        self.tcb.get_srtt()
+    }
+
+    pub(crate) fn inflight_bytes(&self) -> u32 {
+        self.tcb.get_inflight_bytes()
+    }
+
+    pub(crate) fn send_window(&self) -> u32 {
+        self.tcb.get_send_window()
+    }
+
+    pub(crate) fn recv_window(&self) -> u32 {
+        self.tcb.get_recv_window_size()
+    }
+
+    pub(crate) fn stats(&self) -> TcpStats {
+        TcpStats {
+            rtt: self.tcb.get_srtt(),
+            send_window: self.tcb.get_send_window(),
+            recv_window: self.tcb.get_recv_window_size(),
+            cwnd: self.tcb.get_cwnd(),
+            ..self.stats.get()
+        }
+    }
+
+    /// Sends `packet` to the device, counting it unless it only releases the tuple.
+    fn send_packet(&self, packet: NetworkPacket) -> std::io::Result<()> {
+        let (ttl, len) = (packet.ttl(), packet.payload.len() as u64);
+        self.packet_sender
+            .send(packet)
+            .or(Err(ErrorKind::UnexpectedEof))?;
+        if ttl != DROP_TTL {
+            self.count(|stats| {
+                stats.segments_sent += 1;
+                stats.bytes_sent += len;
+            });
+        }
+        Ok(())
+    }
+
+    fn count_received(&self, payload: &Bytes) {
+        self.count(|stats| {
+            stats.segments_received += 1;
+            stats.bytes_received += payload.len() as u64;
+        });
+    }
+
+    fn count(&self, f: impl FnOnce(&mut TcpStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    pub(crate) fn watch_state(&self) -> watch::Receiver<TcpState> {
+        self.tcb.subscribe_state()
+    }
+
+    pub(crate) fn set_recv_buffer_size(&mut self, size: usize) {
+        self.tcb.set_recv_buffer_size(size);
+    }
+
+    pub(crate) fn set_linger(&mut self, linger: std::time::Duration) {
+        self.linger = linger;
+    }
+
+    pub(crate) fn linger(&self) -> std::time::Duration {
+        self.linger
+    }
+
+    pub(crate) fn clock(&self) -> std::sync::Arc<dyn Clock> {
+        self.tcb.get_clock()
+    }
+
+    pub(crate) fn set_dscp(&mut self, dscp: u8) {
+        self.dscp = dscp & 0x3f;
+    }
+
+    pub(crate) fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    pub(crate) fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl.max(1);
+    }
+
+    pub(crate) fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: std::time::Duration) {
+        self.tcb.set_timeout(timeout);
+    }
+
+    pub(crate) fn set_shutdown_linger(&mut self, linger: Option<std::time::Duration>) {
+        self.shutdown_linger = linger;
+    }
+
+    pub(crate) fn shutdown_linger(&self) -> Option<std::time::Duration> {
+        self.shutdown_linger
+    }
+
+    pub(crate) fn set_close_with_rst(&mut self, rst: bool) {
+        self.close_with_rst = rst;
+    }
+
+    pub(crate) fn close_with_rst(&self) -> bool {
+        self.close_with_rst
+    }
+
+    /// Releases the tuple in the dispatcher and moves to `Closed`.
+    fn close(&mut self) -> std::io::Result<()> {
+        self.packet_to_send = Some(self.create_rev_packet(NON, DROP_TTL, None, Bytes::new())?);
+        self.tcb.change_state(TcpState::Closed);
+        Ok(())
+    }
+
+    /// Resets the connection and releases its tuple in the dispatcher.
+    pub(crate) fn abort(&mut self) -> std::io::Result<()> {
+        if matches!(self.tcb.get_state(), TcpState::Closed | TcpState::TimeWait) {
+            return Ok(());
+        }
+        self.send_packet(self.create_rev_packet(RST | ACK, self.ttl, None, Bytes::new())?)?;
+        self.send_packet(self.create_rev_packet(NON, DROP_TTL, None, Bytes::new())?)?;
+        self.tcb.change_state(TcpState::Closed);
+        self.shutdown.ready();
+        // The driver, readers and writers learn that the connection is gone
+        for waker in [
+            self.driver.take(),
+            self.read_notify.take(),
+            self.write_notify.take(),
+        ] {
+            waker.into_iter().for_each(Waker::wake);
+        }
+        Ok(())
+    }
+
+    /// Enters TIME_WAIT once both FINs are exchanged after an active close. The tuple stays
+    /// reserved for 2MSL so a retransmitted FIN is answered instead of spawning a new stream.
+    fn time_wait(&mut self) {
+        self.tcb.change_state(TcpState::TimeWait);
+        self.tcb.reset_time_wait();
+        self.shutdown.ready();
+    }
+
+    /// Re-acknowledges stray FINs during TIME_WAIT and resolves once the 2MSL timer fires.
+    fn poll_time_wait(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while let Poll::Ready(Some(p)) = self.stream_receiver.poll_recv(cx) {
+            let IpStackPacketProtocol::Tcp(t) = p.transport_protocol() else {
+                continue; // ICMP errors no longer matter
+            };
+            if t.flags() & FIN != 0 {
+                self.send_packet(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?)?;
+                self.tcb.reset_time_wait();
+            }
+        }
+        if matches!(self.tcb.timeout.poll(cx), Poll::Pending) {
+            return Poll::Pending;
+        }
+        trace!("TIME_WAIT elapsed for {:?}", self.dst_addr);
+        self.close()?;
+        if let Some(packet) = self.packet_to_send.take() {
+            self.send_packet(packet)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Keeps the stream alive until TIME_WAIT is over, if it is in that state.
+    pub(crate) async fn wait_time_wait(&mut self) -> std::io::Result<()> {
+        if self.tcb.get_state() != TcpState::TimeWait {
+            return Ok(());
+        }
+        std::future::poll_fn(|cx| self.poll_time_wait(cx)).await
+    }
+
+    /// Handles an ICMP error about a segment still in flight, errors quoting anything else
+    /// are ignored as they may be spoofed (RFC 5927). A port unreachable or any error while
+    /// connecting aborts the connection, other unreachable errors are kept and reported
+    /// should the connection time out (RFC 1122 4.2.3.9).
+    fn on_icmp_error(&mut self, error: IcmpError) -> std::io::Result<()> {
+        if !self.tcb.is_in_flight(error.seq) {
+            trace!("ignoring ICMP error for {:?}", self.dst_addr);
+            return Ok(());
+        }
+        match error.kind {
+            IcmpErrorKind::PacketTooBig(mtu) => self.on_packet_too_big(error.seq, mtu),
+            IcmpErrorKind::Unreachable(kind) => {
+                trace!("ICMP error {:?} for {:?}", kind, self.dst_addr);
+                if self.tcb.get_state() == TcpState::SynSent || kind == ErrorKind::ConnectionRefused
+                {
+                    self.tcb.change_state(TcpState::Closed);
+                    self.shutdown.ready();
+                    return Err(Error::from(kind));
+                }
+                self.soft_error = Some(kind);
+                Ok(())
+            }
+        }
+    }
+
+    /// Lowers the MTU after the segment at `seq` did not fit (RFC 1191, RFC 8201) and resends
+    /// it in pieces that fit.
+    fn on_packet_too_big(&mut self, seq: u32, mtu: u16) -> std::io::Result<()> {
+        let (min_mtu, ip_header_size) = if self.src_addr.is_ipv4() {
+            (MIN_MTU_V4, Ipv4Header::MIN_LEN)
+        } else {
+            (MIN_MTU_V6, Ipv6Header::LEN)
+        };
+        let mtu = cmp::max(mtu, min_mtu);
+        if mtu >= self.mtu {
+            return Ok(());
+        }
+        trace!("path MTU towards {:?} is {}", self.src_addr, mtu);
+        self.mtu = mtu;
+        let max = mtu as usize - ip_header_size - TcpHeader::MIN_LEN;
+        self.resend_split(seq, max)
+    }
+
+    /// Adopts a lower MTU of the device set with [`IpStack::set_mtu`](crate::IpStack::set_mtu),
+    /// clamping the MSS to it and resending the segments in flight that no longer fit.
+    fn sync_mtu(&mut self) -> std::io::Result<()> {
+        let mtu = self.packet_sender.mtu();
+        if mtu >= self.mtu {
+            return Ok(());
+        }
+        trace!("MTU of the device lowered to {}", mtu);
+        self.mtu = mtu;
+        let ip_header_size = match self.src_addr {
+            SocketAddr::V4(_) => Ipv4Header::MIN_LEN,
+            SocketAddr::V6(_) => Ipv6Header::LEN,
+        };
+        let max = (mtu as usize).saturating_sub(ip_header_size + TcpHeader::MIN_LEN);
+        self.tcb.clamp_mss(max as u16);
+        let oversized: Vec<u32> = self
+            .tcb
+            .inflight_packets
+            .iter()
+            .filter(|packet| packet.payload.len() > max)
+            .map(|packet| packet.seq)
+            .collect();
+        for seq in oversized {
+            self.resend_split(seq, max)?;
+        }
+        Ok(())
+    }
+
+    /// Splits the segment in flight at `seq` into pieces of at most `max` bytes and sends them.
+    fn resend_split(&mut self, seq: u32, max: usize) -> std::io::Result<()> {
+        for (seq, payload) in self.tcb.split_inflight_packet(seq, max) {
+            self.send_packet(self.create_rev_packet(PSH | ACK, self.ttl, seq, payload)?)?;
+        }
+        Ok(())
+    }
+
+    /// The SYN/ACK answering the peer's SYN, sent again with our initial sequence number as
+    /// `seq` when the SYN is retransmitted.
+    fn create_syn_ack(&self, seq: impl Into<Option<u32>>) -> std::io::Result<NetworkPacket> {
+        let flags = if self.tcb.is_ecn_enabled() {
+            SYN | ACK | ECE
+        } else {
+            SYN | ACK
+        };
+        self.create_rev_packet(flags, self.ttl, seq, Bytes::new())
+    }
+
+    /// Answers a suspicious RST or SYN with an ACK, a genuine peer then resets with the
+    /// exact sequence number.
+    fn send_challenge_ack(&mut self) -> std::io::Result<()> {
+        if !self.tcb.allow_challenge_ack() {
+            trace!("challenge ACK limit reached for {:?}", self.dst_addr);
+            return Ok(());
+        }
+        self.send_packet(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?)?;
+        Ok(())
+    }
+
+    /// Processes incoming segments without a caller reading, received data stays queued.
+    fn poll_drive(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.poll_read(cx, &mut tokio::io::ReadBuf::new(&mut []))
+    }
+
+    /// Like a read, but the data stays queued and is neither consumed nor acknowledged.
+    pub(crate) fn poll_peek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<usize>> {
+        let drive = self.as_mut().poll_drive(cx);
+        let n = self.tcb.peek_unordered_packets(buf.initialize_unfilled());
+        buf.advance(n);
+        match drive {
+            Poll::Ready(Err(err)) if n == 0 => Poll::Ready(Err(err)),
+            Poll::Pending if n == 0 && buf.remaining() > 0 => {
+                self.read_notify = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(Ok(n)),
+        }
+    }
+
+    /// Queues the payload of a segment for reading and records its FIN if it is in order.
+    fn receive_segment(&mut self, t: &TcpHeaderWrapper, payload: Bytes) -> std::io::Result<()> {
+        let end = t.inner().sequence_number.wrapping_add(payload.len() as u32);
+        let fin = t.flags() & FIN != 0;
+        self.tcb
+            .record_duplicate(t.inner().sequence_number, payload.len());
+        let Some((seq, payload)) =
+            self.tcb
+                .trim_to_recv_window(t.inner().sequence_number, payload, fin)
+        else {
+            // An old duplicate or beyond the window, a duplicate ACK tells the peer what we expect
+            self.packet_to_send =
+                Some(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?);
+            return Ok(());
+        };
+        // A FIN cut off with the end of the window is not received yet
+        let fin = fin && seq.wrapping_add(payload.len() as u32) == end;
+        let in_order = seq == self.tcb.get_recv_next();
+        if !payload.is_empty() && (in_order || self.tcb.is_sack_permitted()) {
+            let len = payload.len() as u32;
+            if !self.tcb.add_unordered_packet(seq, payload) {
+                return Ok(());
+            }
+            if in_order && fin {
+                self.tcb.set_fin_seq(seq.wrapping_add(len));
+            }
+        } else if in_order && fin {
+            self.tcb.set_fin_seq(seq);
+        }
+        if !in_order {
+            self.packet_to_send =
+                Some(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?);
+        }
+        Ok(())
+    }
+
+    /// Shuts down the write side and discards what the peer still sends until its FIN.
+    pub(crate) async fn close_gracefully(&mut self) -> std::io::Result<()> {
+        tokio::io::AsyncWriteExt::shutdown(self).await?;
+        tokio::io::copy(self, &mut tokio::io::sink()).await?;
+        Ok(())
+    }
+
+    /// Sends a window probe when the persist timer fires. The probe carries an already
+    /// acknowledged sequence number, so the peer answers with an ACK holding its current window.
+    fn poll_persist(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
+        while let Poll::Ready(()) = self.tcb.poll_persist(cx) {
+            trace!("zero window probe to {:?}", self.src_addr);
+            let seq = self.tcb.get_last_ack().wrapping_sub(1);
+            self.send_packet(self.create_rev_packet(ACK, self.ttl, seq, Bytes::new())?)?;
+        }
+        Ok(())
+    }
+
+    /// Sends as much of `payload` as the window allows and returns the number of bytes sent.
+    fn send_payload(&mut self, payload: Bytes) -> std::io::Result<usize> {
+        let mut packet = self.create_rev_packet(PSH | ACK, self.ttl, None, payload)?;
+        let seq = self.tcb.get_seq();
+        let payload_len = packet.payload.len();
+        if payload_len == 0 {
+            return Ok(0);
+        }
+        if self.tcb.take_ecn_cwr() {
+            // Tells the peer the window was reduced for its ECE
+            packet = self.create_rev_packet(PSH | ACK | CWR, self.ttl, None, packet.payload)?;
+        }
+        let payload = packet.payload.clone();
+        self.send_packet(packet)?;
+        self.tcb.add_inflight_packet(seq, payload);
+        self.tcb.on_paced_send(payload_len);
+        Ok(payload_len)
+    }
+
+    /// Whether a write has to wait for the peer to acknowledge data or open its window.
+    fn is_write_blocked(&self) -> bool {
+        (self.tcb.get_send_window() as u64) < self.tcb.get_avg_send_window() / 2
+            || self.tcb.is_send_buffer_full()
+            || self.tcb.get_send_buffer_space() == 0
+    }
+
+    /// Resends the segments a retransmission asks for.
+    fn retransmit(&mut self) -> std::io::Result<()> {
+        self.sync_mtu()?;
+        if let Some(s) = self.tcb.retransmission.take() {
+            let packets = self.tcb.get_retransmission_packets(s);
+            if !packets.is_empty() {
+                let mut seqs = Vec::with_capacity(packets.len());
+                for packet in packets {
+                    let rev_packet = self.create_rev_packet(
+                        PSH | ACK,
+                        self.ttl,
+                        packet.seq,
+                        packet.payload.clone(),
+                    )?;
+
+                    self.send_packet(rev_packet)?;
+                    seqs.push(packet.seq);
+                }
+                let metrics = self.packet_sender.metrics();
+                IpStackMetrics::add(&metrics.retransmissions, seqs.len() as u64);
+                self.count(|stats| stats.retransmissions += seqs.len() as u64);
+                for seq in seqs {
+                    self.tcb.mark_retransmitted(seq);
+                }
+            } else if !self.tcb.inflight_packets.is_empty() {
+                error!(
+                    "Packet {} not found in inflight_packets, seq: {}, last_ack: {}, ack: {}",
+                    s,
+                    self.tcb.get_seq(),
+                    self.tcb.get_last_ack(),
+                    self.tcb.get_ack()
+                );
+                self.send_packet(self.create_rev_packet(
+                    RST | ACK,
+                    self.ttl,
+                    None,
+                    Bytes::new(),
+                )?)?;
+                self.tcb.change_state(TcpState::Closed);
+                self.shutdown.ready();
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the peer acknowledged everything written.
+    fn is_all_acked(&self) -> bool {
+        self.tcb.get_last_ack() == self.tcb.get_seq() && !self.tcb.has_unsent()
+    }
+
+    /// Sends data held back by Nagle's algorithm once it may leave, or right away with `force`.
+    fn send_unsent(&mut self, force: bool) -> std::io::Result<()> {
+        if !self.tcb.can_send()
+            || !self.tcb.has_unsent()
+            || self.tcb.is_send_buffer_full()
+            || !(force || self.tcb.nagle_allows(self.tcb.get_unsent_len()))
+        {
+            return Ok(());
+        }
+        let payload = self.tcb.take_unsent().freeze();
+        let sent = self.send_payload(payload.clone())?;
+        self.tcb.add_unsent(&payload[sent..]);
+        Ok(())
+    }
+}
+
+impl IpStackTcpStream {
+    /// Drives the stream from a task of its own, so keepalives and other segments are
+    /// answered while the application neither reads nor writes. Resolves once the
+    /// connection ended, its error is kept for the next read.
+    pub(crate) fn poll_background(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if !self
+            .driver
+            .as_ref()
+            .is_some_and(|d| d.will_wake(cx.waker()))
+        {
+            self.driver = Some(cx.waker().clone());
+        }
+        let result = match self
+            .as_mut()
+            .poll_segments(cx, &mut tokio::io::ReadBuf::new(&mut []))
+        {
+            // Reads see EOF, but the connection lives on until it is closed
+            Poll::Ready(Ok(())) if self.tcb.get_state() != TcpState::Closed => {
+                return Poll::Pending
+            }
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.driver = None;
+        if let Err(err) = result {
+            self.driver_error = Some(err.kind());
+        }
+        if let Some(waker) = self.read_notify.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_notify.take() {
+            waker.wake();
+        }
+        if let Shutdown::Pending(waker) = &self.shutdown {
+            waker.wake_by_ref();
+        }
+        Poll::Ready(())
+    }
+
+    /// Takes the error the background driver stopped with.
+    pub(crate) fn take_error(&mut self) -> Option<Error> {
+        self.driver_error.take().map(Error::from)
+    }
+
+    /// Detaches the background driver, returning its waker.
+    pub(crate) fn take_driver(&mut self) -> Option<Waker> {
+        self.driver.take()
+    }
+
+    fn poll_segments(
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         loop {
+            let connecting = self.tcb.get_state() == TcpState::SynSent;
+            if (self.tcb.can_send() || self.tcb.is_fin_unacked() || connecting)
+                && matches!(self.tcb.poll_rto(cx), Poll::Ready(_))
+            {
+                if let Some(seq) = self.tcb.on_rto_expired() {
+                    trace!("retransmission timeout for {:?}", self.dst_addr);
+                    if connecting {
+                        self.send_packet(self.create_rev_packet(
+                            SYN,
+                            self.ttl,
+                            seq,
+                            Bytes::new(),
+                        )?)?;
+                    } else if self.tcb.is_fin_unacked() {
+                        // The FIN only leaves once all data is acknowledged, so it is alone
+                        self.send_packet(self.create_rev_packet(
+                            FIN | ACK,
+                            self.ttl,
+                            seq,
+                            Bytes::new(),
+                        )?)?;
+                    } else {
+                        self.tcb.retransmission = Some(seq);
+                    }
+                } else {
+                    trace!("retransmissions exhausted for {:?}", self.dst_addr);
+                    if !connecting {
+                        self.send_packet(self.create_rev_packet(
+                            RST | ACK,
+                            self.ttl,
+                            None,
+                            Bytes::new(),
+                        )?)?;
+                    }
+                    self.tcb.change_state(TcpState::Closed);
+                    self.shutdown.ready();
+                    let kind = self.soft_error.take().unwrap_or(ErrorKind::TimedOut);
+                    return Poll::Ready(Err(Error::from(kind)));
+                }
+            }
             if self.tcb.retransmission.is_some() {
                 self.write_notify = Some(cx.waker().clone());
-                if matches!(self.as_mut().poll_flush(cx), Poll::Pending) {
-                    return Poll::Pending;
+                self.retransmit()?;
+            }
+            let force = matches!(self.shutdown, Shutdown::Pending(_));
+            self.send_unsent(force)?;
+            if self.tcb.can_send() {
+                if self.tcb.has_unsent() || self.write_notify.is_some() {
+                    self.tcb.arm_persist();
                 }
+                self.poll_persist(cx)?;
             }
 
             if let Some(packet) = self.packet_to_send.take() {
-                self.packet_sender
-                    .send(packet)
-                    .or(Err(ErrorKind::UnexpectedEof))?;
+                self.send_packet(packet)?;
             }
             if self.tcb.get_state() == TcpState::Closed {
                 self.shutdown.ready();
+                if self.evicted {
+                    return Poll::Ready(Err(Error::from(ErrorKind::ConnectionAborted)));
+                }
                 return Poll::Ready(Ok(()));
             }
 
-            if self.tcb.get_state() == TcpState::FinWait2(false) {
-                self.packet_to_send =
-                    Some(self.create_rev_packet(NON, DROP_TTL, None, Vec::new())?);
-                self.tcb.change_state(TcpState::Closed);
+            if self.tcb.get_state() == TcpState::TimeWait {
+                if let Poll::Ready(result) = self.poll_time_wait(cx) {
+                    result?;
+                }
                 self.shutdown.ready();
-                return Poll::Ready(Err(Error::from(ErrorKind::ConnectionAborted)));
+                return Poll::Ready(Ok(()));
             }
 
-            let min = self.tcb.get_available_read_buffer_size() as u16;
-            self.tcb.change_recv_window(min);
+            let min = self.tcb.get_available_read_buffer_size() as u32;
+            if self.tcb.change_recv_window(min) && self.tcb.can_recv() {
+                // The application caught up, tell the peer the window is open again
+                self.send_packet(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?)?;
+            }
 
-            if matches!(Pin::new(&mut self.tcb.timeout).poll(cx), Poll::Ready(_)) {
+            if matches!(self.tcb.timeout.poll(cx), Poll::Ready(_)) {
                 trace!("timeout reached for {:?}", self.dst_addr);
-                self.packet_sender
-                    .send(self.create_rev_packet(RST | ACK, TTL, None, Vec::new())?)
-                    .or(Err(ErrorKind::UnexpectedEof))?;
+                self.send_packet(self.create_rev_packet(
+                    RST | ACK,
+                    self.ttl,
+                    None,
+                    Bytes::new(),
+                )?)?;
                 self.tcb.change_state(TcpState::Closed);
                 self.shutdown.ready();
                 return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
@@ -218,66 +1000,162 @@ impl AsyncRead for IpStackTcpStream {
             self.tcb.reset_timeout();
 
             if self.tcb.get_state() == TcpState::SynReceived(false) {
-                self.packet_to_send =
-                    Some(self.create_rev_packet(SYN | ACK, TTL, None, Vec::new())?);
+                self.packet_to_send = Some(self.create_syn_ack(None)?);
                 self.tcb.add_seq_one();
                 self.tcb.change_state(TcpState::SynReceived(true));
                 continue;
             }
 
-            if let Some(b) = self
-                .tcb
-                .get_unordered_packets()
-                .filter(|_| matches!(self.shutdown, Shutdown::None))
+            if let Some(b) = Some(buf.remaining())
+                .filter(|&n| n > 0 && !matches!(self.tcb.get_state(), TcpState::SynReceived(_)))
+                .and_then(|n| self.tcb.get_unordered_packets(n))
             {
                 self.tcb.add_ack(b.len() as u32);
                 buf.put_slice(&b);
-                self.packet_sender
-                    .send(self.create_rev_packet(ACK, TTL, None, Vec::new())?)
-                    .or(Err(ErrorKind::UnexpectedEof))?;
+                self.send_packet(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?)?;
                 return Poll::Ready(Ok(()));
             }
-            if self.tcb.get_state() == TcpState::FinWait1(true) {
-                self.packet_to_send =
-                    Some(self.create_rev_packet(FIN | ACK, TTL, None, Vec::new())?);
-                self.tcb.add_seq_one();
+            if self.tcb.is_fin_reached() && self.tcb.can_recv() {
+                // Everything before the peer's FIN was read, acknowledge it and report EOF
                 self.tcb.add_ack(1);
-                self.tcb.change_state(TcpState::FinWait2(true));
+                self.send_packet(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?)?;
+                match self.tcb.get_state() {
+                    TcpState::Established => self.tcb.change_state(TcpState::CloseWait),
+                    TcpState::FinWait1 => self.tcb.change_state(TcpState::Closing),
+                    _ => self.time_wait(),
+                }
                 continue;
-            } else if matches!(self.shutdown, Shutdown::Pending(_))
-                && self.tcb.get_state() == TcpState::Established
+            }
+            if matches!(self.shutdown, Shutdown::Pending(_))
+                && self.tcb.can_send()
                 && self.tcb.get_last_ack() == self.tcb.get_seq()
+                && !self.tcb.has_unsent()
             {
                 self.packet_to_send =
-                    Some(self.create_rev_packet(FIN | ACK, TTL, None, Vec::new())?);
-                self.tcb.add_seq_one();
-                self.tcb.change_state(TcpState::FinWait1(false));
+                    Some(self.create_rev_packet(FIN | ACK, self.ttl, None, Bytes::new())?);
+                self.tcb.add_fin();
+                let state = match self.tcb.get_state() {
+                    TcpState::CloseWait => TcpState::LastAck,
+                    _ => TcpState::FinWait1,
+                };
+                self.tcb.change_state(state);
                 continue;
             }
             match self.stream_receiver.poll_recv(cx) {
                 Poll::Ready(Some(p)) => {
+                    if let Some(waker) = self.read_notify.take() {
+                        // The receiver now wakes the driving task, let the reader see the segment
+                        waker.wake();
+                    }
                     let IpStackPacketProtocol::Tcp(t) = p.transport_protocol() else {
-                        unreachable!()
+                        if let Some(error) = p.icmp_error() {
+                            self.on_icmp_error(error)?;
+                        }
+                        continue;
                     };
-                    if t.flags() & RST != 0 {
+                    let flags = t.flags() & !(ECE | CWR | URG);
+                    if self.tcb.get_state() == TcpState::SynSent {
+                        // Only a segment acknowledging our SYN is acceptable (RFC 9293 3.10.7.3)
+                        let h = t.inner();
+                        if flags & ACK == 0 || h.acknowledgment_number != self.tcb.get_seq() {
+                            continue;
+                        }
+                        self.count_received(&p.payload);
+                        if flags & RST != 0 {
+                            self.tcb.change_state(TcpState::Closed);
+                            self.shutdown.ready();
+                            return Poll::Ready(Err(Error::from(ErrorKind::ConnectionRefused)));
+                        }
+                        if flags & SYN == 0 {
+                            continue;
+                        }
+                        // The window of a SYN is never scaled
+                        self.tcb.change_send_window(h.window_size);
+                        self.tcb.on_syn_ack(
+                            h.sequence_number,
+                            t.window_scale(),
+                            t.sack_permitted(),
+                        );
+                        if let Some(mss) = t.mss() {
+                            let local_mss = self.tcb.get_local_mss();
+                            self.tcb.set_mss(mss, local_mss);
+                        }
+                        self.tcb.change_state(TcpState::Established);
+                        self.tcb.change_last_ack(h.acknowledgment_number);
                         self.packet_to_send =
-                            Some(self.create_rev_packet(NON, DROP_TTL, None, Vec::new())?);
-                        self.tcb.change_state(TcpState::Closed);
-                        self.shutdown.ready();
-                        return Poll::Ready(Err(Error::from(ErrorKind::ConnectionReset)));
+                            Some(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?);
+                        continue;
+                    }
+                    if flags & RST != 0 {
+                        // RFC 5961, only a RST at exactly RCV.NXT resets the connection
+                        let seq = t.inner().sequence_number;
+                        if seq == self.tcb.get_ack() {
+                            self.packet_to_send =
+                                Some(self.create_rev_packet(NON, DROP_TTL, None, Bytes::new())?);
+                            self.tcb.change_state(TcpState::Closed);
+                            self.shutdown.ready();
+                            return Poll::Ready(Err(Error::from(ErrorKind::ConnectionReset)));
+                        }
+                        if self.tcb.in_recv_window(seq) {
+                            self.send_challenge_ack()?;
+                        }
+                        continue;
+                    }
+                    if flags & (SYN | ACK) == SYN
+                        && self.tcb.get_state() == TcpState::SynReceived(true)
+                    {
+                        if t.inner().sequence_number == self.tcb.get_ack().wrapping_sub(1) {
+                            // The peer retransmitted its SYN, so our SYN/ACK was lost
+                            trace!("duplicate SYN from {:?}", self.src_addr);
+                            let seq = self.tcb.get_seq().wrapping_sub(1);
+                            self.packet_to_send = Some(self.create_syn_ack(seq)?);
+                        }
+                        continue;
+                    }
+                    if flags & SYN != 0 && !matches!(self.tcb.get_state(), TcpState::SynReceived(_))
+                    {
+                        // A SYN on a synchronized connection is never trusted (RFC 5961 4.2)
+                        self.send_challenge_ack()?;
+                        continue;
                     }
                     if self.tcb.check_pkt_type(&t, &p.payload) == PacketStatus::Invalid {
                         continue;
                     }
+                    self.count_received(&p.payload);
+                    self.tcb
+                        .update_ecn(p.ecn() == CE, t.inner().ece, t.inner().cwr);
+                    if t.inner().urg && self.tcb.can_recv() {
+                        // The urgent pointer marks the byte after the urgent data (RFC 6093)
+                        let mark = t.inner().sequence_number;
+                        self.tcb
+                            .set_urgent(mark.wrapping_add(t.inner().urgent_pointer as u32));
+                    }
 
                     if self.tcb.get_state() == TcpState::SynReceived(true) {
-                        if t.flags() == ACK {
+                        if flags == ACK {
                             self.tcb.change_last_ack(t.inner().acknowledgment_number);
                             self.tcb.change_send_window(t.inner().window_size);
                             self.tcb.change_state(TcpState::Established);
                         }
-                    } else if self.tcb.get_state() == TcpState::Established {
-                        if t.flags() == ACK {
+                    } else if self.tcb.can_send() {
+                        if self.tcb.is_sack_permitted() {
+                            self.tcb.update_sack(&t.sack_blocks());
+                        }
+                        if self.tcb.get_state() == TcpState::CloseWait
+                            && (flags & FIN != 0 || !p.payload.is_empty())
+                        {
+                            // Nothing follows the peer's FIN, so this is a retransmission
+                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                            self.tcb.change_send_window(t.inner().window_size);
+                            self.packet_to_send =
+                                Some(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?);
+                            if let Some(ref n) = self.write_notify {
+                                n.wake_by_ref();
+                                self.write_notify = None;
+                            };
+                            continue;
+                        }
+                        if flags == ACK {
                             match self.tcb.check_pkt_type(&t, &p.payload) {
                                 PacketStatus::WindowUpdate => {
                                     self.tcb.change_send_window(t.inner().window_size);
@@ -291,34 +1169,26 @@ impl AsyncRead for IpStackTcpStream {
                                 PacketStatus::KeepAlive => {
                                     self.tcb.change_last_ack(t.inner().acknowledgment_number);
                                     self.tcb.change_send_window(t.inner().window_size);
-                                    self.packet_to_send =
-                                        Some(self.create_rev_packet(ACK, TTL, None, Vec::new())?);
+                                    self.packet_to_send = Some(self.create_rev_packet(
+                                        ACK,
+                                        self.ttl,
+                                        None,
+                                        Bytes::new(),
+                                    )?);
                                     continue;
                                 }
                                 PacketStatus::RetransmissionRequest => {
                                     self.tcb.change_send_window(t.inner().window_size);
-                                    self.tcb.retransmission = Some(t.inner().acknowledgment_number);
-                                    if matches!(self.as_mut().poll_flush(cx), Poll::Pending) {
-                                        return Poll::Pending;
+                                    if !self.tcb.on_dup_ack() {
+                                        continue;
                                     }
+                                    self.tcb.retransmission = Some(t.inner().acknowledgment_number);
+                                    self.retransmit()?;
                                     continue;
                                 }
                                 PacketStatus::NewPacket => {
-                                    // if t.inner().sequence_number != self.tcb.get_ack() {
-                                    //     dbg!(t.inner().sequence_number);
-                                    //     self.packet_to_send = Some(self.create_rev_packet(
-                                    //         ACK,
-                                    //         TTL,
-                                    //         None,
-                                    //         Vec::new(),
-                                    //     )?);
-                                    //     continue;
-                                    // }
-
                                     self.tcb.change_last_ack(t.inner().acknowledgment_number);
-                                    self.tcb
-                                        .add_unordered_packet(t.inner().sequence_number, p.payload);
-
+                                    self.receive_segment(&t, p.payload)?;
                                     self.tcb.change_send_window(t.inner().window_size);
                                     if let Some(ref n) = self.write_notify {
                                         n.wake_by_ref();
@@ -337,14 +1207,13 @@ impl AsyncRead for IpStackTcpStream {
                                 }
                             };
                         }
-                        if t.flags() == (FIN | ACK) {
-                            self.tcb.add_ack(1);
-                            self.packet_to_send =
-                                Some(self.create_rev_packet(ACK, TTL, None, Vec::new())?);
-                            self.tcb.change_state(TcpState::FinWait1(true));
+                        if flags & FIN != 0 {
+                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                            self.tcb.change_send_window(t.inner().window_size);
+                            self.receive_segment(&t, p.payload)?;
                             continue;
                         }
-                        if t.flags() == (PSH | ACK) {
+                        if flags == (PSH | ACK) {
                             if !matches!(
                                 self.tcb.check_pkt_type(&t, &p.payload),
                                 PacketStatus::NewPacket
@@ -352,117 +1221,241 @@ impl AsyncRead for IpStackTcpStream {
                                 continue;
                             }
                             self.tcb.change_last_ack(t.inner().acknowledgment_number);
-
-                            if p.payload.is_empty()
-                                || self.tcb.get_ack() != t.inner().sequence_number
-                            {
+                            if p.payload.is_empty() {
                                 continue;
                             }
-
                             self.tcb.change_send_window(t.inner().window_size);
-
-                            self.tcb
-                                .add_unordered_packet(t.inner().sequence_number, p.payload);
+                            self.receive_segment(&t, p.payload)?;
                             continue;
                         }
-                    } else if self.tcb.get_state() == TcpState::FinWait1(false) {
-                        if t.flags() == ACK {
-                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
-                            self.tcb.add_ack(1);
-                            self.tcb.change_state(TcpState::FinWait2(true));
-                            continue;
-                        } else if t.flags() == (FIN | ACK) {
-                            self.tcb.add_ack(1);
-                            self.packet_to_send =
-                                Some(self.create_rev_packet(ACK, TTL, None, Vec::new())?);
-                            self.tcb.change_send_window(t.inner().window_size);
-                            self.tcb.change_state(TcpState::FinWait2(true));
-                            continue;
+                    } else if self.tcb.can_recv() {
+                        // Our FIN is sent, the peer may keep sending until its own FIN
+                        self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                        self.tcb.change_send_window(t.inner().window_size);
+                        if self.tcb.get_state() == TcpState::FinWait1
+                            && t.inner().acknowledgment_number == self.tcb.get_seq()
+                        {
+                            self.tcb.change_state(TcpState::FinWait2);
+                            self.shutdown.ready();
+                        }
+                        if flags & FIN != 0 || !p.payload.is_empty() {
+                            self.receive_segment(&t, p.payload)?;
                         }
-                    } else if self.tcb.get_state() == TcpState::FinWait2(true) {
-                        if t.flags() == ACK {
-                            self.tcb.change_state(TcpState::FinWait2(false));
-                        } else if t.flags() == (FIN | ACK) {
+                        continue;
+                    } else if matches!(self.tcb.get_state(), TcpState::LastAck | TcpState::Closing)
+                    {
+                        // Both FINs are sent, only the ACK of ours is missing
+                        if flags & FIN != 0 {
+                            // Our ACK of the peer's FIN was lost
                             self.packet_to_send =
-                                Some(self.create_rev_packet(ACK, TTL, None, Vec::new())?);
-                            self.tcb.change_state(TcpState::FinWait2(false));
+                                Some(self.create_rev_packet(ACK, self.ttl, None, Bytes::new())?);
+                        }
+                        if t.inner().acknowledgment_number == self.tcb.get_seq() {
+                            self.tcb.change_last_ack(t.inner().acknowledgment_number);
+                            if self.tcb.get_state() == TcpState::Closing {
+                                self.time_wait();
+                            } else {
+                                self.close()?;
+                            }
+                        }
+                    }
+                }
+                Poll::Ready(None) => {
+                    match self.tcb.get_state() {
+                        TcpState::SynSent => self.tcb.change_state(TcpState::Closed),
+                        TcpState::Closed | TcpState::TimeWait => {}
+                        _ => {
+                            // Evicted by the flow limits, the device learns from a reset
+                            let rst =
+                                self.create_rev_packet(RST | ACK, self.ttl, None, Bytes::new())?;
+                            if let Err(err) = self.send_packet(rst) {
+                                trace!("Error sending RST packet: {:?}", err);
+                            }
+                            self.tcb.change_state(TcpState::Closed);
+                            self.evicted = true;
+                            self.shutdown.ready();
+                            return Poll::Ready(Err(Error::from(ErrorKind::ConnectionAborted)));
                         }
                     }
+                    self.shutdown.ready();
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending
+                    if matches!(
+                        self.tcb.get_state(),
+                        TcpState::CloseWait | TcpState::LastAck | TcpState::Closing
+                    ) =>
+                {
+                    // The peer has finished sending, reads see EOF
+                    return Poll::Ready(Ok(()));
                 }
-                Poll::Ready(None) => return Poll::Ready(Ok(())),
                 Poll::Pending => return Poll::Pending,
             }
         }
     }
 }
 
+impl AsyncRead for IpStackTcpStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(kind) = self.driver_error.take() {
+            return Poll::Ready(Err(Error::from(kind)));
+        }
+        if buf.remaining() > 0 {
+            self.read_notify = None;
+        }
+        // With a driver, timers and the receiver wake the driver and it wakes the reader
+        let poll = match self.driver.clone() {
+            Some(driver) => self
+                .as_mut()
+                .poll_segments(&mut Context::from_waker(&driver), buf),
+            None => self.as_mut().poll_segments(cx, buf),
+        };
+        if poll.is_pending() && buf.remaining() > 0 {
+            self.read_notify = Some(cx.waker().clone());
+        }
+        poll
+    }
+}
+
 impl AsyncWrite for IpStackTcpStream {
+    /// Sends `buf` in as many segments as the windows allow. Returns how many bytes were
+    /// accepted, which is less than `buf.len()` when the send buffer or a window fills up.
     fn poll_write(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        if self.tcb.get_state() != TcpState::Established {
+        if self.tcb.get_state() == TcpState::CloseWait {
+            // Nobody reads after EOF, so the ACKs of what we write are processed here
+            if let Poll::Ready(Err(err)) = self.as_mut().poll_drive(cx) {
+                return Poll::Ready(Err(err));
+            }
+        }
+        if self.tcb.can_send() && self.is_write_blocked() {
+            // The ACKs that free the send buffer are processed here in case nobody reads
+            if let Poll::Ready(Err(err)) = self.as_mut().poll_drive(cx) {
+                return Poll::Ready(Err(err));
+            }
+        }
+        if self.evicted {
+            return Poll::Ready(Err(Error::from(ErrorKind::ConnectionAborted)));
+        }
+        if !self.tcb.can_send() {
             return Poll::Ready(Err(Error::from(ErrorKind::NotConnected)));
         }
         self.tcb.reset_timeout();
+        self.sync_mtu()?;
 
-        if (self.tcb.get_send_window() as u64) < self.tcb.get_avg_send_window() / 2
-            || self.tcb.is_send_buffer_full()
-        {
+        if self.is_write_blocked() {
             self.write_notify = Some(cx.waker().clone());
+            self.tcb.arm_persist();
+            self.poll_persist(cx)?;
             return Poll::Pending;
         }
 
         if self.tcb.retransmission.is_some() {
             self.write_notify = Some(cx.waker().clone());
-            if matches!(self.as_mut().poll_flush(cx), Poll::Pending) {
-                return Poll::Pending;
+            self.retransmit()?;
+        }
+
+        let buf = &buf[..cmp::min(buf.len(), self.tcb.get_send_buffer_space())];
+        let unsent_len = self.tcb.get_unsent_len();
+        if !self.tcb.nagle_allows(unsent_len + buf.len()) {
+            self.tcb.add_unsent(buf);
+            return Poll::Ready(Ok(buf.len()));
+        }
+
+        if self.packet_sender.poll_ready(cx).is_pending() || self.tcb.poll_pacing(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut payload = self.tcb.take_unsent();
+        payload.extend_from_slice(buf);
+        let payload = payload.freeze();
+        let mut sent = self.send_payload(payload.clone())?;
+        while sent > 0 && sent < payload.len() {
+            if self.packet_sender.poll_ready(cx).is_pending()
+                || self.tcb.poll_pacing(cx).is_pending()
+            {
+                break;
+            }
+            // Segments the rest of a large write as far as the windows allow
+            let usable = self.tcb.get_usable_window() as usize;
+            if usable == 0 {
+                break;
             }
+            let end = cmp::min(payload.len(), sent + usable);
+            match self.send_payload(payload.slice(sent..end))? {
+                0 => break,
+                n => sent += n,
+            }
+        }
+        if sent < unsent_len {
+            // Not even the coalesced bytes fit into the window, keep the rest for later
+            self.tcb.add_unsent(&payload[sent..unsent_len]);
+        }
+        match sent.saturating_sub(unsent_len) {
+            0 if !buf.is_empty() => {
+                self.write_notify = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            n => Poll::Ready(Ok(n)),
         }
+    }
 
-        let packet = self.create_rev_packet(PSH | ACK, TTL, None, buf.to_vec())?;
-        let seq = self.tcb.get_seq();
-        let payload_len = packet.payload.len();
-        let payload = packet.payload.clone();
-        self.packet_sender
-            .send(packet)
-            .or(Err(ErrorKind::UnexpectedEof))?;
-        self.tcb.add_inflight_packet(seq, payload);
+    /// Packs the slices into one write, so they leave in as few segments as the window allows.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let limit = self.tcb.get_send_buffer_space();
+        let mut buf = Vec::with_capacity(cmp::min(limit, bufs.iter().map(|b| b.len()).sum()));
+        for b in bufs {
+            let n = cmp::min(b.len(), limit - buf.len());
+            buf.extend_from_slice(&b[..n]);
+            if buf.len() == limit {
+                break;
+            }
+        }
+        self.poll_write(cx, &buf)
+    }
 
-        Poll::Ready(Ok(payload_len))
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
+    /// Resolves once everything written was acknowledged by the peer, so `write_all` and
+    /// `flush` guarantee delivery like they do on a `TcpStream`. Fails when the connection
+    /// ends first, like after the retransmissions are exhausted.
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<std::io::Result<()>> {
-        if self.tcb.get_state() != TcpState::Established {
+        if !self.tcb.can_send() {
             return Poll::Ready(Err(Error::from(ErrorKind::NotConnected)));
         }
 
-        if let Some(s) = self.tcb.retransmission.take() {
-            if let Some(packet) = self.tcb.inflight_packets.iter().find(|p| p.seq == s) {
-                let rev_packet =
-                    self.create_rev_packet(PSH | ACK, TTL, packet.seq, packet.payload.clone())?;
-
-                self.packet_sender
-                    .send(rev_packet)
-                    .or(Err(ErrorKind::UnexpectedEof))?;
-            } else {
-                error!("Packet {} not found in inflight_packets", s);
-                error!("seq: {}", self.tcb.get_seq());
-                error!("last_ack: {}", self.tcb.get_last_ack());
-                error!("ack: {}", self.tcb.get_ack());
-                error!("inflight_packets:");
-                for p in self.tcb.inflight_packets.iter() {
-                    error!("seq: {}", p.seq);
-                    error!("payload len: {}", p.payload.len());
-                }
-                panic!("Please report these values at: https://github.com/narrowlink/ipstack/");
-            }
+        self.send_unsent(true)?;
+        self.retransmit()?;
+        if self.is_all_acked() {
+            return Poll::Ready(Ok(()));
         }
-        Poll::Ready(Ok(()))
+        // The ACKs are processed here in case nobody reads
+        if let Poll::Ready(Err(err)) = self.as_mut().poll_drive(cx) {
+            return Poll::Ready(Err(err));
+        }
+        if self.is_all_acked() {
+            return Poll::Ready(Ok(()));
+        }
+        if !self.tcb.can_send() {
+            return Poll::Ready(Err(Error::from(ErrorKind::NotConnected)));
+        }
+        self.write_notify = Some(cx.waker().clone());
+        Poll::Pending
     }
 
     fn poll_shutdown(
@@ -473,20 +1466,56 @@ impl AsyncWrite for IpStackTcpStream {
             return Poll::Ready(Ok(()));
         } else if matches!(self.shutdown, Shutdown::None) {
             self.shutdown.pending(cx.waker().clone());
+            match self.shutdown_linger {
+                Some(linger) if linger.is_zero() => {
+                    self.abort()?;
+                    return Poll::Ready(Ok(()));
+                }
+                Some(linger) => self.tcb.arm_linger(linger),
+                None => {}
+            }
+        }
+        if self.tcb.can_send() && self.tcb.poll_linger(cx).is_ready() {
+            // Our FIN still waits for data to be acknowledged, the peer learns from a reset
+            trace!("shutdown linger expired for {:?}", self.dst_addr);
+            self.abort()?;
+            return Poll::Ready(Err(Error::from(ErrorKind::TimedOut)));
+        }
+        match self.as_mut().poll_drive(cx) {
+            Poll::Ready(Ok(())) if !matches!(self.shutdown, Shutdown::Ready) => Poll::Pending,
+            poll => poll,
         }
-        self.poll_read(
-            cx,
-            &mut tokio::io::ReadBuf::uninit(&mut [MaybeUninit::<u8>::uninit()]),
-        )
     }
 }
 
 impl Drop for IpStackTcpStream {
     fn drop(&mut self) {
-        if let Ok(p) = self.create_rev_packet(NON, DROP_TTL, None, Vec::new()) {
+        if let Ok(p) = self.create_rev_packet(NON, DROP_TTL, None, Bytes::new()) {
             if let Err(err) = self.packet_sender.send(p) {
                 trace!("Error sending NON packet: {:?}", err);
             }
         }
     }
 }
+
+/// Flow label of the segments from `src` to `dst`, 0 unless [`IpStackConfig::ipv6_flow_label`].
+fn tcp_flow_label(src: SocketAddr, dst: SocketAddr, config: &IpStackConfig) -> Ipv6FlowLabel {
+    match config.ipv6_flow_label && src.is_ipv6() {
+        true => flow_label(src, dst, IpNumber::TCP),
+        false => Ipv6FlowLabel::ZERO,
+    }
+}
+
+/// The MSS assumed when the peer sends none and the largest we accept, derived from `mtu`.
+fn mss_limits(addr: SocketAddr, mtu: u16, config: &IpStackConfig) -> (u16, u16) {
+    let (ip_header_size, default_mss) = if addr.is_ipv4() {
+        (Ipv4Header::MIN_LEN, DEFAULT_MSS)
+    } else {
+        (Ipv6Header::LEN, DEFAULT_MSS_V6)
+    };
+    let mut local_mss = mtu.saturating_sub((ip_header_size + TcpHeader::MIN_LEN) as u16);
+    if let Some(clamp) = config.mss_clamp {
+        local_mss = cmp::min(local_mss, clamp);
+    }
+    (default_mss, local_mss)
+}
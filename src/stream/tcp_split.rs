@@ -0,0 +1,133 @@
+use super::IpStackTcpStream;
+use std::{
+    io::{Error, IoSlice},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Read half of an [`IpStackTcpStream`], created by [`IpStackTcpStream::split`].
+pub struct ReadHalf<'a>(&'a IpStackTcpStream);
+
+/// Write half of an [`IpStackTcpStream`], created by [`IpStackTcpStream::split`].
+pub struct WriteHalf<'a>(&'a IpStackTcpStream);
+
+/// Owned read half of an [`IpStackTcpStream`], created by [`IpStackTcpStream::into_split`].
+pub struct OwnedReadHalf(Arc<IpStackTcpStream>);
+
+/// Owned write half of an [`IpStackTcpStream`], created by [`IpStackTcpStream::into_split`].
+pub struct OwnedWriteHalf(Arc<IpStackTcpStream>);
+
+impl<'a> ReadHalf<'a> {
+    pub(super) fn new(stream: &'a IpStackTcpStream) -> Self {
+        ReadHalf(stream)
+    }
+}
+
+impl<'a> WriteHalf<'a> {
+    pub(super) fn new(stream: &'a IpStackTcpStream) -> Self {
+        WriteHalf(stream)
+    }
+}
+
+impl OwnedReadHalf {
+    pub(super) fn new(stream: Arc<IpStackTcpStream>) -> Self {
+        OwnedReadHalf(stream)
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.0.local_addr()
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.0.peer_addr()
+    }
+}
+
+impl OwnedWriteHalf {
+    pub(super) fn new(stream: Arc<IpStackTcpStream>) -> Self {
+        OwnedWriteHalf(stream)
+    }
+    /// Resets the connection, see [`IpStackTcpStream::abort`].
+    pub fn abort(&self) -> std::io::Result<()> {
+        self.0.abort()
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.0.local_addr()
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.0.peer_addr()
+    }
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut &*self.0).poll_write(cx, buf)
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut &*self.0).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut &*self.0).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut &*self.0).poll_shutdown(cx)
+    }
+}
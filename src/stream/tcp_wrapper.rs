@@ -1,115 +1,528 @@
-use super::tcp::IpStackTcpStream as IpStackTcpStreamInner;
+use super::{
+    normalize_addr,
+    tcb::TcpState,
+    tcp::IpStackTcpStream as IpStackTcpStreamInner,
+    tcp_split::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf},
+};
 use crate::{
+    egress::EgressSender,
+    filter,
     packet::{NetworkPacket, TcpHeaderWrapper},
-    IpStackError, PacketSender,
+    IpStackConfig, IpStackError, PacketSender, ReassemblyUsage,
+};
+use std::{
+    io::IoSlice,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
-use std::{net::SocketAddr, pin::Pin, time::Duration};
-use tokio::{io::AsyncWriteExt, sync::mpsc, time::timeout};
+use tokio::{
+    io::ReadBuf,
+    sync::{mpsc, watch},
+};
+
+/// TCP options the peer sent in its SYN.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpSynOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    /// The options field as received, including padding.
+    pub raw: Vec<u8>,
+}
+
+impl TcpSynOptions {
+    fn new(tcp: &TcpHeaderWrapper) -> Self {
+        TcpSynOptions {
+            mss: tcp.mss(),
+            window_scale: tcp.window_scale(),
+            sack_permitted: tcp.sack_permitted(),
+            raw: tcp.inner().options.as_slice().to_vec(),
+        }
+    }
+}
+
+/// Counters and window sizes of a TCP connection, from [`IpStackTcpStream::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpStats {
+    /// Payload bytes of the segments sent, retransmitted ones included.
+    pub bytes_sent: u64,
+    /// Payload bytes of the segments received, duplicates included.
+    pub bytes_received: u64,
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    /// Segments sent again after a timeout or for fast retransmit.
+    pub retransmissions: u64,
+    /// Smoothed round-trip time, as [`IpStackTcpStream::rtt`].
+    pub rtt: Option<Duration>,
+    /// The window the peer last advertised, in bytes after scaling.
+    pub send_window: u32,
+    /// The window advertised to the peer, in bytes.
+    pub recv_window: u32,
+    /// The congestion window, in bytes.
+    pub cwnd: u32,
+}
 
 pub struct IpStackTcpStream {
-    inner: Option<Box<IpStackTcpStreamInner>>,
+    inner: Option<Arc<Mutex<Box<IpStackTcpStreamInner>>>>,
     peer_addr: SocketAddr,
     local_addr: SocketAddr,
-    stream_sender: PacketSender,
+    stream_sender: mpsc::WeakUnboundedSender<NetworkPacket>, // the dispatcher holds the sender
+    syn_options: TcpSynOptions,
+    hostname: Option<String>, // of the fake address the stream was opened to
+    target: Option<SocketAddr>, // the destination rewritten by `IpStackConfig::dnat`
+    state: watch::Receiver<TcpState>,
 }
 
 impl IpStackTcpStream {
+    /// Accepts the connection the SYN `packet` with header `tcp` opens, its replies carry
+    /// the DSCP of the SYN.
     pub(crate) fn new(
-        local_addr: SocketAddr,
-        peer_addr: SocketAddr,
+        packet: NetworkPacket,
         tcp: TcpHeaderWrapper,
-        pkt_sender: PacketSender,
-        mtu: u16,
-        tcp_timeout: Duration,
+        stream_sender: PacketSender,
+        stream_receiver: mpsc::UnboundedReceiver<NetworkPacket>,
+        pkt_sender: EgressSender,
+        config: &IpStackConfig,
+        reassembly: ReassemblyUsage,
     ) -> Result<IpStackTcpStream, IpStackError> {
-        let (stream_sender, stream_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
+        let syn_options = TcpSynOptions::new(&tcp);
+        let (local_addr, peer_addr) = (packet.src_addr(), packet.dst_addr());
+        let target = filter::rewrite_destination(config, &packet);
         IpStackTcpStreamInner::new(
             local_addr,
             peer_addr,
             tcp,
             pkt_sender,
             stream_receiver,
-            mtu,
-            tcp_timeout,
+            config,
+            reassembly,
         )
-        .map(|inner| IpStackTcpStream {
-            inner: Some(Box::new(inner)),
-            peer_addr,
+        .map(|mut inner| {
+            inner.set_dscp(packet.dscp());
+            if config.tcp_fast_open && !packet.payload.is_empty() {
+                inner.accept_syn_data(packet.payload);
+            }
+            let normalize = config.normalize_mapped_addrs;
+            let (local_addr, peer_addr) = (
+                normalize_addr(local_addr, normalize),
+                normalize_addr(peer_addr, normalize),
+            );
+            let mut stream = Self::spawn(inner, local_addr, peer_addr, stream_sender, syn_options);
+            stream.hostname = config
+                .fake_ip_pool
+                .as_ref()
+                .and_then(|pool| pool.lookup(peer_addr.ip()));
+            stream.target = target;
+            stream
+        })
+    }
+    /// Opens a connection to `local_addr` on the device, the stream leaves
+    /// [`TcpState::SynSent`] once the handshake is done or failed.
+    pub(crate) fn connect(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        stream_sender: PacketSender,
+        stream_receiver: mpsc::UnboundedReceiver<NetworkPacket>,
+        pkt_sender: EgressSender,
+        config: &IpStackConfig,
+        reassembly: ReassemblyUsage,
+    ) -> Result<IpStackTcpStream, IpStackError> {
+        IpStackTcpStreamInner::connect(
             local_addr,
-            stream_sender,
+            peer_addr,
+            pkt_sender,
+            stream_receiver,
+            config,
+            reassembly,
+        )
+        .map(|inner| {
+            let syn_options = TcpSynOptions::default();
+            let normalize = config.normalize_mapped_addrs;
+            let (local_addr, peer_addr) = (
+                normalize_addr(local_addr, normalize),
+                normalize_addr(peer_addr, normalize),
+            );
+            Self::spawn(inner, local_addr, peer_addr, stream_sender, syn_options)
         })
     }
+    fn spawn(
+        inner: IpStackTcpStreamInner,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        stream_sender: PacketSender,
+        syn_options: TcpSynOptions,
+    ) -> IpStackTcpStream {
+        let state = inner.watch_state();
+        let inner = Arc::new(Mutex::new(Box::new(inner)));
+        tokio::spawn(drive(inner.clone()));
+        IpStackTcpStream {
+            state,
+            inner: Some(inner),
+            peer_addr,
+            local_addr,
+            stream_sender: stream_sender.downgrade(),
+            syn_options,
+            hostname: None,
+            target: None,
+        }
+    }
+    pub(crate) fn reset_unknown(
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        tcp: &TcpHeaderWrapper,
+        payload_len: usize,
+        pkt_sender: EgressSender,
+        config: &IpStackConfig,
+    ) {
+        IpStackTcpStreamInner::reset_unknown(
+            local_addr,
+            peer_addr,
+            tcp,
+            payload_len,
+            pkt_sender,
+            config,
+        );
+    }
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
+    /// Where to forward the connection: the destination given by
+    /// [`IpStackConfig::with_dnat`], otherwise [`peer_addr`](Self::peer_addr).
+    pub fn target_addr(&self) -> SocketAddr {
+        self.target.unwrap_or(self.peer_addr)
+    }
+    /// Options of the peer's SYN, for mirroring them onto an upstream connection. Empty for
+    /// streams opened with [`IpStack::connect_tcp`](crate::IpStack::connect_tcp).
+    pub fn syn_options(&self) -> &TcpSynOptions {
+        &self.syn_options
+    }
+    /// The host name the peer address was allocated for by
+    /// [`IpStackConfig::fake_ip_pool`], `None` for other addresses.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+    /// Disables Nagle's algorithm when `nodelay` is true, so every write is sent as soon as
+    /// the window allows instead of being coalesced while data is unacknowledged.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> std::io::Result<()> {
+        match self.inner_mut() {
+            Some(mut inner) => inner.set_nodelay(nodelay),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+        }
+    }
+    pub fn nodelay(&self) -> bool {
+        self.with_inner(|inner| inner.nodelay())
+    }
+    /// Smoothed round-trip time to the peer (RFC 6298), `None` until an ACK was timed.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.rtt())
+    }
+    /// Bytes sent to the peer that it has not acknowledged yet.
+    pub fn inflight_bytes(&self) -> u32 {
+        self.with_inner(|inner| inner.inflight_bytes())
+    }
+    /// The window the peer last advertised, in bytes after scaling.
+    pub fn send_window(&self) -> u32 {
+        self.with_inner(|inner| inner.send_window())
+    }
+    /// The window advertised to the peer, in bytes. It shrinks while received data waits to
+    /// be read.
+    pub fn recv_window(&self) -> u32 {
+        self.with_inner(|inner| inner.recv_window())
+    }
+    /// Counters of the segments exchanged so far and the current windows.
+    pub fn stats(&self) -> TcpStats {
+        self.with_inner(|inner| inner.stats())
+    }
+    /// Current state of the connection.
+    pub fn state(&self) -> TcpState {
+        *self.state.borrow()
+    }
+    /// A receiver notified on every state change. It keeps seeing the close sequence after
+    /// the stream is dropped, ending with [`TcpState::Closed`].
+    pub fn watch_state(&self) -> watch::Receiver<TcpState> {
+        self.state.clone()
+    }
+    /// Receives data without removing it from the queue, a later read returns it again.
+    /// Resolves with 0 at end of stream.
+    pub async fn peek(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        std::future::poll_fn(|cx| self.poll_peek(cx, &mut buf)).await
+    }
+    /// Polling variant of [`peek`](Self::peek), the bytes are appended to `buf`.
+    pub fn poll_peek(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_inner(|inner| Pin::new(&mut **Pin::into_inner(inner)).poll_peek(cx, buf))
+    }
+    /// Overrides [`IpStackConfig::tcp_recv_buffer_size`] for this stream, the advertised
+    /// window follows from the next segment on.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_recv_buffer_size(size);
+        }
+    }
+    /// Overrides [`IpStackConfig::tcp_linger`] for this stream.
+    pub fn set_linger(&mut self, linger: Duration) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_linger(linger);
+        }
+    }
+    pub fn linger(&self) -> Duration {
+        self.with_inner(|inner| inner.linger())
+    }
+    /// Bounds how long [`shutdown`](tokio::io::AsyncWriteExt::shutdown) waits for the data
+    /// sent to be acknowledged before our FIN may follow. Once `linger` passes the connection
+    /// is reset and the shutdown fails with `TimedOut`, zero resets it right away. `None`,
+    /// the default, waits as long as the connection lives.
+    pub fn set_shutdown_linger(&mut self, linger: Option<Duration>) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_shutdown_linger(linger);
+        }
+    }
+    pub fn shutdown_linger(&self) -> Option<Duration> {
+        self.with_inner(|inner| inner.shutdown_linger())
+    }
+    /// Resets the connection with RST|ACK right away, skipping the FIN handshake. Data not
+    /// yet acknowledged is discarded, later reads see EOF and writes fail.
+    pub fn abort(&self) -> std::io::Result<()> {
+        match self.inner.as_ref() {
+            Some(inner) => inner.lock().unwrap_or_else(PoisonError::into_inner).abort(),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotConnected)),
+        }
+    }
+    /// Resets the connection instead of closing it with a FIN once the stream is dropped,
+    /// whatever the [`linger`](Self::set_linger), to pass on a reset of the upstream
+    /// connection.
+    pub fn set_close_with_rst(&mut self, rst: bool) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_close_with_rst(rst);
+        }
+    }
+    pub fn close_with_rst(&self) -> bool {
+        self.with_inner(|inner| inner.close_with_rst())
+    }
+    /// Sets the DSCP (the upper six bits of the TOS byte or traffic class) of the segments
+    /// sent from now on. Accepted streams start with the DSCP of the SYN, so the QoS marking
+    /// of the device survives the proxy.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_dscp(dscp);
+        }
+    }
+    pub fn dscp(&self) -> u8 {
+        self.with_inner(|inner| inner.dscp())
+    }
+    /// Overrides [`IpStackConfig::ttl`] for the segments sent from now on.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_ttl(ttl);
+        }
+    }
+    pub fn ttl(&self) -> u8 {
+        self.with_inner(|inner| inner.ttl())
+    }
+    /// Takes the urgent mark the peer sent last (RFC 6093) as the number of bytes to read
+    /// before reaching it, 0 once they were read. The urgent data itself stays in line with
+    /// the rest of the stream, a proxy can mark it again on its upstream connection.
+    pub fn take_urgent(&mut self) -> Option<usize> {
+        self.inner_mut().and_then(|mut inner| inner.take_urgent())
+    }
+    /// Overrides the idle timeout of this stream, counting from now.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(mut inner) = self.inner_mut() {
+            inner.set_timeout(timeout);
+        }
+    }
+    /// Splits the stream into halves borrowing it, to read and write concurrently.
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        (ReadHalf::new(self), WriteHalf::new(self))
+    }
+    /// Splits the stream into halves that can be moved to separate tasks. The connection is
+    /// closed once both halves are dropped.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let stream = Arc::new(self);
+        (
+            OwnedReadHalf::new(stream.clone()),
+            OwnedWriteHalf::new(stream),
+        )
+    }
+    /// Takes the error that ended the connection while no one was reading.
+    pub(crate) fn take_error(&mut self) -> Option<std::io::Error> {
+        self.inner_mut().and_then(|mut inner| inner.take_error())
+    }
     pub fn stream_sender(&self) -> PacketSender {
-        self.stream_sender.clone()
+        // A closed sender once the dispatcher released the connection
+        self.stream_sender
+            .upgrade()
+            .unwrap_or_else(|| mpsc::unbounded_channel().0)
+    }
+    fn inner_mut(&mut self) -> Option<MutexGuard<'_, Box<IpStackTcpStreamInner>>> {
+        self.inner
+            .as_ref()
+            .map(|inner| inner.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+    fn with_inner<T: Default>(&self, f: impl FnOnce(&IpStackTcpStreamInner) -> T) -> T {
+        self.inner.as_ref().map_or_else(T::default, |inner| {
+            f(&inner.lock().unwrap_or_else(PoisonError::into_inner))
+        })
+    }
+    fn poll_inner<T>(
+        &self,
+        f: impl FnOnce(Pin<&mut Box<IpStackTcpStreamInner>>) -> Poll<std::io::Result<T>>,
+    ) -> Poll<std::io::Result<T>> {
+        match self.inner.as_ref() {
+            Some(inner) => {
+                let mut inner = inner.lock().unwrap_or_else(PoisonError::into_inner);
+                f(Pin::new(&mut *inner))
+            }
+            None => Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::NotConnected))),
+        }
     }
 }
 
 impl tokio::io::AsyncRead for IpStackTcpStream {
     fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.inner.as_mut() {
-            Some(mut inner) => Pin::new(&mut inner).poll_read(cx, buf),
-            None => {
-                std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::NotConnected)))
-            }
-        }
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut &*self).poll_read(cx, buf)
     }
 }
 
 impl tokio::io::AsyncWrite for IpStackTcpStream {
     fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        match self.inner.as_mut() {
-            Some(mut inner) => Pin::new(&mut inner).poll_write(cx, buf),
-            None => {
-                std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::NotConnected)))
-            }
-        }
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut &*self).poll_write(cx, buf)
     }
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        match self.inner.as_mut() {
-            Some(mut inner) => Pin::new(&mut inner).poll_flush(cx),
-            None => {
-                std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::NotConnected)))
-            }
-        }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut &*self).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut &*self).poll_flush(cx)
     }
     fn poll_shutdown(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        match self.inner.as_mut() {
-            Some(mut inner) => Pin::new(&mut inner).poll_shutdown(cx),
-            None => {
-                std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::NotConnected)))
-            }
-        }
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut &*self).poll_shutdown(cx)
+    }
+}
+
+impl tokio::io::AsyncRead for &IpStackTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.poll_inner(|inner| inner.poll_read(cx, buf))
+    }
+}
+
+impl tokio::io::AsyncWrite for &IpStackTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        self.poll_inner(|inner| inner.poll_write(cx, buf))
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        self.poll_inner(|inner| inner.poll_write_vectored(cx, bufs))
+    }
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.poll_inner(|inner| inner.poll_flush(cx))
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        self.poll_inner(|inner| inner.poll_shutdown(cx))
     }
 }
 
 impl Drop for IpStackTcpStream {
     fn drop(&mut self) {
-        if let Some(mut inner) = self.inner.take() {
-            tokio::spawn(async move {
-                if let Err(err) = timeout(Duration::from_secs(2), inner.shutdown()).await {
-                    log::warn!("Error while dropping IpStackTcpStream: {:?}", err);
+        if let Some(inner) = self.inner.take() {
+            let driver = inner
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take_driver();
+            match Arc::into_inner(inner) {
+                Some(inner) => {
+                    tokio::spawn(close(
+                        inner.into_inner().unwrap_or_else(PoisonError::into_inner),
+                    ));
                 }
-            });
+                // The driver closes the stream once it sees it was dropped
+                None => driver.into_iter().for_each(Waker::wake),
+            }
+        }
+    }
+}
+
+/// Receives segments while the application does not, until the connection ends or the
+/// stream is dropped. Whoever lets go of the stream last closes it.
+async fn drive(inner: Arc<Mutex<Box<IpStackTcpStreamInner>>>) {
+    std::future::poll_fn(|cx| {
+        if Arc::strong_count(&inner) == 1 {
+            return Poll::Ready(());
         }
+        let mut guard = inner.lock().unwrap_or_else(PoisonError::into_inner);
+        Pin::new(&mut **guard).poll_background(cx)
+    })
+    .await;
+    inner
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take_driver();
+    if let Some(inner) = Arc::into_inner(inner) {
+        close(inner.into_inner().unwrap_or_else(PoisonError::into_inner)).await;
+    }
+}
+
+async fn close(mut inner: Box<IpStackTcpStreamInner>) {
+    let linger = inner.linger();
+    let clock = inner.clock();
+    let expired = clock.sleep_until(clock.now() + linger);
+    let reset = inner.close_with_rst()
+        || linger.is_zero()
+        || tokio::select! {
+            _ = inner.close_gracefully() => false,
+            _ = expired => true,
+        };
+    if reset {
+        // The FIN handshake did not finish in time, the peer learns from a reset
+        if let Err(err) = inner.abort() {
+            log::warn!("Error while dropping IpStackTcpStream: {:?}", err);
+        }
+    }
+    if let Err(err) = inner.wait_time_wait().await {
+        log::trace!("Error in TIME_WAIT: {:?}", err);
     }
 }
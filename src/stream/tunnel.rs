@@ -0,0 +1,173 @@
+use super::IpStackUnknownTransport;
+use etherparse::IpNumber;
+
+/// The header of a GRE packet (RFC 2784 with the key and sequence number of RFC 2890).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GreHeader {
+    /// The EtherType of the encapsulated packet, like 0x0800 for IPv4.
+    pub protocol_type: u16,
+    pub checksum: Option<u16>,
+    pub key: Option<u32>,
+    pub sequence: Option<u32>,
+}
+
+impl GreHeader {
+    const CHECKSUM: u8 = 0x80;
+    const KEY: u8 = 0x20;
+    const SEQUENCE: u8 = 0x10;
+
+    /// Parses the header at the start of `buf` and returns it with its length. Only version
+    /// 0 is understood, the enhanced GRE of PPTP is left to the caller.
+    fn parse(buf: &[u8]) -> Option<(Self, usize)> {
+        let (flags, version) = (*buf.first()?, *buf.get(1)? & 0x07);
+        if version != 0 {
+            return None;
+        }
+        let protocol_type = u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?);
+        let mut len = 4;
+        let mut field = |flag: u8| -> Option<Option<[u8; 4]>> {
+            if flags & flag == 0 {
+                return Some(None);
+            }
+            let bytes = buf.get(len..len + 4)?.try_into().ok()?;
+            len += 4;
+            Some(Some(bytes))
+        };
+        // A present field that is cut off makes the packet malformed
+        let checksum = field(Self::CHECKSUM)?.map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let key = field(Self::KEY)?.map(u32::from_be_bytes);
+        let sequence = field(Self::SEQUENCE)?.map(u32::from_be_bytes);
+        let header = GreHeader {
+            protocol_type,
+            checksum,
+            key,
+            sequence,
+        };
+        Some((header, len))
+    }
+}
+
+/// The header of an ESP packet (RFC 4303), everything after it is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspHeader {
+    /// The security parameters index, identifying the security association.
+    pub spi: u32,
+    pub sequence: u32,
+}
+
+impl EspHeader {
+    const LEN: usize = 8;
+
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..Self::LEN)?;
+        Some(EspHeader {
+            spi: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            sequence: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        })
+    }
+}
+
+/// A GRE packet from the device with its header parsed, for passing the tunnel on.
+pub struct IpStackGre {
+    header: GreHeader,
+    header_len: usize,
+    transport: IpStackUnknownTransport,
+}
+
+impl IpStackGre {
+    pub fn header(&self) -> &GreHeader {
+        &self.header
+    }
+    /// The encapsulated packet, following the GRE header.
+    pub fn payload(&self) -> &[u8] {
+        &self.transport.payload()[self.header_len..]
+    }
+    /// The packet as a whole, giving its addresses and sending replies.
+    pub fn transport(&self) -> &IpStackUnknownTransport {
+        &self.transport
+    }
+    pub fn into_transport(self) -> IpStackUnknownTransport {
+        self.transport
+    }
+}
+
+/// An ESP packet from the device with its header parsed, for passing the tunnel on.
+pub struct IpStackEsp {
+    header: EspHeader,
+    transport: IpStackUnknownTransport,
+}
+
+impl IpStackEsp {
+    pub fn header(&self) -> &EspHeader {
+        &self.header
+    }
+    /// The encrypted data, following the ESP header.
+    pub fn payload(&self) -> &[u8] {
+        &self.transport.payload()[EspHeader::LEN..]
+    }
+    /// The packet as a whole, giving its addresses and sending replies.
+    pub fn transport(&self) -> &IpStackUnknownTransport {
+        &self.transport
+    }
+    pub fn into_transport(self) -> IpStackUnknownTransport {
+        self.transport
+    }
+}
+
+/// The GRE or ESP stream `transport` carries, or `transport` as an unknown transport if it
+/// is neither or its header is malformed.
+pub(crate) fn classify(transport: IpStackUnknownTransport) -> super::IpStackStream {
+    match transport.ip_protocol() {
+        IpNumber::GRE => match GreHeader::parse(transport.payload()) {
+            Some((header, header_len)) => super::IpStackStream::Gre(IpStackGre {
+                header,
+                header_len,
+                transport,
+            }),
+            None => super::IpStackStream::UnknownTransport(transport),
+        },
+        IpNumber::ENCAPSULATING_SECURITY_PAYLOAD => match EspHeader::parse(transport.payload()) {
+            Some(header) => super::IpStackStream::Esp(IpStackEsp { header, transport }),
+            None => super::IpStackStream::UnknownTransport(transport),
+        },
+        _ => super::IpStackStream::UnknownTransport(transport),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gre_header() {
+        let buf = [
+            0x30, 0x00, 0x08, 0x00, // key and sequence present, IPv4
+            0x00, 0x00, 0x00, 0x2a, // key
+            0x00, 0x00, 0x00, 0x07, // sequence
+            0x45,
+        ];
+        let (header, len) = GreHeader::parse(&buf).unwrap();
+        assert_eq!(len, 12);
+        assert_eq!(
+            header,
+            GreHeader {
+                protocol_type: 0x0800,
+                checksum: None,
+                key: Some(42),
+                sequence: Some(7),
+            }
+        );
+        // The sequence number is cut off
+        assert!(GreHeader::parse(&buf[..10]).is_none());
+        // Enhanced GRE
+        assert!(GreHeader::parse(&[0x30, 0x01, 0x88, 0x0b]).is_none());
+    }
+
+    #[test]
+    fn esp_header() {
+        let buf = [0, 0, 1, 0, 0, 0, 0, 3, 0xff];
+        let header = EspHeader::parse(&buf).unwrap();
+        assert_eq!((header.spi, header.sequence), (256, 3));
+        assert!(EspHeader::parse(&buf[..7]).is_none());
+    }
+}
@@ -1,122 +1,391 @@
+use super::normalize_addr;
 use crate::{
-    packet::{IpHeader, NetworkPacket, TransportHeader},
-    IpStackError, PacketReceiver, PacketSender, TTL,
+    egress::EgressSender,
+    filter,
+    packet::{flow_label, IcmpErrorKind, IpHeader, NetworkPacket, TransportHeader, UdpLiteHeader},
+    IpStackConfig, IpStackError, PacketReceiver, DROP_TTL,
 };
-use etherparse::{IpNumber, Ipv4Header, Ipv6FlowLabel, Ipv6Header, UdpHeader};
-use std::{future::Future, net::SocketAddr, pin::Pin, time::Duration};
+use bytes::Bytes;
+use etherparse::{
+    IpFragOffset, IpNumber, Ipv4Dscp, Ipv4Ecn, Ipv4Header, Ipv6FlowLabel, Ipv6FragmentHeader,
+    Ipv6Header, UdpHeader,
+};
+use log::trace;
+use std::{cmp, future::Future, net::SocketAddr, pin::Pin, time::Duration};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::mpsc,
-    time::Sleep,
+    sync::watch,
+    time::{Instant, Sleep},
 };
 
+/// Which datagrams of a flow push back its idle timeout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpTimeoutRefresh {
+    /// Only datagrams sent to the device.
+    Outbound,
+    /// Only datagrams received from the device.
+    Inbound,
+    /// Datagrams in either direction.
+    #[default]
+    Both,
+}
+
+/// Counters of a UDP flow, from [`IpStackUdpStream::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpStats {
+    /// Payload bytes of the datagrams sent.
+    pub bytes_sent: u64,
+    /// Payload bytes of the datagrams received.
+    pub bytes_received: u64,
+    /// Datagrams sent, each counted once even if it was fragmented.
+    pub datagrams_sent: u64,
+    pub datagrams_received: u64,
+}
+
 #[derive(Debug)]
 pub struct IpStackUdpStream {
     src_addr: SocketAddr,
     dst_addr: SocketAddr,
-    stream_sender: PacketSender,
     stream_receiver: PacketReceiver,
-    pkt_sender: PacketSender,
-    first_payload: Option<Vec<u8>>,
+    pkt_sender: EgressSender,
+    first_payload: Option<(Bytes, u8)>,
     timeout: Pin<Box<Sleep>>,
     udp_timeout: Duration,
-    mtu: u16,
+    refresh: UdpTimeoutRefresh,
+    send: SendOptions,
+    coverage: Option<u16>, // of the last datagram received, None for plain UDP
+    hostname: Option<String>,
+    target: Option<SocketAddr>, // the destination rewritten by `IpStackConfig::dnat`
+    normalize: bool,            // reports IPv4-mapped addresses as IPv4 ones
+    flow: watch::Sender<Option<Instant>>, // the idle deadline, None once the flow ended
+    origin: Option<Box<NetworkPacket>>, // the opening datagram, quoted by `reject`
+    stats: UdpStats,
 }
 
 impl IpStackUdpStream {
-    pub fn new(
-        src_addr: SocketAddr,
-        dst_addr: SocketAddr,
-        payload: Vec<u8>,
-        pkt_sender: PacketSender,
-        mtu: u16,
-        udp_timeout: Duration,
+    pub(crate) fn new(
+        packet: NetworkPacket,
+        stream_receiver: PacketReceiver,
+        pkt_sender: EgressSender,
+        config: &IpStackConfig,
     ) -> Self {
-        let (stream_sender, stream_receiver) = mpsc::unbounded_channel::<NetworkPacket>();
-        let deadline = tokio::time::Instant::now() + udp_timeout;
+        let deadline = Instant::now() + config.udp_timeout;
+        let (src_addr, dst_addr, tos) = (packet.src_addr(), packet.dst_addr(), packet.tos());
+        let coverage = packet.udp_lite_coverage();
+        let target = filter::rewrite_destination(config, &packet);
+        let origin = config
+            .udp_port_unreachable
+            .then(|| Box::new(packet.clone()));
         IpStackUdpStream {
             src_addr,
             dst_addr,
-            stream_sender,
             stream_receiver,
             pkt_sender,
-            first_payload: Some(payload),
+            first_payload: Some((packet.payload, tos)),
             timeout: Box::pin(tokio::time::sleep_until(deadline)),
-            udp_timeout,
-            mtu,
-        }
-    }
-
-    pub(crate) fn stream_sender(&self) -> PacketSender {
-        self.stream_sender.clone()
-    }
-
-    fn create_rev_packet(&self, ttl: u8, mut payload: Vec<u8>) -> std::io::Result<NetworkPacket> {
-        const UHS: usize = 8; // udp header size is 8
-        match (self.dst_addr.ip(), self.src_addr.ip()) {
-            (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
-                let mut ip_h = Ipv4Header::new(0, ttl, IpNumber::UDP, dst.octets(), src.octets())
-                    .map_err(IpStackError::from)?;
-                let line_buffer = self.mtu.saturating_sub((ip_h.header_len() + UHS) as u16);
-                payload.truncate(line_buffer as usize);
-                ip_h.set_payload_len(payload.len() + UHS)
-                    .map_err(IpStackError::from)?;
-                let udp_header = UdpHeader::with_ipv4_checksum(
-                    self.dst_addr.port(),
-                    self.src_addr.port(),
-                    &ip_h,
-                    &payload,
-                )
-                .map_err(IpStackError::from)?;
-                Ok(NetworkPacket {
-                    ip: IpHeader::Ipv4(ip_h),
-                    transport: TransportHeader::Udp(udp_header),
-                    payload,
-                })
+            udp_timeout: config.udp_timeout,
+            refresh: config.udp_timeout_refresh,
+            send: SendOptions {
+                lite_coverage: coverage.map(|_| 0),
+                tos: tos & !0b11, // the DSCP of the flow, without ECN
+                ..SendOptions::new(config)
+            },
+            coverage,
+            hostname: config
+                .fake_ip_pool
+                .as_ref()
+                .and_then(|pool| pool.lookup(dst_addr.ip())),
+            target,
+            normalize: config.normalize_mapped_addrs,
+            flow: watch::Sender::new(Some(deadline)),
+            origin,
+            stats: UdpStats::default(),
+        }
+    }
+
+    fn create_rev_packet(&self, ttl: u8, payload: Bytes) -> std::io::Result<NetworkPacket> {
+        let opts = SendOptions {
+            tos: 0,
+            ttl,
+            ..self.send
+        };
+        create_packet(self.dst_addr, self.src_addr, &opts, payload)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        normalize_addr(self.src_addr, self.normalize)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        normalize_addr(self.dst_addr, self.normalize)
+    }
+
+    /// Where to forward the flow: the destination given by [`IpStackConfig::with_dnat`],
+    /// otherwise [`peer_addr`](Self::peer_addr).
+    pub fn target_addr(&self) -> SocketAddr {
+        self.target.unwrap_or_else(|| self.peer_addr())
+    }
+
+    /// The host name the peer address was allocated for by
+    /// [`IpStackConfig::fake_ip_pool`], `None` for other addresses.
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// Overrides the idle timeout of this stream, counting from now.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.udp_timeout = timeout;
+        self.reset_timeout();
+    }
+
+    /// Overrides [`IpStackConfig::udp_timeout_refresh`] for this stream.
+    pub fn set_timeout_refresh(&mut self, refresh: UdpTimeoutRefresh) {
+        self.refresh = refresh;
+    }
+
+    /// Ends the flow now. Its tuple is removed from the stack, so the next datagram of the
+    /// peer is accepted as a new stream, and datagrams already received can still be read.
+    pub fn close(&mut self) {
+        if self.flow.send_replace(None).is_none() {
+            return;
+        }
+        self.stream_receiver.close();
+        if let Ok(packet) = self.create_rev_packet(DROP_TTL, Bytes::new()) {
+            let _ = self.pkt_sender.send(packet);
+        }
+    }
+
+    /// Refuses the flow, answering its first datagram with an ICMP port unreachable error
+    /// unless [`IpStackConfig::udp_port_unreachable`] is off, and closes it.
+    pub fn reject(mut self) {
+        if let Some(origin) = self.origin.take() {
+            if !self.pkt_sender.allow_icmp() {
+                trace!("ICMP rate limit reached for {}", self.src_addr);
+                return;
+            }
+            match origin.port_unreachable(self.send.ttl) {
+                Ok(packet) => {
+                    let _ = self.pkt_sender.send(packet);
+                }
+                Err(err) => trace!("port unreachable for {}: {}", self.src_addr, err),
             }
-            (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
-                let mut ip_h = Ipv6Header {
-                    traffic_class: 0,
-                    flow_label: Ipv6FlowLabel::ZERO,
-                    payload_length: 0,
-                    next_header: IpNumber::UDP,
-                    hop_limit: ttl,
-                    source: dst.octets(),
-                    destination: src.octets(),
+        }
+    }
+
+    /// Resolves once the flow ended by [`close`](Self::close), the idle timeout or dropping
+    /// the stream. The future does not borrow the stream and sees the timeout without
+    /// anyone reading.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut flow = self.flow.subscribe();
+        async move {
+            loop {
+                let deadline = *flow.borrow_and_update();
+                let Some(deadline) = deadline else {
+                    return;
                 };
-                let line_buffer = self.mtu.saturating_sub((ip_h.header_len() + UHS) as u16);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => {
+                        // Traffic moves the deadline without a notification
+                        if flow.borrow().is_some_and(|d| d <= Instant::now()) {
+                            return;
+                        }
+                    }
+                    changed = flow.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-                payload.truncate(line_buffer as usize);
+    /// Receives the next datagram whole, unlike reads that cut it at the buffer's end.
+    /// Fails with `UnexpectedEof` once the stack has shut down.
+    pub async fn recv_datagram(&mut self) -> std::io::Result<Bytes> {
+        std::future::poll_fn(|cx| self.poll_recv_datagram(cx)).await
+    }
 
-                ip_h.payload_length = (payload.len() + UHS) as u16;
-                let udp_header = UdpHeader::with_ipv6_checksum(
-                    self.dst_addr.port(),
-                    self.src_addr.port(),
-                    &ip_h,
-                    &payload,
-                )
-                .map_err(IpStackError::from)?;
-                Ok(NetworkPacket {
-                    ip: IpHeader::Ipv6(ip_h),
-                    transport: TransportHeader::Udp(udp_header),
-                    payload,
-                })
-            }
-            _ => unreachable!(),
+    /// Polling variant of [`recv_datagram`](Self::recv_datagram).
+    pub fn poll_recv_datagram(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<Bytes>> {
+        self.poll_recv_datagram_with_tos(cx)
+            .map_ok(|(payload, _)| payload)
+    }
+
+    /// Receives the next datagram with the TOS byte (IPv4) or traffic class (IPv6) it
+    /// arrived with, DSCP in the upper six bits and ECN in the lower two.
+    pub async fn recv_datagram_with_tos(&mut self) -> std::io::Result<(Bytes, u8)> {
+        std::future::poll_fn(|cx| self.poll_recv_datagram_with_tos(cx)).await
+    }
+
+    /// Polling variant of [`recv_datagram_with_tos`](Self::recv_datagram_with_tos).
+    pub fn poll_recv_datagram_with_tos(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<(Bytes, u8)>> {
+        if let Some(first) = self.first_payload.take() {
+            self.count_received(&first.0);
+            return std::task::Poll::Ready(Ok(first));
+        }
+        if self.flow.borrow().is_some()
+            && matches!(self.timeout.as_mut().poll(cx), std::task::Poll::Ready(_))
+        {
+            self.close();
+            return std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::TimedOut)));
+        }
+
+        loop {
+            return match self.stream_receiver.poll_recv(cx) {
+                std::task::Poll::Ready(Some(p)) => {
+                    if let Some(error) = p.icmp_error() {
+                        // The device could not deliver a datagram sent to it, like a
+                        // connected socket the next receive reports the error
+                        match error.kind {
+                            IcmpErrorKind::Unreachable(kind) => {
+                                std::task::Poll::Ready(Err(std::io::Error::from(kind)))
+                            }
+                            IcmpErrorKind::PacketTooBig(_) => continue,
+                        }
+                    } else {
+                        if self.refresh != UdpTimeoutRefresh::Outbound {
+                            self.reset_timeout();
+                        }
+                        let tos = p.tos();
+                        if self.coverage.is_some() {
+                            self.coverage = p.udp_lite_coverage();
+                        }
+                        self.count_received(&p.payload);
+                        std::task::Poll::Ready(Ok((p.payload, tos)))
+                    }
+                }
+                std::task::Poll::Ready(None) => std::task::Poll::Ready(Err(std::io::Error::from(
+                    std::io::ErrorKind::UnexpectedEof,
+                ))),
+                std::task::Poll::Pending => std::task::Poll::Pending,
+            };
         }
     }
 
-    pub fn local_addr(&self) -> SocketAddr {
-        self.src_addr
+    /// Sends `datagram` whole, fragmented if it exceeds the MTU. Fails with `EMSGSIZE` if it
+    /// exceeds the largest IP packet, or the MTU while
+    /// [`IpStackConfig::udp_fragmentation`] is off. Writes do the same for their buffer.
+    pub fn send_datagram(&mut self, datagram: Bytes) -> std::io::Result<()> {
+        if self.flow.borrow().is_none() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotConnected));
+        }
+        self.refresh_outbound();
+        let len = datagram.len() as u64;
+        send_datagram(
+            &self.pkt_sender,
+            self.dst_addr,
+            self.src_addr,
+            &self.send,
+            datagram,
+        )?;
+        self.stats.bytes_sent += len;
+        self.stats.datagrams_sent += 1;
+        Ok(())
     }
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.dst_addr
+    /// Counters of the datagrams sent and received.
+    pub fn stats(&self) -> UdpStats {
+        self.stats
+    }
+
+    fn count_received(&mut self, payload: &[u8]) {
+        self.stats.bytes_received += payload.len() as u64;
+        self.stats.datagrams_received += 1;
+    }
+
+    /// Sets the TOS byte (IPv4) or traffic class (IPv6) of the datagrams sent from now on,
+    /// for example an ECT codepoint in the lower two bits for QUIC's ECN.
+    pub fn set_tos(&mut self, tos: u8) {
+        self.send.tos = tos;
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.send.tos
+    }
+
+    /// Overrides [`IpStackConfig::ttl`] for the datagrams sent from now on.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.send.ttl = ttl.max(1);
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.send.ttl
+    }
+
+    /// Sets the DSCP (the upper six bits of the TOS byte) of the datagrams sent from now on,
+    /// keeping their ECN codepoint. Streams start with the DSCP of their first datagram.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.send.tos = (dscp & 0x3f) << 2 | self.send.tos & 0b11;
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.send.tos >> 2
+    }
+
+    /// Whether the flow is UDP-Lite (RFC 3828) rather than UDP.
+    pub fn is_udp_lite(&self) -> bool {
+        self.coverage.is_some()
+    }
+
+    /// The checksum coverage of the last UDP-Lite datagram received, the bytes from the
+    /// start of its header the checksum protects, with 0 for the whole datagram. `None`
+    /// for UDP.
+    pub fn checksum_coverage(&self) -> Option<u16> {
+        self.coverage
+    }
+
+    /// Sets the checksum coverage of the UDP-Lite datagrams sent from now on, 0 covers
+    /// them whole (the default) and a coverage inside the header is raised to it. Has no
+    /// effect on UDP.
+    pub fn set_checksum_coverage(&mut self, coverage: u16) {
+        if let Some(lite_coverage) = &mut self.send.lite_coverage {
+            *lite_coverage = match coverage {
+                0 => 0,
+                c => cmp::max(c, UdpLiteHeader::LEN as u16),
+            };
+        }
+    }
+
+    /// Sends `payload` as datagrams of `segment_size` bytes, the last one may be shorter,
+    /// like a write with UDP GSO. Fails with `InvalidInput` for a zero `segment_size`,
+    /// segments are otherwise sent as by [`send_datagram`](Self::send_datagram).
+    pub fn send_segmented(&mut self, payload: Bytes, segment_size: usize) -> std::io::Result<()> {
+        if segment_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "segment size is zero",
+            ));
+        }
+        // All segments but the last have the same size, only the first can be refused
+        for offset in (0..payload.len()).step_by(segment_size) {
+            let end = cmp::min(offset + segment_size, payload.len());
+            self.send_datagram(payload.slice(offset..end))?;
+        }
+        Ok(())
+    }
+
+    fn refresh_outbound(&mut self) {
+        if self.refresh != UdpTimeoutRefresh::Inbound {
+            self.reset_timeout();
+        }
     }
 
     fn reset_timeout(&mut self) {
-        let deadline = tokio::time::Instant::now() + self.udp_timeout;
+        let deadline = Instant::now() + self.udp_timeout;
         self.timeout.as_mut().reset(deadline);
+        self.flow.send_if_modified(|flow| {
+            if let Some(d) = flow {
+                *d = deadline;
+            }
+            false
+        });
     }
 }
 
@@ -126,23 +395,15 @@ impl AsyncRead for IpStackUdpStream {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        if let Some(p) = self.first_payload.take() {
-            buf.put_slice(&p);
-            return std::task::Poll::Ready(Ok(()));
-        }
-        if matches!(self.timeout.as_mut().poll(cx), std::task::Poll::Ready(_)) {
-            return std::task::Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::TimedOut)));
-        }
-
-        self.reset_timeout();
-
-        match self.stream_receiver.poll_recv(cx) {
-            std::task::Poll::Ready(Some(p)) => {
-                buf.put_slice(&p.payload);
+        match self.poll_recv_datagram(cx) {
+            std::task::Poll::Ready(Ok(p)) => {
+                buf.put_slice(&p);
+                std::task::Poll::Ready(Ok(()))
+            }
+            std::task::Poll::Ready(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
                 std::task::Poll::Ready(Ok(()))
             }
-            std::task::Poll::Ready(None) => std::task::Poll::Ready(Ok(())),
-            std::task::Poll::Pending => std::task::Poll::Pending,
+            poll => poll.map_ok(|_| ()),
         }
     }
 }
@@ -153,13 +414,8 @@ impl AsyncWrite for IpStackUdpStream {
         _cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
-        self.reset_timeout();
-        let packet = self.create_rev_packet(TTL, buf.to_vec())?;
-        let payload_len = packet.payload.len();
-        self.pkt_sender
-            .send(packet)
-            .or(Err(std::io::ErrorKind::UnexpectedEof))?;
-        std::task::Poll::Ready(Ok(payload_len))
+        self.send_datagram(Bytes::copy_from_slice(buf))?;
+        std::task::Poll::Ready(Ok(buf.len()))
     }
 
     fn poll_flush(
@@ -176,3 +432,223 @@ impl AsyncWrite for IpStackUdpStream {
         std::task::Poll::Ready(Ok(()))
     }
 }
+
+impl Drop for IpStackUdpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// How datagrams of a stream or the stack are sent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SendOptions {
+    pub fragment: bool,
+    pub tos: u8,
+    pub ttl: u8,
+    pub flow_label: bool,
+    pub lite_coverage: Option<u16>, // UDP-Lite with this checksum coverage, None for UDP
+}
+
+impl SendOptions {
+    pub(crate) fn new(config: &IpStackConfig) -> Self {
+        SendOptions {
+            fragment: config.udp_fragmentation,
+            tos: 0,
+            ttl: config.ttl,
+            flow_label: config.ipv6_flow_label,
+            lite_coverage: None,
+        }
+    }
+}
+
+/// Builds a datagram from `src` to `dst`, failing with `EMSGSIZE` if it exceeds the largest
+/// IP packet.
+fn create_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    opts: &SendOptions,
+    payload: Bytes,
+) -> std::io::Result<NetworkPacket> {
+    let (tos, ttl) = (opts.tos, opts.ttl);
+    let protocol = match opts.lite_coverage {
+        Some(_) => IpNumber::UDP_LITE,
+        None => IpNumber::UDP,
+    };
+    let ip = match (src.ip(), dst.ip()) {
+        (std::net::IpAddr::V4(src_ip), std::net::IpAddr::V4(dst_ip)) => {
+            let mut ip_h = Ipv4Header::new(0, ttl, protocol, src_ip.octets(), dst_ip.octets())
+                .map_err(IpStackError::from)?;
+            ip_h.dscp = Ipv4Dscp::try_new(tos >> 2).unwrap_or(Ipv4Dscp::ZERO);
+            ip_h.ecn = Ipv4Ecn::try_new(tos & 0b11).unwrap_or(Ipv4Ecn::ZERO);
+            ip_h.set_payload_len(payload.len() + UdpHeader::LEN)
+                .map_err(|_| message_too_long())?;
+            IpHeader::Ipv4(ip_h)
+        }
+        (std::net::IpAddr::V6(src_ip), std::net::IpAddr::V6(dst_ip)) => {
+            let payload_length =
+                u16::try_from(payload.len() + UdpHeader::LEN).map_err(|_| message_too_long())?;
+            IpHeader::Ipv6(Ipv6Header {
+                traffic_class: tos,
+                flow_label: match opts.flow_label {
+                    true => flow_label(src, dst, protocol),
+                    false => Ipv6FlowLabel::ZERO,
+                },
+                payload_length,
+                next_header: protocol,
+                hop_limit: ttl,
+                source: src_ip.octets(),
+                destination: dst_ip.octets(),
+            })
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "source and destination of different IP versions",
+            ))
+        }
+    };
+    let transport = match (&ip, opts.lite_coverage) {
+        (_, Some(coverage)) => {
+            let len = UdpLiteHeader::LEN + payload.len();
+            let mut udp_header = UdpLiteHeader {
+                source_port: src.port(),
+                destination_port: dst.port(),
+                // A coverage beyond the datagram is not allowed, it is covered whole then
+                coverage: if coverage as usize > len { 0 } else { coverage },
+                checksum: 0,
+            };
+            udp_header.checksum = udp_header.calc_checksum(&ip, &payload);
+            TransportHeader::UdpLite(udp_header)
+        }
+        (IpHeader::Ipv4(ip_h), None) => TransportHeader::Udp(
+            UdpHeader::with_ipv4_checksum(src.port(), dst.port(), ip_h, &payload)
+                .map_err(|_| message_too_long())?,
+        ),
+        (IpHeader::Ipv6(ip_h), None) => TransportHeader::Udp(
+            UdpHeader::with_ipv6_checksum(src.port(), dst.port(), ip_h, &payload)
+                .map_err(|_| message_too_long())?,
+        ),
+    };
+    Ok(NetworkPacket {
+        ip,
+        transport,
+        payload,
+    })
+}
+
+/// Splits a datagram larger than `mtu` into IP fragments, the UDP header travels in the
+/// first (RFC 791 2.3, RFC 8200 4.5). A packet of an unknown transport is split as is.
+pub(crate) fn fragment(
+    packet: NetworkPacket,
+    mtu: u16,
+) -> Result<Vec<NetworkPacket>, IpStackError> {
+    let mut data = Vec::with_capacity(UdpHeader::LEN + packet.payload.len());
+    match packet.transport {
+        TransportHeader::Udp(udp_header) => data.extend_from_slice(&udp_header.to_bytes()),
+        TransportHeader::UdpLite(udp_header) => data.extend_from_slice(&udp_header.to_bytes()),
+        TransportHeader::Unknown => {}
+        _ => return Ok(vec![packet]),
+    }
+    data.extend_from_slice(&packet.payload);
+    let data = Bytes::from(data);
+    let identification: u32 = rand::random();
+    let header_len = match &packet.ip {
+        IpHeader::Ipv4(ip_h) => ip_h.header_len(),
+        IpHeader::Ipv6(_) => Ipv6Header::LEN + Ipv6FragmentHeader::LEN,
+    };
+    // Every fragment but the last carries a multiple of eight bytes
+    let size = cmp::max((mtu as usize).saturating_sub(header_len) & !7, 8);
+    (0..data.len())
+        .step_by(size)
+        .map(|offset| {
+            let chunk = data.slice(offset..cmp::min(offset + size, data.len()));
+            let more_fragments = offset + chunk.len() < data.len();
+            let fragment_offset = IpFragOffset::try_new((offset / 8) as u16)?;
+            match &packet.ip {
+                IpHeader::Ipv4(ip_h) => {
+                    let mut ip_h = ip_h.clone();
+                    ip_h.identification = identification as u16;
+                    ip_h.dont_fragment = false;
+                    ip_h.more_fragments = more_fragments;
+                    ip_h.fragment_offset = fragment_offset;
+                    ip_h.set_payload_len(chunk.len())?;
+                    Ok(NetworkPacket {
+                        ip: IpHeader::Ipv4(ip_h),
+                        transport: TransportHeader::Unknown,
+                        payload: chunk,
+                    })
+                }
+                IpHeader::Ipv6(ip_h) => {
+                    let frag_h = Ipv6FragmentHeader::new(
+                        ip_h.next_header,
+                        fragment_offset,
+                        more_fragments,
+                        identification,
+                    );
+                    let mut ip_h = ip_h.clone();
+                    ip_h.next_header = IpNumber::IPV6_FRAGMENTATION_HEADER;
+                    ip_h.payload_length = (Ipv6FragmentHeader::LEN + chunk.len()) as u16;
+                    let mut payload = Vec::with_capacity(Ipv6FragmentHeader::LEN + chunk.len());
+                    payload.extend_from_slice(&frag_h.to_bytes());
+                    payload.extend_from_slice(&chunk);
+                    Ok(NetworkPacket {
+                        ip: IpHeader::Ipv6(ip_h),
+                        transport: TransportHeader::Unknown,
+                        payload: payload.into(),
+                    })
+                }
+            }
+        })
+        .collect()
+}
+
+fn packet_len(packet: &NetworkPacket) -> usize {
+    let header_len = match &packet.ip {
+        IpHeader::Ipv4(ip_h) => ip_h.header_len(),
+        IpHeader::Ipv6(_) => Ipv6Header::LEN,
+    };
+    header_len + UdpHeader::LEN + packet.payload.len()
+}
+
+/// The error of a socket refusing a datagram that is too large.
+#[cfg(unix)]
+pub(crate) fn message_too_long() -> std::io::Error {
+    std::io::Error::from_raw_os_error(libc::EMSGSIZE)
+}
+
+#[cfg(windows)]
+pub(crate) fn message_too_long() -> std::io::Error {
+    const WSAEMSGSIZE: i32 = 10040;
+    std::io::Error::from_raw_os_error(WSAEMSGSIZE)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn message_too_long() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, "message too long")
+}
+
+/// Queues `datagram` from `src` to `dst` for the device, fragmented if it exceeds the MTU.
+/// Without fragmentation such a datagram fails with `EMSGSIZE`.
+pub(crate) fn send_datagram(
+    pkt_sender: &EgressSender,
+    src: SocketAddr,
+    dst: SocketAddr,
+    opts: &SendOptions,
+    datagram: Bytes,
+) -> std::io::Result<()> {
+    let packet = create_packet(src, dst, opts, datagram)?;
+    let mtu = pkt_sender.mtu();
+    let packets = if packet_len(&packet) <= mtu as usize {
+        vec![packet]
+    } else if opts.fragment {
+        self::fragment(packet, mtu)?
+    } else {
+        return Err(message_too_long());
+    };
+    for packet in packets {
+        pkt_sender
+            .send(packet)
+            .or(Err(std::io::ErrorKind::UnexpectedEof))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use super::udp::{send_datagram, SendOptions};
+use crate::{
+    egress::EgressSender, packet::NetworkPacket, IpStackConfig, PacketReceiver, PacketSender,
+};
+use bytes::Bytes;
+use std::{
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// How UDP datagrams from the device are handed to the application.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpMode {
+    /// An [`IpStackUdpStream`](super::IpStackUdpStream) is accepted for every 4-tuple.
+    #[default]
+    PerFlow,
+    /// All datagrams arrive on the one [`IpStackUdpSocket`] taken from
+    /// [`IpStack::udp_socket`](crate::IpStack::udp_socket), no streams are created.
+    Single,
+}
+
+/// What happens to UDP datagrams sent to a broadcast or multicast address, which no peer
+/// answers as a flow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpBroadcastPolicy {
+    /// They are handled like any other datagram.
+    #[default]
+    Stream,
+    /// They are dropped.
+    Drop,
+    /// Each is accepted as an [`IpStackStream::UdpBroadcast`](super::IpStackStream::UdpBroadcast).
+    Deliver,
+    /// They arrive on the [`IpStackUdpSocket`], which the stack then offers under
+    /// [`UdpMode::PerFlow`] too.
+    Socket,
+}
+
+/// A UDP datagram from the device to a broadcast or multicast address, accepted under
+/// [`UdpBroadcastPolicy::Deliver`].
+#[derive(Debug)]
+pub struct IpStackUdpBroadcast {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    payload: Bytes,
+}
+
+impl IpStackUdpBroadcast {
+    pub(crate) fn new(packet: NetworkPacket) -> Self {
+        IpStackUdpBroadcast {
+            src_addr: packet.src_addr(),
+            dst_addr: packet.dst_addr(),
+            payload: packet.payload,
+        }
+    }
+
+    /// The sender on the device side.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.src_addr
+    }
+
+    /// The broadcast or multicast address with the destination port.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.dst_addr
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+}
+
+/// Receives every UDP datagram of the stack with its original addresses, and sends
+/// datagrams from any address.
+#[derive(Debug)]
+pub struct IpStackUdpSocket {
+    receiver: PacketReceiver,
+    pkt_sender: EgressSender,
+    send: SendOptions,
+}
+
+impl IpStackUdpSocket {
+    pub(crate) fn new(pkt_sender: EgressSender, config: &IpStackConfig) -> (Self, PacketSender) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let socket = IpStackUdpSocket {
+            receiver,
+            pkt_sender,
+            send: SendOptions::new(config),
+        };
+        (socket, sender)
+    }
+
+    /// Receives the next datagram with its source on the device side and the destination
+    /// it was sent to. Fails with `UnexpectedEof` once the stack has shut down.
+    pub async fn recv_from(&mut self) -> std::io::Result<(Bytes, SocketAddr, SocketAddr)> {
+        std::future::poll_fn(|cx| self.poll_recv_from(cx)).await
+    }
+
+    /// Polling variant of [`recv_from`](Self::recv_from).
+    pub fn poll_recv_from(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<(Bytes, SocketAddr, SocketAddr)>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(p)) => {
+                let (src, dst) = (p.src_addr(), p.dst_addr());
+                Poll::Ready(Ok((p.payload, src, dst)))
+            }
+            Poll::Ready(None) => {
+                Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Sends `datagram` from `src` to `dst` on the device, a reply swaps the addresses of
+    /// the datagram it answers. Fails with `InvalidInput` if the addresses are of different
+    /// IP versions, large datagrams are handled as by
+    /// [`IpStackUdpStream::send_datagram`](super::IpStackUdpStream::send_datagram).
+    pub fn send_to(
+        &self,
+        datagram: Bytes,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> std::io::Result<()> {
+        send_datagram(&self.pkt_sender, src, dst, &self.send, datagram)
+    }
+}
@@ -1,53 +1,70 @@
 use crate::{
+    egress::EgressSender,
     packet::{IpHeader, NetworkPacket, TransportHeader},
-    PacketSender, TTL,
 };
-use etherparse::{IpNumber, Ipv4Header, Ipv6FlowLabel, Ipv6Header};
+use bytes::Bytes;
+use etherparse::{IpNumber, Ipv4Header, Ipv4Options, Ipv6FlowLabel, Ipv6Header};
 use std::{io::Error, mem, net::IpAddr};
 
+/// An IP packet of a protocol the stack does not terminate, like GRE, ESP or OSPF, parsed
+/// so it can be routed by its protocol and addresses without looking at the raw bytes.
 pub struct IpStackUnknownTransport {
     src_addr: IpAddr,
     dst_addr: IpAddr,
-    payload: Vec<u8>,
+    payload: Bytes,
     protocol: IpNumber,
+    ip_options: Ipv4Options,
     mtu: u16,
-    packet_sender: PacketSender,
+    ttl: u8,
+    packet_sender: EgressSender,
 }
 
 impl IpStackUnknownTransport {
     pub(crate) fn new(
         src_addr: IpAddr,
         dst_addr: IpAddr,
-        payload: Vec<u8>,
+        payload: Bytes,
         ip: &IpHeader,
         mtu: u16,
-        packet_sender: PacketSender,
+        ttl: u8,
+        packet_sender: EgressSender,
     ) -> Self {
-        let protocol = match ip {
-            IpHeader::Ipv4(ip) => ip.protocol,
-            IpHeader::Ipv6(ip) => ip.next_header,
+        let (protocol, ip_options) = match ip {
+            IpHeader::Ipv4(ip) => (ip.protocol, ip.options.clone()),
+            IpHeader::Ipv6(ip) => (ip.next_header, Ipv4Options::default()),
         };
         IpStackUnknownTransport {
             src_addr,
             dst_addr,
             payload,
             protocol,
+            ip_options,
             mtu,
+            ttl,
             packet_sender,
         }
     }
+    fn mtu(&self) -> u16 {
+        std::cmp::min(self.mtu, self.packet_sender.mtu())
+    }
     pub fn src_addr(&self) -> IpAddr {
         self.src_addr
     }
     pub fn dst_addr(&self) -> IpAddr {
         self.dst_addr
     }
+    /// The IP payload, after the IPv4 header or the fixed IPv6 header.
     pub fn payload(&self) -> &[u8] {
         &self.payload
     }
+    /// The protocol of the payload, for IPv6 the next header of the fixed header.
     pub fn ip_protocol(&self) -> IpNumber {
         self.protocol
     }
+    /// The IPv4 options of the packet, like record route or timestamp, empty for IPv6.
+    pub fn ip_options(&self) -> &[u8] {
+        self.ip_options.as_slice()
+    }
     pub fn send(&self, mut payload: Vec<u8>) -> Result<(), Error> {
         loop {
             let packet = self.create_rev_packet(&mut payload)?;
@@ -63,9 +80,10 @@ impl IpStackUnknownTransport {
     pub fn create_rev_packet(&self, payload: &mut Vec<u8>) -> Result<NetworkPacket, Error> {
         match (self.dst_addr, self.src_addr) {
             (std::net::IpAddr::V4(dst), std::net::IpAddr::V4(src)) => {
-                let mut ip_h = Ipv4Header::new(0, TTL, self.protocol, dst.octets(), src.octets())
-                    .map_err(crate::IpStackError::from)?;
-                let line_buffer = self.mtu.saturating_sub(ip_h.header_len() as u16);
+                let mut ip_h =
+                    Ipv4Header::new(0, self.ttl, self.protocol, dst.octets(), src.octets())
+                        .map_err(crate::IpStackError::from)?;
+                let line_buffer = self.mtu().saturating_sub(ip_h.header_len() as u16);
 
                 let p = if payload.len() > line_buffer as usize {
                     payload.drain(0..line_buffer as usize).collect::<Vec<u8>>()
@@ -77,7 +95,7 @@ impl IpStackUnknownTransport {
                 Ok(NetworkPacket {
                     ip: IpHeader::Ipv4(ip_h),
                     transport: TransportHeader::Unknown,
-                    payload: p,
+                    payload: p.into(),
                 })
             }
             (std::net::IpAddr::V6(dst), std::net::IpAddr::V6(src)) => {
@@ -85,23 +103,22 @@ impl IpStackUnknownTransport {
                     traffic_class: 0,
                     flow_label: Ipv6FlowLabel::ZERO,
                     payload_length: 0,
-                    next_header: IpNumber::UDP,
-                    hop_limit: TTL,
+                    next_header: self.protocol,
+                    hop_limit: self.ttl,
                     source: dst.octets(),
                     destination: src.octets(),
                 };
-                let line_buffer = self.mtu.saturating_sub(ip_h.header_len() as u16);
-                payload.truncate(line_buffer as usize);
-                ip_h.payload_length = payload.len() as u16;
+                let line_buffer = self.mtu().saturating_sub(ip_h.header_len() as u16);
                 let p = if payload.len() > line_buffer as usize {
                     payload.drain(0..line_buffer as usize).collect::<Vec<u8>>()
                 } else {
                     mem::take(payload)
                 };
+                ip_h.payload_length = p.len() as u16;
                 Ok(NetworkPacket {
                     ip: IpHeader::Ipv6(ip_h),
                     transport: TransportHeader::Unknown,
-                    payload: p,
+                    payload: p.into(),
                 })
             }
             _ => unreachable!(),
@@ -0,0 +1,285 @@
+//! A [`PacketDevice`] driving a TUN file descriptor through io_uring. Enabled by the
+//! `uring` feature, on Linux.
+
+use crate::PacketDevice;
+use io_uring::{opcode, types, IoUring};
+use std::{
+    collections::VecDeque,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+use tokio::io::unix::AsyncFd;
+
+/// Reads kept in flight, and as many buffers for writes.
+const DEPTH: usize = 32;
+/// Fits any packet a TUN device returns, with packet information and a virtio-net header.
+const BUFFER_SIZE: usize = u16::MAX as usize + 1 + 4 + crate::vnet::HEADER_LEN;
+const CANCEL: u64 = u64::MAX;
+
+/// A [`PacketDevice`] over the file descriptor of a TUN device, reading and writing it
+/// through an io_uring instead of a syscall per packet.
+///
+/// Its buffers are registered with the ring, so the kernel copies packets straight into and
+/// out of them, and 32 reads are kept in flight, resubmitted as the stack takes
+/// their packets. A batch of packets is written with a single `io_uring_enter`. Reads are
+/// not multishot, as multishot reads only take provided buffers and no registered ones.
+///
+/// The ring completes on the runtime of the stack: its completions signal an eventfd the
+/// runtime polls, so no thread is spawned.
+pub struct UringDevice {
+    // Declared before `buffers`, as the kernel writes into them until the ring is gone
+    ring: IoUring,
+    fd: OwnedFd,
+    event: AsyncFd<OwnedFd>,
+    /// The read buffers followed by the write buffers, registered with the ring.
+    buffers: Box<[u8]>,
+    /// Read buffers holding a packet, with its length, in the order they were read.
+    ready: VecDeque<(usize, usize)>,
+    /// Write buffers not in use.
+    free: Vec<usize>,
+    in_flight: usize,
+    /// The error of a completed write, returned by the next call.
+    error: Option<io::Error>,
+}
+
+impl std::fmt::Debug for UringDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringDevice")
+            .field("fd", &self.fd)
+            .field("ready", &self.ready.len())
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+impl UringDevice {
+    /// Takes over `fd`, the file descriptor of a TUN device. Has to be called within a tokio
+    /// runtime with IO enabled.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        let ring = IoUring::new(4 * DEPTH as u32)?;
+        let event = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let event = unsafe { OwnedFd::from_raw_fd(event) };
+        ring.submitter().register_eventfd(event.as_raw_fd())?;
+        let mut buffers = vec![0u8; 2 * DEPTH * BUFFER_SIZE].into_boxed_slice();
+        let iovecs: Vec<libc::iovec> = buffers
+            .chunks_exact_mut(BUFFER_SIZE)
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: BUFFER_SIZE,
+            })
+            .collect();
+        // The buffers stay allocated until the ring is dropped
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+        let mut device = UringDevice {
+            ring,
+            fd,
+            event: AsyncFd::new(event)?,
+            buffers,
+            ready: VecDeque::new(),
+            free: (DEPTH..2 * DEPTH).collect(),
+            in_flight: 0,
+            error: None,
+        };
+        for index in 0..DEPTH {
+            device.push_read(index)?;
+        }
+        device.ring.submit()?;
+        Ok(device)
+    }
+
+    fn push_read(&mut self, index: usize) -> io::Result<()> {
+        let buffer = self.buffers[index * BUFFER_SIZE..].as_mut_ptr();
+        let read = opcode::ReadFixed::new(
+            types::Fd(self.fd.as_raw_fd()),
+            buffer,
+            BUFFER_SIZE as u32,
+            index as u16,
+        )
+        .build()
+        .user_data(index as u64);
+        self.push(&read)
+    }
+
+    fn push(&mut self, entry: &io_uring::squeue::Entry) -> io::Result<()> {
+        // The queue holds all reads and writes, it is only full if the ring was not entered
+        while unsafe { self.ring.submission().push(entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Handles the completions, noting the packets read and the buffers written.
+    fn reap(&mut self) -> io::Result<()> {
+        let completions: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect();
+        for (user_data, result) in completions {
+            if user_data == CANCEL {
+                continue;
+            }
+            self.in_flight -= 1;
+            let index = user_data as usize;
+            if index >= DEPTH {
+                self.free.push(index);
+                if result < 0 {
+                    self.error
+                        .get_or_insert(io::Error::from_raw_os_error(-result));
+                }
+            } else if result > 0 {
+                self.ready.push_back((index, result as usize));
+            } else if result == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the device was closed",
+                ));
+            } else if -result == libc::EAGAIN || -result == libc::EINTR {
+                self.push_read(index)?;
+            } else {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+        }
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the ring to signal completions.
+    async fn completed(&mut self) -> io::Result<()> {
+        let mut guard = self.event.readable().await?;
+        let mut count = 0u64;
+        let n = unsafe {
+            libc::read(
+                self.event.get_ref().as_raw_fd(),
+                (&mut count as *mut u64).cast(),
+                8,
+            )
+        };
+        if n < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error);
+            }
+            guard.clear_ready();
+        }
+        Ok(())
+    }
+
+    /// Waits for a packet to be read. Cancel safe, as read packets stay in `ready`.
+    async fn readable(&mut self) -> io::Result<()> {
+        loop {
+            self.reap()?;
+            if !self.ready.is_empty() {
+                return Ok(());
+            }
+            // Completions not reaped yet do not signal the eventfd again
+            if self.ring.completion().is_empty() {
+                self.completed().await?;
+            }
+        }
+    }
+
+    /// Copies the first packet read into `buf` and reads into its buffer again, once the
+    /// ring is entered.
+    fn take(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((index, len)) = self.ready.pop_front() else {
+            return Ok(0);
+        };
+        let start = index * BUFFER_SIZE;
+        let len = len.min(buf.len());
+        buf[..len].copy_from_slice(&self.buffers[start..start + len]);
+        self.push_read(index)?;
+        Ok(len)
+    }
+
+    /// Copies `packet` into a write buffer and queues writing it, once the ring is entered.
+    async fn write(&mut self, packet: &[u8]) -> io::Result<()> {
+        let index = loop {
+            if let Some(index) = self.free.pop() {
+                break index;
+            }
+            // Write the packets queued so far to free their buffers
+            self.ring.submit()?;
+            self.reap()?;
+            if self.free.is_empty() && self.ring.completion().is_empty() {
+                self.completed().await?;
+            }
+        };
+        let len = packet.len().min(BUFFER_SIZE);
+        let start = index * BUFFER_SIZE;
+        self.buffers[start..start + len].copy_from_slice(&packet[..len]);
+        let write = opcode::WriteFixed::new(
+            types::Fd(self.fd.as_raw_fd()),
+            self.buffers[start..].as_ptr(),
+            len as u32,
+            index as u16,
+        )
+        .build()
+        .user_data(index as u64);
+        self.push(&write)
+    }
+}
+
+impl PacketDevice for UringDevice {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.readable().await?;
+        let len = self.take(buf)?;
+        self.ring.submit()?;
+        Ok(len)
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.write(packet).await?;
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    async fn recv_many(&mut self, bufs: &mut [Vec<u8>], lens: &mut [usize]) -> io::Result<usize> {
+        self.readable().await?;
+        let mut count = 0;
+        while count < bufs.len() && !self.ready.is_empty() {
+            lens[count] = self.take(&mut bufs[count])?;
+            count += 1;
+        }
+        self.ring.submit()?;
+        Ok(count)
+    }
+
+    async fn send_many(&mut self, packets: &[Vec<u8>]) -> io::Result<()> {
+        for packet in packets {
+            self.write(packet).await?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+}
+
+impl Drop for UringDevice {
+    fn drop(&mut self) {
+        // The kernel may still write into the buffers for reads in flight, wait them out
+        for index in 0..DEPTH {
+            let cancel = opcode::AsyncCancel::new(index as u64)
+                .build()
+                .user_data(CANCEL);
+            if unsafe { self.ring.submission().push(&cancel) }.is_err() {
+                let _ = self.ring.submit();
+            }
+        }
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                break;
+            }
+            for entry in self.ring.completion() {
+                if entry.user_data() != CANCEL {
+                    self.in_flight -= 1;
+                }
+            }
+        }
+    }
+}
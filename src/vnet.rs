@@ -0,0 +1,480 @@
+use log::trace;
+use std::borrow::Cow;
+
+/// The length of `struct virtio_net_hdr`, which a TUN device opened with `IFF_VNET_HDR`
+/// puts before every packet, after the packet information.
+pub(crate) const HEADER_LEN: usize = 10;
+
+const F_NEEDS_CSUM: u8 = 1;
+const GSO_NONE: u8 = 0;
+const GSO_TCPV4: u8 = 1;
+const GSO_TCPV6: u8 = 4;
+const GSO_UDP_L4: u8 = 5;
+const GSO_ECN: u8 = 0x80;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_URG: u8 = 0x20;
+const TCP_CWR: u8 = 0x80;
+
+/// The virtio-net header, in the little-endian byte order of virtio 1.0 that the kernel
+/// uses on little-endian hosts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl Header {
+    fn parse(buf: &[u8; HEADER_LEN]) -> Self {
+        let field = |at: usize| u16::from_le_bytes([buf[at], buf[at + 1]]);
+        Header {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: field(2),
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+        buf
+    }
+}
+
+/// The packets read from the device as one, see [`segments`].
+pub(crate) enum Segments<'a> {
+    One(Option<Cow<'a, [u8]>>),
+    Many(std::vec::IntoIter<Vec<u8>>),
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Segments::One(packet) => packet.take(),
+            Segments::Many(segments) => segments.next().map(Cow::Owned),
+        }
+    }
+}
+
+/// The packets in `data` read from the device, only `data` itself without a virtio-net
+/// header. With one, a TCP or UDP super-segment the kernel coalesced is split into its
+/// segments, and a checksum the kernel left to us is completed.
+pub(crate) fn segments(data: &[u8], vnet_hdr: bool) -> Segments<'_> {
+    if !vnet_hdr {
+        return Segments::One(Some(Cow::Borrowed(data)));
+    }
+    let Some((header, packet)) = data.split_first_chunk::<HEADER_LEN>() else {
+        return Segments::Many(Vec::new().into_iter());
+    };
+    let header = Header::parse(header);
+    let segments = match header.gso_type & !GSO_ECN {
+        GSO_NONE if header.flags & F_NEEDS_CSUM == 0 => {
+            return Segments::One(Some(Cow::Borrowed(packet)));
+        }
+        GSO_NONE => {
+            let start = header.csum_start as usize;
+            complete_checksum(packet, start, start + header.csum_offset as usize)
+                .map(|packet| vec![packet])
+        }
+        GSO_TCPV4 | GSO_TCPV6 | GSO_UDP_L4 => split(packet, header.gso_size as usize),
+        _ => None,
+    };
+    if segments.is_none() {
+        trace!(
+            "dropping a malformed packet with GSO type {}",
+            header.gso_type
+        );
+    }
+    Segments::Many(segments.unwrap_or_default().into_iter())
+}
+
+/// Prepends a virtio-net header asking for no offloads to the packet in `frame`, after the
+/// `offset` bytes of its packet information.
+pub(crate) fn prepend(frame: &mut Vec<u8>, offset: usize) {
+    frame.splice(offset..offset, Header::default().to_bytes());
+}
+
+/// Prepends virtio-net headers to the packets in `frames`, after the `offset` bytes of their
+/// packet information. Consecutive TCP segments of one flow are coalesced into one frame
+/// the kernel segments again, saving it the work for each of them.
+pub(crate) fn encode(frames: Vec<Vec<u8>>, offset: usize) -> Vec<Vec<u8>> {
+    let mut encoded = Vec::with_capacity(frames.len());
+    let mut run: Option<Run> = None;
+    for frame in frames {
+        if let Some(run) = &mut run {
+            if run.append(&frame, offset) {
+                continue;
+            }
+        }
+        if let Some(run) = run.take() {
+            encoded.push(run.finish(offset));
+        }
+        match Run::start(frame, offset) {
+            Ok(started) => run = Some(started),
+            Err(mut frame) => {
+                prepend(&mut frame, offset);
+                encoded.push(frame);
+            }
+        }
+    }
+    encoded.extend(run.map(|run| run.finish(offset)));
+    encoded
+}
+
+/// TCP segments of one flow coalesced into the frame of the first.
+struct Run {
+    frame: Vec<u8>,
+    ip_len: usize,
+    tcp_len: usize,
+    segment_len: usize,
+    segments: usize,
+    next_seq: u32,
+    closed: bool,
+}
+
+impl Run {
+    /// A run of the TCP segment in `frame`, or `frame` back if it carries no data or is
+    /// otherwise not one to coalesce.
+    fn start(frame: Vec<u8>, offset: usize) -> Result<Self, Vec<u8>> {
+        let packet = &frame[offset..];
+        let Some((ip_len, TCP)) = ip_header(packet) else {
+            return Err(frame);
+        };
+        // A fragment or a packet with the DF bit cleared is left alone
+        if packet[0] >> 4 == 4 && u16::from_be_bytes([packet[6], packet[7]]) != 0x4000 {
+            return Err(frame);
+        }
+        let Some(tcp_len) = tcp_header_len(packet, ip_len) else {
+            return Err(frame);
+        };
+        let segment_len = packet.len() - ip_len - tcp_len;
+        let flags = packet[ip_len + 13];
+        if segment_len == 0 || flags & (TCP_SYN | TCP_RST | TCP_URG) != 0 {
+            return Err(frame);
+        }
+        let seq = read_u32(packet, ip_len + 4);
+        Ok(Run {
+            ip_len,
+            tcp_len,
+            segment_len,
+            segments: 1,
+            next_seq: seq.wrapping_add(segment_len as u32),
+            closed: flags & (TCP_FIN | TCP_PSH) != 0,
+            frame,
+        })
+    }
+
+    /// Appends the payload of the TCP segment in `frame` if it continues the run.
+    fn append(&mut self, frame: &[u8], offset: usize) -> bool {
+        if self.closed || frame.len() < offset || frame[..offset] != self.frame[..offset] {
+            return false;
+        }
+        let (packet, first) = (&frame[offset..], &self.frame[offset..]);
+        let (ip_len, tcp_len) = (self.ip_len, self.tcp_len);
+        let header_len = ip_len + tcp_len;
+        if ip_header(packet) != Some((ip_len, TCP))
+            || tcp_header_len(packet, ip_len) != Some(tcp_len)
+        {
+            return false;
+        }
+        let payload_len = packet.len() - header_len;
+        if payload_len == 0
+            || payload_len > self.segment_len
+            || first.len() + payload_len > u16::MAX as usize
+            || read_u32(packet, ip_len + 4) != self.next_seq
+        {
+            return false;
+        }
+        // All but the lengths, IPv4 identification, sequence number and checksums match
+        let ip_same = match packet[0] >> 4 {
+            4 => packet[..2] == first[..2] && packet[6..10] == first[6..10],
+            _ => packet[..4] == first[..4] && packet[6..8] == first[6..8],
+        };
+        let flags = packet[ip_len + 13];
+        let tcp = ip_len..header_len;
+        let tcp_same = packet[tcp.start..tcp.start + 4] == first[tcp.start..tcp.start + 4]
+            && packet[tcp.start + 8..tcp.start + 13] == first[tcp.start + 8..tcp.start + 13]
+            && flags & !(TCP_FIN | TCP_PSH) == first[ip_len + 13]
+            && packet[tcp.start + 14..tcp.start + 16] == first[tcp.start + 14..tcp.start + 16]
+            && packet[tcp.start + 18..tcp.end] == first[tcp.start + 18..tcp.end];
+        let addrs_same = match packet[0] >> 4 {
+            4 => packet[12..ip_len] == first[12..ip_len],
+            _ => packet[8..40] == first[8..40],
+        };
+        if !(ip_same && tcp_same && addrs_same) {
+            return false;
+        }
+        self.frame.extend_from_slice(&packet[header_len..]);
+        self.frame[offset + ip_len + 13] |= flags & (TCP_FIN | TCP_PSH);
+        self.segments += 1;
+        self.next_seq = self.next_seq.wrapping_add(payload_len as u32);
+        self.closed = payload_len < self.segment_len || flags & (TCP_FIN | TCP_PSH) != 0;
+        true
+    }
+
+    /// The frame with its virtio-net header, asking the kernel to segment it if it holds
+    /// more than one segment.
+    fn finish(mut self, offset: usize) -> Vec<u8> {
+        if self.segments == 1 {
+            prepend(&mut self.frame, offset);
+            return self.frame;
+        }
+        let ip_len = self.ip_len;
+        let packet = &mut self.frame[offset..];
+        let ipv4 = packet[0] >> 4 == 4;
+        set_ip_len(packet, ip_len);
+        // The kernel completes the checksum of every segment from the pseudo header's
+        let tcp_len = packet.len() - ip_len;
+        let pseudo = fold(pseudo_sum(packet, TCP, tcp_len));
+        packet[ip_len + 16..ip_len + 18].copy_from_slice(&pseudo.to_be_bytes());
+        let mut gso_type = if ipv4 { GSO_TCPV4 } else { GSO_TCPV6 };
+        if packet[ip_len + 13] & TCP_CWR != 0 {
+            gso_type |= GSO_ECN;
+        }
+        let header = Header {
+            flags: F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: (ip_len + self.tcp_len) as u16,
+            gso_size: self.segment_len as u16,
+            csum_start: ip_len as u16,
+            csum_offset: 16,
+        };
+        self.frame.splice(offset..offset, header.to_bytes());
+        self.frame
+    }
+}
+
+/// Splits the TCP or UDP super-segment in `packet` into segments of `gso_size` bytes of
+/// payload, with their lengths, TCP sequence numbers and flags and checksums set.
+fn split(packet: &[u8], gso_size: usize) -> Option<Vec<Vec<u8>>> {
+    let (ip_len, protocol) = ip_header(packet)?;
+    let l4_len = match protocol {
+        TCP => tcp_header_len(packet, ip_len)?,
+        UDP => 8,
+        _ => return None,
+    };
+    let header_len = ip_len + l4_len;
+    if gso_size == 0 || packet.len() <= header_len {
+        return None;
+    }
+    let (headers, payload) = packet.split_at(header_len);
+    let count = payload.len().div_ceil(gso_size);
+    let mut segments = Vec::with_capacity(count);
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let mut segment = Vec::with_capacity(header_len + chunk.len());
+        segment.extend_from_slice(headers);
+        segment.extend_from_slice(chunk);
+        if segment[0] >> 4 == 4 {
+            let id = u16::from_be_bytes([segment[4], segment[5]]).wrapping_add(i as u16);
+            segment[4..6].copy_from_slice(&id.to_be_bytes());
+        }
+        set_ip_len(&mut segment, ip_len);
+        let l4_len = segment.len() - ip_len;
+        let checksum_at = match protocol {
+            TCP => {
+                let seq = read_u32(&segment, ip_len + 4).wrapping_add((i * gso_size) as u32);
+                segment[ip_len + 4..ip_len + 8].copy_from_slice(&seq.to_be_bytes());
+                if i + 1 < count {
+                    segment[ip_len + 13] &= !(TCP_FIN | TCP_PSH);
+                }
+                if i > 0 {
+                    segment[ip_len + 13] &= !TCP_CWR;
+                }
+                ip_len + 16
+            }
+            _ => {
+                segment[ip_len + 4..ip_len + 6].copy_from_slice(&(l4_len as u16).to_be_bytes());
+                ip_len + 6
+            }
+        };
+        segment[checksum_at..checksum_at + 2].fill(0);
+        let sum = pseudo_sum(&segment, protocol, l4_len) + sum(&segment[ip_len..]);
+        let checksum = match !fold(sum) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        segment[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+        segments.push(segment);
+    }
+    Some(segments)
+}
+
+/// Completes the partial checksum at `at` in `packet`, covering everything from `start`.
+fn complete_checksum(packet: &[u8], start: usize, at: usize) -> Option<Vec<u8>> {
+    if start > at || at + 2 > packet.len() {
+        return None;
+    }
+    let mut packet = packet.to_vec();
+    let checksum = match !fold(sum(&packet[start..])) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    Some(packet)
+}
+
+/// The header length and payload protocol of the IP packet in `packet`. IPv6 packets with
+/// extension headers are not understood.
+fn ip_header(packet: &[u8]) -> Option<(usize, u8)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let len = (packet[0] & 0x0f) as usize * 4;
+            (len >= 20 && packet.len() >= len).then(|| (len, packet[9]))
+        }
+        6 if packet.len() >= 40 => Some((40, packet[6])),
+        _ => None,
+    }
+}
+
+fn tcp_header_len(packet: &[u8], ip_len: usize) -> Option<usize> {
+    let len = (*packet.get(ip_len + 12)? >> 4) as usize * 4;
+    (len >= 20 && packet.len() >= ip_len + len).then_some(len)
+}
+
+/// Sets the length fields of the IP header of `packet` to its length, and for IPv4 the
+/// header checksum.
+fn set_ip_len(packet: &mut [u8], ip_len: usize) {
+    let len = packet.len();
+    if packet[0] >> 4 == 4 {
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[10..12].fill(0);
+        let checksum = !fold(sum(&packet[..ip_len]));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    } else {
+        packet[4..6].copy_from_slice(&((len - ip_len) as u16).to_be_bytes());
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// The sum of the pseudo header of the IP packet in `packet`, for `len` bytes of `protocol`.
+fn pseudo_sum(packet: &[u8], protocol: u8, len: usize) -> u32 {
+    let addrs = match packet[0] >> 4 {
+        4 => &packet[12..20],
+        _ => &packet[8..40],
+    };
+    sum(addrs) + protocol as u32 + len as u32
+}
+
+/// The ones' complement sum of `bytes` as 16-bit words, not yet folded.
+fn sum(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::{PacketBuilder, SlicedPacket, TransportSlice};
+
+    fn tcp_segment(seq: u32, payload: &[u8], psh: bool) -> Vec<u8> {
+        let mut builder = PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(80, 40000, seq, 1024)
+            .ack(7);
+        if psh {
+            builder = builder.psh();
+        }
+        let mut buf = Vec::new();
+        builder.write(&mut buf, payload).unwrap();
+        // Don't fragment, as the stack sends TCP
+        buf[6] = 0x40;
+        buf[10..12].fill(0);
+        let checksum = !fold(sum(&buf[..20]));
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    fn has_valid_checksums(packet: &[u8]) -> bool {
+        let sliced = SlicedPacket::from_ip(packet).unwrap();
+        let Some(etherparse::NetSlice::Ipv4(ip)) = &sliced.net else {
+            panic!("not IPv4");
+        };
+        let Some(TransportSlice::Tcp(tcp)) = &sliced.transport else {
+            panic!("not TCP");
+        };
+        let header = ip.header().to_header();
+        let checksum = tcp.to_header().calc_checksum_ipv4(&header, tcp.payload());
+        header.calc_header_checksum() == header.header_checksum
+            && checksum.is_ok_and(|checksum| checksum == tcp.checksum())
+    }
+
+    #[test]
+    fn coalesce_and_split() {
+        let payload: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let sent = vec![
+            tcp_segment(1000, &payload[..1000], false),
+            tcp_segment(2000, &payload[1000..2000], false),
+            tcp_segment(3000, &payload[2000..], true),
+            // Not continuing the run
+            tcp_segment(9000, &payload[..10], false),
+        ];
+        let frames = encode(sent.clone(), 0);
+        assert_eq!(frames.len(), 2);
+        let header = Header::parse(frames[0][..HEADER_LEN].try_into().unwrap());
+        assert_eq!(header.gso_type, GSO_TCPV4);
+        assert_eq!(header.gso_size, 1000);
+        assert_eq!((header.csum_start, header.csum_offset), (20, 16));
+        assert_eq!(frames[0].len(), HEADER_LEN + 40 + 2500);
+        assert_eq!(frames[1][..HEADER_LEN], [0; HEADER_LEN]);
+
+        // The kernel would segment the frame the same way
+        let mut frame = frames[0].clone();
+        frame[..HEADER_LEN].copy_from_slice(&Header { flags: 0, ..header }.to_bytes());
+        let split: Vec<_> = segments(&frame, true).collect();
+        assert_eq!(split.len(), 3);
+        for (split, segment) in split.iter().zip(&sent) {
+            assert!(has_valid_checksums(split));
+            // All but the IPv4 identification
+            assert_eq!(split[..4], segment[..4]);
+            assert_eq!(split[20..], segment[20..]);
+        }
+    }
+
+    #[test]
+    fn needs_csum() {
+        let mut packet = tcp_segment(1, b"data", false);
+        let pseudo = fold(pseudo_sum(&packet, TCP, packet.len() - 20));
+        packet[36..38].copy_from_slice(&pseudo.to_be_bytes());
+        let header = Header {
+            flags: F_NEEDS_CSUM,
+            csum_start: 20,
+            csum_offset: 16,
+            ..Header::default()
+        };
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(&packet);
+        let completed: Vec<_> = segments(&frame, true).collect();
+        assert_eq!(completed.len(), 1);
+        assert!(has_valid_checksums(&completed[0]));
+    }
+}
@@ -0,0 +1,283 @@
+//! A device the tests play the host side of, and helpers building and parsing its packets.
+#![allow(dead_code)]
+
+use etherparse::{
+    IpNumber, Ipv4Dscp, Ipv4Ecn, Ipv4Header, NetHeaders, PacketBuilder, PacketHeaders,
+    TransportHeader,
+};
+use ipstack::{
+    stream::{IpStackStream, IpStackUdpStream},
+    IpStack, IpStackConfig, PacketDevice,
+};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// How long a packet is waited for before a test fails.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The stack's end of a [`Host`].
+pub struct Device {
+    from_host: UnboundedReceiver<Vec<u8>>,
+    to_host: UnboundedSender<Vec<u8>>,
+}
+
+impl PacketDevice for Device {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .from_host
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.to_host
+            .send(packet.to_vec())
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// The host behind the device, sending packets to the stack and receiving its answers.
+pub struct Host {
+    to_stack: UnboundedSender<Vec<u8>>,
+    from_stack: UnboundedReceiver<Vec<u8>>,
+}
+
+impl Host {
+    pub fn send(&self, packet: Vec<u8>) {
+        self.to_stack.send(packet).unwrap();
+    }
+
+    /// The next packet from the stack, failing the test when none comes.
+    pub async fn recv(&mut self) -> Vec<u8> {
+        tokio::time::timeout(TIMEOUT, self.from_stack.recv())
+            .await
+            .expect("no packet from the stack")
+            .expect("the stack stopped")
+    }
+
+    /// Fails the test if the stack sends a packet within `wait`.
+    pub async fn expect_none(&mut self, wait: Duration) {
+        if let Ok(Some(packet)) = tokio::time::timeout(wait, self.from_stack.recv()).await {
+            panic!("unexpected packet {:?}", Packet::parse(&packet));
+        }
+    }
+}
+
+pub fn device() -> (Device, Host) {
+    let (to_stack, from_host) = unbounded_channel();
+    let (to_host, from_stack) = unbounded_channel();
+    (
+        Device { from_host, to_host },
+        Host {
+            to_stack,
+            from_stack,
+        },
+    )
+}
+
+/// A stack on a fresh device with `config`, and the host behind it.
+pub fn stack(config: IpStackConfig) -> (IpStack, Host) {
+    let (device, host) = device();
+    (IpStack::with_device(config, device), host)
+}
+
+/// The next stream the stack accepts, failing the test when none comes.
+pub async fn accept(stack: &mut IpStack) -> IpStackStream {
+    tokio::time::timeout(TIMEOUT, stack.accept())
+        .await
+        .expect("no stream accepted")
+        .unwrap()
+}
+
+/// The next stream the stack accepts as a UDP stream.
+pub async fn accept_udp(stack: &mut IpStack) -> IpStackUdpStream {
+    match accept(stack).await {
+        IpStackStream::Udp(stream) => stream,
+        _ => panic!("no UDP stream"),
+    }
+}
+
+pub fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+pub fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+/// The flags of [`tcp`].
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+pub const ACK: u8 = 0x10;
+pub const FIN: u8 = 0x01;
+
+fn builder(
+    src: SocketAddr,
+    dst: SocketAddr,
+) -> etherparse::PacketBuilderStep<etherparse::IpHeaders> {
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => PacketBuilder::ipv4(s.octets(), d.octets(), 64),
+        (IpAddr::V6(s), IpAddr::V6(d)) => PacketBuilder::ipv6(s.octets(), d.octets(), 64),
+        _ => panic!("mixed address families"),
+    }
+}
+
+/// A TCP segment from `src` to `dst`.
+pub fn tcp(src: &str, dst: &str, flags: u8, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let (src, dst) = (addr(src), addr(dst));
+    let mut builder = builder(src, dst).tcp(src.port(), dst.port(), seq, 65535);
+    if flags & SYN != 0 {
+        builder = builder.syn();
+    }
+    if flags & ACK != 0 {
+        builder = builder.ack(ack);
+    }
+    if flags & RST != 0 {
+        builder = builder.rst();
+    }
+    if flags & FIN != 0 {
+        builder = builder.fin();
+    }
+    if flags & PSH != 0 {
+        builder = builder.psh();
+    }
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+/// A UDP datagram from `src` to `dst`.
+pub fn udp(src: &str, dst: &str, payload: &[u8]) -> Vec<u8> {
+    let (src, dst) = (addr(src), addr(dst));
+    let builder = builder(src, dst).udp(src.port(), dst.port());
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+/// A UDP-Lite datagram from `src` to `dst` whose checksum covers `coverage` bytes, IPv4 only.
+pub fn udp_lite(src: &str, dst: &str, coverage: u16, payload: &[u8]) -> Vec<u8> {
+    let (src, dst) = (addr(src), addr(dst));
+    let (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) = (src.ip(), dst.ip()) else {
+        panic!("UDP-Lite over IPv6");
+    };
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&coverage.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let covered = match coverage {
+        0 => datagram.len(),
+        coverage => coverage as usize,
+    };
+    let mut pseudo = [src_ip.octets(), dst_ip.octets()].concat();
+    pseudo.extend_from_slice(&[0, IpNumber::UDP_LITE.0]);
+    pseudo.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    let mut sum = pseudo
+        .chunks(2)
+        .chain(datagram[..covered].chunks(2))
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    datagram[6..8].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    let mut ip = Ipv4Header::new(
+        datagram.len() as u16,
+        64,
+        IpNumber::UDP_LITE,
+        src_ip.octets(),
+        dst_ip.octets(),
+    )
+    .unwrap();
+    ip.header_checksum = ip.calc_header_checksum();
+    [ip.to_bytes().to_vec(), datagram].concat()
+}
+
+/// Sets the TOS byte of the IPv4 packet `packet`.
+pub fn set_tos(packet: &mut [u8], tos: u8) {
+    let (mut ip, _) = Ipv4Header::from_slice(packet).unwrap();
+    ip.dscp = Ipv4Dscp::try_new(tos >> 2).unwrap();
+    ip.ecn = Ipv4Ecn::try_new(tos & 0b11).unwrap();
+    ip.header_checksum = ip.calc_header_checksum();
+    packet[..ip.header_len()].copy_from_slice(&ip.to_bytes());
+}
+
+/// Opens a connection from `src` to `dst` with the initial sequence number 1000, returning
+/// the next sequence number of the stack.
+pub async fn handshake(host: &mut Host, src: &str, dst: &str) -> u32 {
+    host.send(tcp(src, dst, SYN, 1000, 0, b""));
+    let syn_ack = Packet::parse(&host.recv().await).tcp().clone();
+    assert!(syn_ack.syn && syn_ack.ack);
+    let seq = syn_ack.sequence_number.wrapping_add(1);
+    host.send(tcp(src, dst, ACK, 1001, seq, b""));
+    seq
+}
+
+/// The parts of a packet from the stack the tests look at.
+#[derive(Debug)]
+pub struct Packet {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub ttl: u8,
+    /// The TOS byte (IPv4) or traffic class (IPv6).
+    pub tos: u8,
+    pub protocol: IpNumber,
+    pub transport: Option<TransportHeader>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn parse(packet: &[u8]) -> Packet {
+        let headers = PacketHeaders::from_ip_slice(packet).expect("invalid packet");
+        let (src, dst, ttl, tos, protocol) = match headers.net.expect("no IP header") {
+            NetHeaders::Ipv4(ip, _) => (
+                ip.source.into(),
+                ip.destination.into(),
+                ip.time_to_live,
+                ip.dscp.value() << 2 | ip.ecn.value(),
+                ip.protocol,
+            ),
+            NetHeaders::Ipv6(ip, _) => (
+                ip.source.into(),
+                ip.destination.into(),
+                ip.hop_limit,
+                ip.traffic_class,
+                ip.next_header,
+            ),
+            NetHeaders::Arp(_) => panic!("ARP packet"),
+        };
+        Packet {
+            src,
+            dst,
+            ttl,
+            tos,
+            protocol,
+            transport: headers.transport,
+            payload: headers.payload.slice().to_vec(),
+        }
+    }
+
+    pub fn tcp(&self) -> &etherparse::TcpHeader {
+        match &self.transport {
+            Some(TransportHeader::Tcp(tcp)) => tcp,
+            other => panic!("not a TCP segment: {other:?}"),
+        }
+    }
+
+    pub fn udp(&self) -> &etherparse::UdpHeader {
+        match &self.transport {
+            Some(TransportHeader::Udp(udp)) => udp,
+            other => panic!("not a UDP datagram: {other:?}"),
+        }
+    }
+}
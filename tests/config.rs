@@ -0,0 +1,32 @@
+use ipstack::{IpStackConfig, IpStackError};
+use std::{net::Ipv4Addr, time::Duration};
+
+#[test]
+fn builder() {
+    let config = IpStackConfig::builder()
+        .mtu(1400)
+        .tcp_timeout(Duration::from_secs(30))
+        .intercept_dns(true)
+        .build()
+        .unwrap();
+    assert_eq!(config.mtu, 1400);
+    assert_eq!(config.tcp_timeout, Duration::from_secs(30));
+    assert!(config.intercept_dns);
+
+    let invalid = [
+        IpStackConfig::builder().mtu(40),
+        IpStackConfig::builder().mss_clamp(0),
+        IpStackConfig::builder().udp_timeout(Duration::ZERO),
+        IpStackConfig::builder().tcp_send_buffer_size(0),
+        IpStackConfig::builder()
+            .intercept_dns(true)
+            .dns_ports(vec![]),
+        IpStackConfig::builder().arp_gateway(Ipv4Addr::new(10, 0, 0, 1)),
+    ];
+    for builder in invalid {
+        assert!(matches!(
+            builder.build(),
+            Err(IpStackError::InvalidConfig(_))
+        ));
+    }
+}
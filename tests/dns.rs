@@ -0,0 +1,139 @@
+mod common;
+
+use common::{accept, addr, handshake, ip, stack, tcp, udp, Packet, ACK, PSH, SYN};
+use ipstack::{
+    dns::{DnsQuery, TYPE_A},
+    fake_ip::FakeIpPool,
+    stream::IpStackStream,
+    IpStack, IpStackConfig,
+};
+
+/// A query for the A records of `name` with the ID `id`.
+fn query(id: u16, name: &str) -> Vec<u8> {
+    let mut message = id.to_be_bytes().to_vec();
+    // RD, one question
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.extend_from_slice(&[0, 0, 1, 0, 1]);
+    message
+}
+
+async fn accept_dns(stack: &mut IpStack) -> DnsQuery {
+    match accept(stack).await {
+        IpStackStream::Dns(query) => query,
+        _ => panic!("no DNS query"),
+    }
+}
+
+#[tokio::test]
+async fn intercept_udp() {
+    let mut config = IpStackConfig::default();
+    config.intercept_dns(true);
+    let (mut stack, mut host) = stack(config);
+
+    host.send(udp(
+        "10.0.0.2:5000",
+        "8.8.8.8:53",
+        &query(0x1234, "example.com"),
+    ));
+    let query = accept_dns(&mut stack).await;
+    assert!(!query.is_tcp());
+    assert_eq!(query.id(), 0x1234);
+    assert_eq!(query.question().name(), "example.com");
+    assert_eq!(query.question().qtype(), TYPE_A);
+    assert_eq!(query.peer_addr(), addr("8.8.8.8:53"));
+    query
+        .respond_addrs(&[ip("1.2.3.4"), ip("::1")], 60)
+        .unwrap();
+
+    // The response comes from the resolver, with the question and an A record only
+    let response = Packet::parse(&host.recv().await);
+    assert_eq!(
+        (response.src, response.dst),
+        (ip("8.8.8.8"), ip("10.0.0.2"))
+    );
+    assert_eq!(response.udp().source_port, 53);
+    let message = response.payload;
+    assert_eq!(message[..2], [0x12, 0x34]);
+    assert_eq!(message[2] & 0x80, 0x80);
+    assert_eq!(message[6..8], [0, 1]);
+    assert_eq!(message[message.len() - 4..], [1, 2, 3, 4]);
+}
+
+#[tokio::test]
+async fn intercept_tcp() {
+    let mut config = IpStackConfig::default();
+    config.intercept_dns(true);
+    let (mut stack, mut host) = stack(config);
+
+    let seq = handshake(&mut host, "10.0.0.2:40000", "8.8.8.8:53").await;
+    let message = query(7, "example.org");
+    let mut prefixed = (message.len() as u16).to_be_bytes().to_vec();
+    prefixed.extend_from_slice(&message);
+    host.send(tcp(
+        "10.0.0.2:40000",
+        "8.8.8.8:53",
+        ACK | PSH,
+        1001,
+        seq,
+        &prefixed,
+    ));
+    let query = accept_dns(&mut stack).await;
+    assert!(query.is_tcp());
+    assert_eq!(query.question().name(), "example.org");
+    query.respond_addrs(&[], 60).unwrap();
+
+    // The length-prefixed response follows the ACK of the query, if that is sent alone
+    let response = loop {
+        let packet = Packet::parse(&host.recv().await);
+        if !packet.payload.is_empty() {
+            break packet.payload;
+        }
+    };
+    assert_eq!(response[..2], ((response.len() - 2) as u16).to_be_bytes());
+    assert_eq!(response[2..4], [0, 7]);
+    assert_eq!(response[8..10], [0, 0]);
+}
+
+#[tokio::test]
+async fn refuse_encrypted() {
+    let mut config = IpStackConfig::default();
+    config.intercept_dns(true);
+    let (_stack, mut host) = stack(config);
+
+    host.send(tcp("10.0.0.2:40000", "1.1.1.1:853", SYN, 1000, 0, b""));
+    let reset = Packet::parse(&host.recv().await);
+    assert!(reset.tcp().rst);
+    assert_eq!(reset.tcp().acknowledgment_number, 1001);
+}
+
+#[tokio::test]
+async fn fake_ip() {
+    let pool = FakeIpPool::new(ip("198.18.0.0"), 15).unwrap();
+    let mut config = IpStackConfig::default();
+    config.intercept_dns(true).fake_ip_pool(pool.clone());
+    let (mut stack, mut host) = stack(config);
+
+    host.send(udp("10.0.0.2:5000", "8.8.8.8:53", &query(1, "Example.COM")));
+    pool.respond(accept_dns(&mut stack).await, 60).unwrap();
+    let response = Packet::parse(&host.recv().await).payload;
+    let fake = ip("198.18.0.1");
+    assert_eq!(response[response.len() - 4..], [198, 18, 0, 1]);
+    assert_eq!(pool.lookup(fake).as_deref(), Some("example.com"));
+
+    // Streams to the fake address are named after the host
+    handshake(&mut host, "10.0.0.2:40000", "198.18.0.1:443").await;
+    let IpStackStream::Tcp(stream) = accept(&mut stack).await else {
+        panic!("no TCP stream");
+    };
+    assert_eq!(stream.hostname(), Some("example.com"));
+    host.send(udp("10.0.0.2:5001", "198.18.0.1:443", b"quic"));
+    let IpStackStream::Udp(stream) = accept(&mut stack).await else {
+        panic!("no UDP stream");
+    };
+    assert_eq!(stream.hostname(), Some("example.com"));
+    assert_eq!(stream.peer_addr().ip(), fake);
+}
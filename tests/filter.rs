@@ -0,0 +1,131 @@
+mod common;
+
+use bytes::Bytes;
+use common::{accept, accept_udp, addr, handshake, ip, stack, tcp, udp, Packet, SYN};
+use etherparse::{icmpv4::DestUnreachableHeader, Icmpv4Type, IpNumber, TransportHeader};
+use ipstack::{
+    stream::{IpStackStream, TcpState},
+    Direction, IpStackConfig, Verdict,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+
+#[tokio::test]
+async fn filter() {
+    let mut config = IpStackConfig::default();
+    config.with_filter(Box::new(|packet, direction| {
+        match (direction, packet.dst_addr().port(), packet.payload()) {
+            (Direction::Ingress, 9, _) => Verdict::Drop,
+            (Direction::Ingress, 23, _) => Verdict::Reject,
+            (Direction::Egress, _, b"secret") => Verdict::Drop,
+            _ => Verdict::Accept,
+        }
+    }));
+    let (mut stack, mut host) = stack(config);
+
+    // Dropped silently, only the datagram behind it opens a stream
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:9", b"discard"));
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"query"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.peer_addr(), addr("1.2.3.4:53"));
+    host.expect_none(Duration::from_millis(50)).await;
+
+    // Packets of the stack are filtered too
+    stream.send_datagram(Bytes::from_static(b"secret")).unwrap();
+    stream.send_datagram(Bytes::from_static(b"public")).unwrap();
+    assert_eq!(Packet::parse(&host.recv().await).payload, b"public");
+
+    // Rejected with an ICMP administratively prohibited error
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:23", b"login"));
+    let error = Packet::parse(&host.recv().await);
+    assert_eq!((error.src, error.dst), (ip("1.2.3.4"), ip("10.0.0.2")));
+    let Some(TransportHeader::Icmpv4(icmp)) = error.transport else {
+        panic!("no ICMP error");
+    };
+    assert_eq!(
+        icmp.icmp_type,
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FilterProhibited)
+    );
+}
+
+#[tokio::test]
+async fn dnat() {
+    let mut config = IpStackConfig::default();
+    config.with_dnat(Box::new(|packet| {
+        (packet.dst_addr().port() == 80).then(|| addr("127.0.0.1:8080"))
+    }));
+    let (mut stack, mut host) = stack(config);
+
+    let seq = handshake(&mut host, "10.0.0.2:40000", "1.2.3.4:80").await;
+    let IpStackStream::Tcp(mut stream) = accept(&mut stack).await else {
+        panic!("no TCP stream");
+    };
+    assert_eq!(stream.peer_addr(), addr("1.2.3.4:80"));
+    assert_eq!(stream.target_addr(), addr("127.0.0.1:8080"));
+
+    // The device keeps talking to the original destination
+    let mut state = stream.watch_state();
+    state
+        .wait_for(|s| *s == TcpState::Established)
+        .await
+        .unwrap();
+    stream.write_all(b"HTTP/1.1 200 OK").await.unwrap();
+    let data = Packet::parse(&host.recv().await);
+    assert_eq!(data.src, ip("1.2.3.4"));
+    assert_eq!(data.tcp().source_port, 80);
+    assert_eq!(data.tcp().sequence_number, seq);
+
+    // Flows the rewrite keeps have no other target
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"query"));
+    let stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.target_addr(), addr("1.2.3.4:53"));
+}
+
+#[tokio::test]
+async fn accept_policy() {
+    let asked = Arc::new(AtomicUsize::new(0));
+    let counter = asked.clone();
+    let mut config = IpStackConfig::default();
+    config.with_accept_policy(Box::new(move |_src, dst, protocol| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        match (protocol, dst.port()) {
+            (IpNumber::TCP, 25) | (IpNumber::UDP, 23) => Verdict::Reject,
+            (IpNumber::UDP, 9) => Verdict::Drop,
+            _ => Verdict::Accept,
+        }
+    }));
+    let (mut stack, mut host) = stack(config);
+
+    // A refused connection is reset
+    host.send(tcp("10.0.0.2:40000", "1.2.3.4:25", SYN, 1000, 0, b""));
+    let reset = Packet::parse(&host.recv().await);
+    assert!(reset.tcp().rst);
+    assert_eq!(reset.tcp().acknowledgment_number, 1001);
+
+    // A refused flow gets an ICMP administratively prohibited error, a dropped one nothing
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:23", b"login"));
+    let error = Packet::parse(&host.recv().await);
+    let Some(TransportHeader::Icmpv4(icmp)) = error.transport else {
+        panic!("no ICMP error");
+    };
+    assert_eq!(
+        icmp.icmp_type,
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FilterProhibited)
+    );
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:9", b"discard"));
+    host.expect_none(Duration::from_millis(50)).await;
+
+    // Only the first datagram of an accepted flow is decided over
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"one"));
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"two"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "one");
+    assert_eq!(stream.recv_datagram().await.unwrap(), "two");
+    assert_eq!(asked.load(Ordering::Relaxed), 4);
+}
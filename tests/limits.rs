@@ -0,0 +1,117 @@
+mod common;
+
+use common::{accept, accept_udp, addr, handshake, stack, tcp, udp, Packet, SYN};
+use etherparse::{icmpv4::DestUnreachableHeader, Icmpv4Type, TransportHeader};
+use ipstack::{
+    stream::{IpStackStream, TcpState},
+    FlowLimitPolicy, IpStackConfig,
+};
+use std::{io::ErrorKind, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn drop_over_limit() {
+    let mut config = IpStackConfig::default();
+    config.max_udp_flows(1);
+    let (mut stack, mut host) = stack(config);
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"first"));
+    let mut stream = accept_udp(&mut stack).await;
+    host.send(udp("10.0.0.2:5001", "1.2.3.4:53", b"dropped"));
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"second"));
+    assert_eq!(stream.recv_datagram().await.unwrap(), "first");
+    assert_eq!(stream.recv_datagram().await.unwrap(), "second");
+    host.expect_none(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn reject_over_limit() {
+    let mut config = IpStackConfig::default();
+    config
+        .max_tcp_connections(1)
+        .max_udp_flows(1)
+        .flow_limit_policy(FlowLimitPolicy::Reject);
+    let (mut stack, mut host) = stack(config);
+
+    host.send(tcp("10.0.0.2:40000", "1.2.3.4:80", SYN, 1000, 0, b""));
+    assert!(Packet::parse(&host.recv().await).tcp().syn);
+    let _connection = accept(&mut stack).await;
+    host.send(tcp("10.0.0.2:40001", "1.2.3.4:80", SYN, 1000, 0, b""));
+    let reset = Packet::parse(&host.recv().await);
+    assert!(reset.tcp().rst);
+    assert_eq!(reset.tcp().destination_port, 40001);
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"first"));
+    let _flow = accept_udp(&mut stack).await;
+    host.send(udp("10.0.0.2:5001", "1.2.3.4:53", b"refused"));
+    let error = Packet::parse(&host.recv().await);
+    let Some(TransportHeader::Icmpv4(icmp)) = error.transport else {
+        panic!("no ICMP error");
+    };
+    assert_eq!(
+        icmp.icmp_type,
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::FilterProhibited)
+    );
+}
+
+#[tokio::test]
+async fn evict_idle() {
+    let mut config = IpStackConfig::default();
+    config
+        .max_udp_flows(2)
+        .flow_limit_policy(FlowLimitPolicy::EvictIdle);
+    let (mut stack, host) = stack(config);
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"a"));
+    let mut a = accept_udp(&mut stack).await;
+    host.send(udp("10.0.0.2:5001", "1.2.3.4:53", b"b"));
+    let mut b = accept_udp(&mut stack).await;
+    assert_eq!(a.recv_datagram().await.unwrap(), "a");
+    assert_eq!(b.recv_datagram().await.unwrap(), "b");
+
+    // The first flow is the more recent one once it received again, the second is evicted
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"a again"));
+    assert_eq!(a.recv_datagram().await.unwrap(), "a again");
+    host.send(udp("10.0.0.2:5002", "1.2.3.4:53", b"c"));
+    let c = accept_udp(&mut stack).await;
+    assert_eq!(c.local_addr(), addr("10.0.0.2:5002"));
+    let err = b.recv_datagram().await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"a still"));
+    assert_eq!(a.recv_datagram().await.unwrap(), "a still");
+}
+
+#[tokio::test]
+async fn evict_idle_connection() {
+    let mut config = IpStackConfig::default();
+    config
+        .max_tcp_connections(1)
+        .flow_limit_policy(FlowLimitPolicy::EvictIdle);
+    let (mut stack, mut host) = stack(config);
+
+    handshake(&mut host, "10.0.0.2:40000", "1.2.3.4:80").await;
+    let IpStackStream::Tcp(mut first) = accept(&mut stack).await else {
+        panic!("no TCP stream");
+    };
+    first
+        .watch_state()
+        .wait_for(|s| *s == TcpState::Established)
+        .await
+        .unwrap();
+
+    // The evicted connection is reset, on the device and for the application
+    host.send(tcp("10.0.0.2:40001", "1.2.3.4:80", SYN, 1000, 0, b""));
+    let _second = accept(&mut stack).await;
+    let mut answers = [host.recv().await, host.recv().await].map(|p| Packet::parse(&p));
+    answers.sort_by_key(|p| p.tcp().destination_port);
+    assert!(answers[0].tcp().rst);
+    assert_eq!(answers[0].tcp().destination_port, 40000);
+    assert!(answers[1].tcp().syn);
+    let mut buf = [0; 16];
+    let err = first.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    let err = first.write_all(b"after eviction").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    assert_eq!(first.state(), TcpState::Closed);
+    host.expect_none(Duration::from_millis(50)).await;
+}
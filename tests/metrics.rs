@@ -0,0 +1,53 @@
+mod common;
+
+use bytes::Bytes;
+use common::{accept, accept_udp, stack, tcp, udp, Packet, ACK, SYN};
+use ipstack::{stream::IpStackStream, IpStackConfig};
+use std::time::Duration;
+
+#[tokio::test]
+async fn counters() {
+    let mut config = IpStackConfig::default();
+    config.validate_checksums(true).max_pending_connections(0);
+    let (mut stack, mut host) = stack(config);
+    let metrics = stack.metrics();
+
+    let datagram = udp("10.0.0.2:5000", "1.2.3.4:53", b"query");
+    let mut corrupted = udp("10.0.0.2:5000", "1.2.3.4:53", b"corrupted");
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    let garbage = vec![0xff; 20];
+    let unknown = tcp("10.0.0.2:40000", "1.2.3.4:80", ACK, 1000, 1, b"");
+    let syn = tcp("10.0.0.2:40001", "1.2.3.4:80", SYN, 1000, 0, b"");
+    let bytes_in = [&datagram, &corrupted, &garbage, &unknown, &syn]
+        .iter()
+        .map(|p| p.len() as u64)
+        .sum::<u64>();
+    host.send(datagram);
+    host.send(corrupted);
+    host.send(garbage);
+    host.send(unknown);
+    host.send(syn);
+
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "query");
+    assert!(matches!(
+        accept(&mut stack).await,
+        IpStackStream::UnknownNetwork(_)
+    ));
+    let reset = host.recv().await;
+    assert!(Packet::parse(&reset).tcp().rst);
+    // The refused SYN is dropped without an answer
+    host.expect_none(Duration::from_millis(50)).await;
+    stream.send_datagram(Bytes::from_static(b"answer")).unwrap();
+    let answer = host.recv().await;
+
+    assert_eq!(metrics.packets_in(), 5);
+    assert_eq!(metrics.bytes_in(), bytes_in);
+    assert_eq!(metrics.packets_out(), 2);
+    assert_eq!(metrics.bytes_out(), (reset.len() + answer.len()) as u64);
+    assert_eq!(metrics.parse_errors(), 1);
+    assert_eq!(metrics.checksum_drops(), 1);
+    assert_eq!(metrics.rsts_sent(), 1);
+    assert_eq!(metrics.accept_queue_drops(), 1);
+    assert_eq!(metrics.retransmissions(), 0);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use bytes::Bytes;
+use common::{accept_udp, addr, ip, stack, tcp, udp, Packet, ACK, SYN};
+use ipstack::{DeviceId, IpStackConfig};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+#[cfg(unix)]
+#[tokio::test]
+async fn added_device_without_packet_information() {
+    let mut config = IpStackConfig::default();
+    config.packet_information(true);
+    let (mut stack, mut host) = stack(config);
+    let (device, mut other) = tokio::io::duplex(u16::MAX as usize);
+    let id = stack.add_device(device);
+    stack.add_route(ip("192.168.0.0"), 16, id);
+
+    // The primary device frames its packets
+    let mut framed = vec![0, 0, 0, 0];
+    framed.extend(udp("10.0.0.2:5000", "1.2.3.4:53", b"primary"));
+    host.send(framed);
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "primary");
+    stream.send_datagram(Bytes::from_static(b"reply")).unwrap();
+    let reply = host.recv().await;
+    assert_eq!(Packet::parse(&reply[4..]).payload, b"reply");
+
+    // The added one exchanges bare IP packets both ways
+    let datagram = udp("192.168.1.2:5000", "1.2.3.4:53", b"added");
+    other.write_all(&datagram).await.unwrap();
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.local_addr(), addr("192.168.1.2:5000"));
+    assert_eq!(stream.recv_datagram().await.unwrap(), "added");
+    stream.send_datagram(Bytes::from_static(b"reply")).unwrap();
+    let mut buf = vec![0; u16::MAX as usize];
+    let n = other.read(&mut buf).await.unwrap();
+    let reply = Packet::parse(&buf[..n]);
+    assert_eq!((reply.src, reply.dst), (ip("1.2.3.4"), ip("192.168.1.2")));
+    assert_eq!(reply.payload, b"reply");
+}
+
+#[tokio::test]
+async fn forward() {
+    let forward_to = Arc::new(OnceLock::new());
+    let mut config = IpStackConfig::default();
+    let device = forward_to.clone();
+    config.with_forward(Box::new(move |packet| {
+        match (packet.src_addr().port(), packet.dst_addr().port()) {
+            (_, 22) => device.get().copied(),
+            (22, _) => Some(DeviceId::PRIMARY),
+            _ => None,
+        }
+    }));
+    let (mut stack, mut host) = stack(config);
+    let (device, mut other) = tokio::io::duplex(u16::MAX as usize);
+    forward_to.set(stack.add_device(device)).unwrap();
+
+    // The SYN passes verbatim but for its TTL, the stack does not answer it
+    let syn = tcp("10.0.0.2:40000", "1.2.3.4:22", SYN, 1000, 0, b"");
+    host.send(syn.clone());
+    let mut buf = vec![0; u16::MAX as usize];
+    let n = other.read(&mut buf).await.unwrap();
+    let forwarded = Packet::parse(&buf[..n]);
+    assert_eq!(forwarded.ttl, 63);
+    assert_eq!(buf[..n][12..], syn[12..]);
+
+    // And so does the answer the other way
+    let syn_ack = tcp("1.2.3.4:22", "10.0.0.2:40000", SYN | ACK, 7000, 1001, b"");
+    other.write_all(&syn_ack).await.unwrap();
+    let answer = Packet::parse(&host.recv().await);
+    assert_eq!(answer.ttl, 63);
+    assert_eq!(answer.tcp().sequence_number, 7000);
+    assert!(timeout(Duration::from_millis(50), stack.accept())
+        .await
+        .is_err());
+}
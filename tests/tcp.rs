@@ -0,0 +1,205 @@
+mod common;
+
+use common::{addr, handshake, ip, stack, tcp, Packet, ACK, PSH, RST, SYN};
+use ipstack::{
+    stream::{IpStackStream, TcpState},
+    Clock, Direction, IpStackConfig, IpStackError, SleepFuture, Verdict,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+#[tokio::test]
+async fn reset_unknown_flow() {
+    let (_stack, mut host) = stack(IpStackConfig::default());
+
+    // An ACK of no connection is reset with its acknowledgment number as sequence number
+    host.send(tcp(
+        "10.0.0.2:40000",
+        "1.2.3.4:80",
+        ACK,
+        1000,
+        5000,
+        b"data",
+    ));
+    let reset = Packet::parse(&host.recv().await);
+    assert_eq!(reset.src, ip("1.2.3.4"));
+    let h = reset.tcp();
+    assert!(h.rst && !h.ack);
+    assert_eq!((h.source_port, h.destination_port), (80, 40000));
+    assert_eq!(h.sequence_number, 5000);
+    assert!(reset.payload.is_empty());
+
+    // A segment without ACK is acknowledged up to its end
+    host.send(tcp("10.0.0.2:40000", "1.2.3.4:80", 0, 1000, 0, b"data"));
+    let h = Packet::parse(&host.recv().await).tcp().clone();
+    assert!(h.rst && h.ack);
+    assert_eq!((h.sequence_number, h.acknowledgment_number), (0, 1004));
+
+    // Nothing else, like the packet of a stream dropped, follows
+    host.expect_none(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn reject_keeps_connection() {
+    let mut config = IpStackConfig::default();
+    config.with_filter(Box::new(|packet, direction| {
+        match direction == Direction::Ingress && packet.payload() == b"bad" {
+            true => Verdict::Reject,
+            false => Verdict::Accept,
+        }
+    }));
+    let (mut stack, mut host) = stack(config);
+
+    let seq = handshake(&mut host, "10.0.0.2:40000", "1.2.3.4:80").await;
+    let IpStackStream::Tcp(mut stream) = stack.accept().await.unwrap() else {
+        panic!("no TCP stream");
+    };
+
+    // The rejected segment is reset without closing the connection on the stack's side
+    host.send(tcp("10.0.0.2:40000", "1.2.3.4:80", ACK, 1001, seq, b"bad"));
+    assert!(Packet::parse(&host.recv().await).tcp().rst);
+    host.send(tcp("10.0.0.2:40000", "1.2.3.4:80", ACK, 1001, seq, b"good"));
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"good");
+}
+
+/// A clock running a thousand times faster than the wall clock.
+struct FastClock(Instant);
+
+impl Clock for FastClock {
+    fn now(&self) -> Instant {
+        self.0 + self.0.elapsed() * 1000
+    }
+    fn sleep_until(&self, deadline: Instant) -> SleepFuture {
+        let wait = deadline.saturating_duration_since(self.now()) / 1000;
+        Box::pin(tokio::time::sleep(wait))
+    }
+}
+
+#[tokio::test]
+async fn linger_on_clock() {
+    let mut config = IpStackConfig::default();
+    config
+        .clock(Arc::new(FastClock(Instant::now())))
+        .tcp_linger(Duration::from_secs(60))
+        .tcp_timeout(Duration::from_secs(3600));
+    let (mut stack, mut host) = stack(config);
+
+    handshake(&mut host, "10.0.0.2:40000", "1.2.3.4:80").await;
+    let stream = stack.accept().await.unwrap();
+    drop(stream);
+
+    // The FIN is never acknowledged, the connection is reset once the linger passed on the
+    // clock, long before its retransmissions are exhausted
+    let mut fins = 0;
+    loop {
+        let h = Packet::parse(&host.recv().await).tcp().clone();
+        if h.rst {
+            break;
+        }
+        assert!(h.fin);
+        fins += 1;
+    }
+    assert!(fins < 8, "reset after {fins} FINs");
+}
+
+#[tokio::test]
+async fn connect() {
+    let mut config = IpStackConfig::default();
+    config.tcp_window_scale(7).tcp_recv_buffer_size(1 << 20);
+    let window = config.tcp_recv_buffer_size.min(u16::MAX as usize) as u16;
+    let (stack, mut host) = stack(config);
+    let (local, remote) = (addr("1.2.3.4:80"), addr("10.0.0.2:40000"));
+
+    let handshake = async {
+        let syn = Packet::parse(&host.recv().await).tcp().clone();
+        assert!(syn.syn && !syn.ack);
+        // The window of a SYN is never scaled
+        assert_eq!(syn.window_size, window);
+        assert_eq!((syn.source_port, syn.destination_port), (80, 40000));
+        let ack = syn.sequence_number.wrapping_add(1);
+        host.send(tcp(
+            "10.0.0.2:40000",
+            "1.2.3.4:80",
+            SYN | ACK,
+            7000,
+            ack,
+            b"",
+        ));
+        let h = Packet::parse(&host.recv().await).tcp().clone();
+        assert!(h.ack && !h.syn);
+        assert_eq!(h.acknowledgment_number, 7001);
+        ack
+    };
+    let (stream, seq) = tokio::join!(stack.connect_tcp(local, remote), handshake);
+    let mut stream = stream.unwrap();
+    assert_eq!(stream.local_addr(), remote);
+    assert_eq!(stream.peer_addr(), local);
+
+    stream.write_all(b"hello").await.unwrap();
+    let data = Packet::parse(&host.recv().await);
+    assert_eq!(data.tcp().sequence_number, seq);
+    assert_eq!(data.payload, b"hello");
+
+    // A reset answering the SYN refuses the connection
+    let refuse = async {
+        let syn = Packet::parse(&host.recv().await).tcp().clone();
+        let ack = syn.sequence_number.wrapping_add(1);
+        host.send(tcp("10.0.0.2:40001", "1.2.3.4:80", RST | ACK, 0, ack, b""));
+    };
+    let (refused, ()) = tokio::join!(stack.connect_tcp(local, addr("10.0.0.2:40001")), refuse);
+    let Err(IpStackError::IoError(err)) = refused else {
+        panic!("connection not refused");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn stats() {
+    let (mut stack, mut host) = stack(IpStackConfig::default());
+    let seq = handshake(&mut host, "10.0.0.2:40000", "1.2.3.4:80").await;
+    let IpStackStream::Tcp(mut stream) = stack.accept().await.unwrap() else {
+        panic!("no TCP stream");
+    };
+    stream
+        .watch_state()
+        .wait_for(|s| *s == TcpState::Established)
+        .await
+        .unwrap();
+
+    // A segment acknowledging data never sent is not received
+    host.send(tcp(
+        "10.0.0.2:40000",
+        "1.2.3.4:80",
+        PSH | ACK,
+        1001,
+        seq,
+        b"hello",
+    ));
+    host.send(tcp(
+        "10.0.0.2:40000",
+        "1.2.3.4:80",
+        PSH | ACK,
+        1006,
+        seq + 100_000,
+        b"stray",
+    ));
+    let mut buf = [0; 16];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    stream.write_all(b"world!").await.unwrap();
+
+    // The SYN/ACK, then everything up to the data
+    let mut sent = 1;
+    while Packet::parse(&host.recv().await).payload != b"world!" {
+        sent += 1;
+    }
+    host.expect_none(Duration::from_millis(100)).await;
+    let stats = stream.stats();
+    assert_eq!((stats.segments_sent, stats.bytes_sent), (sent + 1, 6));
+    assert_eq!((stats.segments_received, stats.bytes_received), (2, 5));
+}
@@ -0,0 +1,358 @@
+mod common;
+
+use bytes::Bytes;
+use common::{accept, accept_udp, addr, ip, set_tos, stack, udp, udp_lite, Packet};
+use etherparse::{
+    icmpv4::DestUnreachableHeader, Icmpv4Type, IpNumber, Ipv4HeaderSlice, TransportHeader,
+    UdpHeaderSlice,
+};
+use ipstack::{
+    stream::{IpStackStream, IpStackUdpStream},
+    IpStackConfig, UdpBroadcastPolicy, UdpMode, UdpTimeoutRefresh,
+};
+use std::{net::Ipv4Addr, time::Duration};
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn datagrams() {
+    let (mut stack, mut host) = stack(IpStackConfig::default());
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"first"));
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"second datagram"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.local_addr(), addr("10.0.0.2:5000"));
+    assert_eq!(stream.peer_addr(), addr("1.2.3.4:53"));
+    assert_eq!(stream.recv_datagram().await.unwrap(), "first");
+    assert_eq!(stream.recv_datagram().await.unwrap(), "second datagram");
+
+    stream.send_datagram(Bytes::from_static(b"reply")).unwrap();
+    let reply = Packet::parse(&host.recv().await);
+    assert_eq!(reply.src, addr("1.2.3.4:53").ip());
+    assert_eq!(reply.dst, addr("10.0.0.2:5000").ip());
+    assert_eq!(
+        (reply.udp().source_port, reply.udp().destination_port),
+        (53, 5000)
+    );
+    assert_eq!(reply.payload, b"reply");
+}
+
+#[tokio::test]
+async fn checksum_validation() {
+    let mut config = IpStackConfig::default();
+    config.validate_checksums(true);
+    let (mut stack, host) = stack(config);
+
+    let mut corrupted = udp("10.0.0.2:5000", "1.2.3.4:53", b"corrupted");
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    host.send(corrupted);
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"intact"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "intact");
+    assert_eq!(stack.udp_checksum_errors(), 1);
+}
+
+#[tokio::test]
+async fn single_socket() {
+    let mut config = IpStackConfig::default();
+    config.udp_mode(UdpMode::Single);
+    let (mut stack, mut host) = stack(config);
+    let mut socket = stack.udp_socket().unwrap();
+    assert!(stack.udp_socket().is_none());
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"one"));
+    host.send(udp("10.0.0.3:6000", "5.6.7.8:123", b"two"));
+    let (payload, src, dst) = socket.recv_from().await.unwrap();
+    assert_eq!(
+        (payload.as_ref(), src, dst),
+        (&b"one"[..], addr("10.0.0.2:5000"), addr("1.2.3.4:53"))
+    );
+    let (payload, src, dst) = socket.recv_from().await.unwrap();
+    assert_eq!(
+        (payload.as_ref(), src, dst),
+        (&b"two"[..], addr("10.0.0.3:6000"), addr("5.6.7.8:123"))
+    );
+
+    socket
+        .send_to(Bytes::from_static(b"reply"), dst, src)
+        .unwrap();
+    let reply = Packet::parse(&host.recv().await);
+    assert_eq!((reply.src, reply.dst), (dst.ip(), src.ip()));
+    assert_eq!(
+        (reply.udp().source_port, reply.udp().destination_port),
+        (123, 6000)
+    );
+    assert_eq!(reply.payload, b"reply");
+
+    // Mixed IP versions are refused
+    let err = socket
+        .send_to(Bytes::new(), addr("[::1]:53"), src)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn send_unsolicited() {
+    let mut config = IpStackConfig::default();
+    config.ttl(32);
+    let (stack, mut host) = stack(config);
+
+    let (src, dst) = (addr("[2001:db8::1]:53"), addr("[fd00::2]:5000"));
+    stack
+        .udp_send_to(src, dst, Bytes::from_static(b"push"))
+        .unwrap();
+    let packet = Packet::parse(&host.recv().await);
+    assert_eq!(
+        (packet.src, packet.dst, packet.ttl),
+        (src.ip(), dst.ip(), 32)
+    );
+    assert_eq!(
+        (packet.udp().source_port, packet.udp().destination_port),
+        (53, 5000)
+    );
+    assert_eq!(packet.payload, b"push");
+
+    let err = stack
+        .udp_send_to(addr("1.2.3.4:53"), dst, Bytes::new())
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn close_and_expiry() {
+    let mut config = IpStackConfig::default();
+    config.udp_timeout(Duration::from_millis(100));
+    let (mut stack, host) = stack(config);
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"first"));
+    let mut stream = accept_udp(&mut stack).await;
+    let closed = stream.closed();
+    stream.close();
+    timeout(Duration::from_secs(1), closed).await.unwrap();
+    let err = stream.send_datagram(Bytes::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    assert_eq!(stream.recv_datagram().await.unwrap(), "first");
+
+    // The tuple is free again, the next datagram opens a new flow that expires when idle
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"second"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "second");
+    timeout(Duration::from_secs(1), stream.closed())
+        .await
+        .unwrap();
+    let err = stream.recv_datagram().await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+}
+
+/// Sends a datagram on `stream` every 40ms until `closed` resolves, false if it does not
+/// within 500ms.
+async fn expires_while_sending(
+    stream: &mut IpStackUdpStream,
+    closed: impl std::future::Future<Output = ()>,
+) -> bool {
+    let keepalive = async {
+        loop {
+            stream.send_datagram(Bytes::from_static(b"out")).unwrap();
+            tokio::time::sleep(Duration::from_millis(40)).await;
+        }
+    };
+    tokio::select! {
+        _ = closed => true,
+        _ = keepalive => unreachable!(),
+        _ = tokio::time::sleep(Duration::from_millis(500)) => false,
+    }
+}
+
+#[tokio::test]
+async fn timeout_refresh() {
+    let mut config = IpStackConfig::default();
+    config
+        .udp_timeout(Duration::from_millis(200))
+        .udp_timeout_refresh(UdpTimeoutRefresh::Inbound);
+    let (mut stack, host) = stack(config);
+
+    // Sending does not keep a flow refreshed by inbound datagrams alive
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"in"));
+    let mut stream = accept_udp(&mut stack).await;
+    let closed = stream.closed();
+    assert!(expires_while_sending(&mut stream, closed).await);
+
+    // Unless the stream overrides the policy
+    host.send(udp("10.0.0.2:5001", "1.2.3.4:53", b"in"));
+    let mut stream = accept_udp(&mut stack).await;
+    stream.set_timeout_refresh(UdpTimeoutRefresh::Both);
+    let closed = stream.closed();
+    assert!(!expires_while_sending(&mut stream, closed).await);
+}
+
+#[tokio::test]
+async fn fragmentation() {
+    let mut config = IpStackConfig::default();
+    config.mtu(576);
+    let (mut stack, mut host) = stack(config);
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"query"));
+    let mut stream = accept_udp(&mut stack).await;
+    let datagram: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+    stream.send_datagram(datagram.clone().into()).unwrap();
+
+    // The fragments fit the MTU and put together carry the UDP header and the datagram
+    let mut reassembled = Vec::new();
+    loop {
+        let fragment = host.recv().await;
+        assert!(fragment.len() <= 576);
+        let ip = Ipv4HeaderSlice::from_slice(&fragment).unwrap();
+        assert_eq!(
+            ip.fragments_offset().byte_offset() as usize,
+            reassembled.len()
+        );
+        reassembled.extend_from_slice(&fragment[ip.slice().len()..]);
+        if !ip.more_fragments() {
+            break;
+        }
+    }
+    let header = UdpHeaderSlice::from_slice(&reassembled).unwrap();
+    assert_eq!(header.length() as usize, reassembled.len());
+    assert_eq!(reassembled[8..], datagram);
+
+    // Without fragmentation the datagram is refused
+    let mut config = IpStackConfig::default();
+    config.mtu(576).udp_fragmentation(false);
+    let (mut stack, host) = common::stack(config);
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"query"));
+    let mut stream = accept_udp(&mut stack).await;
+    let result = stream.send_datagram(datagram.into());
+    assert!(result.is_err());
+    #[cfg(unix)]
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EMSGSIZE));
+}
+
+#[tokio::test]
+async fn segmented_send() {
+    let (mut stack, mut host) = stack(IpStackConfig::default());
+
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:443", b"hello"));
+    let mut stream = accept_udp(&mut stack).await;
+    stream
+        .send_segmented(Bytes::from_static(b"0123456789"), 4)
+        .unwrap();
+    for segment in [&b"0123"[..], b"4567", b"89"] {
+        let packet = Packet::parse(&host.recv().await);
+        assert_eq!(packet.udp().length as usize, 8 + segment.len());
+        assert_eq!(packet.payload, segment);
+    }
+    assert_eq!(stream.stats().datagrams_sent, 3);
+
+    let err = stream
+        .send_segmented(Bytes::from_static(b"x"), 0)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn tos() {
+    let (mut stack, mut host) = stack(IpStackConfig::default());
+
+    // DSCP 46 (expedited forwarding) with CE
+    let mut datagram = udp("10.0.0.2:5000", "1.2.3.4:443", b"quic");
+    set_tos(&mut datagram, 46 << 2 | 0b11);
+    host.send(datagram);
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(
+        stream.recv_datagram_with_tos().await.unwrap(),
+        (Bytes::from_static(b"quic"), 46 << 2 | 0b11)
+    );
+
+    // Replies keep the DSCP of the flow without its ECN codepoint
+    assert_eq!(stream.dscp(), 46);
+    stream.send_datagram(Bytes::from_static(b"a")).unwrap();
+    assert_eq!(Packet::parse(&host.recv().await).tos, 46 << 2);
+    stream.set_tos(0b10);
+    stream.set_dscp(10);
+    stream.send_datagram(Bytes::from_static(b"b")).unwrap();
+    assert_eq!(Packet::parse(&host.recv().await).tos, 10 << 2 | 0b10);
+}
+
+#[tokio::test]
+async fn port_unreachable() {
+    let (mut stack, mut host) = stack(IpStackConfig::default());
+
+    let datagram = udp("10.0.0.2:5000", "1.2.3.4:53", b"query");
+    host.send(datagram.clone());
+    accept_udp(&mut stack).await.reject();
+    let error = Packet::parse(&host.recv().await);
+    assert_eq!((error.src, error.dst), (ip("1.2.3.4"), ip("10.0.0.2")));
+    let Some(TransportHeader::Icmpv4(icmp)) = error.transport else {
+        panic!("no ICMP error");
+    };
+    assert_eq!(
+        icmp.icmp_type,
+        Icmpv4Type::DestinationUnreachable(DestUnreachableHeader::Port)
+    );
+    assert_eq!(error.payload, datagram);
+
+    // Not when turned off
+    let mut config = IpStackConfig::default();
+    config.udp_port_unreachable(false);
+    let (mut stack, mut host) = common::stack(config);
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"query"));
+    accept_udp(&mut stack).await.reject();
+    host.expect_none(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn udp_lite_flow() {
+    let mut config = IpStackConfig::default();
+    config.validate_checksums(true);
+    let (mut stack, mut host) = stack(config);
+
+    // Corruption beyond the checksum coverage goes unnoticed
+    let mut datagram = udp_lite("10.0.0.2:5000", "1.2.3.4:5004", 12, b"headbody");
+    *datagram.last_mut().unwrap() ^= 0xff;
+    host.send(datagram);
+    let mut stream = accept_udp(&mut stack).await;
+    assert!(stream.is_udp_lite());
+    assert_eq!(stream.recv_datagram().await.unwrap(), &b"headbod\x86"[..]);
+    assert_eq!(stream.checksum_coverage(), Some(12));
+
+    // Replies are UDP-Lite covering what was set
+    stream.set_checksum_coverage(10);
+    stream.send_datagram(Bytes::from_static(b"reply")).unwrap();
+    let reply = Packet::parse(&host.recv().await);
+    assert_eq!(reply.protocol, IpNumber::UDP_LITE);
+    assert_eq!(reply.payload[..2], 5004u16.to_be_bytes());
+    assert_eq!(reply.payload[2..4], 5000u16.to_be_bytes());
+    assert_eq!(reply.payload[4..6], 10u16.to_be_bytes());
+    assert_eq!(reply.payload[8..], *b"reply");
+}
+
+#[tokio::test]
+async fn broadcast() {
+    let mut config = IpStackConfig::default();
+    config
+        .udp_broadcast(UdpBroadcastPolicy::Deliver)
+        .subnet_broadcast(Ipv4Addr::new(10, 0, 0, 255));
+    let (mut stack, host) = stack(config);
+
+    for dst in [
+        "255.255.255.255:67",
+        "10.0.0.255:137",
+        "239.255.255.250:1900",
+    ] {
+        host.send(udp("10.0.0.2:5000", dst, b"hello"));
+        let IpStackStream::UdpBroadcast(broadcast) = accept(&mut stack).await else {
+            panic!("no broadcast for {dst}");
+        };
+        assert_eq!(broadcast.local_addr(), addr("10.0.0.2:5000"));
+        assert_eq!(broadcast.peer_addr(), addr(dst));
+        assert_eq!(broadcast.payload(), "hello");
+    }
+
+    // Dropped, only the unicast datagram behind it is accepted
+    let mut config = IpStackConfig::default();
+    config.udp_broadcast(UdpBroadcastPolicy::Drop);
+    let (mut stack, host) = common::stack(config);
+    host.send(udp("10.0.0.2:5000", "255.255.255.255:67", b"dropped"));
+    host.send(udp("10.0.0.2:5000", "1.2.3.4:53", b"unicast"));
+    let mut stream = accept_udp(&mut stack).await;
+    assert_eq!(stream.recv_datagram().await.unwrap(), "unicast");
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use common::{accept, ip, stack};
+use etherparse::{IpNumber, Ipv6Header, UdpHeader};
+use ipstack::{stream::IpStackStream, IpStackConfig};
+use std::net::IpAddr;
+
+fn octets(addr: &str) -> [u8; 16] {
+    match ip(addr) {
+        IpAddr::V6(ip) => ip.octets(),
+        IpAddr::V4(_) => panic!("not an IPv6 address"),
+    }
+}
+
+#[tokio::test]
+async fn send_raw() {
+    let (stack, mut host) = stack(IpStackConfig::default());
+
+    // A datagram behind a Hop-by-Hop Options header with a PadN option
+    let payload = b"ping";
+    let ip_header = Ipv6Header {
+        payload_length: (8 + UdpHeader::LEN + payload.len()) as u16,
+        next_header: IpNumber::IPV6_HEADER_HOP_BY_HOP,
+        hop_limit: 64,
+        source: octets("fd00::1"),
+        destination: octets("fd00::2"),
+        ..Default::default()
+    };
+    let udp = UdpHeader::with_ipv6_checksum(5000, 53, &ip_header, payload).unwrap();
+    let mut packet = ip_header.to_bytes().to_vec();
+    packet.extend_from_slice(&[IpNumber::UDP.0, 0, 1, 4, 0, 0, 0, 0]);
+    packet.extend_from_slice(&udp.to_bytes());
+    packet.extend_from_slice(payload);
+
+    stack.send_raw(&packet).unwrap();
+    assert_eq!(host.recv().await, packet);
+
+    let err = stack.send_raw(&packet[..20]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn reply_ipv6() {
+    let mut config = IpStackConfig::default();
+    config.mtu(1280);
+    let (mut stack, mut host) = stack(config);
+
+    let ospf = IpNumber(89);
+    let hello = b"hello";
+    let ip_header = Ipv6Header {
+        payload_length: hello.len() as u16,
+        next_header: ospf,
+        hop_limit: 1,
+        source: octets("fe80::1"),
+        destination: octets("ff02::5"),
+        ..Default::default()
+    };
+    let mut packet = ip_header.to_bytes().to_vec();
+    packet.extend_from_slice(hello);
+    host.send(packet);
+    let IpStackStream::UnknownTransport(unknown) = accept(&mut stack).await else {
+        panic!("no unknown transport");
+    };
+    assert_eq!(unknown.ip_protocol(), ospf);
+    assert_eq!(unknown.payload(), hello);
+
+    // A reply over the MTU leaves split, with the protocol it arrived with
+    let reply = (0..2000).map(|i| i as u8).collect::<Vec<u8>>();
+    unknown.send(reply.clone()).unwrap();
+    let mut received = Vec::new();
+    while received.len() < reply.len() {
+        let packet = host.recv().await;
+        assert!(packet.len() <= 1280);
+        let (header, payload) = Ipv6Header::from_slice(&packet).unwrap();
+        assert_eq!(header.next_header, ospf);
+        assert_eq!(header.payload_length as usize, payload.len());
+        assert_eq!(
+            (header.source, header.destination),
+            (octets("ff02::5"), octets("fe80::1"))
+        );
+        received.extend_from_slice(payload);
+    }
+    assert_eq!(received, reply);
+}